@@ -101,6 +101,7 @@ impl EncryptionObserver for CliEncryptionObserver {
     fn failed(&self, error: HelixError) {
         let message = format!("Encryption failed, Reason : {}", error.message);
         self.print_file_message(&message);
+        println!();
     }
 
     fn end(&self, end_state: EncryptionEndState) {
@@ -109,7 +110,7 @@ impl EncryptionObserver for CliEncryptionObserver {
             EncryptionEndState::Unchanged => "Unchanged",
         };
         self.print_file_message(message);
-        print!("\n");
+        println!();
     }
 }
 
@@ -174,8 +175,9 @@ impl DecryptionObserver for CliDecryptionObserver {
     }
 
     fn failed(&self, error: HelixError) {
-        let message = format!("Encryption failed, Reason : {}", error.message);
+        let message = format!("Decryption failed, Reason : {}", error.message);
         self.print_file_message(&message);
+        println!();
     }
 
     fn end(&self, end_state: DecryptionEndState) {
@@ -185,7 +187,7 @@ impl DecryptionObserver for CliDecryptionObserver {
             DecryptionEndState::BlockNotFound => "Block not found",
         };
         self.print_file_message(message);
-        print!("\n");
+        println!();
     }
 
     fn init_size(&mut self, file_size: u64) {
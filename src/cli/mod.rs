@@ -1,6 +1,19 @@
+use crate::crypto::padding::Padding;
+use crate::crypto::recipient::Identity;
+use crate::crypto::suite::CipherSuite;
+use crate::filecrypto::chunking::Chunking;
+use crate::filecrypto::compression::{Compression, CompressionStats};
+use crate::helix_crypto::core::HelixCompactor;
 use crate::helix_crypto::core::HelixDecryptor;
 use crate::helix_crypto::core::HelixEncryptor;
+use crate::helix_crypto::core::HelixReKeyer;
+use crate::helix_crypto::core::HelixRecipientEncryptor;
+use crate::helix_crypto::recipients::HelixRecipients;
+use crate::helix_crypto::rotation::HelixMasterKeyRotator;
+use crate::helix_crypto::slots::HelixKeySlots;
 use clap::{command, Args, Parser, Subcommand};
+use secrecy::{ExposeSecret, SecretString};
+use zeroize::Zeroizing;
 use std::path::PathBuf;
 
 use self::file::CliDecryptionObserverFactory;
@@ -24,6 +37,24 @@ enum HelixSubCommand {
     Encrypt(EncryptArgs),
     ///Decrypts the files from helix capsule and puts it in target directory
     Decrypt(DecryptArgs),
+    ///Changes the passphrase of a helix capsule. Encrypted files are not touched
+    Passwd(UnlockArgs),
+    ///Sets a new passphrase with the recovery code printed when the capsule was created
+    Recover(UnlockArgs),
+    ///Replaces the master key and rewraps every file key. Encrypted files are not touched.
+    ///Other key slots have to be removed first
+    RotateMasterKey(UnlockArgs),
+    ///Re-encrypts every file of a helix capsule under a fresh file key.
+    ///Chunks of deduplicated files in .helix/chunks keep the capsule's chunk keys
+    Rekey(UnlockArgs),
+    ///Rewrites pack files that are mostly replaced blocks, or too small on their own, into full ones
+    Compact(UnlockArgs),
+    ///Manages the passphrase key slots of a helix capsule
+    Slot(SlotArgs),
+    ///Splits the master key into shares, a threshold of which decrypt without a passphrase. Rotating the master key invalidates them
+    Share(ShareArgs),
+    ///Manages the public key recipients of a helix capsule
+    Recipient(RecipientArgs),
 }
 
 #[derive(Args)]
@@ -32,23 +63,202 @@ struct EncryptArgs {
     #[arg(short, long, value_name = "DIRECTORY")]
     source: Option<PathBuf>,
 
-    ///The location of helix capsule. Defaults to current working directory
-    #[arg(short, long, value_name = "DIRECTORY")]
+    ///The location of helix capsule, a directory or a single file ending in .hlx. Defaults to current working directory
+    #[arg(short, long, value_name = "PATH")]
     target: Option<PathBuf>,
 
     #[arg(short, long)]
-    delete: bool
+    delete: bool,
+
+    ///Cipher suite for files encrypted in this run: xchacha20poly1305, chacha20poly1305 or aes256gcm. Remembered as the capsule default
+    #[arg(short, long, value_name = "SUITE")]
+    cipher: Option<String>,
+
+    ///Pads blocks so their size hides the exact file size: none, padme or pow2. Remembered as the capsule default
+    #[arg(short, long, value_name = "PADDING")]
+    padding: Option<String>,
+
+    ///Compresses every chunk that shrinks before encrypting it: none or zstd. Remembered as the capsule default
+    #[arg(short = 'z', long, value_name = "COMPRESSION")]
+    compression: Option<String>,
+
+    ///Splits files at content-defined boundaries and stores each distinct chunk once: fixed or cdc. Remembered as the capsule default, not used with --index-key
+    #[arg(long, value_name = "CHUNKING")]
+    chunking: Option<String>,
+
+    ///Packs small files into encrypted pack files of about this many MiB, hiding the file count. 0 turns packing off. Remembered as the capsule default
+    #[arg(long, value_name = "MIB")]
+    pack_size: Option<u64>,
+
+    ///Encrypts without a passphrase using an index key exported with `recipient index-key`. Files are wrapped to the capsule recipients only
+    #[arg(short, long, value_name = "FILE")]
+    index_key: Option<PathBuf>,
+
+    ///Keyfile needed together with the passphrase. Leave the passphrase empty to unlock with the keyfile alone
+    #[arg(short, long, value_name = "FILE")]
+    keyfile: Option<PathBuf>,
 }
 
 #[derive(Args)]
 struct DecryptArgs {
-    ///The location of helix capsule. Defaults to current working directory
-    #[arg(short, long, value_name = "DIRECTORY")]
+    ///The location of helix capsule, a directory or a single .hlx file. Defaults to current working directory
+    #[arg(short, long, value_name = "PATH")]
     source: Option<PathBuf>,
 
     ///The location where all files will be decrypted. Defaults to current working directory
     #[arg(short, long, value_name = "DIRECTORY")]
     target: Option<PathBuf>,
+
+    ///Decrypts with the identity file of a recipient instead of a passphrase
+    #[arg(short, long, value_name = "FILE")]
+    identity: Option<PathBuf>,
+
+    ///Keyfile needed together with the passphrase
+    #[arg(short, long, value_name = "FILE")]
+    keyfile: Option<PathBuf>,
+
+    ///Decrypts with master key shares instead of a passphrase. Repeat for each share file
+    #[arg(long, value_name = "FILE")]
+    share: Vec<PathBuf>,
+
+    ///Capsule generation printed by the last encrypt. Refuses to decrypt an older copy of the capsule
+    #[arg(short, long, value_name = "GENERATION")]
+    min_generation: Option<u64>,
+}
+
+#[derive(Args)]
+struct SlotArgs {
+    #[command(subcommand)]
+    subcommand: SlotSubCommand,
+}
+
+#[derive(Subcommand)]
+enum SlotSubCommand {
+    ///Adds a key slot with a new passphrase
+    Add(SlotLabelArgs),
+    ///Lists the labels of all key slots
+    List(CapsuleArgs),
+    ///Removes a key slot. The last slot can not be removed
+    Remove(SlotLabelArgs),
+}
+
+#[derive(Args)]
+struct ShareArgs {
+    ///The location of helix capsule. Defaults to current working directory
+    #[arg(short, long, value_name = "DIRECTORY")]
+    capsule: Option<PathBuf>,
+
+    ///Number of shares needed to decrypt
+    #[arg(short, long)]
+    threshold: u8,
+
+    ///Number of shares to create
+    #[arg(short, long)]
+    shares: u8,
+
+    ///Directory to write one file per share to. Shares are printed when not given
+    #[arg(short, long, value_name = "DIRECTORY")]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    keyfile: KeyfileArgs,
+}
+
+#[derive(Args)]
+struct RecipientArgs {
+    #[command(subcommand)]
+    subcommand: RecipientSubCommand,
+}
+
+#[derive(Subcommand)]
+enum RecipientSubCommand {
+    ///Generates a new identity file. Its public key is written as a comment
+    Keygen(OutputArgs),
+    ///Adds a recipient by public key. The recipient's identity can decrypt the capsule
+    Add(RecipientAddArgs),
+    ///Lists the labels and public keys of all recipients
+    List(CapsuleArgs),
+    ///Removes a recipient
+    Remove(SlotLabelArgs),
+    ///Exports the index key needed to encrypt without a passphrase. It can not decrypt
+    IndexKey(CapsuleOutputArgs),
+}
+
+#[derive(Args)]
+struct OutputArgs {
+    ///File to write to
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct CapsuleOutputArgs {
+    ///The location of helix capsule. Defaults to current working directory
+    #[arg(short, long, value_name = "DIRECTORY")]
+    capsule: Option<PathBuf>,
+
+    ///File to write to
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+
+    #[command(flatten)]
+    keyfile: KeyfileArgs,
+}
+
+#[derive(Args)]
+struct RecipientAddArgs {
+    ///The location of helix capsule. Defaults to current working directory
+    #[arg(short, long, value_name = "DIRECTORY")]
+    capsule: Option<PathBuf>,
+
+    ///Label of the recipient, e.g. the name of the person using it
+    #[arg(short, long)]
+    label: String,
+
+    ///Public key of the recipient as printed by `recipient keygen`
+    #[arg(short, long, value_name = "KEY")]
+    public_key: String,
+
+    #[command(flatten)]
+    keyfile: KeyfileArgs,
+}
+
+#[derive(Args)]
+struct CapsuleArgs {
+    ///The location of helix capsule. Defaults to current working directory
+    #[arg(short, long, value_name = "DIRECTORY")]
+    capsule: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct UnlockArgs {
+    ///The location of helix capsule. Defaults to current working directory
+    #[arg(short, long, value_name = "DIRECTORY")]
+    capsule: Option<PathBuf>,
+
+    #[command(flatten)]
+    keyfile: KeyfileArgs,
+}
+
+#[derive(Args)]
+struct KeyfileArgs {
+    ///Keyfile needed together with the passphrase, for key slots that use one
+    #[arg(short, long, value_name = "FILE")]
+    keyfile: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct SlotLabelArgs {
+    ///The location of helix capsule. Defaults to current working directory
+    #[arg(short, long, value_name = "DIRECTORY")]
+    capsule: Option<PathBuf>,
+
+    ///Label of the key slot, e.g. the name of the person using it
+    #[arg(short, long)]
+    label: String,
+
+    #[command(flatten)]
+    keyfile: KeyfileArgs,
 }
 
 pub fn execute_helix_command() {
@@ -56,6 +266,14 @@ pub fn execute_helix_command() {
     match command.subcommand {
         HelixSubCommand::Encrypt(enc_args) => encrypt(enc_args),
         HelixSubCommand::Decrypt(dec_args) => decrypt(dec_args),
+        HelixSubCommand::Passwd(passwd_args) => passwd(passwd_args),
+        HelixSubCommand::Recover(recover_args) => recover(recover_args),
+        HelixSubCommand::RotateMasterKey(rotate_args) => rotate_master_key(rotate_args),
+        HelixSubCommand::Rekey(rekey_args) => rekey(rekey_args),
+        HelixSubCommand::Compact(compact_args) => compact(compact_args),
+        HelixSubCommand::Slot(slot_args) => slot(slot_args),
+        HelixSubCommand::Share(share_args) => share(share_args),
+        HelixSubCommand::Recipient(recipient_args) => recipient(recipient_args),
     }
 }
 
@@ -68,10 +286,75 @@ fn encrypt(enc_args: EncryptArgs) {
         None => String::from("."),
         Some(e) => e.to_str().unwrap().to_owned(),
     };
-    let passphrase = rpassword::prompt_password("Enter passphrase: ").unwrap();
+    let cipher_suite = match enc_args.cipher.map(|name| CipherSuite::from_name(&name)) {
+        None => None,
+        Some(Ok(cipher_suite)) => Some(cipher_suite),
+        Some(Err(e)) => {
+            println!("Failed to encrypt, Reason : {}", e.message);
+            return;
+        }
+    };
+    let padding = match enc_args.padding.map(|name| Padding::from_name(&name)) {
+        None => None,
+        Some(Ok(padding)) => Some(padding),
+        Some(Err(e)) => {
+            println!("Failed to encrypt, Reason : {}", e.message);
+            return;
+        }
+    };
+    let compression = match enc_args.compression.map(|name| Compression::from_name(&name)) {
+        None => None,
+        Some(Ok(compression)) => Some(compression),
+        Some(Err(e)) => {
+            println!("Failed to encrypt, Reason : {}", e.message);
+            return;
+        }
+    };
+    let chunking = match enc_args.chunking.map(|name| Chunking::from_name(&name)) {
+        None => None,
+        Some(Ok(chunking)) => Some(chunking),
+        Some(Err(e)) => {
+            println!("Failed to encrypt, Reason : {}", e.message);
+            return;
+        }
+    };
+    let pack_size = enc_args.pack_size.map(|mib| mib.saturating_mul(1024 * 1024));
+    if let Some(index_key_path) = enc_args.index_key {
+        let index_key = match std::fs::read_to_string(index_key_path) {
+            Ok(index_key) => Zeroizing::new(index_key),
+            Err(e) => {
+                println!("Failed to encrypt, Reason : {}", e);
+                return;
+            }
+        };
+        let mut encryptor = HelixRecipientEncryptor::from(
+            &source,
+            &destination,
+            &index_key,
+            &CliEncryptionObserverFactory,
+            enc_args.delete,
+            cipher_suite,
+        )
+        .with_padding(padding)
+        .with_compression(compression)
+        .with_pack_size(pack_size);
+        if let Err(e) = encryptor.encrypt() {
+            println!("Failed to encrypt, Reason : {}", e.message);
+        }
+        print_compression_stats(encryptor.compression_stats());
+        return;
+    }
+    let keyfile = match read_keyfile(enc_args.keyfile) {
+        Ok(keyfile) => keyfile,
+        Err(e) => {
+            println!("Failed to encrypt, Reason : {}", e);
+            return;
+        }
+    };
+    let passphrase = prompt_secret("Enter passphrase: ");
     if !HelixEncryptor::has_helix_folder(&destination) {
-        let confirm_passphrase = rpassword::prompt_password("Confirm passphrase: ").unwrap();
-        if !confirm_passphrase.eq(&passphrase) {
+        let confirm_passphrase = prompt_secret("Confirm passphrase: ");
+        if confirm_passphrase.expose_secret() != passphrase.expose_secret() {
             println!("Passphrase did not match. Try again!");
             return;
         }
@@ -81,10 +364,38 @@ fn encrypt(enc_args: EncryptArgs) {
         &destination,
         &passphrase,
         &CliEncryptionObserverFactory,
-        enc_args.delete
-    );
-    if let Err(e) = encryptor.encrypt() {
-        println!("Failed to encrypt, Reason : {}", e.message);
+        enc_args.delete,
+        cipher_suite,
+    )
+    .with_padding(padding)
+    .with_compression(compression)
+    .with_chunking(chunking)
+    .with_pack_size(pack_size)
+    .with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    let result = encryptor.encrypt();
+    print_compression_stats(encryptor.compression_stats());
+    if let Some(recovery_code) = encryptor.take_recovery_code() {
+        println!("Recovery code: {}", recovery_code.as_str());
+        println!("Write it down and keep it safe. It is shown only once and resets the passphrase with `helix recover`.");
+    }
+    match result {
+        Ok(_) => {
+            if let Some(generation) = encryptor.generation() {
+                println!("Capsule generation: {}", generation);
+            }
+        }
+        Err(e) => println!("Failed to encrypt, Reason : {}", e.message),
+    }
+}
+
+fn print_compression_stats(stats: Option<CompressionStats>) {
+    if let Some(stats) = stats.filter(|stats| stats.plain_bytes > 0) {
+        println!(
+            "Compressed {} bytes to {} bytes ({:.1}%)",
+            stats.plain_bytes,
+            stats.stored_bytes,
+            stats.ratio()
+        );
     }
 }
 
@@ -97,14 +408,389 @@ fn decrypt(dec_args: DecryptArgs) {
         None => String::from("."),
         Some(e) => e.to_str().unwrap().to_owned(),
     };
-    let passphrase = rpassword::prompt_password("Enter passphrase: ").unwrap();
+    if !dec_args.share.is_empty() {
+        let shares: std::io::Result<Vec<Zeroizing<String>>> = dec_args
+            .share
+            .iter()
+            .map(|share| std::fs::read_to_string(share).map(Zeroizing::new))
+            .collect();
+        let shares = match shares {
+            Ok(shares) => shares,
+            Err(e) => decryption_failed(&e.to_string()),
+        };
+        let mut decryptor = HelixDecryptor::from_shares(
+            &source,
+            &destination,
+            &shares,
+            &CliDecryptionObserverFactory,
+        )
+        .with_min_generation(dec_args.min_generation);
+        if let Err(e) = decryptor.decrypt() {
+            decryption_failed(&e.message);
+        }
+        return;
+    }
+    if let Some(identity_path) = dec_args.identity {
+        let identity = std::fs::read_to_string(identity_path)
+            .map_err(|e| e.to_string())
+            .and_then(|identity| Identity::from_hex(&identity).map_err(|e| e.message));
+        let identity = match identity {
+            Ok(identity) => identity,
+            Err(message) => decryption_failed(&message),
+        };
+        let mut decryptor = HelixDecryptor::from_identity(
+            &source,
+            &destination,
+            &identity,
+            &CliDecryptionObserverFactory,
+        )
+        .with_min_generation(dec_args.min_generation);
+        if let Err(e) = decryptor.decrypt() {
+            decryption_failed(&e.message);
+        }
+        return;
+    }
+    let keyfile = match read_keyfile(dec_args.keyfile) {
+        Ok(keyfile) => keyfile,
+        Err(e) => decryption_failed(&e.to_string()),
+    };
+    let passphrase = prompt_secret("Enter passphrase: ");
     let mut decryptor = HelixDecryptor::from(
         &source,
         &destination,
         &passphrase,
         &CliDecryptionObserverFactory,
-    );
+    )
+    .with_keyfile(keyfile.as_deref().map(Vec::as_slice))
+    .with_min_generation(dec_args.min_generation);
     if let Err(e) = decryptor.decrypt() {
-        println!("Failed to decrypt, Reason : {}", e.message);
+        decryption_failed(&e.message);
+    }
+}
+
+/// A decryption that left files behind exits with a failure status, so scripts do
+/// not mistake it for a complete restore.
+fn decryption_failed(reason: &str) -> ! {
+    println!("Failed to decrypt, Reason : {}", reason);
+    std::process::exit(1)
+}
+
+fn passwd(args: UnlockArgs) {
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let Some(key_slots) = open_key_slots(&capsule_path(args.capsule)) else {
+        return;
+    };
+    let mut key_slots = key_slots.with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    let passphrase = prompt_secret("Enter current passphrase: ");
+    let new_passphrase = prompt_secret("Enter new passphrase: ");
+    let confirm_passphrase = prompt_secret("Confirm new passphrase: ");
+    if confirm_passphrase.expose_secret() != new_passphrase.expose_secret() {
+        println!("Passphrase did not match. Try again!");
+        return;
+    }
+    let result = key_slots.change_passphrase(passphrase.expose_secret(), new_passphrase.expose_secret());
+    match result {
+        Ok(_) => println!("Passphrase changed"),
+        Err(e) => println!("Failed to change passphrase, Reason : {}", e.message),
+    }
+}
+
+fn recover(args: UnlockArgs) {
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let Some(key_slots) = open_key_slots(&capsule_path(args.capsule)) else {
+        return;
+    };
+    let mut key_slots = key_slots.with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    let recovery_code = prompt_secret("Enter recovery code: ");
+    let new_passphrase = prompt_secret("Enter new passphrase: ");
+    let confirm_passphrase = prompt_secret("Confirm new passphrase: ");
+    if confirm_passphrase.expose_secret() != new_passphrase.expose_secret() {
+        println!("Passphrase did not match. Try again!");
+        return;
+    }
+    match key_slots.recover(recovery_code.expose_secret(), new_passphrase.expose_secret()) {
+        Ok(_) => println!("Passphrase reset"),
+        Err(e) => println!("Failed to recover, Reason : {}", e.message),
+    }
+}
+
+fn rotate_master_key(args: UnlockArgs) {
+    let capsule = capsule_path(args.capsule);
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let passphrase = prompt_secret("Enter passphrase: ");
+    let result = HelixMasterKeyRotator::open(&capsule).and_then(|rotator| {
+        rotator
+            .with_keyfile(keyfile.as_deref().map(Vec::as_slice))
+            .rotate(passphrase.expose_secret())
+    });
+    match result {
+        Ok(recovery_code) => {
+            println!("Master key rotated");
+            if let Some(recovery_code) = recovery_code {
+                println!("New recovery code: {}", recovery_code.as_str());
+                println!("The previous recovery code no longer works.");
+            }
+        }
+        Err(e) => println!("Failed to rotate master key, Reason : {}", e.message),
+    }
+}
+
+fn rekey(args: UnlockArgs) {
+    let capsule = capsule_path(args.capsule);
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let passphrase = prompt_secret("Enter passphrase: ");
+    let rekeyer = HelixReKeyer::from(&capsule, &passphrase, &CliEncryptionObserverFactory)
+        .with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    if let Err(e) = rekeyer.rekey() {
+        println!("Failed to rekey, Reason : {}", e.message);
+    }
+}
+
+fn compact(args: UnlockArgs) {
+    let capsule = capsule_path(args.capsule);
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let passphrase = prompt_secret("Enter passphrase: ");
+    let compactor = HelixCompactor::from(&capsule, &passphrase)
+        .with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    match compactor.compact() {
+        Ok(moved) => println!("Repacked {} blocks", moved),
+        Err(e) => println!("Failed to compact, Reason : {}", e.message),
+    }
+}
+
+fn slot(slot_args: SlotArgs) {
+    match slot_args.subcommand {
+        SlotSubCommand::Add(args) => add_slot(args),
+        SlotSubCommand::List(args) => list_slots(args),
+        SlotSubCommand::Remove(args) => remove_slot(args),
+    }
+}
+
+fn share(args: ShareArgs) {
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let Some(key_slots) = open_key_slots(&capsule_path(args.capsule)) else {
+        return;
+    };
+    let mut key_slots = key_slots.with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    let passphrase = prompt_secret("Enter passphrase: ");
+    let shares = match key_slots.split(passphrase.expose_secret(), args.threshold, args.shares) {
+        Ok(shares) => shares,
+        Err(e) => {
+            println!("Failed to split master key, Reason : {}", e.message);
+            return;
+        }
+    };
+    let Some(output) = args.output else {
+        for share in shares {
+            println!("{}", share.as_str());
+        }
+        return;
+    };
+    if let Err(e) = std::fs::create_dir_all(&output) {
+        println!("Failed to write share, Reason : {}", e);
+        return;
+    }
+    for (index, share) in shares.iter().enumerate() {
+        let path = output.join(format!("share-{}.txt", index + 1));
+        if let Err(e) = write_secret(&path, &Zeroizing::new(format!("{}\n", share.as_str()))) {
+            println!("Failed to write share, Reason : {}", e);
+            return;
+        }
+        println!("Share {} written to {}", index + 1, path.display());
+    }
+}
+
+/// Reads a passphrase or code without echoing it. The secret is zeroized on drop.
+fn prompt_secret(prompt: &str) -> SecretString {
+    SecretString::from(rpassword::prompt_password(prompt).unwrap())
+}
+
+fn read_keyfile(keyfile: Option<PathBuf>) -> std::io::Result<Option<Zeroizing<Vec<u8>>>> {
+    keyfile.map(|keyfile| std::fs::read(keyfile).map(Zeroizing::new)).transpose()
+}
+
+/// Reads the keyfile of a command, None if it could not be read.
+fn load_keyfile(args: KeyfileArgs) -> Option<Option<Zeroizing<Vec<u8>>>> {
+    match read_keyfile(args.keyfile) {
+        Ok(keyfile) => Some(keyfile),
+        Err(e) => {
+            println!("Failed to read keyfile, Reason : {}", e);
+            None
+        }
+    }
+}
+
+fn capsule_path(capsule: Option<PathBuf>) -> String {
+    match capsule {
+        None => String::from("."),
+        Some(e) => e.to_str().unwrap().to_owned(),
+    }
+}
+
+fn open_key_slots(capsule: &str) -> Option<HelixKeySlots> {
+    match HelixKeySlots::open(capsule) {
+        Ok(key_slots) => Some(key_slots),
+        Err(e) => {
+            println!("Failed to open capsule, Reason : {}", e.message);
+            None
+        }
+    }
+}
+
+fn add_slot(args: SlotLabelArgs) {
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let Some(key_slots) = open_key_slots(&capsule_path(args.capsule)) else {
+        return;
+    };
+    let mut key_slots = key_slots.with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    let passphrase = prompt_secret("Enter an existing passphrase: ");
+    let new_passphrase = prompt_secret("Enter new passphrase: ");
+    let confirm_passphrase = prompt_secret("Confirm new passphrase: ");
+    if confirm_passphrase.expose_secret() != new_passphrase.expose_secret() {
+        println!("Passphrase did not match. Try again!");
+        return;
+    }
+    match key_slots.add(passphrase.expose_secret(), &args.label, new_passphrase.expose_secret()) {
+        Ok(_) => println!("Key slot {} added", args.label),
+        Err(e) => println!("Failed to add key slot, Reason : {}", e.message),
+    }
+}
+
+fn list_slots(args: CapsuleArgs) {
+    let Some(key_slots) = open_key_slots(&capsule_path(args.capsule)) else {
+        return;
+    };
+    for label in key_slots.list() {
+        println!("{}", label);
+    }
+}
+
+fn remove_slot(args: SlotLabelArgs) {
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let Some(key_slots) = open_key_slots(&capsule_path(args.capsule)) else {
+        return;
+    };
+    let mut key_slots = key_slots.with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    let passphrase = prompt_secret("Enter an existing passphrase: ");
+    match key_slots.remove(passphrase.expose_secret(), &args.label) {
+        Ok(_) => println!("Key slot {} removed", args.label),
+        Err(e) => println!("Failed to remove key slot, Reason : {}", e.message),
+    }
+}
+
+fn recipient(recipient_args: RecipientArgs) {
+    match recipient_args.subcommand {
+        RecipientSubCommand::Keygen(args) => keygen(args),
+        RecipientSubCommand::Add(args) => add_recipient(args),
+        RecipientSubCommand::List(args) => list_recipients(args),
+        RecipientSubCommand::Remove(args) => remove_recipient(args),
+        RecipientSubCommand::IndexKey(args) => export_index_key(args),
+    }
+}
+
+fn open_recipients(capsule: &str) -> Option<HelixRecipients> {
+    match HelixRecipients::open(capsule) {
+        Ok(recipients) => Some(recipients),
+        Err(e) => {
+            println!("Failed to open capsule, Reason : {}", e.message);
+            None
+        }
+    }
+}
+
+/// Writes a secret readable only by the current user.
+fn write_secret(path: &PathBuf, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents.as_bytes())
+}
+
+fn keygen(args: OutputArgs) {
+    let identity = Identity::generate();
+    let public_key = identity.recipient().to_hex();
+    let contents = Zeroizing::new(format!(
+        "# public key: {}\n{}\n",
+        public_key,
+        identity.to_hex().as_str()
+    ));
+    match write_secret(&args.output, &contents) {
+        Ok(_) => println!("Public key: {}", public_key),
+        Err(e) => println!("Failed to write identity, Reason : {}", e),
+    }
+}
+
+fn add_recipient(args: RecipientAddArgs) {
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let Some(recipients) = open_recipients(&capsule_path(args.capsule)) else {
+        return;
+    };
+    let mut recipients = recipients.with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    let passphrase = prompt_secret("Enter an existing passphrase: ");
+    match recipients.add(passphrase.expose_secret(), &args.label, &args.public_key) {
+        Ok(_) => println!("Recipient {} added", args.label),
+        Err(e) => println!("Failed to add recipient, Reason : {}", e.message),
+    }
+}
+
+fn list_recipients(args: CapsuleArgs) {
+    let Some(recipients) = open_recipients(&capsule_path(args.capsule)) else {
+        return;
+    };
+    for (label, public_key) in recipients.list() {
+        println!("{} {}", label, public_key);
+    }
+}
+
+fn remove_recipient(args: SlotLabelArgs) {
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let Some(recipients) = open_recipients(&capsule_path(args.capsule)) else {
+        return;
+    };
+    let mut recipients = recipients.with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    let passphrase = prompt_secret("Enter an existing passphrase: ");
+    match recipients.remove(passphrase.expose_secret(), &args.label) {
+        Ok(_) => println!("Recipient {} removed", args.label),
+        Err(e) => println!("Failed to remove recipient, Reason : {}", e.message),
+    }
+}
+
+fn export_index_key(args: CapsuleOutputArgs) {
+    let Some(keyfile) = load_keyfile(args.keyfile) else {
+        return;
+    };
+    let Some(recipients) = open_recipients(&capsule_path(args.capsule)) else {
+        return;
+    };
+    let mut recipients = recipients.with_keyfile(keyfile.as_deref().map(Vec::as_slice));
+    let passphrase = prompt_secret("Enter an existing passphrase: ");
+    let result = recipients
+        .index_key(passphrase.expose_secret())
+        .map_err(|e| e.message)
+        .and_then(|index_key| write_secret(&args.output, &index_key).map_err(|e| e.to_string()));
+    match result {
+        Ok(_) => println!("Index key written to {}", args.output.display()),
+        Err(message) => println!("Failed to export index key, Reason : {}", message),
     }
 }
@@ -0,0 +1,60 @@
+//! Associated data that binds ciphertexts to the files row they belong to.
+//!
+//! The wrapped file key, the encrypted path and the block chunks of a row are each
+//! sealed with the purpose of the field and the id of the row as associated data.
+//! A key moved to another row, a path moved into another field or a block renamed to
+//! the block of another file therefore fails authentication, even when it was
+//! encrypted under the same key.
+
+const FILE_KEY: &[u8] = b"helix/binding/file-key/v1";
+const FILE_PATH: &[u8] = b"helix/binding/file-path/v1";
+const CONTENT: &[u8] = b"helix/binding/content/v1";
+const PACK: &[u8] = b"helix/binding/pack/v1";
+
+/// Field of a files row a ciphertext is bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// `files.key`, wrapped under the master key or to recipients.
+    FileKey,
+    /// `files.file_path`.
+    FilePath,
+    /// The chunks of the block. The chunk index is added per chunk by the stream,
+    /// see `chacha::stream`. Chunks in the chunk store are bound to their chunk id.
+    Content,
+    /// The chunks of a pack file, bound to the pack id.
+    Pack,
+}
+
+impl Field {
+    fn label(&self) -> &'static [u8] {
+        match self {
+            Field::FileKey => FILE_KEY,
+            Field::FilePath => FILE_PATH,
+            Field::Content => CONTENT,
+            Field::Pack => PACK,
+        }
+    }
+}
+
+/// The purpose label followed by the length prefixed id.
+pub fn associated_data(field: Field, id: &str) -> Vec<u8> {
+    let label = field.label();
+    let mut associated_data = Vec::with_capacity(label.len() + 8 + id.len());
+    associated_data.extend_from_slice(label);
+    associated_data.extend_from_slice(&(id.len() as u64).to_be_bytes());
+    associated_data.extend_from_slice(id.as_bytes());
+    associated_data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{associated_data, Field};
+
+    #[test]
+    fn associated_data_test() {
+        let key = associated_data(Field::FileKey, "id");
+        assert_eq!(key, associated_data(Field::FileKey, "id"));
+        assert_ne!(key, associated_data(Field::FilePath, "id"));
+        assert_ne!(key, associated_data(Field::FileKey, "other id"));
+    }
+}
@@ -1,21 +1,28 @@
 pub mod keys {
-    use chacha20poly1305::{
-        aead::{generic_array::GenericArray, OsRng},
-        consts::{U12, U32},
-        AeadCore, ChaCha20Poly1305, KeyInit,
-    };
+    use std::{collections::BTreeMap, fmt, ops::RangeInclusive, sync::Mutex};
+
+    use chacha20poly1305::{aead::OsRng, AeadCore, ChaCha20Poly1305, KeyInit};
     use json::object;
     use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+    use zeroize::{Zeroize, Zeroizing};
 
     use crate::{
-        crypto::{ByteDecryptor, ByteEncryptor},
-        util::hex::{decode, decode_vec, encode, encode_vec},
+        crypto::{suite::CipherSuite, ByteDecryptor, ByteEncryptor},
+        errors::HelixError,
+        util::hex::{decode_vec, encode_vec},
     };
 
     use super::{decryptors::ByteDecryptorImpl, encryptors::ByteEncryptorImpl};
 
-    const KEY_SIZE: usize = 32;
-    const NONCE_SIZE: usize = 12;
+    pub const KEY_SIZE: usize = 32;
+    const LEGACY_NONCE_SIZE: usize = 12;
+    pub const KEY_MATERIAL_SIZE: usize = KEY_SIZE + LEGACY_NONCE_SIZE;
+    const WRAP_FORMAT_VERSION: u32 = 2;
+    /// Same as v2, sealed with associated data, see `crypto::binding`.
+    const BOUND_WRAP_FORMAT_VERSION: u32 = 3;
+    /// Room for the nonce and tag a wrapped key grows by, so that the plain key is
+    /// sealed in place and never left behind in a reallocated buffer.
+    const WRAP_OVERHEAD: usize = 24 + 16;
 
     pub struct StorableKey {
         key: String,
@@ -23,87 +30,258 @@ pub mod keys {
     }
 
     pub struct KeyDecryptor<'a> {
+        master_key: &'a Key,
         byte_decryptor: ByteDecryptorImpl<'a>,
+        bound_decryptor: Option<ByteDecryptorImpl<'a>>,
+        legacy_nonce: Option<&'a [u8]>,
     }
 
     impl<'a> KeyDecryptor<'a> {
         pub fn from(master_key: &'a Key) -> Self {
             let byte_decryptor = ByteDecryptorImpl::from(master_key);
-            return KeyDecryptor { byte_decryptor };
+            return KeyDecryptor {
+                master_key,
+                byte_decryptor,
+                bound_decryptor: None,
+                legacy_nonce: None,
+            };
+        }
+
+        /// Associated data v3 keys were sealed with. Keys wrapped before binding
+        /// existed are still opened without it.
+        pub fn with_associated_data(mut self, associated_data: Vec<u8>) -> Self {
+            self.bound_decryptor = Some(
+                ByteDecryptorImpl::from(self.master_key).with_associated_data(associated_data),
+            );
+            self
         }
 
-        pub fn decrypt(&self, key_string: &str) -> Key {
-            let key_json = json::parse(key_string).unwrap();
-            let nonce_ge = {
-                let nonce = key_json["nonce"].to_string();
-                let mut nonce_bytes = [0u8; NONCE_SIZE];
-                decode(nonce, &mut nonce_bytes);
-                *GenericArray::from_slice(&nonce_bytes)
+        /// Also accepts keys wrapped in the v1 format, which encrypted with a fixed nonce
+        /// stored next to the wrapping key: master keys wrapped by a passphrase, and file
+        /// keys wrapped by the master key itself.
+        pub fn with_legacy_nonce(master_key: &'a Key, legacy_nonce: &'a [u8]) -> Self {
+            let byte_decryptor = ByteDecryptorImpl::from(master_key);
+            return KeyDecryptor {
+                master_key,
+                byte_decryptor,
+                bound_decryptor: None,
+                legacy_nonce: Some(legacy_nonce),
             };
-            let key_ge = {
-                let key = key_json["key"].to_string();
-                let mut encrypted_key = decode_vec(&key);
-                self.byte_decryptor.decrypt(&mut encrypted_key);
-                let key: [u8; KEY_SIZE] = encrypted_key.try_into().unwrap();
-                *GenericArray::from_slice(&key)
+        }
+
+        pub fn decrypt(&self, key_string: &str) -> Result<Key, HelixError> {
+            let key_json = json::parse(key_string).map_err(|_| {
+                HelixError::from(
+                    "MalformedData",
+                    "InvalidWrappedKey",
+                    "Wrapped key is not valid JSON",
+                )
+            })?;
+            // Keys wrapped before cipher suites existed carry no suite and are ChaCha20-Poly1305.
+            let suite = match key_json["suite"].as_str() {
+                Some(name) => CipherSuite::from_name(name)?,
+                None => CipherSuite::ChaCha20Poly1305,
             };
-            Key {
-                key: key_ge,
-                nonce: nonce_ge,
+            let mut encrypted_key = Zeroizing::new(decode_vec(&key_json["key"].to_string()));
+            match (key_json["v"].as_u32(), self.legacy_nonce) {
+                (Some(WRAP_FORMAT_VERSION), _) => self.byte_decryptor.decrypt(&mut encrypted_key)?,
+                (Some(BOUND_WRAP_FORMAT_VERSION), _) => self
+                    .bound_decryptor
+                    .as_ref()
+                    .unwrap_or(&self.byte_decryptor)
+                    .decrypt(&mut encrypted_key)?,
+                (None, Some(legacy_nonce)) => self
+                    .byte_decryptor
+                    .decrypt_with_nonce(legacy_nonce, &mut encrypted_key)?,
+                _ => {
+                    return Err(HelixError::from(
+                        "UnsupportedFormat",
+                        "UnknownKeyFormat",
+                        "Wrapped key format is not supported",
+                    ))
+                }
+            }
+            if encrypted_key.len() != KEY_SIZE {
+                return Err(HelixError::from(
+                    "MalformedData",
+                    "InvalidKeyLength",
+                    "Wrapped key has an invalid length",
+                ));
+            }
+            Ok(Key::from_parts(suite, &encrypted_key))
+        }
+
+        pub fn is_legacy(key_string: &str) -> bool {
+            match json::parse(key_string) {
+                Ok(key_json) => key_json["v"].as_u32() != Some(WRAP_FORMAT_VERSION),
+                Err(_) => false,
+            }
+        }
+
+        /// Nonce a v1 wrapped key was stored with, the key was used together with it.
+        /// None for keys of any other format.
+        pub fn legacy_nonce(key_string: &str) -> Option<Vec<u8>> {
+            let key_json = json::parse(key_string).ok()?;
+            if !key_json["v"].is_null() {
+                return None;
+            }
+            key_json["nonce"].as_str().map(decode_vec)
+        }
+
+        /// True for keys sealed with associated data.
+        pub fn is_bound(key_string: &str) -> bool {
+            match json::parse(key_string) {
+                Ok(key_json) => key_json["v"].as_u32() == Some(BOUND_WRAP_FORMAT_VERSION),
+                Err(_) => false,
             }
         }
     }
 
     pub struct KeyEncryptor<'a> {
         byte_encryptor: ByteEncryptorImpl<'a>,
+        version: u32,
     }
 
     impl<'a> KeyEncryptor<'a> {
         pub fn from(master_key: &'a Key) -> Self {
             let byte_encryptor = ByteEncryptorImpl::from(master_key);
-            return KeyEncryptor { byte_encryptor };
+            return KeyEncryptor {
+                byte_encryptor,
+                version: WRAP_FORMAT_VERSION,
+            };
+        }
+
+        /// Seals keys together with associated data, they are written as v3.
+        pub fn with_associated_data(mut self, associated_data: Vec<u8>) -> Self {
+            self.byte_encryptor = self.byte_encryptor.with_associated_data(associated_data);
+            self.version = BOUND_WRAP_FORMAT_VERSION;
+            self
         }
 
         pub fn encrypt(&self, key: &Key) -> String {
-            let nonce_string = encode(&key.nonce);
-            let mut vec = key.key.to_vec();
+            let mut vec = Vec::with_capacity(KEY_SIZE + WRAP_OVERHEAD);
+            vec.extend_from_slice(key.bytes());
             self.byte_encryptor.encrypt(&mut vec);
             let key_string = encode_vec(vec);
             let ob = object! {
+                v: self.version,
                 key: key_string,
-                nonce: nonce_string
+                suite: key.suite.name()
             };
             ob.dump()
             // json::stringify(ob)
         }
     }
 
-    #[derive(Debug)]
+    /// A 256 bit key for one cipher suite. Nonces are never part of a key, every
+    /// encryption picks its own (see `ByteEncryptorImpl` and `stream`).
+    ///
+    /// The key bytes live on the heap so they never move, their page is locked in
+    /// memory where the OS allows it and they are zeroized on drop.
     pub struct Key {
-        pub(super) key: GenericArray<u8, U32>,
-        pub(super) nonce: GenericArray<u8, U12>,
+        pub(super) suite: CipherSuite,
+        pub(super) key: Box<[u8; KEY_SIZE]>,
     }
 
     impl Key {
         pub fn new() -> Self {
-            Self::new_internal(&mut OsRng, &mut OsRng)
+            Self::generate(CipherSuite::default())
+        }
+
+        pub fn generate(suite: CipherSuite) -> Self {
+            let mut key = Self::locked(suite);
+            OsRng.fill_bytes(&mut key.key[..]);
+            key
+        }
+
+        pub fn from_parts(suite: CipherSuite, key: &[u8]) -> Self {
+            let mut locked = Self::locked(suite);
+            locked.key.copy_from_slice(key);
+            locked
+        }
+
+        /// A zero key whose page is locked before any key material is written to it.
+        fn locked(suite: CipherSuite) -> Self {
+            let mut key = Box::new([0u8; KEY_SIZE]);
+            let mut locked_regions = LOCKED_REGIONS.lock().unwrap();
+            for region in lock_regions(&key) {
+                *locked_regions.entry(region).or_insert(0) += 1;
+            }
+            // Failing to lock, e.g. over RLIMIT_MEMLOCK, only loses the protection.
+            // SAFETY: `key` is a live heap allocation of exactly KEY_SIZE bytes, mlock
+            // only advises the OS about the pages holding that range.
+            unsafe { memsec::mlock(key.as_mut_ptr(), KEY_SIZE) };
+            Self { suite, key }
+        }
+
+        pub fn suite(&self) -> CipherSuite {
+            self.suite
+        }
+
+        pub(crate) fn bytes(&self) -> &[u8] {
+            &self.key[..]
         }
 
-        pub fn from_seed(seed: [u8; 32]) -> Self {
+        /// Legacy passphrase key, returned with the fixed nonce v1 wrapping used with it.
+        pub fn from_seed(seed: [u8; 32]) -> (Self, Vec<u8>) {
             let mut iv_seed = seed;
             iv_seed.reverse();
-            let mut rng = StdRng::from_seed(seed);
-            let mut iv_rng = StdRng::from_seed(iv_seed);
+            let rng = StdRng::from_seed(seed);
+            let iv_rng = StdRng::from_seed(iv_seed);
             Self::new_internal(rng, iv_rng)
         }
 
         fn new_internal(
             key_rng: impl CryptoRng + RngCore,
             iv_rng: impl CryptoRng + RngCore,
-        ) -> Self {
-            let key = ChaCha20Poly1305::generate_key(key_rng);
-            let nonce = ChaCha20Poly1305::generate_nonce(iv_rng);
-            Self { key, nonce }
+        ) -> (Self, Vec<u8>) {
+            let mut generated = ChaCha20Poly1305::generate_key(key_rng);
+            let nonce = ChaCha20Poly1305::generate_nonce(iv_rng).to_vec();
+            let key = Self::from_parts(CipherSuite::ChaCha20Poly1305, &generated);
+            generated.as_mut_slice().zeroize();
+            (key, nonce)
+        }
+    }
+
+    impl Drop for Key {
+        /// Pages are unlocked once no other key is left in their region, locks do not
+        /// nest.
+        fn drop(&mut self) {
+            self.key.zeroize();
+            let mut locked_regions = LOCKED_REGIONS.lock().unwrap();
+            let mut unlock = true;
+            for region in lock_regions(&self.key) {
+                let count = locked_regions.get_mut(&region).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    locked_regions.remove(&region);
+                } else {
+                    unlock = false;
+                }
+            }
+            if unlock {
+                // SAFETY: the allocation of `key` is still live, munlock zeroes exactly
+                // its KEY_SIZE bytes before unlocking their pages.
+                unsafe { memsec::munlock(self.key.as_mut_ptr(), KEY_SIZE) };
+            }
+        }
+    }
+
+    /// Live keys per 64 KiB region of memory. Pages are at most that large and never
+    /// cross a region, so a page is not unlocked while a key in it is alive.
+    static LOCKED_REGIONS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+    const LOCK_REGION_SIZE: usize = 64 * 1024;
+
+    fn lock_regions(key: &[u8; KEY_SIZE]) -> RangeInclusive<usize> {
+        let start = key.as_ptr() as usize;
+        start / LOCK_REGION_SIZE..=(start + KEY_SIZE - 1) / LOCK_REGION_SIZE
+    }
+
+    impl fmt::Debug for Key {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Key")
+                .field("suite", &self.suite)
+                .finish_non_exhaustive()
         }
     }
 
@@ -113,69 +291,333 @@ pub mod keys {
         let key_encryptor = KeyEncryptor::from(&key);
         let key_decryptor = KeyDecryptor::from(&key);
         let encrypted = key_encryptor.encrypt(&key);
-        let decrypted = key_decryptor.decrypt(&encrypted);
+        let decrypted = key_decryptor.decrypt(&encrypted).unwrap();
         print!("{:?}", decrypted);
+        assert_eq!(decrypted.bytes(), key.bytes());
+        assert!(!format!("{:?}", decrypted).contains(&format!("{:?}", key.bytes())));
+    }
+
+    #[test]
+    fn bound_key_test() {
+        let key = Key::new();
+        let encrypted = KeyEncryptor::from(&key)
+            .with_associated_data(b"row a".to_vec())
+            .encrypt(&key);
+        assert!(KeyDecryptor::is_bound(&encrypted));
+        let decrypted = KeyDecryptor::from(&key)
+            .with_associated_data(b"row a".to_vec())
+            .decrypt(&encrypted)
+            .unwrap();
+        assert_eq!(decrypted.bytes(), key.bytes());
+        assert!(KeyDecryptor::from(&key)
+            .with_associated_data(b"row b".to_vec())
+            .decrypt(&encrypted)
+            .is_err());
+        assert!(KeyDecryptor::from(&key).decrypt(&encrypted).is_err());
     }
 }
 
 pub mod encryptors {
 
-    use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit};
+    use chacha20poly1305::aead::OsRng;
+    use rand::RngCore;
 
-    use crate::crypto::ByteEncryptor;
+    use crate::crypto::{suite::SuiteCipher, ByteEncryptor};
 
     use super::keys::Key;
 
+    /// Seals each buffer under a fresh random nonce, written in front of the ciphertext.
     pub struct ByteEncryptorImpl<'a> {
         key: &'a Key,
-        cipher: ChaCha20Poly1305,
+        cipher: SuiteCipher,
+        associated_data: Vec<u8>,
     }
 
     impl<'a> ByteEncryptorImpl<'a> {
         pub fn from(key: &'a Key) -> Self {
             Self {
                 key,
-                cipher: ChaCha20Poly1305::new(&key.key),
+                cipher: SuiteCipher::new(key.suite, key.bytes()),
+                associated_data: Vec::new(),
             }
         }
+
+        /// Authenticated along with every buffer but not stored, the decryptor has to
+        /// supply the same bytes.
+        pub fn with_associated_data(mut self, associated_data: Vec<u8>) -> Self {
+            self.associated_data = associated_data;
+            self
+        }
     }
 
     impl ByteEncryptor for ByteEncryptorImpl<'_> {
         fn encrypt(&self, buffer: &mut Vec<u8>) {
+            let mut nonce = vec![0u8; self.key.suite.nonce_size()];
+            OsRng.fill_bytes(&mut nonce);
             self.cipher
-                .encrypt_in_place(&self.key.nonce, b"", buffer)
+                .encrypt_in_place(&nonce, &self.associated_data, buffer)
                 .unwrap();
+            buffer.splice(0..0, nonce);
         }
     }
 }
 
 pub mod decryptors {
 
-    use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit};
-
-    use crate::crypto::{ByteDecryptor, ByteEncryptor};
+    use crate::{
+        crypto::{suite::SuiteCipher, ByteDecryptor},
+        errors::HelixError,
+    };
 
-    use super::{encryptors::ByteEncryptorImpl, keys::Key};
+    use super::keys::Key;
 
     pub struct ByteDecryptorImpl<'a> {
         key: &'a Key,
-        cipher: ChaCha20Poly1305,
+        cipher: SuiteCipher,
+        associated_data: Vec<u8>,
     }
 
     impl<'a> ByteDecryptorImpl<'a> {
         pub fn from(key: &'a Key) -> Self {
             Self {
                 key,
-                cipher: ChaCha20Poly1305::new(&key.key),
+                cipher: SuiteCipher::new(key.suite, key.bytes()),
+                associated_data: Vec::new(),
             }
         }
+
+        pub fn with_associated_data(mut self, associated_data: Vec<u8>) -> Self {
+            self.associated_data = associated_data;
+            self
+        }
+
+        pub(crate) fn decrypt_with_nonce(
+            &self,
+            nonce: &[u8],
+            buffer: &mut Vec<u8>,
+        ) -> Result<(), HelixError> {
+            if nonce.len() != self.key.suite.nonce_size() {
+                return Err(authentication_failed());
+            }
+            self.cipher
+                .decrypt_in_place(nonce, &self.associated_data, buffer)
+                .map_err(|_| authentication_failed())
+        }
     }
 
     impl ByteDecryptor for ByteDecryptorImpl<'_> {
-        fn decrypt(&self, buffer: &mut Vec<u8>) {
+        fn decrypt(&self, buffer: &mut Vec<u8>) -> Result<(), HelixError> {
+            let nonce_size = self.key.suite.nonce_size();
+            if buffer.len() < nonce_size {
+                return Err(authentication_failed());
+            }
+            let nonce: Vec<u8> = buffer.drain(..nonce_size).collect();
+            self.decrypt_with_nonce(&nonce, buffer)
+        }
+    }
+
+    fn authentication_failed() -> HelixError {
+        HelixError::from(
+            "MalformedData",
+            "AuthenticationFailed",
+            "Encrypted data failed authentication",
+        )
+    }
+}
+
+pub mod stream {
+    //! STREAM construction (Hoang, Reyhanitabar, Rogaway, Vizár) for chunked files.
+    //!
+    //! Every chunk gets its own nonce made of a per-file prefix, a big endian chunk
+    //! counter and a flag that is set only for the final chunk. Reordered, duplicated,
+    //! dropped or appended chunks therefore fail authentication. The block header is
+    //! passed as associated data so it cannot be altered either. Streams that bind
+    //! the chunk index also append the counter to the associated data of each chunk.
+
+    use std::borrow::Cow;
+
+    use chacha20poly1305::aead::OsRng;
+    use rand::RngCore;
+
+    use crate::{
+        crypto::suite::{CipherSuite, SuiteCipher},
+        errors::HelixError,
+    };
+
+    use super::keys::Key;
+
+    /// Bytes of the nonce taken by the chunk counter and the final-chunk flag.
+    const NONCE_SUFFIX_SIZE: usize = 5;
+
+    pub fn nonce_prefix_size(suite: CipherSuite) -> usize {
+        suite.nonce_size() - NONCE_SUFFIX_SIZE
+    }
+
+    pub fn random_nonce_prefix(suite: CipherSuite) -> Vec<u8> {
+        let mut prefix = vec![0u8; nonce_prefix_size(suite)];
+        OsRng.fill_bytes(&mut prefix);
+        prefix
+    }
+
+    struct NonceSequence {
+        prefix: Vec<u8>,
+        counter: u32,
+        finished: bool,
+    }
+
+    impl NonceSequence {
+        fn from(prefix: Vec<u8>) -> Self {
+            Self {
+                prefix,
+                counter: 0,
+                finished: false,
+            }
+        }
+
+        fn next(&mut self, last: bool) -> Result<Vec<u8>, HelixError> {
+            if self.finished {
+                return Err(HelixError::from(
+                    "MalformedBlock",
+                    "StreamFinished",
+                    "Chunk found after the final chunk",
+                ));
+            }
+            let mut nonce = Vec::with_capacity(self.prefix.len() + NONCE_SUFFIX_SIZE);
+            nonce.extend_from_slice(&self.prefix);
+            nonce.extend_from_slice(&self.counter.to_be_bytes());
+            nonce.push(last as u8);
+            if last {
+                self.finished = true;
+            } else {
+                self.counter = self.counter.checked_add(1).ok_or(HelixError::from(
+                    "MalformedBlock",
+                    "ChunkCounterOverflow",
+                    "File has too many chunks",
+                ))?;
+            }
+            Ok(nonce)
+        }
+    }
+
+    fn check_prefix(key: &Key, prefix: &[u8]) -> Result<(), HelixError> {
+        if prefix.len() != nonce_prefix_size(key.suite) {
+            return Err(HelixError::from(
+                "MalformedBlock",
+                "CipherSuiteMismatch",
+                "Block nonce does not match the cipher suite of its key",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Associated data of one chunk, the chunk counter is taken from its nonce.
+    fn chunk_associated_data<'d>(
+        associated_data: &'d [u8],
+        bind_chunk_index: bool,
+        nonce: &[u8],
+    ) -> Cow<'d, [u8]> {
+        if !bind_chunk_index {
+            return Cow::Borrowed(associated_data);
+        }
+        let counter = &nonce[nonce.len() - NONCE_SUFFIX_SIZE..nonce.len() - 1];
+        Cow::Owned([associated_data, counter].concat())
+    }
+
+    pub struct StreamEncryptor {
+        cipher: SuiteCipher,
+        nonces: NonceSequence,
+        associated_data: Vec<u8>,
+        bind_chunk_index: bool,
+    }
+
+    impl StreamEncryptor {
+        pub fn from(key: &Key, prefix: Vec<u8>, associated_data: Vec<u8>) -> Result<Self, HelixError> {
+            check_prefix(key, &prefix)?;
+            Ok(Self {
+                cipher: SuiteCipher::new(key.suite, key.bytes()),
+                nonces: NonceSequence::from(prefix),
+                associated_data,
+                bind_chunk_index: false,
+            })
+        }
+
+        pub fn with_chunk_index(mut self) -> Self {
+            self.bind_chunk_index = true;
+            self
+        }
+
+        pub fn encrypt_next(&mut self, buffer: &mut Vec<u8>, last: bool) -> Result<(), HelixError> {
+            let nonce = self.nonces.next(last)?;
+            let associated_data =
+                chunk_associated_data(&self.associated_data, self.bind_chunk_index, &nonce);
             self.cipher
-                .decrypt_in_place(&self.key.nonce, b"", buffer)
+                .encrypt_in_place(&nonce, &associated_data, buffer)
                 .unwrap();
+            Ok(())
+        }
+    }
+
+    pub struct StreamDecryptor {
+        cipher: SuiteCipher,
+        nonces: NonceSequence,
+        associated_data: Vec<u8>,
+        bind_chunk_index: bool,
+        legacy_nonce: Option<Vec<u8>>,
+    }
+
+    impl StreamDecryptor {
+        pub fn from(key: &Key, prefix: Vec<u8>, associated_data: Vec<u8>) -> Result<Self, HelixError> {
+            check_prefix(key, &prefix)?;
+            Ok(Self {
+                cipher: SuiteCipher::new(key.suite, key.bytes()),
+                nonces: NonceSequence::from(prefix),
+                associated_data,
+                bind_chunk_index: false,
+                legacy_nonce: None,
+            })
+        }
+
+        /// Chunks of a block written before STREAM was used, all sealed with the same
+        /// nonce and no associated data. Nothing marks their final chunk, so such a
+        /// block that was cut after a chunk still decrypts.
+        pub fn legacy(key: &Key, nonce: Vec<u8>) -> Result<Self, HelixError> {
+            if nonce.len() != key.suite.nonce_size() {
+                return Err(HelixError::from(
+                    "MalformedBlock",
+                    "CipherSuiteMismatch",
+                    "Block nonce does not match the cipher suite of its key",
+                ));
+            }
+            Ok(Self {
+                cipher: SuiteCipher::new(key.suite, key.bytes()),
+                nonces: NonceSequence::from(Vec::new()),
+                associated_data: Vec::new(),
+                bind_chunk_index: false,
+                legacy_nonce: Some(nonce),
+            })
+        }
+
+        pub fn with_chunk_index(mut self) -> Self {
+            self.bind_chunk_index = true;
+            self
+        }
+
+        pub fn decrypt_next(&mut self, buffer: &mut Vec<u8>, last: bool) -> Result<(), HelixError> {
+            let nonce = match &self.legacy_nonce {
+                Some(nonce) => nonce.clone(),
+                None => self.nonces.next(last)?,
+            };
+            let associated_data =
+                chunk_associated_data(&self.associated_data, self.bind_chunk_index, &nonce);
+            self.cipher
+                .decrypt_in_place(&nonce, &associated_data, buffer)
+                .map_err(|_| {
+                    HelixError::from(
+                        "MalformedBlock",
+                        "ChunkAuthenticationFailed",
+                        "Chunk failed authentication, block is corrupted, reordered or truncated",
+                    )
+                })
         }
     }
 }
@@ -183,7 +625,11 @@ pub mod decryptors {
 #[cfg(test)]
 mod tests {
     use crate::crypto::{
-        chacha::{decryptors::ByteDecryptorImpl, encryptors::ByteEncryptorImpl, keys::Key},
+        chacha::{
+            decryptors::ByteDecryptorImpl,
+            encryptors::ByteEncryptorImpl,
+            keys::{Key, KeyEncryptor},
+        },
         ByteDecryptor, ByteEncryptor,
     };
 
@@ -197,10 +643,72 @@ mod tests {
         println!("{:?}", res);
         println!("{:?}", data.len());
         println!("{:?}", data);
-        decryptor.decrypt(&mut data);
+        decryptor.decrypt(&mut data).unwrap();
         print!("{:?}", data.len());
     }
 
+    #[test]
+    fn stream_rejects_out_of_order_chunks() {
+        use crate::crypto::chacha::stream::{random_nonce_prefix, StreamDecryptor, StreamEncryptor};
+
+        let key = Key::new();
+        let prefix = random_nonce_prefix(key.suite());
+        let mut encryptor = StreamEncryptor::from(&key, prefix.clone(), b"header".to_vec()).unwrap();
+        let mut first = b"first".to_vec();
+        let mut second = b"second".to_vec();
+        encryptor.encrypt_next(&mut first, false).unwrap();
+        encryptor.encrypt_next(&mut second, true).unwrap();
+
+        let mut decryptor = StreamDecryptor::from(&key, prefix.clone(), b"header".to_vec()).unwrap();
+        assert!(decryptor.decrypt_next(&mut second.clone(), false).is_err());
+
+        let mut decryptor = StreamDecryptor::from(&key, prefix.clone(), b"header".to_vec()).unwrap();
+        assert!(decryptor.decrypt_next(&mut first.clone(), true).is_err());
+
+        let mut decryptor = StreamDecryptor::from(&key, prefix.clone(), b"other".to_vec()).unwrap();
+        assert!(decryptor.decrypt_next(&mut first.clone(), false).is_err());
+
+        let mut decryptor = StreamDecryptor::from(&key, prefix.clone(), b"header".to_vec()).unwrap();
+        decryptor.decrypt_next(&mut first, false).unwrap();
+        decryptor.decrypt_next(&mut second, true).unwrap();
+        assert_eq!(first, b"first");
+        assert_eq!(second, b"second");
+    }
+
+    #[test]
+    fn legacy_file_key_test() {
+        use crate::{
+            crypto::{chacha::keys::KeyDecryptor, suite::CipherSuite},
+            util::hex::decode_vec,
+        };
+
+        // Wrapped at baseline by `KeyEncryptor::from(&master_key)`.
+        let wrapped = concat!(
+            r#"{"key":"940a3e18be6d7714befee86953c9bb5de121687ef540b8eb1e97cbd3a0996eeb"#,
+            r#"02abcf29f79354616f6799aafdd498c8","nonce":"b0d6878faae801dd851a85fe"}"#,
+        );
+        let master_key = Key::from_parts(
+            CipherSuite::ChaCha20Poly1305,
+            &decode_vec("f692897885c0cb20a3e4cd106a0b15bc360794f17e73298909b0798873ec7441"),
+        );
+        let master_nonce = decode_vec("f692897885c0cb20a3e4cd10");
+        let error = KeyDecryptor::from(&master_key).decrypt(wrapped).unwrap_err();
+        assert_eq!(error.detailed_code, "UnknownKeyFormat");
+        let file_key = KeyDecryptor::with_legacy_nonce(&master_key, &master_nonce)
+            .decrypt(wrapped)
+            .unwrap();
+        assert_eq!(
+            file_key.bytes(),
+            decode_vec("b0d6878faae801dd851a85fe96d3b17ea2150086bdef71721f4843a7fd475c11"),
+        );
+        let file_nonce = KeyDecryptor::legacy_nonce(wrapped).unwrap();
+        assert_eq!(file_nonce, decode_vec("b0d6878faae801dd851a85fe"));
+        assert!(KeyDecryptor::with_legacy_nonce(&master_key, &[0u8; 12]).decrypt(wrapped).is_err());
+
+        let rewrapped = KeyEncryptor::from(&master_key).encrypt(&file_key);
+        assert!(KeyDecryptor::legacy_nonce(&rewrapped).is_none());
+    }
+
     // #[test]
     // fn keygen_test() {
     //     let key = Key::from_seed(String::from("input"));
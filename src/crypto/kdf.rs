@@ -0,0 +1,321 @@
+//! HKDF-SHA256 key hierarchy.
+//!
+//! Neither the master key nor a file key is used directly. Every purpose gets its own
+//! subkey, derived with a distinct info label, so one key can never end up encrypting
+//! two kinds of data.
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::util::hex::encode;
+
+use super::{
+    chacha::keys::{Key, KEY_SIZE},
+    suite::CipherSuite,
+};
+
+const FILE_KEY_WRAP: &[u8] = b"helix/master/file-key-wrap/v1";
+const ROW_AUTH: &[u8] = b"helix/master/row-auth/v1";
+const SECRET_WRAP: &[u8] = b"helix/master/secret-wrap/v1";
+const MANIFEST_AUTH: &[u8] = b"helix/master/manifest-auth/v1";
+const METADATA_WRAP: &[u8] = b"helix/master/metadata-wrap/v1";
+const INDEX_METADATA_WRAP: &[u8] = b"helix/capsule/metadata-wrap/v1";
+const FILE_ID: &[u8] = b"helix/capsule/file-id/v1";
+const CONTENT_HASH: &[u8] = b"helix/capsule/content-hash/v1";
+const PACK_KEY: &[u8] = b"helix/capsule/pack-key/v1";
+const CAPSULE_ROW_AUTH: &[u8] = b"helix/capsule/row-auth/v1";
+const CONTENT: &[u8] = b"helix/file/content/v1";
+const FILE_PATH: &[u8] = b"helix/file/path/v1";
+const BLOCK_NAME: &[u8] = b"helix/file/block-name/v1";
+const CHUNK_ID: &[u8] = b"helix/chunk/id/v1";
+const CHUNK_KEY: &[u8] = b"helix/chunk/key/v1";
+const CHUNK_BOUNDARY: &[u8] = b"helix/chunk/boundary/v1";
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn derive(key: &Key, info: &[u8]) -> Zeroizing<[u8; KEY_SIZE]> {
+    let hkdf = Hkdf::<Sha256>::new(None, key.bytes());
+    let mut output = Zeroizing::new([0u8; KEY_SIZE]);
+    hkdf.expand(info, &mut output[..]).unwrap();
+    output
+}
+
+fn derive_key(key: &Key, info: &[u8]) -> Key {
+    Key::from_parts(key.suite(), &derive(key, info)[..])
+}
+
+/// Subkeys of the capsule master key.
+pub struct MasterSubKeys {
+    /// Wraps the per file keys stored in the files table.
+    pub file_key_wrap: Key,
+    /// Wraps the capsule secret stored in the settings table.
+    pub secret_wrap: Key,
+    /// Wraps the key the capsule metadata is encrypted with.
+    pub metadata_wrap: Key,
+    row_auth: Zeroizing<[u8; KEY_SIZE]>,
+    manifest_auth: Zeroizing<[u8; KEY_SIZE]>,
+    legacy_file_key_wrap: Option<(Key, Vec<u8>)>,
+}
+
+impl MasterSubKeys {
+    pub fn derive(master_key: &Key) -> Self {
+        Self {
+            file_key_wrap: derive_key(master_key, FILE_KEY_WRAP),
+            secret_wrap: derive_key(master_key, SECRET_WRAP),
+            metadata_wrap: derive_key(master_key, METADATA_WRAP),
+            row_auth: derive(master_key, ROW_AUTH),
+            manifest_auth: derive(master_key, MANIFEST_AUTH),
+            legacy_file_key_wrap: None,
+        }
+    }
+
+    /// Also opens file keys wrapped in the v1 format. Those were wrapped by the
+    /// master key itself, with the nonce its slot stored next to it.
+    pub fn with_legacy_nonce(mut self, master_key: &Key, legacy_nonce: Vec<u8>) -> Self {
+        let master_key = Key::from_parts(master_key.suite(), master_key.bytes());
+        self.legacy_file_key_wrap = Some((master_key, legacy_nonce));
+        self
+    }
+
+    /// Master key and nonce of v1 file keys, see `with_legacy_nonce`.
+    pub fn legacy_file_key_wrap(&self) -> Option<(&Key, &[u8])> {
+        self.legacy_file_key_wrap
+            .as_ref()
+            .map(|(master_key, legacy_nonce)| (master_key, legacy_nonce.as_slice()))
+    }
+
+    /// MAC of the capsule manifest, see `helix_crypto::manifest`. Its own key, so a
+    /// manifest can never pass for a row MAC or the other way round.
+    pub fn manifest_mac(&self, fields: &[&str]) -> String {
+        row_mac(&self.manifest_auth[..], fields)
+    }
+
+    pub fn verify_manifest_mac(&self, fields: &[&str], manifest_mac: &str) -> bool {
+        verify_row_mac(&self.manifest_auth[..], fields, manifest_mac)
+    }
+
+    pub fn row_mac(&self, fields: &[&str]) -> String {
+        row_mac(&self.row_auth[..], fields)
+    }
+
+    pub fn verify_row_mac(&self, fields: &[&str], row_mac: &str) -> bool {
+        verify_row_mac(&self.row_auth[..], fields, row_mac)
+    }
+}
+
+/// HMAC-SHA256 over the given fields. Every field is length prefixed so
+/// that moving bytes from one field to the next changes the tag.
+fn row_mac(key: &[u8], fields: &[&str]) -> String {
+    encode(&row_hmac(key, fields).finalize().into_bytes())
+}
+
+fn verify_row_mac(key: &[u8], fields: &[&str], row_mac: &str) -> bool {
+    match hex::decode(row_mac) {
+        Ok(tag) => row_hmac(key, fields).verify_slice(&tag).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn row_hmac(key: &[u8], fields: &[&str]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    for field in fields {
+        mac.update(&(field.len() as u64).to_be_bytes());
+        mac.update(field.as_bytes());
+    }
+    mac
+}
+
+/// Subkeys of the capsule secret, a random key generated with the capsule. It is
+/// independent of the master key so that values keyed with it stay stable when the
+/// master key changes.
+pub struct CapsuleSubKeys {
+    /// Also wraps the metadata key, so that an encrypt-only host holding the index
+    /// key can open the metadata. Always of the default suite, the index key does not
+    /// record the suite of the capsule secret.
+    pub metadata_wrap: Key,
+    file_id: Zeroizing<[u8; KEY_SIZE]>,
+    content_hash: Zeroizing<[u8; KEY_SIZE]>,
+    pack_key: Key,
+    row_auth: Zeroizing<[u8; KEY_SIZE]>,
+}
+
+impl CapsuleSubKeys {
+    pub fn derive(capsule_secret: &Key) -> Self {
+        let metadata_wrap = derive(capsule_secret, INDEX_METADATA_WRAP);
+        Self {
+            metadata_wrap: Key::from_parts(CipherSuite::default(), &metadata_wrap[..]),
+            file_id: derive(capsule_secret, FILE_ID),
+            content_hash: derive(capsule_secret, CONTENT_HASH),
+            pack_key: derive_key(capsule_secret, PACK_KEY),
+            row_auth: derive(capsule_secret, CAPSULE_ROW_AUTH),
+        }
+    }
+
+    /// Primary key of the files row for a source path. Without the capsule secret
+    /// a guessed path can not be checked against the capsule.
+    pub fn file_id(&self, file_path: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.file_id[..]).unwrap();
+        mac.update(file_path.as_bytes());
+        encode(&mac.finalize().into_bytes())
+    }
+
+    /// Keyed form of a SHA-256 content digest, used to skip unchanged files.
+    /// Unlike the bare digest it can not be matched against known documents.
+    pub fn content_hash(&self, content_digest: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.content_hash[..]).unwrap();
+        mac.update(content_digest.as_bytes());
+        encode(&mac.finalize().into_bytes())
+    }
+
+    /// Key a pack file is encrypted with. Packs are written by encrypt-only hosts
+    /// too, so the key comes from the capsule secret and not the master key.
+    pub fn pack_key(&self, pack_id: &str, suite: CipherSuite) -> Key {
+        let mut info = Vec::from(PACK_KEY);
+        info.extend_from_slice(pack_id.as_bytes());
+        Key::from_parts(suite, &derive(&self.pack_key, &info)[..])
+    }
+
+    /// Row MAC for rows whose key is wrapped to recipients. Such rows are written by
+    /// encrypt-only hosts, which hold the capsule secret but not the master key.
+    pub fn row_mac(&self, fields: &[&str]) -> String {
+        row_mac(&self.row_auth[..], fields)
+    }
+
+    pub fn verify_row_mac(&self, fields: &[&str], row_mac: &str) -> bool {
+        verify_row_mac(&self.row_auth[..], fields, row_mac)
+    }
+}
+
+/// Subkeys of a single file key.
+pub struct FileSubKeys {
+    /// Encrypts the block contents.
+    pub content: Key,
+    /// Encrypts the relative path stored in the files table.
+    pub file_path: Key,
+    /// Name of the block file. Derived from the key, so a new key means a new block.
+    pub block_name: String,
+    /// Nonce a v1 file key was stored with, see `legacy`.
+    pub legacy_nonce: Option<Vec<u8>>,
+}
+
+impl FileSubKeys {
+    pub fn derive(file_key: &Key) -> Self {
+        Self {
+            content: derive_key(file_key, CONTENT),
+            file_path: derive_key(file_key, FILE_PATH),
+            block_name: encode(&derive(file_key, BLOCK_NAME)[..]),
+            legacy_nonce: None,
+        }
+    }
+
+    /// Keys of a file whose key is wrapped in the v1 format. That key encrypted the
+    /// block and the path itself, with the nonce stored next to it, and the block is
+    /// named after the files row.
+    pub fn legacy(file_key: &Key, legacy_nonce: Vec<u8>, block_name: &str) -> Self {
+        Self {
+            content: Key::from_parts(file_key.suite(), file_key.bytes()),
+            file_path: Key::from_parts(file_key.suite(), file_key.bytes()),
+            block_name: String::from(block_name),
+            legacy_nonce: Some(legacy_nonce),
+        }
+    }
+}
+
+/// Subkeys of the chunk secret, a random key wrapped under the master key. Chunks
+/// are shared between files, so neither their names nor their keys can come from a
+/// file key.
+pub struct ChunkSubKeys {
+    chunk_id: Zeroizing<[u8; KEY_SIZE]>,
+    chunk_key: Key,
+    boundary_seed: u64,
+}
+
+impl ChunkSubKeys {
+    pub fn derive(chunk_secret: &Key) -> Self {
+        let boundary = derive(chunk_secret, CHUNK_BOUNDARY);
+        Self {
+            chunk_id: derive(chunk_secret, CHUNK_ID),
+            chunk_key: derive_key(chunk_secret, CHUNK_KEY),
+            boundary_seed: u64::from_be_bytes(boundary[..8].try_into().unwrap()),
+        }
+    }
+
+    /// Name of a chunk in the chunk store. Equal chunks get equal names, which is
+    /// what deduplicates them, but without the chunk secret a name can not be
+    /// matched against known content.
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.chunk_id[..]).unwrap();
+        mac.update(data);
+        encode(&mac.finalize().into_bytes())
+    }
+
+    /// Key a chunk is encrypted with. Derived from its name, so a chunk stored under
+    /// another name fails to decrypt.
+    pub fn chunk_key(&self, chunk_id: &str, suite: CipherSuite) -> Key {
+        let mut info = Vec::from(CHUNK_KEY);
+        info.extend_from_slice(chunk_id.as_bytes());
+        Key::from_parts(suite, &derive(&self.chunk_key, &info)[..])
+    }
+
+    /// Seed of the FastCDC gear table, so chunk boundaries do not reveal content
+    /// shared with files outside the capsule.
+    pub fn boundary_seed(&self) -> u64 {
+        self.boundary_seed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{chacha::keys::Key, suite::CipherSuite};
+
+    use super::{CapsuleSubKeys, ChunkSubKeys, FileSubKeys, MasterSubKeys};
+
+    #[test]
+    fn subkeys_are_distinct_test() {
+        let file_key = Key::new();
+        let sub_keys = FileSubKeys::derive(&file_key);
+        assert_ne!(sub_keys.content.bytes(), sub_keys.file_path.bytes());
+        assert_ne!(sub_keys.content.bytes(), file_key.bytes());
+        assert_eq!(sub_keys.content.suite(), file_key.suite());
+        assert_eq!(FileSubKeys::derive(&file_key).block_name, sub_keys.block_name);
+
+        let master_sub_keys = MasterSubKeys::derive(&Key::new());
+        let mac = master_sub_keys.row_mac(&["id", "key"]);
+        assert!(master_sub_keys.verify_row_mac(&["id", "key"], &mac));
+        assert!(!master_sub_keys.verify_row_mac(&["idk", "ey"], &mac));
+    }
+
+    #[test]
+    fn file_id_test() {
+        let capsule_sub_keys = CapsuleSubKeys::derive(&Key::new());
+        let file_id = capsule_sub_keys.file_id("/home/helix/notes.txt");
+        assert_eq!(file_id, capsule_sub_keys.file_id("/home/helix/notes.txt"));
+        assert_ne!(file_id, capsule_sub_keys.file_id("/home/helix/notes.md"));
+        let other_capsule = CapsuleSubKeys::derive(&Key::new());
+        assert_ne!(file_id, other_capsule.file_id("/home/helix/notes.txt"));
+        assert_ne!(capsule_sub_keys.content_hash("digest"), other_capsule.content_hash("digest"));
+        let suite = CipherSuite::default();
+        let pack_key = capsule_sub_keys.pack_key("pack", suite);
+        assert_ne!(pack_key.bytes(), capsule_sub_keys.pack_key("other pack", suite).bytes());
+        assert_ne!(pack_key.bytes(), other_capsule.pack_key("pack", suite).bytes());
+        let mac = capsule_sub_keys.row_mac(&["id", "key"]);
+        assert!(capsule_sub_keys.verify_row_mac(&["id", "key"], &mac));
+        assert!(!other_capsule.verify_row_mac(&["id", "key"], &mac));
+    }
+
+    #[test]
+    fn chunk_id_test() {
+        let chunk_sub_keys = ChunkSubKeys::derive(&Key::new());
+        let chunk_id = chunk_sub_keys.chunk_id(b"chunk");
+        assert_eq!(chunk_id, chunk_sub_keys.chunk_id(b"chunk"));
+        assert_ne!(chunk_id, ChunkSubKeys::derive(&Key::new()).chunk_id(b"chunk"));
+        let suite = CipherSuite::Aes256Gcm;
+        let chunk_key = chunk_sub_keys.chunk_key(&chunk_id, suite);
+        assert_eq!(chunk_key.suite(), suite);
+        assert_eq!(chunk_key.bytes(), chunk_sub_keys.chunk_key(&chunk_id, suite).bytes());
+        let other_id = chunk_sub_keys.chunk_id(b"other chunk");
+        assert_ne!(chunk_key.bytes(), chunk_sub_keys.chunk_key(&other_id, suite).bytes());
+    }
+}
@@ -1,9 +1,19 @@
+use crate::errors::HelixError;
+
+pub mod binding;
 pub mod chacha;
+pub mod kdf;
+pub mod padding;
+pub mod passphrase;
+pub mod recipient;
+pub mod recovery;
+pub mod shares;
+pub mod suite;
 
 pub trait ByteEncryptor {
     fn encrypt(&self, plain: &mut Vec<u8>);
 }
 
 pub trait ByteDecryptor {
-    fn decrypt(&self, cipher: &mut Vec<u8>);
+    fn decrypt(&self, cipher: &mut Vec<u8>) -> Result<(), HelixError>;
 }
@@ -0,0 +1,106 @@
+//! Length-hiding padding of blocks.
+//!
+//! The plain length of a file is rounded up to a coarser size before encryption, so
+//! the size of its block only tells which bucket the file falls into. The true length
+//! is stored inside the authenticated ciphertext (see `CCFileEncryptor`).
+
+use crate::errors::HelixError;
+
+/// Padding policy of a block. The id is what goes on disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    /// PADMÉ, at most 12% overhead and O(log log n) bits of the length leak.
+    Padme,
+    /// Next power of two, up to 100% overhead and O(log log n) bits leak.
+    PowerOfTwo,
+}
+
+impl Padding {
+    pub fn id(&self) -> u8 {
+        match self {
+            Padding::None => 0,
+            Padding::Padme => 1,
+            Padding::PowerOfTwo => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, HelixError> {
+        match id {
+            0 => Ok(Padding::None),
+            1 => Ok(Padding::Padme),
+            2 => Ok(Padding::PowerOfTwo),
+            _ => Err(HelixError::from(
+                "UnsupportedFormat",
+                "UnknownPadding",
+                &format!("Padding {} is not supported", id),
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Padding::None => "none",
+            Padding::Padme => "padme",
+            Padding::PowerOfTwo => "pow2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, HelixError> {
+        match name {
+            "none" => Ok(Padding::None),
+            "padme" => Ok(Padding::Padme),
+            "pow2" => Ok(Padding::PowerOfTwo),
+            _ => Err(HelixError::from(
+                "BadInput",
+                "UnknownPadding",
+                &format!("Unknown padding {}, expected one of none, padme, pow2", name),
+            )),
+        }
+    }
+
+    /// Length a plain length of `len` bytes is padded to.
+    pub fn padded_len(&self, len: u64) -> u64 {
+        match self {
+            Padding::None => len,
+            Padding::Padme => padme(len),
+            Padding::PowerOfTwo => len.next_power_of_two(),
+        }
+    }
+}
+
+/// Keeps the exponent and only as many mantissa bits as the exponent has bits,
+/// the remaining low bits are rounded up.
+fn padme(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+    let exponent = 63 - len.leading_zeros();
+    let exponent_bits = 32 - exponent.leading_zeros();
+    let mask = (1u64 << (exponent - exponent_bits)) - 1;
+    (len + mask) & !mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Padding;
+
+    #[test]
+    fn padded_len_test() {
+        assert_eq!(Padding::None.padded_len(1000), 1000);
+        assert_eq!(Padding::PowerOfTwo.padded_len(1000), 1024);
+        assert_eq!(Padding::PowerOfTwo.padded_len(1024), 1024);
+        assert_eq!(Padding::Padme.padded_len(0), 0);
+        assert_eq!(Padding::Padme.padded_len(9), 10);
+        assert_eq!(Padding::Padme.padded_len(1000), 1024);
+        assert_eq!(Padding::Padme.padded_len(1_000_000), 1_015_808);
+        for len in [1u64, 100, 4097, 123_456_789] {
+            let padded = Padding::Padme.padded_len(len);
+            assert!(padded >= len && padded - len <= len / 8);
+        }
+        assert_eq!(Padding::from_name("pow2").unwrap(), Padding::PowerOfTwo);
+        assert_eq!(Padding::from_id(Padding::Padme.id()).unwrap(), Padding::Padme);
+        assert!(Padding::from_name("zero").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::errors::HelixError;
+
+use super::{
+    chacha::keys::{Key, KEY_MATERIAL_SIZE, KEY_SIZE},
+    suite::CipherSuite,
+};
+
+pub const ARGON2ID: &str = "argon2id";
+pub const LEGACY_SHA256: &str = "sha256-seed";
+pub const SALT_SIZE: usize = 16;
+/// Shortest keyfile accepted, anything less is guessable.
+pub const MIN_KEYFILE_SIZE: usize = 32;
+const KEYFILE_DOMAIN: &[u8] = b"helix/keyfile/v1";
+
+/// Argon2id cost parameters. Memory cost is in KiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Self {
+            memory_cost: 64 * 1024,
+            time_cost: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl Argon2Params {
+    /// True when any cost is lower than the given baseline, i.e. the key should be rewrapped.
+    pub fn weaker_than(&self, other: &Argon2Params) -> bool {
+        self.memory_cost < other.memory_cost
+            || self.time_cost < other.time_cost
+            || self.parallelism < other.parallelism
+    }
+}
+
+pub fn generate_salt() -> [u8; SALT_SIZE] {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Digest of a keyfile, used as the Argon2 secret. Keyfiles of any size are reduced
+/// to 32 bytes so they can be fed to Argon2 as is.
+pub fn keyfile_secret(keyfile: &[u8]) -> Result<Zeroizing<[u8; 32]>, HelixError> {
+    if keyfile.len() < MIN_KEYFILE_SIZE {
+        return Err(HelixError::from(
+            "BadInput",
+            "KeyfileTooShort",
+            &format!("Keyfile must be at least {} bytes", MIN_KEYFILE_SIZE),
+        ));
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(KEYFILE_DOMAIN);
+    hasher.update(keyfile);
+    Ok(Zeroizing::new(hasher.finalize().into()))
+}
+
+/// Returns the wrapping key and the nonce v1 wraps used with it. The trailing bytes of
+/// the Argon2 output are only needed to unlock keys wrapped before sealed wrapping.
+/// A keyfile secret keys Argon2, both the passphrase and the keyfile are then needed.
+pub fn derive_key(
+    passphrase: &str,
+    keyfile_secret: Option<&[u8; 32]>,
+    salt: &[u8],
+    params: &Argon2Params,
+) -> (Key, Vec<u8>) {
+    let argon2_params = Params::new(
+        params.memory_cost,
+        params.time_cost,
+        params.parallelism,
+        Some(KEY_MATERIAL_SIZE),
+    )
+    .unwrap();
+    let argon2 = match keyfile_secret {
+        Some(secret) => {
+            Argon2::new_with_secret(secret, Algorithm::Argon2id, Version::V0x13, argon2_params)
+                .unwrap()
+        }
+        None => Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params),
+    };
+    let mut output = Zeroizing::new([0u8; KEY_MATERIAL_SIZE]);
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt, &mut output[..])
+        .unwrap();
+    let key = Key::from_parts(CipherSuite::ChaCha20Poly1305, &output[..KEY_SIZE]);
+    (key, output[KEY_SIZE..].to_vec())
+}
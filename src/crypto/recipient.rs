@@ -0,0 +1,272 @@
+//! X25519 public key recipients.
+//!
+//! A key is wrapped to a recipient with an ephemeral-static Diffie-Hellman exchange.
+//! The shared secret goes through HKDF-SHA256, bound to both public keys, and the
+//! result seals the key. Anyone can wrap to a recipient, only the holder of the
+//! matching identity can unwrap.
+
+use chacha20poly1305::aead::OsRng;
+use hkdf::Hkdf;
+use json::{array, object, JsonValue};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    errors::HelixError,
+    util::hex::{decode_vec, encode, encode_vec},
+};
+
+use super::{
+    chacha::{
+        decryptors::ByteDecryptorImpl,
+        encryptors::ByteEncryptorImpl,
+        keys::{Key, KEY_SIZE},
+    },
+    suite::CipherSuite,
+    ByteDecryptor, ByteEncryptor,
+};
+
+const RECIPIENT_WRAP: &[u8] = b"helix/recipient/wrap/v1";
+const WRAP_FORMAT_VERSION: u32 = 2;
+/// Same as v2, every stanza sealed with associated data, see `crypto::binding`.
+const BOUND_WRAP_FORMAT_VERSION: u32 = 3;
+
+/// Public half, safe to hand to an encrypt-only host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipient {
+    public_key: PublicKey,
+}
+
+impl Recipient {
+    pub fn from_hex(public_key: &str) -> Result<Self, HelixError> {
+        let bytes: [u8; 32] = hex::decode(public_key.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(HelixError::from(
+                "BadInput",
+                "InvalidPublicKey",
+                "Public key must be 64 hex characters",
+            ))?;
+        Ok(Self {
+            public_key: PublicKey::from(bytes),
+        })
+    }
+
+    pub fn to_hex(&self) -> String {
+        encode(self.public_key.as_bytes())
+    }
+}
+
+/// Private half, kept in an identity file by whoever may decrypt.
+pub struct Identity {
+    secret: StaticSecret,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            secret: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    pub fn from_bytes(secret: [u8; 32]) -> Self {
+        Self {
+            secret: StaticSecret::from(secret),
+        }
+    }
+
+    /// Reads the first line of an identity file that is not a comment.
+    pub fn from_hex(identity: &str) -> Result<Self, HelixError> {
+        let line = identity
+            .lines()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .unwrap_or_default();
+        let bytes = Zeroizing::new(hex::decode(line).unwrap_or_default());
+        let bytes: Zeroizing<[u8; 32]> = Zeroizing::new(bytes[..].try_into().map_err(|_| {
+            HelixError::from(
+                "BadInput",
+                "InvalidIdentity",
+                "Identity file does not contain a private key",
+            )
+        })?);
+        Ok(Self::from_bytes(*bytes))
+    }
+
+    pub fn to_hex(&self) -> Zeroizing<String> {
+        Zeroizing::new(encode(self.secret.as_bytes()))
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8; 32] {
+        self.secret.as_bytes()
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient {
+            public_key: PublicKey::from(&self.secret),
+        }
+    }
+}
+
+fn wrapping_key(shared_secret: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> Key {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+    let mut output = Zeroizing::new([0u8; KEY_SIZE]);
+    hkdf.expand(RECIPIENT_WRAP, &mut output[..]).unwrap();
+    Key::from_parts(CipherSuite::XChaCha20Poly1305, &output[..])
+}
+
+/// Wraps a key to every recipient. `block` is stored in the clear next to the
+/// stanzas so that a host without any identity can find the block it wrote.
+/// With associated data the key is written as v3 and only unwraps with the same.
+pub fn wrap_to_recipients(
+    key: &Key,
+    recipients: &[Recipient],
+    block: Option<&str>,
+    associated_data: Option<&[u8]>,
+) -> String {
+    let mut stanzas = array![];
+    for recipient in recipients {
+        let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&ephemeral_secret);
+        let shared_secret = ephemeral_secret.diffie_hellman(&recipient.public_key);
+        let wrapping_key = wrapping_key(shared_secret.as_bytes(), &ephemeral, &recipient.public_key);
+        // Room for nonce and tag, the plain key must not be left in a reallocated buffer.
+        let mut wrapped = Vec::with_capacity(KEY_SIZE + 24 + 16);
+        wrapped.extend_from_slice(key.bytes());
+        ByteEncryptorImpl::from(&wrapping_key)
+            .with_associated_data(associated_data.unwrap_or_default().to_vec())
+            .encrypt(&mut wrapped);
+        stanzas
+            .push(object! {
+                pk: recipient.to_hex(),
+                epk: encode(ephemeral.as_bytes()),
+                key: encode_vec(wrapped)
+            })
+            .unwrap();
+    }
+    let version = match associated_data {
+        Some(_) => BOUND_WRAP_FORMAT_VERSION,
+        None => WRAP_FORMAT_VERSION,
+    };
+    let mut ob = object! {
+        v: version,
+        suite: key.suite().name(),
+        recipients: stanzas
+    };
+    if let Some(block) = block {
+        ob["block"] = JsonValue::from(block);
+    }
+    ob.dump()
+}
+
+/// None when the key was not wrapped to this identity. The associated data is only
+/// used for v3 keys, older ones were wrapped without.
+pub fn unwrap_with_identity(
+    key_string: &str,
+    identity: &Identity,
+    associated_data: Option<&[u8]>,
+) -> Result<Option<Key>, HelixError> {
+    let key_json = parse(key_string)?;
+    let associated_data = match key_json["v"].as_u32() {
+        Some(BOUND_WRAP_FORMAT_VERSION) => associated_data.unwrap_or_default(),
+        _ => b"",
+    };
+    let suite = CipherSuite::from_name(key_json["suite"].as_str().unwrap_or_default())?;
+    let recipient = identity.recipient();
+    let own_public_key = recipient.to_hex();
+    for stanza in key_json["recipients"].members() {
+        if stanza["pk"].as_str() != Some(&own_public_key) {
+            continue;
+        }
+        let ephemeral = Recipient::from_hex(stanza["epk"].as_str().unwrap_or_default())?;
+        let shared_secret = identity.secret.diffie_hellman(&ephemeral.public_key);
+        let wrapping_key = wrapping_key(
+            shared_secret.as_bytes(),
+            &ephemeral.public_key,
+            &recipient.public_key,
+        );
+        let mut wrapped = Zeroizing::new(decode_vec(stanza["key"].as_str().unwrap_or_default()));
+        ByteDecryptorImpl::from(&wrapping_key)
+            .with_associated_data(associated_data.to_vec())
+            .decrypt(&mut wrapped)?;
+        if wrapped.len() != KEY_SIZE {
+            return Err(HelixError::from(
+                "MalformedData",
+                "InvalidKeyLength",
+                "Wrapped key has an invalid length",
+            ));
+        }
+        return Ok(Some(Key::from_parts(suite, &wrapped)));
+    }
+    Ok(None)
+}
+
+pub fn is_recipient_wrapped(key_string: &str) -> bool {
+    match json::parse(key_string) {
+        Ok(key_json) => key_json["recipients"].is_array(),
+        Err(_) => false,
+    }
+}
+
+/// True for keys wrapped with associated data.
+pub fn is_bound(key_string: &str) -> bool {
+    match json::parse(key_string) {
+        Ok(key_json) => key_json["v"].as_u32() == Some(BOUND_WRAP_FORMAT_VERSION),
+        Err(_) => false,
+    }
+}
+
+/// Block name recorded by `wrap_to_recipients`, if any.
+pub fn wrapped_block(key_string: &str) -> Option<String> {
+    let key_json = json::parse(key_string).ok()?;
+    key_json["block"].as_str().map(String::from)
+}
+
+fn parse(key_string: &str) -> Result<JsonValue, HelixError> {
+    json::parse(key_string).map_err(|_| {
+        HelixError::from(
+            "MalformedData",
+            "InvalidWrappedKey",
+            "Wrapped key is not valid JSON",
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::chacha::keys::Key;
+
+    use super::{unwrap_with_identity, wrap_to_recipients, wrapped_block, Identity};
+
+    #[test]
+    fn recipient_wrap_test() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let eve = Identity::generate();
+        let key = Key::new();
+        let wrapped = wrap_to_recipients(
+            &key,
+            &[alice.recipient(), bob.recipient()],
+            Some("block"),
+            Some(b"row"),
+        );
+        for identity in [&alice, &bob] {
+            let unwrapped = unwrap_with_identity(&wrapped, identity, Some(b"row"))
+                .unwrap()
+                .unwrap();
+            assert_eq!(unwrapped.bytes(), key.bytes());
+            assert_eq!(unwrapped.suite(), key.suite());
+        }
+        assert!(unwrap_with_identity(&wrapped, &alice, Some(b"other row")).is_err());
+        assert!(unwrap_with_identity(&wrapped, &eve, Some(b"row"))
+            .unwrap()
+            .is_none());
+        assert_eq!(wrapped_block(&wrapped).unwrap(), "block");
+        let restored = Identity::from_hex(&format!("# comment\n{}\n", alice.to_hex().as_str())).unwrap();
+        assert_eq!(restored.recipient(), alice.recipient());
+    }
+}
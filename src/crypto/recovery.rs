@@ -0,0 +1,149 @@
+//! Printable recovery codes.
+//!
+//! A recovery code is 160 random bits followed by a 16 bit checksum, written as
+//! base32 in groups of four. The code has enough entropy to be used as key material
+//! directly, its wrapping key is derived with HKDF and no passphrase KDF.
+
+use chacha20poly1305::aead::OsRng;
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::errors::HelixError;
+
+use super::{
+    chacha::keys::{Key, KEY_SIZE},
+    suite::CipherSuite,
+};
+
+pub const RECOVERY_KDF: &str = "recovery-hkdf";
+const RECOVERY_KEY_SIZE: usize = 20;
+const CHECKSUM_SIZE: usize = 2;
+const GROUP_SIZE: usize = 4;
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const RECOVERY_WRAP: &[u8] = b"helix/recovery/wrap/v1";
+
+pub struct RecoveryKey {
+    bytes: [u8; RECOVERY_KEY_SIZE],
+}
+
+impl RecoveryKey {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; RECOVERY_KEY_SIZE];
+        OsRng.fill_bytes(&mut bytes);
+        Self { bytes }
+    }
+
+    /// Parses a code as printed by `to_code`. Case, spaces and dashes are ignored.
+    pub fn from_code(code: &str) -> Result<Self, HelixError> {
+        let invalid = || {
+            HelixError::from(
+                "BadInput",
+                "InvalidRecoveryCode",
+                "Recovery code is mistyped or incomplete",
+            )
+        };
+        let symbols: String = code
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let symbols = Zeroizing::new(symbols);
+        let decoded = Zeroizing::new(base32_decode(&symbols).ok_or_else(invalid)?);
+        if decoded.len() != RECOVERY_KEY_SIZE + CHECKSUM_SIZE {
+            return Err(invalid());
+        }
+        let (bytes, checksum) = decoded.split_at(RECOVERY_KEY_SIZE);
+        let bytes: [u8; RECOVERY_KEY_SIZE] = bytes.try_into().unwrap();
+        if checksum != Self::checksum(&bytes) {
+            return Err(invalid());
+        }
+        Ok(Self { bytes })
+    }
+
+    pub fn to_code(&self) -> Zeroizing<String> {
+        let mut data = Zeroizing::new(self.bytes.to_vec());
+        data.extend_from_slice(&Self::checksum(&self.bytes));
+        let symbols = Zeroizing::new(base32_encode(&data));
+        let code = symbols
+            .as_bytes()
+            .chunks(GROUP_SIZE)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect::<Vec<_>>()
+            .join("-");
+        Zeroizing::new(code)
+    }
+
+    pub fn wrapping_key(&self) -> Key {
+        let hkdf = Hkdf::<Sha256>::new(None, &self.bytes);
+        let mut output = Zeroizing::new([0u8; KEY_SIZE]);
+        hkdf.expand(RECOVERY_WRAP, &mut output[..]).unwrap();
+        Key::from_parts(CipherSuite::XChaCha20Poly1305, &output[..])
+    }
+
+    fn checksum(bytes: &[u8; RECOVERY_KEY_SIZE]) -> [u8; CHECKSUM_SIZE] {
+        let digest = Sha256::digest(bytes);
+        [digest[0], digest[1]]
+    }
+}
+
+impl Drop for RecoveryKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut symbols = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            symbols.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        symbols.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    symbols
+}
+
+fn base32_decode(symbols: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for symbol in symbols.bytes() {
+        let value = ALPHABET.iter().position(|c| *c == symbol)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecoveryKey;
+
+    #[test]
+    fn recovery_code_test() {
+        let recovery_key = RecoveryKey::generate();
+        let code = recovery_key.to_code();
+        assert_eq!(code.len(), 44);
+        let parsed = RecoveryKey::from_code(&code.to_lowercase().replace('-', " ")).unwrap();
+        assert_eq!(parsed.bytes, recovery_key.bytes);
+
+        let mut mistyped = code.as_bytes().to_vec();
+        mistyped[0] = if mistyped[0] == b'A' { b'B' } else { b'A' };
+        let error = RecoveryKey::from_code(std::str::from_utf8(&mistyped).unwrap()).err().unwrap();
+        assert_eq!(error.detailed_code, "InvalidRecoveryCode");
+        assert!(RecoveryKey::from_code("ABCD-EFGH").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Shamir secret sharing of a key for split custody.
+//!
+//! Each share is one line of text:
+//! `helix-share-v1:<threshold>:<suite>:<key check>:<share>:<checksum>`. The key check
+//! is derived from the shared key and tells a wrong combination of shares apart from
+//! the right one, the checksum catches shares that were mistyped or cut short.
+
+use std::collections::HashSet;
+
+use sha2::{Digest, Sha256};
+use blahaj::{Share, Sharks};
+use zeroize::Zeroizing;
+
+use crate::{
+    errors::HelixError,
+    util::hex::{decode_vec, encode},
+};
+
+use super::{
+    chacha::keys::{Key, KEY_SIZE},
+    kdf::derive,
+    suite::CipherSuite,
+};
+
+const SHARE_PREFIX: &str = "helix-share-v1";
+const KEY_CHECK: &[u8] = b"helix/share/check";
+const KEY_CHECK_SIZE: usize = 8;
+const CHECKSUM_SIZE: usize = 4;
+
+/// Splits a key into `count` shares, any `threshold` of which rebuild it.
+pub fn split(key: &Key, threshold: u8, count: u8) -> Result<Vec<Zeroizing<String>>, HelixError> {
+    if threshold < 2 || threshold > count {
+        return Err(HelixError::from(
+            "BadInput",
+            "InvalidThreshold",
+            "Threshold must be at least 2 and at most the number of shares",
+        ));
+    }
+    let key_check = key_check(key);
+    let shares = Sharks(threshold)
+        .dealer(key.bytes())
+        .take(count as usize)
+        .map(|share| {
+            let share = Zeroizing::new(Vec::from(&share));
+            let share = Zeroizing::new(encode(&share));
+            let body = Zeroizing::new(format!(
+                "{}:{}:{}:{}:{}",
+                SHARE_PREFIX,
+                threshold,
+                key.suite().name(),
+                key_check,
+                share.as_str()
+            ));
+            Zeroizing::new(format!("{}:{}", body.as_str(), checksum(&body)))
+        })
+        .collect();
+    Ok(shares)
+}
+
+/// Rebuilds a key from at least the threshold of its shares.
+pub fn combine(shares: &[Zeroizing<String>]) -> Result<Key, HelixError> {
+    let mut parsed = Vec::new();
+    let mut seen = HashSet::new();
+    for share in shares {
+        let share = ParsedShare::parse(share)?;
+        let mixed = parsed.first().is_some_and(|first: &ParsedShare| {
+            (first.threshold, first.suite, &first.key_check)
+                != (share.threshold, share.suite, &share.key_check)
+        });
+        if mixed {
+            return Err(HelixError::from(
+                "BadInput",
+                "MixedShares",
+                "Shares belong to different keys",
+            ));
+        }
+        // Interpolating the same point twice would divide by zero.
+        if seen.insert(share.share.x.0) {
+            parsed.push(share);
+        }
+    }
+    let Some(first) = parsed.first() else {
+        return Err(not_enough_shares());
+    };
+    if parsed.len() < first.threshold as usize {
+        return Err(not_enough_shares());
+    }
+    let secret = Sharks(first.threshold)
+        .recover(parsed.iter().map(|share| &share.share))
+        .map_err(|_| not_enough_shares())?;
+    let secret = Zeroizing::new(secret);
+    if secret.len() != KEY_SIZE {
+        return Err(invalid_share());
+    }
+    let key = Key::from_parts(first.suite, &secret);
+    if key_check(&key) != first.key_check {
+        return Err(HelixError::from(
+            "BadInput",
+            "ShareMismatch",
+            "Shares do not rebuild the key they were split from",
+        ));
+    }
+    Ok(key)
+}
+
+struct ParsedShare {
+    threshold: u8,
+    suite: CipherSuite,
+    key_check: String,
+    share: Share,
+}
+
+impl ParsedShare {
+    fn parse(text: &str) -> Result<Self, HelixError> {
+        let text = text.trim();
+        let (body, share_checksum) = text.rsplit_once(':').ok_or_else(invalid_share)?;
+        if checksum(body) != share_checksum {
+            return Err(invalid_share());
+        }
+        let fields: Vec<&str> = body.split(':').collect();
+        let [prefix, threshold, suite, key_check, share] = fields[..] else {
+            return Err(invalid_share());
+        };
+        if prefix != SHARE_PREFIX {
+            return Err(invalid_share());
+        }
+        Ok(Self {
+            threshold: threshold.parse().map_err(|_| invalid_share())?,
+            suite: CipherSuite::from_name(suite)?,
+            key_check: String::from(key_check),
+            share: parse_share(share)?,
+        })
+    }
+}
+
+/// A share at x = 0 would be the secret itself, the dealer never hands one out.
+fn parse_share(share: &str) -> Result<Share, HelixError> {
+    let share = Share::try_from(decode_vec(share).as_slice()).map_err(|_| invalid_share())?;
+    match share.x.0 {
+        0 => Err(invalid_share()),
+        _ => Ok(share),
+    }
+}
+
+fn key_check(key: &Key) -> String {
+    encode(&derive(key, KEY_CHECK)[..KEY_CHECK_SIZE])
+}
+
+fn checksum(body: &str) -> String {
+    encode(&Sha256::digest(body.as_bytes())[..CHECKSUM_SIZE])
+}
+
+fn invalid_share() -> HelixError {
+    HelixError::from(
+        "BadInput",
+        "InvalidShare",
+        "Share is mistyped or incomplete",
+    )
+}
+
+fn not_enough_shares() -> HelixError {
+    HelixError::from(
+        "BadInput",
+        "NotEnoughShares",
+        "Not enough shares to rebuild the key",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use zeroize::Zeroizing;
+
+    use crate::crypto::chacha::keys::Key;
+
+    use super::{checksum, combine, split};
+
+    #[test]
+    fn split_combine_test() {
+        let key = Key::new();
+        let shares = split(&key, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+        let combined = combine(&shares[1..4]).unwrap();
+        assert_eq!(combined.bytes(), key.bytes());
+        assert_eq!(combined.suite(), key.suite());
+        let combined = combine(&[shares[4].clone(), shares[0].clone(), shares[2].clone()]).unwrap();
+        assert_eq!(combined.bytes(), key.bytes());
+
+        let error = combine(&shares[..2]).err().unwrap();
+        assert_eq!(error.detailed_code, "NotEnoughShares");
+        let repeated = [shares[0].clone(), shares[0].clone(), shares[1].clone()];
+        assert_eq!(combine(&repeated).err().unwrap().detailed_code, "NotEnoughShares");
+        let mut mistyped = shares[0].as_bytes().to_vec();
+        let position = mistyped.len() - 12;
+        mistyped[position] = if mistyped[position] == b'0' { b'1' } else { b'0' };
+        let mistyped = Zeroizing::new(String::from_utf8(mistyped).unwrap());
+        let error = combine(&[mistyped, shares[1].clone(), shares[2].clone()]).err().unwrap();
+        assert_eq!(error.detailed_code, "InvalidShare");
+        let other = split(&Key::new(), 3, 5).unwrap();
+        let error = combine(&[other[0].clone(), shares[1].clone(), shares[2].clone()]).err().unwrap();
+        assert_eq!(error.detailed_code, "MixedShares");
+        let (body, _) = shares[0].rsplit_once(':').unwrap();
+        let (head, share) = body.rsplit_once(':').unwrap();
+        let body = format!("{}:00{}", head, &share[2..]);
+        let at_zero = Zeroizing::new(format!("{}:{}", body, checksum(&body)));
+        let error = combine(&[at_zero, shares[1].clone(), shares[2].clone()]).err().unwrap();
+        assert_eq!(error.detailed_code, "InvalidShare");
+        assert!(split(&key, 1, 5).is_err());
+        assert!(split(&key, 6, 5).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{
+    aead::{generic_array::GenericArray, AeadInPlace, Error},
+    ChaCha20Poly1305, KeyInit, XChaCha20Poly1305,
+};
+
+use crate::errors::HelixError;
+
+/// AEAD used for a key, a block or a whole capsule. The id is what goes on disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CipherSuite {
+    ChaCha20Poly1305,
+    /// 24 byte nonces are safe to pick at random, so this is the default.
+    #[default]
+    XChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl CipherSuite {
+    pub fn id(&self) -> u8 {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => 1,
+            CipherSuite::XChaCha20Poly1305 => 2,
+            CipherSuite::Aes256Gcm => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, HelixError> {
+        match id {
+            1 => Ok(CipherSuite::ChaCha20Poly1305),
+            2 => Ok(CipherSuite::XChaCha20Poly1305),
+            3 => Ok(CipherSuite::Aes256Gcm),
+            _ => Err(HelixError::from(
+                "UnsupportedFormat",
+                "UnknownCipherSuite",
+                &format!("Cipher suite {} is not supported", id),
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => "chacha20poly1305",
+            CipherSuite::XChaCha20Poly1305 => "xchacha20poly1305",
+            CipherSuite::Aes256Gcm => "aes256gcm",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, HelixError> {
+        match name {
+            "chacha20poly1305" => Ok(CipherSuite::ChaCha20Poly1305),
+            "xchacha20poly1305" => Ok(CipherSuite::XChaCha20Poly1305),
+            "aes256gcm" => Ok(CipherSuite::Aes256Gcm),
+            _ => Err(HelixError::from(
+                "BadInput",
+                "UnknownCipherSuite",
+                &format!(
+                    "Unknown cipher suite {}, expected one of chacha20poly1305, xchacha20poly1305, aes256gcm",
+                    name
+                ),
+            )),
+        }
+    }
+
+    pub fn nonce_size(&self) -> usize {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => 24,
+            CipherSuite::ChaCha20Poly1305 | CipherSuite::Aes256Gcm => 12,
+        }
+    }
+}
+
+pub(crate) enum SuiteCipher {
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+impl SuiteCipher {
+    pub fn new(suite: CipherSuite, key: &[u8]) -> Self {
+        let key = GenericArray::from_slice(key);
+        match suite {
+            CipherSuite::ChaCha20Poly1305 => Self::ChaCha20Poly1305(ChaCha20Poly1305::new(key)),
+            CipherSuite::XChaCha20Poly1305 => Self::XChaCha20Poly1305(XChaCha20Poly1305::new(key)),
+            CipherSuite::Aes256Gcm => Self::Aes256Gcm(Box::new(Aes256Gcm::new(key))),
+        }
+    }
+
+    pub fn encrypt_in_place(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match self {
+            Self::ChaCha20Poly1305(cipher) => {
+                cipher.encrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+            }
+            Self::XChaCha20Poly1305(cipher) => {
+                cipher.encrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+            }
+            Self::Aes256Gcm(cipher) => {
+                cipher.encrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+            }
+        }
+    }
+
+    pub fn decrypt_in_place(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match self {
+            Self::ChaCha20Poly1305(cipher) => {
+                cipher.decrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+            }
+            Self::XChaCha20Poly1305(cipher) => {
+                cipher.decrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+            }
+            Self::Aes256Gcm(cipher) => {
+                cipher.decrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CipherSuite, SuiteCipher};
+
+    #[test]
+    fn suite_round_trip_test() {
+        for suite in [
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::XChaCha20Poly1305,
+            CipherSuite::Aes256Gcm,
+        ] {
+            assert_eq!(CipherSuite::from_id(suite.id()).unwrap(), suite);
+            assert_eq!(CipherSuite::from_name(suite.name()).unwrap(), suite);
+            let cipher = SuiteCipher::new(suite, &[1u8; 32]);
+            let nonce = vec![2u8; suite.nonce_size()];
+            let mut data = b"helix".to_vec();
+            cipher.encrypt_in_place(&nonce, b"", &mut data).unwrap();
+            cipher.decrypt_in_place(&nonce, b"", &mut data).unwrap();
+            assert_eq!(data, b"helix");
+        }
+    }
+}
@@ -2,21 +2,44 @@ pub trait ChunkObserver {
     fn bytes_processed(&mut self, byte_count: u64);
 }
 
+/// Every chunk of a padded block starts with the length of its data, the rest of
+/// the chunk is padding.
+const DATA_LENGTH_SIZE: usize = 4;
+const TAG_SIZE: usize = 16;
+
 pub mod encryptors {
+    use zeroize::Zeroize;
+
     use crate::{
         crypto::{
-            chacha::{encryptors::ByteEncryptorImpl, keys::Key},
-            ByteEncryptor,
+            chacha::{
+                keys::Key,
+                stream::{random_nonce_prefix, StreamEncryptor},
+            },
+            padding::Padding,
+        },
+        errors::HelixError,
+        filecrypto::{
+            compression::{Compression, CompressionStats},
+            FileEncryptor,
+        },
+        fileio::{
+            header::{BlockHeader, BlockKind},
+            readers::FileReader,
+            writers::ChunkWriter,
         },
-        filecrypto::FileEncryptor,
-        fileio::{readers::FileReader, writers::ChunkWriter},
     };
 
-    use super::ChunkObserver;
+    use super::{ChunkObserver, DATA_LENGTH_SIZE, TAG_SIZE};
 
     pub struct CCFileEncryptor<'a> {
         key: &'a Key,
         chunk_size: u32,
+        padding: Padding,
+        compression: Compression,
+        kind: BlockKind,
+        associated_data: Vec<u8>,
+        stats: CompressionStats,
         observer: &'a mut dyn ChunkObserver,
     }
 
@@ -25,79 +48,381 @@ pub mod encryptors {
             Self {
                 key,
                 chunk_size,
+                padding: Padding::None,
+                compression: Compression::None,
+                kind: BlockKind::Data,
+                associated_data: Vec::new(),
+                stats: CompressionStats::default(),
                 observer,
             }
         }
+
+        /// Binds every chunk to e.g. the file it belongs to, see `crypto::binding`.
+        /// It is not stored, decryption has to supply the same bytes.
+        pub fn with_associated_data(mut self, associated_data: Vec<u8>) -> Self {
+            self.associated_data = associated_data;
+            self
+        }
+
+        /// Pads the plain length of the block. Padding that does not fit the last
+        /// chunk goes into chunks of its own, none is larger than the chunk size.
+        pub fn with_padding(mut self, padding: Padding) -> Self {
+            self.padding = padding;
+            self
+        }
+
+        /// Compresses every chunk that shrinks. Padding is applied to the compressed
+        /// size, which is the size that would otherwise show. With padding, chunks
+        /// before the last are padded back to full size so they do not show it either.
+        pub fn with_compression(mut self, compression: Compression) -> Self {
+            self.compression = compression;
+            self
+        }
+
+        pub fn with_kind(mut self, kind: BlockKind) -> Self {
+            self.kind = kind;
+            self
+        }
+
+        /// Plain and stored bytes of the blocks encrypted so far.
+        pub fn stats(&self) -> CompressionStats {
+            self.stats
+        }
     }
 
-    impl<'a> FileEncryptor for CCFileEncryptor<'a> {
-        fn encrypt(&mut self, source: &str, destination: &str) {
-            let byte_encryptor = ByteEncryptorImpl::from(&self.key);
-            let mut reader = FileReader::from(self.chunk_size, source);
-            let mut writer = ChunkWriter::from(destination);
-            let mut data = reader.next();
-            while let Some(mut buffer) = data {
+    impl<'a> CCFileEncryptor<'a> {
+        /// Encrypts plain chunks from any source into a block, e.g. the chunks of
+        /// another block while it is being decrypted.
+        pub fn encrypt_chunks(
+            &mut self,
+            mut chunks: impl Iterator<Item = Result<Vec<u8>, HelixError>>,
+            destination: &str,
+        ) -> Result<(), HelixError> {
+            let suite = self.key.suite();
+            let header = BlockHeader::from(suite, self.chunk_size, random_nonce_prefix(suite))
+                .with_padding(self.padding)
+                .with_compression(self.compression)
+                .with_kind(self.kind);
+            let associated_data = [header.to_bytes(), self.associated_data.clone()].concat();
+            let mut stream_encryptor =
+                StreamEncryptor::from(self.key, header.nonce_prefix.clone(), associated_data)?
+                    .with_chunk_index();
+            let mut writer = ChunkWriter::from(destination, &header);
+            // An empty file still gets one (empty) final chunk so truncation is detectable.
+            let mut buffer = chunks.next().transpose()?.unwrap_or_default();
+            let full_len = self.full_len();
+            let mut stored_len = 0;
+            let mut padding_len = 0;
+            loop {
+                let next = chunks.next().transpose()?;
                 let len = buffer.len();
-                byte_encryptor.encrypt(&mut buffer);
+                if self.compression != Compression::None {
+                    let compressed =
+                        self.compression.compress(&buffer, self.chunk_size as usize, TAG_SIZE);
+                    buffer.zeroize();
+                    buffer = compressed;
+                }
+                self.stats += CompressionStats {
+                    plain_bytes: len as u64,
+                    stored_bytes: buffer.len() as u64,
+                };
+                if self.padding != Padding::None {
+                    let zeros = full_len.saturating_sub(buffer.len());
+                    let zeros = if next.is_none() {
+                        stored_len += buffer.len() as u64;
+                        padding_len = self.padding.padded_len(stored_len) - stored_len;
+                        let zeros = zeros.min(padding_len as usize);
+                        padding_len -= zeros as u64;
+                        zeros
+                    } else {
+                        stored_len += full_len as u64;
+                        zeros
+                    };
+                    buffer = Self::frame(buffer, zeros);
+                }
+                stream_encryptor.encrypt_next(&mut buffer, next.is_none() && padding_len == 0)?;
                 writer.write(buffer);
-                data = reader.next();
                 self.observer.bytes_processed(len as u64);
+                match next {
+                    Some(data) => buffer = data,
+                    None => break,
+                }
+            }
+            while padding_len > 0 {
+                let zeros = full_len.min(padding_len as usize);
+                padding_len -= zeros as u64;
+                let mut buffer = Self::frame(Vec::new(), zeros);
+                stream_encryptor.encrypt_next(&mut buffer, padding_len == 0)?;
+                writer.write(buffer);
             }
             writer.close();
+            Ok(())
+        }
+
+        /// Largest data a chunk stores, compression adds a flag byte to the chunk size.
+        fn full_len(&self) -> usize {
+            let flag_len = usize::from(self.compression != Compression::None);
+            self.chunk_size.max(1) as usize + flag_len
+        }
+
+        /// Prefixes a chunk with the length of its data and appends `zeros` bytes of
+        /// padding.
+        fn frame(mut data: Vec<u8>, zeros: usize) -> Vec<u8> {
+            let mut framed = Vec::with_capacity(DATA_LENGTH_SIZE + data.len() + zeros + TAG_SIZE);
+            framed.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            framed.extend_from_slice(&data);
+            framed.resize(framed.len() + zeros, 0);
+            data.zeroize();
+            framed
+        }
+    }
+
+    impl<'a> FileEncryptor for CCFileEncryptor<'a> {
+        fn encrypt(&mut self, source: &str, destination: &str) -> Result<(), HelixError> {
+            let mut reader = FileReader::from(self.chunk_size, source)?;
+            self.encrypt_chunks(std::iter::from_fn(|| reader.next().map(Ok)), destination)
         }
     }
 }
 
 pub mod decryptors {
+    use zeroize::Zeroize;
 
     use crate::{
         crypto::{
-            chacha::{decryptors::ByteDecryptorImpl, keys::Key},
-            ByteDecryptor,
+            chacha::{keys::Key, stream::StreamDecryptor},
+            padding::Padding,
         },
+        errors::HelixError,
         filecrypto::FileDecryptor,
-        fileio::{readers::ChunkReader, writers::FileWriter},
+        fileio::{
+            header::{BlockHeader, BINDING_VERSION, LEGACY_VERSION},
+            readers::ChunkReader,
+            writers::FileWriter,
+        },
     };
 
-    use super::ChunkObserver;
+    use super::{ChunkObserver, DATA_LENGTH_SIZE};
 
     pub struct CCFileDecryptor<'a> {
         key: &'a Key,
+        associated_data: Vec<u8>,
+        legacy_nonce: Option<Vec<u8>>,
         observer: &'a mut dyn ChunkObserver,
     }
 
     impl<'a> CCFileDecryptor<'a> {
         pub fn from(key: &'a Key, observer: &'a mut dyn ChunkObserver) -> Self {
-            Self { key, observer }
+            Self {
+                key,
+                associated_data: Vec::new(),
+                legacy_nonce: None,
+                observer,
+            }
+        }
+
+        /// Associated data the block was encrypted with.
+        pub fn with_associated_data(mut self, associated_data: Vec<u8>) -> Self {
+            self.associated_data = associated_data;
+            self
+        }
+
+        /// Decrypts a version 0 block with the nonce stored next to its file key, see
+        /// `legacy_chunks`.
+        pub fn with_legacy_nonce(mut self, legacy_nonce: Vec<u8>) -> Self {
+            self.legacy_nonce = Some(legacy_nonce);
+            self
+        }
+    }
+
+    impl<'a> CCFileDecryptor<'a> {
+        /// Opens a block for chunk by chunk decryption without writing plaintext anywhere.
+        /// Blocks older than `BINDING_VERSION` ignore the associated data.
+        pub fn chunks(
+            key: &Key,
+            source: &str,
+            associated_data: &[u8],
+        ) -> Result<DecryptedChunks, HelixError> {
+            let reader = ChunkReader::from(source)?;
+            let header = reader.header().clone();
+            if header.version == LEGACY_VERSION {
+                return Err(HelixError::from(
+                    "UnsupportedBlockFormat",
+                    "LegacyBlock",
+                    "Block has no header, it needs the nonce stored with its file key",
+                ));
+            }
+            if header.suite != key.suite() {
+                return Err(HelixError::from(
+                    "MalformedBlock",
+                    "CipherSuiteMismatch",
+                    "Block cipher suite does not match the cipher suite of its key",
+                ));
+            }
+            let stream_decryptor = if header.version >= BINDING_VERSION {
+                let associated_data = [&header.to_bytes()[..], associated_data].concat();
+                StreamDecryptor::from(key, header.nonce_prefix.clone(), associated_data)?
+                    .with_chunk_index()
+            } else {
+                StreamDecryptor::from(key, header.nonce_prefix.clone(), header.to_bytes())?
+            };
+            let chunks = DecryptedChunks::from(reader, stream_decryptor)?;
+            if chunks.next.is_none() {
+                return Err(HelixError::from(
+                    "MalformedBlock",
+                    "EmptyBlock",
+                    "Block does not contain any chunk",
+                ));
+            }
+            Ok(chunks)
+        }
+
+        /// Opens a version 0 block, see `BlockHeader::legacy`. Its chunks are sealed
+        /// with the file key and the nonce that was stored next to it. An empty file
+        /// was written as an empty block.
+        pub fn legacy_chunks(
+            key: &Key,
+            nonce: &[u8],
+            source: &str,
+        ) -> Result<DecryptedChunks, HelixError> {
+            let reader = ChunkReader::from(source)?;
+            if reader.header().version != LEGACY_VERSION {
+                return Err(HelixError::from(
+                    "MalformedBlock",
+                    "UnexpectedBlockHeader",
+                    "Block has a header but its file key predates block headers",
+                ));
+            }
+            let stream_decryptor = StreamDecryptor::legacy(key, nonce.to_vec())?;
+            DecryptedChunks::from(reader, stream_decryptor)
         }
     }
 
     impl<'a> FileDecryptor for CCFileDecryptor<'a> {
-        fn decrypt(&mut self, source: &str, destination: &str) {
-            let byte_encryptor = ByteDecryptorImpl::from(&self.key);
-            let mut reader = ChunkReader::from(source);
+        fn decrypt(&mut self, source: &str, destination: &str) -> Result<(), HelixError> {
+            let chunks = match &self.legacy_nonce {
+                Some(nonce) => Self::legacy_chunks(self.key, nonce, source)?,
+                None => Self::chunks(self.key, source, &self.associated_data)?,
+            };
             let mut writer = FileWriter::from(destination);
-            let mut data = reader.next();
-            while let Some(mut buffer) = data {
+            for buffer in chunks {
+                let buffer = buffer?;
                 let len = buffer.len();
-                byte_encryptor.decrypt(&mut buffer);
                 writer.write(buffer);
-                data = reader.next();
                 self.observer.bytes_processed(len as u64);
             }
             writer.close();
+            Ok(())
+        }
+    }
+
+    /// Plain chunks of a block, authenticated one at a time. The last chunk is only
+    /// accepted if it carries the final-chunk flag. Padding is stripped, compressed
+    /// chunks are decompressed, and empty chunks are skipped unless the whole block
+    /// is empty.
+    pub struct DecryptedChunks {
+        reader: ChunkReader,
+        header: BlockHeader,
+        stream_decryptor: StreamDecryptor,
+        next: Option<Vec<u8>>,
+        yielded: bool,
+    }
+
+    impl DecryptedChunks {
+        fn from(
+            mut reader: ChunkReader,
+            stream_decryptor: StreamDecryptor,
+        ) -> Result<Self, HelixError> {
+            let header = reader.header().clone();
+            let next = reader.next().transpose()?;
+            Ok(Self {
+                reader,
+                header,
+                stream_decryptor,
+                next,
+                yielded: false,
+            })
+        }
+
+        pub fn header(&self) -> &BlockHeader {
+            &self.header
+        }
+    }
+
+    impl Iterator for DecryptedChunks {
+        type Item = Result<Vec<u8>, HelixError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                let mut buffer = self.next.take()?;
+                self.next = match self.reader.next().transpose() {
+                    Ok(next) => next,
+                    Err(error) => return Some(Err(error)),
+                };
+                let last = self.next.is_none();
+                let padding = self.header.padding;
+                let chunk_size = self.header.chunk_size as usize;
+                let result = self
+                    .stream_decryptor
+                    .decrypt_next(&mut buffer, last)
+                    .and_then(|_| strip_padding(padding, &mut buffer))
+                    .and_then(|_| self.header.compression.decompress(buffer, chunk_size));
+                match result {
+                    Ok(buffer) if buffer.is_empty() && (!last || self.yielded) => continue,
+                    Ok(buffer) => {
+                        self.yielded = true;
+                        return Some(Ok(buffer));
+                    }
+                    Err(error) => {
+                        self.next = None;
+                        return Some(Err(error));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Leaves only the data of a chunk of a padded block.
+    fn strip_padding(padding: Padding, buffer: &mut Vec<u8>) -> Result<(), HelixError> {
+        if padding == Padding::None {
+            return Ok(());
+        }
+        let data_len = buffer
+            .get(..DATA_LENGTH_SIZE)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize);
+        match data_len {
+            Some(len) if len <= buffer.len() - DATA_LENGTH_SIZE => {
+                buffer.copy_within(DATA_LENGTH_SIZE..DATA_LENGTH_SIZE + len, 0);
+                buffer[len..].zeroize();
+                buffer.truncate(len);
+                Ok(())
+            }
+            _ => Err(HelixError::from(
+                "MalformedBlock",
+                "InvalidPadding",
+                "Chunk data length does not fit the chunk",
+            )),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{env, fs};
+
     use crate::{
-        crypto::chacha::keys::Key,
+        crypto::{chacha::keys::Key, padding::Padding, suite::CipherSuite},
         filecrypto::{
             chacha::{decryptors::CCFileDecryptor, encryptors::CCFileEncryptor},
+            compression::Compression,
             FileDecryptor, FileEncryptor,
         },
+        fileio::{
+            header::BLOCK_FORMAT_VERSION,
+            readers::ChunkReader,
+            writers::ChunkWriter,
+        },
+        util::{hex::decode_vec, uuid::generate},
     };
 
     use super::ChunkObserver;
@@ -118,14 +443,296 @@ mod tests {
         // let source = String::from("D:\\test\\1.txt");
         let dest = String::from("D:\\test\\1enc3");
         print!("encrypting");
-        encryptor.encrypt(&source, &dest);
+        encryptor.encrypt(&source, &dest).unwrap();
         let mut binding = NOPObserver;
         let mut decryptor = CCFileDecryptor::from(&key, &mut binding);
         let dec_source = String::from("D:\\test\\1enc3");
         let dec_dest = String::from("D:\\test\\dec.pdf");
         // let dec_dest = String::from("D:\\test\\1dec.txt");
         print!("decrypting");
-        decryptor.decrypt(&dec_source, &dec_dest);
+        decryptor.decrypt(&dec_source, &dec_dest).unwrap();
         print!("done")
     }
+
+    fn temp_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("helix-{}-{}", generate(), name));
+        String::from(path.to_str().unwrap())
+    }
+
+    fn encrypt_to_block(key: &Key, data: &[u8], chunk_size: u32) -> String {
+        encrypt_with(key, data, chunk_size, Padding::None, Compression::None)
+    }
+
+    fn encrypt_with(
+        key: &Key,
+        data: &[u8],
+        chunk_size: u32,
+        padding: Padding,
+        compression: Compression,
+    ) -> String {
+        let source = temp_path("plain");
+        let block = temp_path("block");
+        fs::write(&source, data).unwrap();
+        let mut observer = NOPObserver;
+        let mut encryptor = CCFileEncryptor::from(key, chunk_size, &mut observer)
+            .with_padding(padding)
+            .with_compression(compression);
+        encryptor.encrypt(&source, &block).unwrap();
+        fs::remove_file(source).unwrap();
+        block
+    }
+
+    fn rewrite_chunks(block: &str, rewrite: impl Fn(&mut Vec<Vec<u8>>)) {
+        let mut reader = ChunkReader::from(block).unwrap();
+        let header = reader.header().clone();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = reader.next() {
+            chunks.push(chunk.unwrap());
+        }
+        rewrite(&mut chunks);
+        let mut writer = ChunkWriter::from(block, &header);
+        for chunk in chunks {
+            writer.write(chunk);
+        }
+        writer.close();
+    }
+
+    fn decrypt_block(key: &Key, block: &str) -> Result<Vec<u8>, crate::errors::HelixError> {
+        let destination = temp_path("decrypted");
+        let mut observer = NOPObserver;
+        let mut decryptor = CCFileDecryptor::from(key, &mut observer);
+        let result = decryptor.decrypt(block, &destination);
+        let data = fs::read(&destination).unwrap_or_default();
+        let _ = fs::remove_file(destination);
+        result.map(|_| data)
+    }
+
+    #[test]
+    fn multi_chunk_round_trip_test() {
+        let key = Key::new();
+        let data: Vec<u8> = (0..100u8).collect();
+        let block = encrypt_to_block(&key, &data, 16);
+        assert_eq!(decrypt_block(&key, &block).unwrap(), data);
+        let empty = encrypt_to_block(&key, b"", 16);
+        assert_eq!(decrypt_block(&key, &empty).unwrap(), b"");
+        fs::remove_file(block).unwrap();
+        fs::remove_file(empty).unwrap();
+    }
+
+    #[test]
+    fn padded_round_trip_test() {
+        let key = Key::new();
+        let data: Vec<u8> = (0..100u8).collect();
+        // 70 and 100 bytes both pad to 128, 20 bytes to 32.
+        let sizes: Vec<u64> = [70, 100, 20]
+            .into_iter()
+            .map(|len| {
+                let block = encrypt_with(&key, &data[..len], 16, Padding::PowerOfTwo, Compression::None);
+                let size = fs::metadata(&block).unwrap().len();
+                fs::remove_file(block).unwrap();
+                size
+            })
+            .collect();
+        assert_eq!(sizes[0], sizes[1]);
+        assert!(sizes[2] < sizes[0]);
+
+        for padding in [Padding::Padme, Padding::PowerOfTwo] {
+            for len in [0, 1, 15, 16, 17, 100] {
+                let block = encrypt_with(&key, &data[..len], 16, padding, Compression::None);
+                assert_eq!(decrypt_block(&key, &block).unwrap(), &data[..len]);
+                // Only the chunks holding data come back, or one empty chunk for an empty file.
+                let chunks: Vec<Vec<u8>> = CCFileDecryptor::chunks(&key, &block, &[])
+                    .unwrap()
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                assert_eq!(chunks.len(), len.div_ceil(16).max(1));
+                fs::remove_file(block).unwrap();
+            }
+        }
+        let block = encrypt_with(&key, &data, 16, Padding::PowerOfTwo, Compression::None);
+        rewrite_chunks(&block, |chunks| {
+            chunks.pop();
+        });
+        assert!(decrypt_block(&key, &block).is_err());
+        fs::remove_file(block).unwrap();
+    }
+
+    #[test]
+    fn compressed_round_trip_test() {
+        let key = Key::new();
+        let text = b"log line: nothing happened\n".repeat(200);
+        let plain = encrypt_to_block(&key, &text, 1024);
+        let compressed = encrypt_with(&key, &text, 1024, Padding::None, Compression::Zstd);
+        let plain_size = fs::metadata(&plain).unwrap().len();
+        assert!(fs::metadata(&compressed).unwrap().len() < plain_size / 4);
+        assert_eq!(decrypt_block(&key, &compressed).unwrap(), text);
+        let padded = encrypt_with(&key, &text, 1024, Padding::Padme, Compression::Zstd);
+        assert_eq!(decrypt_block(&key, &padded).unwrap(), text);
+
+        // Incompressible chunks are stored raw, one flag byte per chunk is all they grow.
+        let random: Vec<u8> = (0..4096).map(|_| rand::random::<u8>()).collect();
+        let raw = encrypt_to_block(&key, &random, 1024);
+        let flagged = encrypt_with(&key, &random, 1024, Padding::None, Compression::Zstd);
+        let raw_size = fs::metadata(&raw).unwrap().len();
+        assert_eq!(fs::metadata(&flagged).unwrap().len(), raw_size + 4);
+        assert_eq!(decrypt_block(&key, &flagged).unwrap(), random);
+        let empty = encrypt_with(&key, b"", 1024, Padding::None, Compression::Zstd);
+        assert_eq!(decrypt_block(&key, &empty).unwrap(), b"");
+
+        // With padding, chunks before the last are stored at full size whether they
+        // compressed or not.
+        let padded_random = encrypt_with(&key, &random, 1024, Padding::Padme, Compression::Zstd);
+        assert_eq!(decrypt_block(&key, &padded_random).unwrap(), random);
+        let stored_lengths = |block: &str| {
+            let mut reader = ChunkReader::from(block).unwrap();
+            std::iter::from_fn(|| reader.next())
+                .map(|chunk| chunk.unwrap().len())
+                .collect::<Vec<_>>()
+        };
+        let text_lengths = stored_lengths(&padded);
+        let random_lengths = stored_lengths(&padded_random);
+        let full_length = random_lengths[0];
+        assert!(text_lengths[..text_lengths.len() - 1].iter().all(|&len| len == full_length));
+        assert!(random_lengths[..random_lengths.len() - 1].iter().all(|&len| len == full_length));
+        for block in [plain, compressed, padded, raw, flagged, empty, padded_random] {
+            fs::remove_file(block).unwrap();
+        }
+    }
+
+    #[test]
+    fn tampered_chunk_order_test() {
+        let key = Key::new();
+        let data: Vec<u8> = (0..100u8).collect();
+        let block = encrypt_to_block(&key, &data, 16);
+
+        rewrite_chunks(&block, |chunks| chunks.swap(0, 1));
+        assert!(decrypt_block(&key, &block).is_err());
+        rewrite_chunks(&block, |chunks| chunks.swap(0, 1));
+
+        rewrite_chunks(&block, |chunks| chunks.insert(1, chunks[0].clone()));
+        assert!(decrypt_block(&key, &block).is_err());
+        rewrite_chunks(&block, |chunks| {
+            chunks.remove(1);
+        });
+
+        rewrite_chunks(&block, |chunks| {
+            chunks.pop();
+        });
+        assert!(decrypt_block(&key, &block).is_err());
+        fs::remove_file(block).unwrap();
+    }
+
+    #[test]
+    fn truncated_block_test() {
+        let key = Key::new();
+        let data: Vec<u8> = (0..100u8).collect();
+        let block = encrypt_to_block(&key, &data, 16);
+        let bytes = fs::read(&block).unwrap();
+        // Seven chunks, each with a length and a tag.
+        let header_len = bytes.len() - data.len() - 7 * (4 + 16);
+        // Cut inside the last chunk, and inside the length of the second one.
+        for cut in [bytes.len() - 3, header_len + 4 + 16 + 16 + 2] {
+            fs::write(&block, &bytes[..cut]).unwrap();
+            let error = decrypt_block(&key, &block).unwrap_err();
+            assert_eq!(error.detailed_code, "TruncatedBlock");
+        }
+        // A forged length is rejected before anything is allocated for it.
+        let mut forged = bytes.clone();
+        forged[header_len..header_len + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        fs::write(&block, forged).unwrap();
+        assert_eq!(decrypt_block(&key, &block).unwrap_err().detailed_code, "OversizedChunk");
+        fs::remove_file(block).unwrap();
+    }
+
+    #[test]
+    fn tampered_header_test() {
+        let key = Key::new();
+        let block = encrypt_to_block(&key, b"header bound", 16);
+        let mut bytes = fs::read(&block).unwrap();
+        // chunk size field of the header
+        bytes[9] ^= 1;
+        fs::write(&block, bytes).unwrap();
+        let error = decrypt_block(&key, &block).unwrap_err();
+        assert_eq!(error.detailed_code, "ChunkAuthenticationFailed");
+        fs::remove_file(block).unwrap();
+    }
+
+    #[test]
+    fn missing_block_test() {
+        let error = ChunkReader::from(&temp_path("missing")).err().unwrap();
+        assert_eq!(error.detailed_code, "BlockNotFound");
+    }
+
+    #[test]
+    fn bound_block_test() {
+        let key = Key::new();
+        let source = temp_path("plain");
+        let block = temp_path("block");
+        fs::write(&source, b"bound to its file").unwrap();
+        let mut observer = NOPObserver;
+        CCFileEncryptor::from(&key, 16, &mut observer)
+            .with_associated_data(b"file a".to_vec())
+            .encrypt(&source, &block)
+            .unwrap();
+        let decrypt = |associated_data: &[u8]| {
+            CCFileDecryptor::chunks(&key, &block, associated_data)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+        };
+        assert_eq!(decrypt(b"file a").unwrap().concat(), b"bound to its file");
+        assert!(decrypt(b"file b").is_err());
+        assert!(decrypt(b"").is_err());
+        fs::remove_file(source).unwrap();
+        fs::remove_file(block).unwrap();
+    }
+
+    #[test]
+    fn legacy_block_test() {
+        // Written at baseline by `CCFileEncryptor` with a chunk size of 24.
+        let key = Key::from_parts(
+            CipherSuite::ChaCha20Poly1305,
+            &decode_vec("b0d6878faae801dd851a85fe96d3b17ea2150086bdef71721f4843a7fd475c11"),
+        );
+        let nonce = decode_vec("b0d6878faae801dd851a85fe");
+        let block = temp_path("block");
+        fs::write(
+            &block,
+            decode_vec(concat!(
+                "00000028f784d0ad7f46426e900b760e1f3fd386631cb962a9a0509a624a0061fd10fcefc937",
+                "a53c68d2f2ed0000001ea09fd7f97f54436e911a23141c2961fedbc2daf99cce42b3d91a10",
+                "5bd2b0",
+            )),
+        )
+        .unwrap();
+        let plain = b"written by the baseline, in two chunks";
+        let error = CCFileDecryptor::chunks(&key, &block, &[]).err().unwrap();
+        assert_eq!(error.detailed_code, "LegacyBlock");
+        assert!(CCFileDecryptor::legacy_chunks(&key, &[0u8; 12], &block)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .is_err());
+        let destination = temp_path("decrypted");
+        let mut observer = NOPObserver;
+        CCFileDecryptor::from(&key, &mut observer)
+            .with_legacy_nonce(nonce.clone())
+            .decrypt(&block, &destination)
+            .unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), plain);
+
+        // Upgrading streams the chunks into a block of the current format.
+        let new_key = Key::new();
+        let upgraded = temp_path("upgraded");
+        let chunks = CCFileDecryptor::legacy_chunks(&key, &nonce, &block).unwrap();
+        let mut observer = NOPObserver;
+        CCFileEncryptor::from(&new_key, chunks.header().chunk_size, &mut observer)
+            .encrypt_chunks(chunks, &upgraded)
+            .unwrap();
+        assert_eq!(ChunkReader::from(&upgraded).unwrap().header().version, BLOCK_FORMAT_VERSION);
+        assert_eq!(decrypt_block(&new_key, &upgraded).unwrap(), plain);
+        let error = CCFileDecryptor::legacy_chunks(&new_key, &nonce, &upgraded).err().unwrap();
+        assert_eq!(error.detailed_code, "UnexpectedBlockHeader");
+        for path in [block, destination, upgraded] {
+            fs::remove_file(path).unwrap();
+        }
+    }
 }
@@ -0,0 +1,115 @@
+//! How files are split before they are stored.
+//!
+//! Fixed chunking keeps one block per file. Content-defined chunking (FastCDC) cuts
+//! files where their content says so, so an insert or an append only changes the
+//! chunks around it and equal chunks of different files can be stored once.
+
+use std::fs::File;
+
+use fastcdc::v2020::{Normalization, StreamCDC};
+
+use crate::errors::HelixError;
+
+pub const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+pub const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+pub const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Chunking {
+    #[default]
+    Fixed,
+    ContentDefined,
+}
+
+impl Chunking {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Chunking::Fixed => "fixed",
+            Chunking::ContentDefined => "cdc",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, HelixError> {
+        match name {
+            "fixed" => Ok(Chunking::Fixed),
+            "cdc" => Ok(Chunking::ContentDefined),
+            _ => Err(HelixError::from(
+                "BadInput",
+                "UnknownChunking",
+                &format!("Unknown chunking {}, expected one of fixed, cdc", name),
+            )),
+        }
+    }
+}
+
+/// Content-defined chunks of a file. The seed keys the gear table, so the same file
+/// is cut at different points in different capsules.
+pub fn content_defined_chunks(
+    file_path: &str,
+    seed: u64,
+) -> Result<impl Iterator<Item = Result<Vec<u8>, HelixError>>, HelixError> {
+    let file = File::open(file_path).map_err(|_| {
+        HelixError::from(
+            "BadInput",
+            "FileNotReadable",
+            &format!("Can not read {}", file_path),
+        )
+    })?;
+    let chunker = StreamCDC::with_level_and_seed(
+        file,
+        MIN_CHUNK_SIZE,
+        AVG_CHUNK_SIZE,
+        MAX_CHUNK_SIZE,
+        Normalization::Level1,
+        seed,
+    );
+    Ok(chunker.map(|chunk| {
+        chunk.map(|chunk| chunk.data).map_err(|_| {
+            HelixError::from(
+                "BadInput",
+                "FileNotReadable",
+                "File changed while it was read",
+            )
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::util::uuid::generate;
+
+    use super::{content_defined_chunks, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+
+    #[test]
+    fn append_keeps_chunks_test() {
+        let mut data = vec![0u8; 3 * MAX_CHUNK_SIZE as usize];
+        let mut state = 7u32;
+        for byte in data.iter_mut() {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            *byte = (state >> 16) as u8;
+        }
+        let path = env::temp_dir().join(format!("helix-{}-cdc", generate()));
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, &data).unwrap();
+        let chunks: Vec<Vec<u8>> = content_defined_chunks(path_str, 1)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|chunk| chunk.len() >= MIN_CHUNK_SIZE as usize
+                && chunk.len() <= MAX_CHUNK_SIZE as usize));
+
+        data.extend_from_slice(b"one more line\n");
+        fs::write(&path, &data).unwrap();
+        let appended: Vec<Vec<u8>> = content_defined_chunks(path_str, 1)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(appended[..chunks.len() - 1], chunks[..chunks.len() - 1]);
+        fs::remove_file(path).unwrap();
+    }
+}
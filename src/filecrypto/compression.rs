@@ -0,0 +1,177 @@
+//! Per chunk compression before encryption.
+//!
+//! Every chunk of a compressed block starts with a flag byte telling whether the rest
+//! of it is zstd compressed or stored raw. Chunks that do not shrink, e.g. of JPEG or
+//! MP4 files, are stored raw.
+
+use std::ops::AddAssign;
+
+use zeroize::Zeroize;
+
+use crate::errors::HelixError;
+
+const RAW: u8 = 0;
+const ZSTD: u8 = 1;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression of a block. The id is what goes on disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, HelixError> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            _ => Err(HelixError::from(
+                "UnsupportedFormat",
+                "UnknownCompression",
+                &format!("Compression {} is not supported", id),
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, HelixError> {
+        match name {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(HelixError::from(
+                "BadInput",
+                "UnknownCompression",
+                &format!("Unknown compression {}, expected one of none, zstd", name),
+            )),
+        }
+    }
+
+    /// Flag byte followed by the chunk, compressed if that makes it smaller. Chunks
+    /// larger than `max_len` are stored raw so they always decompress. The result
+    /// has `spare` bytes of capacity left, e.g. for the tag it is sealed with.
+    pub(super) fn compress(&self, data: &[u8], max_len: usize, spare: usize) -> Vec<u8> {
+        let compressed = match self {
+            Compression::Zstd if data.len() <= max_len => {
+                zstd::bulk::compress(data, ZSTD_LEVEL).ok()
+            }
+            _ => None,
+        };
+        let (flag, payload) = match &compressed {
+            Some(compressed) if compressed.len() < data.len() => (ZSTD, compressed.as_slice()),
+            _ => (RAW, data),
+        };
+        let mut chunk = Vec::with_capacity(1 + payload.len() + spare);
+        chunk.push(flag);
+        chunk.extend_from_slice(payload);
+        if let Some(mut compressed) = compressed {
+            compressed.zeroize();
+        }
+        chunk
+    }
+
+    /// Reverses `compress`. Chunks of uncompressed blocks and empty chunks, that only
+    /// held padding, are returned as they are.
+    pub(super) fn decompress(
+        &self,
+        mut chunk: Vec<u8>,
+        max_len: usize,
+    ) -> Result<Vec<u8>, HelixError> {
+        if *self == Compression::None || chunk.is_empty() {
+            return Ok(chunk);
+        }
+        let data = match chunk[0] {
+            RAW => {
+                let len = chunk.len() - 1;
+                chunk.copy_within(1.., 0);
+                chunk[len..].zeroize();
+                chunk.truncate(len);
+                return Ok(chunk);
+            }
+            ZSTD => zstd::bulk::decompress(&chunk[1..], max_len).map_err(|_| {
+                HelixError::from(
+                    "MalformedBlock",
+                    "DecompressionFailed",
+                    "Chunk does not decompress to at most the chunk size",
+                )
+            }),
+            flag => Err(HelixError::from(
+                "MalformedBlock",
+                "UnknownChunkFlag",
+                &format!("Chunk flag {} is not supported", flag),
+            )),
+        };
+        chunk.zeroize();
+        data
+    }
+}
+
+/// Plain bytes read and bytes stored after compression, padding not included.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub plain_bytes: u64,
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Stored size as a percentage of the plain size.
+    pub fn ratio(&self) -> f64 {
+        if self.plain_bytes == 0 {
+            return 100f64;
+        }
+        self.stored_bytes as f64 / self.plain_bytes as f64 * 100f64
+    }
+}
+
+impl AddAssign for CompressionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.plain_bytes += other.plain_bytes;
+        self.stored_bytes += other.stored_bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compression;
+
+    #[test]
+    fn compress_test() {
+        let text = b"helix helix helix helix helix helix helix helix helix helix".repeat(10);
+        let compressed = Compression::Zstd.compress(&text, text.len(), 16);
+        assert!(compressed.len() < text.len());
+        assert_eq!(
+            Compression::Zstd
+                .decompress(compressed, text.len())
+                .unwrap(),
+            text
+        );
+        let error = Compression::Zstd
+            .decompress(Compression::Zstd.compress(&text, text.len(), 0), 10)
+            .unwrap_err();
+        assert_eq!(error.detailed_code, "DecompressionFailed");
+
+        // Random bytes do not shrink and are stored raw.
+        let random: Vec<u8> = (0..4096).map(|_| rand::random::<u8>()).collect();
+        let stored = Compression::Zstd.compress(&random, random.len(), 0);
+        assert_eq!(stored.len(), random.len() + 1);
+        assert_eq!(
+            Compression::Zstd.decompress(stored, random.len()).unwrap(),
+            random
+        );
+        assert!(Compression::Zstd.decompress(vec![7, 1, 2], 10).is_err());
+    }
+}
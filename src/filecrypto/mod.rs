@@ -1,9 +1,13 @@
+use crate::errors::HelixError;
+
 pub mod chacha;
+pub mod chunking;
+pub mod compression;
 
 pub trait FileEncryptor{
-    fn encrypt(&mut self, source: &str, destination: &str);
+    fn encrypt(&mut self, source: &str, destination: &str) -> Result<(), HelixError>;
 }
 
 pub trait FileDecryptor{
-    fn decrypt(&mut self, source: &str, destination: &str);
-}
\ No newline at end of file
+    fn decrypt(&mut self, source: &str, destination: &str) -> Result<(), HelixError>;
+}
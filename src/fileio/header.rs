@@ -0,0 +1,324 @@
+use std::io::{Cursor, Read};
+
+use crate::{
+    crypto::{chacha::stream::nonce_prefix_size, padding::Padding, suite::CipherSuite},
+    errors::HelixError,
+    filecrypto::compression::Compression,
+};
+
+const MAGIC: [u8; 4] = *b"HLXB";
+pub const BLOCK_FORMAT_VERSION: u8 = 5;
+/// First version with a padding byte, older blocks are read as unpadded.
+const PADDING_VERSION: u8 = 2;
+/// First version with a compression byte, older blocks are read as uncompressed.
+const COMPRESSION_VERSION: u8 = 3;
+/// First version with a kind byte, older blocks always hold file data.
+const KIND_VERSION: u8 = 4;
+/// First version whose chunks are bound to their file and index, see `crypto::binding`.
+/// The layout is unchanged, only the associated data of the chunks grows.
+pub const BINDING_VERSION: u8 = 5;
+/// Blocks written before the header existed, see `BlockHeader::legacy`.
+pub const LEGACY_VERSION: u8 = 0;
+/// Chunk size blocks were written with before the header existed.
+const LEGACY_CHUNK_SIZE: u32 = 2 * 1024 * 1024;
+/// Largest stored chunk of a block without a header, its length prefix starts with a
+/// zero byte where a header starts with the magic.
+const LEGACY_MAX_STORED_CHUNK: u32 = LEGACY_CHUNK_SIZE + 16;
+
+/// What the plaintext of a block is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    /// File contents, or one chunk of them in the chunk store.
+    #[default]
+    Data,
+    /// The ids of the chunks a file is made of, see `helix_crypto::dedup`.
+    ChunkList,
+    /// Small blocks packed together, see `helix_crypto::packs`.
+    Pack,
+}
+
+impl BlockKind {
+    fn id(&self) -> u8 {
+        match self {
+            BlockKind::Data => 0,
+            BlockKind::ChunkList => 1,
+            BlockKind::Pack => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, HelixError> {
+        match id {
+            0 => Ok(BlockKind::Data),
+            1 => Ok(BlockKind::ChunkList),
+            2 => Ok(BlockKind::Pack),
+            _ => Err(HelixError::from(
+                "UnsupportedBlockFormat",
+                "UnknownBlockKind",
+                &format!("Block kind {} is not supported", id),
+            )),
+        }
+    }
+}
+
+/// Fixed header at the start of every block file.
+///
+/// Layout: magic (4) | version (1) | cipher suite (1) | chunk size (4, BE) | padding (1) |
+/// compression (1) | kind (1) | nonce prefix. The nonce prefix length follows from the
+/// cipher suite, older versions lack the bytes added after them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub version: u8,
+    pub suite: CipherSuite,
+    pub chunk_size: u32,
+    pub padding: Padding,
+    pub compression: Compression,
+    pub kind: BlockKind,
+    pub nonce_prefix: Vec<u8>,
+}
+
+impl BlockHeader {
+    pub fn from(suite: CipherSuite, chunk_size: u32, nonce_prefix: Vec<u8>) -> Self {
+        Self {
+            version: BLOCK_FORMAT_VERSION,
+            suite,
+            chunk_size,
+            padding: Padding::None,
+            compression: Compression::None,
+            kind: BlockKind::Data,
+            nonce_prefix,
+        }
+    }
+
+    /// Header of a block written before headers existed. Such a block is nothing
+    /// but its ChaCha20-Poly1305 chunks, all sealed with the file key and the nonce
+    /// stored next to it, without associated data.
+    pub fn legacy() -> Self {
+        Self {
+            version: LEGACY_VERSION,
+            suite: CipherSuite::ChaCha20Poly1305,
+            chunk_size: LEGACY_CHUNK_SIZE,
+            padding: Padding::None,
+            compression: Compression::None,
+            kind: BlockKind::Data,
+            nonce_prefix: Vec::new(),
+        }
+    }
+
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_kind(mut self, kind: BlockKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.version == LEGACY_VERSION {
+            return Vec::new();
+        }
+        let mut bytes = Vec::with_capacity(13 + self.nonce_prefix.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.suite.id());
+        bytes.extend_from_slice(&self.chunk_size.to_be_bytes());
+        if self.version >= PADDING_VERSION {
+            bytes.push(self.padding.id());
+        }
+        if self.version >= COMPRESSION_VERSION {
+            bytes.push(self.compression.id());
+        }
+        if self.version >= KIND_VERSION {
+            bytes.push(self.kind.id());
+        }
+        bytes.extend_from_slice(&self.nonce_prefix);
+        bytes
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, HelixError> {
+        let mut fixed = [0u8; 10];
+        reader.read_exact(&mut fixed).map_err(|_| not_a_block())?;
+        if fixed[..4] != MAGIC {
+            return Err(not_a_block());
+        }
+        let version = fixed[4];
+        if version == 0 || version > BLOCK_FORMAT_VERSION {
+            return Err(HelixError::from(
+                "UnsupportedBlockFormat",
+                "UnknownBlockVersion",
+                &format!("Block format version {} is not supported", version),
+            ));
+        }
+        let suite = CipherSuite::from_id(fixed[5]).map_err(|_| {
+            HelixError::from(
+                "UnsupportedBlockFormat",
+                "UnknownCipherSuite",
+                &format!("Block cipher suite {} is not supported", fixed[5]),
+            )
+        })?;
+        let chunk_size = u32::from_be_bytes(fixed[6..10].try_into().unwrap());
+        let padding = if version >= PADDING_VERSION {
+            let id = read_byte(reader)?;
+            Padding::from_id(id).map_err(|_| {
+                HelixError::from(
+                    "UnsupportedBlockFormat",
+                    "UnknownPadding",
+                    &format!("Block padding {} is not supported", id),
+                )
+            })?
+        } else {
+            Padding::None
+        };
+        let compression = if version >= COMPRESSION_VERSION {
+            let id = read_byte(reader)?;
+            Compression::from_id(id).map_err(|_| {
+                HelixError::from(
+                    "UnsupportedBlockFormat",
+                    "UnknownCompression",
+                    &format!("Block compression {} is not supported", id),
+                )
+            })?
+        } else {
+            Compression::None
+        };
+        let kind = if version >= KIND_VERSION {
+            BlockKind::from_id(read_byte(reader)?)?
+        } else {
+            BlockKind::Data
+        };
+        let mut nonce_prefix = vec![0u8; nonce_prefix_size(suite)];
+        reader
+            .read_exact(&mut nonce_prefix)
+            .map_err(|_| not_a_block())?;
+        Ok(Self {
+            version,
+            suite,
+            chunk_size,
+            padding,
+            compression,
+            kind,
+            nonce_prefix,
+        })
+    }
+
+    /// Reads the header of a block, a block that starts with the length of a chunk
+    /// instead is a version 0 block. The returned reader starts at the first chunk.
+    pub fn read_or_legacy(mut reader: Box<dyn Read>) -> Result<(Self, Box<dyn Read>), HelixError> {
+        let mut start = Vec::with_capacity(MAGIC.len());
+        (&mut reader)
+            .take(MAGIC.len() as u64)
+            .read_to_end(&mut start)
+            .map_err(|_| not_a_block())?;
+        let mut reader: Box<dyn Read> = Box::new(Cursor::new(start.clone()).chain(reader));
+        if start == MAGIC {
+            let header = Self::read_from(&mut reader)?;
+            return Ok((header, reader));
+        }
+        // An empty block is an empty file that was written before headers existed.
+        let legacy = match <[u8; 4]>::try_from(start.as_slice()) {
+            Ok(length) => u32::from_be_bytes(length) <= LEGACY_MAX_STORED_CHUNK,
+            Err(_) => start.is_empty(),
+        };
+        if !legacy {
+            return Err(not_a_block());
+        }
+        Ok((Self::legacy(), reader))
+    }
+}
+
+fn read_byte(reader: &mut impl Read) -> Result<u8, HelixError> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte).map_err(|_| not_a_block())?;
+    Ok(byte[0])
+}
+
+fn not_a_block() -> HelixError {
+    HelixError::from(
+        "MalformedBlock",
+        "InvalidBlockHeader",
+        "Block does not start with a helix block header",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use crate::{
+        crypto::{padding::Padding, suite::CipherSuite},
+        filecrypto::compression::Compression,
+    };
+
+    use super::{BlockHeader, BlockKind};
+
+    #[test]
+    fn header_round_trip_test() {
+        let header = BlockHeader::from(CipherSuite::ChaCha20Poly1305, 1024, vec![7u8; 7]);
+        let bytes = header.to_bytes();
+        let read = BlockHeader::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(header, read);
+        let header = BlockHeader::from(CipherSuite::XChaCha20Poly1305, 1024, vec![7u8; 19]);
+        let bytes = header.to_bytes();
+        let read = BlockHeader::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(header, read);
+        let header = BlockHeader::from(CipherSuite::Aes256Gcm, 1024, vec![7u8; 7])
+            .with_padding(Padding::Padme)
+            .with_compression(Compression::Zstd)
+            .with_kind(BlockKind::ChunkList);
+        let bytes = header.to_bytes();
+        let read = BlockHeader::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(header, read);
+    }
+
+    #[test]
+    fn older_version_test() {
+        for (version, len) in [(1, 17), (2, 18), (3, 19), (4, 20)] {
+            let mut header = BlockHeader::from(CipherSuite::ChaCha20Poly1305, 1024, vec![7u8; 7]);
+            header.version = version;
+            let bytes = header.to_bytes();
+            assert_eq!(bytes.len(), len);
+            let read = BlockHeader::read_from(&mut bytes.as_slice()).unwrap();
+            assert_eq!(read.padding, Padding::None);
+            assert_eq!(read.compression, Compression::None);
+            assert_eq!(read.kind, BlockKind::Data);
+            assert_eq!(read.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn legacy_block_test() {
+        let chunk = [&40u32.to_be_bytes()[..], &[7u8; 40]].concat();
+        let reader = Box::new(Cursor::new(chunk.clone()));
+        let (header, mut reader) = BlockHeader::read_or_legacy(reader).unwrap();
+        assert_eq!(header, BlockHeader::legacy());
+        assert!(header.to_bytes().is_empty());
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, chunk);
+        let (header, _) = BlockHeader::read_or_legacy(Box::new(Cursor::new(Vec::new()))).unwrap();
+        assert_eq!(header.version, 0);
+
+        let bytes = BlockHeader::from(CipherSuite::Aes256Gcm, 1024, vec![7u8; 7]).to_bytes();
+        let (header, _) = BlockHeader::read_or_legacy(Box::new(Cursor::new(bytes))).unwrap();
+        assert_eq!(header.suite, CipherSuite::Aes256Gcm);
+        for garbage in [b"HLX".to_vec(), b"not a block".to_vec()] {
+            let error = BlockHeader::read_or_legacy(Box::new(Cursor::new(garbage))).err().unwrap();
+            assert_eq!(error.detailed_code, "InvalidBlockHeader");
+        }
+    }
+
+    #[test]
+    fn unknown_version_test() {
+        let mut bytes = BlockHeader::from(CipherSuite::Aes256Gcm, 1024, vec![7u8; 7]).to_bytes();
+        bytes[4] = 99;
+        let error = BlockHeader::read_from(&mut bytes.as_slice()).unwrap_err();
+        assert_eq!(error.code, "UnsupportedBlockFormat");
+        assert_eq!(error.detailed_code, "UnknownBlockVersion");
+    }
+}
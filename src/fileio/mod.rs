@@ -1,2 +1,4 @@
+pub mod header;
+pub mod mounts;
 pub mod readers;
-pub mod writers;
\ No newline at end of file
+pub mod writers;
@@ -0,0 +1,96 @@
+//! Files that are a byte range of another file.
+//!
+//! A capsule container leaves its blocks, chunks and packs where they are in the
+//! `.hlx` file and mounts each of them at the path it would have in a capsule
+//! folder. Reads, hashes, sizes and removals of stored blocks go through here, so a
+//! mounted block is read straight from the container. A file written to a mounted
+//! path takes the place of the mounted range. Packed blocks are mounted the same way
+//! from a decrypted copy of their pack.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// File, offset and length of every mounted path.
+static MOUNTS: Mutex<BTreeMap<PathBuf, (PathBuf, u64, u64)>> = Mutex::new(BTreeMap::new());
+
+pub fn mount(path: &Path, file: &Path, offset: u64, length: u64) {
+    MOUNTS
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), (file.to_path_buf(), offset, length));
+}
+
+/// Unmounts every path below `folder`.
+pub fn unmount_all(folder: &Path) {
+    MOUNTS
+        .lock()
+        .unwrap()
+        .retain(|path, _| !path.starts_with(folder));
+}
+
+/// Unmounts every path mounted from `file`.
+pub fn unmount_file(file: &Path) {
+    MOUNTS
+        .lock()
+        .unwrap()
+        .retain(|_, (mounted_from, _, _)| mounted_from != file);
+}
+
+pub fn is_mounted(path: &Path) -> bool {
+    MOUNTS.lock().unwrap().contains_key(path)
+}
+
+pub fn open(path: &str) -> io::Result<Box<dyn Read>> {
+    if let Ok(file) = File::open(path) {
+        return Ok(Box::new(file));
+    }
+    let Some((file_path, offset, length)) = MOUNTS.lock().unwrap().get(Path::new(path)).cloned()
+    else {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    };
+    let mut file = File::open(file_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(Box::new(file.take(length)))
+}
+
+pub fn exists(path: &Path) -> bool {
+    path.exists() || is_mounted(path)
+}
+
+pub fn len(path: &Path) -> Option<u64> {
+    if let Ok(metadata) = fs::metadata(path) {
+        return Some(metadata.len());
+    }
+    MOUNTS.lock().unwrap().get(path).map(|(_, _, length)| *length)
+}
+
+/// Removes the file written to `path` and the range mounted there.
+pub fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+    MOUNTS.lock().unwrap().remove(path);
+}
+
+/// Names and lengths of the files in `folder`, written or mounted.
+pub fn list(folder: &Path) -> Vec<(String, u64)> {
+    let mut files = BTreeMap::new();
+    for (path, (_, _, length)) in MOUNTS.lock().unwrap().iter() {
+        if path.parent() == Some(folder) {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            files.insert(name, *length);
+        }
+    }
+    if let Ok(entries) = fs::read_dir(folder) {
+        for entry in entries.flatten() {
+            if let Ok(metadata) = entry.metadata() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                files.insert(name, metadata.len());
+            }
+        }
+    }
+    files.into_iter().collect()
+}
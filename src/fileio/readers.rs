@@ -1,36 +1,50 @@
-use std::{
-    fs::File,
-    io::{BufRead, BufReader, Read},
-};
+use std::{fs::File, io::Read};
 
+use crate::errors::HelixError;
+
+use super::{header::BlockHeader, mounts};
+
+/// Room left after each plain chunk for the AEAD tag, so chunks are encrypted in
+/// place and no copy of the plaintext is left in a reallocated buffer.
+const CHUNK_OVERHEAD: usize = 16;
+/// Most a stored chunk can exceed the chunk size of its block by: the tag, the data
+/// length of padded chunks and the flag of compressed chunks, with room to spare.
+const MAX_STORED_OVERHEAD: u32 = 64;
+
+/// Reads plain chunks straight into their own buffers. There is no intermediate
+/// buffer that would keep plaintext around after the chunk is encrypted.
 pub struct FileReader {
-    buf_reader: BufReader<File>,
+    file: File,
+    capacity: usize,
     has_more: bool,
 }
 
 impl FileReader {
-    pub fn from(capacity: u32, file_path: &str) -> Self {
-        let file = File::open(file_path).unwrap();
-        let cap = capacity.try_into().unwrap();
-        let buf_reader = BufReader::with_capacity(cap, file);
-        FileReader {
-            buf_reader,
+    pub fn from(capacity: u32, file_path: &str) -> Result<Self, HelixError> {
+        let file = File::open(file_path).map_err(|_| {
+            HelixError::from(
+                "BadInput",
+                "FileNotReadable",
+                &format!("Can not read {}", file_path),
+            )
+        })?;
+        Ok(FileReader {
+            file,
+            capacity: capacity.try_into().unwrap(),
             has_more: true,
-        }
+        })
     }
 
     pub fn next(&mut self) -> Option<Vec<u8>> {
-        let reader = &mut self.buf_reader;
         if !self.has_more {
             return Option::None;
         }
-        let (buf, len) = {
-            let buf = reader.fill_buf().unwrap().to_vec();
-            let len = buf.len();
-            (buf, len)
-        };
+        let mut buf = Vec::with_capacity(self.capacity + CHUNK_OVERHEAD);
+        let len = (&mut self.file)
+            .take(self.capacity as u64)
+            .read_to_end(&mut buf)
+            .unwrap();
         if len > 0 {
-            reader.consume(len);
             return Option::Some(buf);
         }
         self.has_more = false;
@@ -39,33 +53,70 @@ impl FileReader {
 }
 
 pub struct ChunkReader {
-    file: File,
+    file: Box<dyn Read>,
+    header: BlockHeader,
     has_more: bool,
 }
 
 impl ChunkReader {
-    pub fn from(file_path: &str) -> Self {
-        let file = File::open(file_path).unwrap();
-        ChunkReader {
+    /// Reads a mounted block straight from the file it is mounted from, see
+    /// `mounts`. A block without a header is read as version 0.
+    pub fn from(file_path: &str) -> Result<Self, HelixError> {
+        let file = mounts::open(file_path).map_err(|_| {
+            HelixError::from(
+                "MalformedBlock",
+                "BlockNotFound",
+                &format!("Block {} is missing from the capsule", file_path),
+            )
+        })?;
+        let (header, file) = BlockHeader::read_or_legacy(file)?;
+        Ok(ChunkReader {
             file,
+            header,
             has_more: true,
-        }
+        })
     }
 
-    pub fn next(&mut self) -> Option<Vec<u8>> {
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    /// Next stored chunk. A block cut inside a chunk or its length, or a length
+    /// larger than any chunk of the block can be, is an error.
+    pub fn next(&mut self) -> Option<Result<Vec<u8>, HelixError>> {
         if !self.has_more {
             return Option::None;
         }
-        let mut length_bytes = [0; 4];
-        let count = self.file.read(&mut length_bytes).unwrap();
-        if count == 0 {
+        let next = self.read_chunk().transpose();
+        if !matches!(next, Some(Ok(_))) {
             self.has_more = false;
-            return Option::None;
         }
+        next
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, HelixError> {
+        let mut length_bytes = Vec::with_capacity(4);
+        (&mut self.file)
+            .take(4)
+            .read_to_end(&mut length_bytes)
+            .map_err(|_| truncated_block())?;
+        if length_bytes.is_empty() {
+            return Ok(None);
+        }
+        let length_bytes: [u8; 4] = length_bytes.try_into().map_err(|_| truncated_block())?;
         let length: u32 = u32::from_be_bytes(length_bytes);
+        if length > self.header.chunk_size.saturating_add(MAX_STORED_OVERHEAD) {
+            return Err(HelixError::from(
+                "MalformedBlock",
+                "OversizedChunk",
+                "Chunk is larger than the chunk size of its block",
+            ));
+        }
         let mut buffer = Self::new_buffer(length);
-        self.file.read_exact(&mut buffer).unwrap();
-        return Option::Some(buffer);
+        self.file
+            .read_exact(&mut buffer)
+            .map_err(|_| truncated_block())?;
+        Ok(Some(buffer))
     }
 
     fn new_buffer(length: u32) -> Vec<u8> {
@@ -77,3 +128,11 @@ impl ChunkReader {
         buffer
     }
 }
+
+fn truncated_block() -> HelixError {
+    HelixError::from(
+        "MalformedBlock",
+        "TruncatedBlock",
+        "Block ends inside a chunk",
+    )
+}
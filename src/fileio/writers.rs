@@ -4,30 +4,41 @@ use std::{
     io::{BufWriter, Write}, path::Path,
 };
 
+use zeroize::Zeroize;
+
+use super::header::BlockHeader;
+
+/// Writes decrypted chunks. Chunks are written straight to the file and zeroized
+/// afterwards, no buffer keeps plaintext around.
 pub struct FileWriter {
-    buf_writer: BufWriter<File>,
+    file: File,
 }
 
 impl FileWriter {
     pub fn from(file_path: &str) -> Self {
+        if let Some(parent) = Path::new(file_path).parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+
         let file = File::options()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(file_path)
             .unwrap();
-        let buf_writer = BufWriter::new(file);
-        FileWriter { buf_writer }
+        FileWriter { file }
     }
 
-    pub fn write(&mut self, data: Vec<u8>) {
-        match self.buf_writer.write(&data) {
-            Ok(_) => (),
-            Err(_) => panic!("Failed to write bytes"),
+    pub fn write(&mut self, mut data: Vec<u8>) {
+        let written = self.file.write_all(&data);
+        data.zeroize();
+        if written.is_err() {
+            panic!("Failed to write bytes");
         }
     }
 
     pub fn close(&mut self) {
-        self.buf_writer.flush().unwrap()
+        self.file.flush().unwrap()
     }
 }
 
@@ -36,7 +47,7 @@ pub struct ChunkWriter {
 }
 
 impl ChunkWriter {
-    pub fn from(file_path: &str) -> Self {
+    pub fn from(file_path: &str, header: &BlockHeader) -> Self {
 
         if let Some(parent) = Path::new(file_path).parent(){
             let _ = fs::create_dir_all(parent);
@@ -45,11 +56,14 @@ impl ChunkWriter {
         let file = File::options()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(file_path)
             .unwrap();
         
         let buf_writer = BufWriter::new(file);
-        ChunkWriter { buf_writer }
+        let mut writer = ChunkWriter { buf_writer };
+        writer.write_internal(header.to_bytes());
+        writer
     }
 
     pub fn write(&mut self, data: Vec<u8>) {
@@ -0,0 +1,91 @@
+use rusqlite::Connection;
+
+use crate::{
+    crypto::{
+        chacha::keys::{Key, KeyDecryptor, KeyEncryptor},
+        kdf::MasterSubKeys,
+    },
+    errors::HelixError,
+    storage::{SettingStore, CAPSULE_SECRET_SETTING, CHUNK_SECRET_SETTING},
+};
+
+/// Loads the capsule secret, creating it the first time a capsule is opened.
+/// The secret is stored wrapped under a master subkey.
+pub(super) struct CapsuleSecretManager<'a> {
+    connection: &'a Connection,
+    setting: &'static str,
+}
+
+impl<'a> CapsuleSecretManager<'a> {
+    pub(super) fn from(connection: &'a Connection) -> Self {
+        Self {
+            connection,
+            setting: CAPSULE_SECRET_SETTING,
+        }
+    }
+
+    /// Manages the chunk secret instead, see `ChunkSubKeys`.
+    pub(super) fn for_chunks(connection: &'a Connection) -> Self {
+        Self {
+            connection,
+            setting: CHUNK_SECRET_SETTING,
+        }
+    }
+
+    pub(super) fn get_or_create(&self, master_sub_keys: &MasterSubKeys) -> Result<Key, HelixError> {
+        let setting_store = SettingStore::from(self.connection);
+        match setting_store.get(self.setting) {
+            Some(wrapped) => KeyDecryptor::from(&master_sub_keys.secret_wrap).decrypt(&wrapped),
+            None => {
+                let capsule_secret = Key::generate(master_sub_keys.secret_wrap.suite());
+                let wrapped = KeyEncryptor::from(&master_sub_keys.secret_wrap).encrypt(&capsule_secret);
+                setting_store.set(self.setting, &wrapped);
+                Ok(capsule_secret)
+            }
+        }
+    }
+
+    pub(super) fn exists(&self) -> bool {
+        SettingStore::from(self.connection).get(self.setting).is_some()
+    }
+
+    /// True when the capsule secret is wrapped under these subkeys, i.e. they belong
+    /// to the master key of this capsule.
+    pub(super) fn verify(&self, master_sub_keys: &MasterSubKeys) -> bool {
+        match SettingStore::from(self.connection).get(self.setting) {
+            Some(wrapped) => KeyDecryptor::from(&master_sub_keys.secret_wrap)
+                .decrypt(&wrapped)
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /// Moves the wrapped capsule secret to a new master key. The secret itself is
+    /// kept, so file ids and plain hashes stay valid.
+    pub(super) fn rewrap(
+        &self,
+        old_master_sub_keys: &MasterSubKeys,
+        new_master_sub_keys: &MasterSubKeys,
+    ) -> Result<(), HelixError> {
+        if SettingStore::from(self.connection).get(self.setting).is_none() {
+            return Ok(());
+        }
+        let capsule_secret = self.get_or_create(old_master_sub_keys)?;
+        let wrapped = KeyEncryptor::from(&new_master_sub_keys.secret_wrap).encrypt(&capsule_secret);
+        SettingStore::from(self.connection).set(self.setting, &wrapped);
+        Ok(())
+    }
+}
+
+#[test]
+fn capsule_secret_is_stable_test() {
+    use crate::{crypto::kdf::CapsuleSubKeys, storage::schema::HelixSchemaCreator};
+
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let master_sub_keys = MasterSubKeys::derive(&Key::new());
+    let manager = CapsuleSecretManager::from(&connection);
+    let created = CapsuleSubKeys::derive(&manager.get_or_create(&master_sub_keys).unwrap());
+    let loaded = CapsuleSubKeys::derive(&manager.get_or_create(&master_sub_keys).unwrap());
+    assert_eq!(created.file_id("notes.txt"), loaded.file_id("notes.txt"));
+}
@@ -0,0 +1,527 @@
+//! Single-file capsule container.
+//!
+//! A capsule can live in one file, e.g. `backup.hlx`, instead of a folder. The file
+//! starts with a fixed header pointing at an index, and the index maps every entry
+//! of the `.helix` folder, the metadata, blocks, chunks and packs, to its bytes:
+//!
+//! ```text
+//! "HLXC" | version | 3 zero bytes | index offset u64 | index length u64
+//! entry bytes ... | index JSON {"entries": {"blocks/<name>": [offset, length], ...}}
+//! ```
+//!
+//! Entries never change once written, so an update appends the new entries and a
+//! new index after the current one and then points the header at it. An update
+//! that is interrupted before the header is rewritten leaves the previous index in
+//! place, its partial tail is cut off by the next update. Once the header points at
+//! the new index, the old index and a replaced metadata entry are zeroed, so the
+//! header can not be pointed back at an older state. Replaced entries and old
+//! indexes are dead bytes, the container is rewritten once they outweigh the live
+//! ones and `MIN_DEAD_BYTES`.
+//!
+//! While a capsule is open its metadata is unpacked into a staging folder laid out
+//! like a capsule folder. Blocks, chunks and packs stay in the container, they are
+//! mounted at their path in the staging folder and read from their range, see
+//! `mounts`. Only what is written is staged until the next save. The header and
+//! index are not encrypted: the metadata records a hash of the blocks, chunks and
+//! packs the index holds, and they authenticate their own contents. Rekeying and
+//! rotation rewrite the container, so nothing they replaced stays behind in it.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    fs::{self, create_dir_all, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use json::{object, JsonValue};
+
+use crate::{
+    errors::HelixError,
+    fileio::mounts,
+    util::{hash::hash_string, uuid::generate},
+};
+
+/// File extension of a capsule container.
+pub(super) const CONTAINER_EXTENSION: &str = "hlx";
+
+const MAGIC: &[u8] = b"HLXC";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_SIZE: u64 = 24;
+const METADATA_ENTRY: &str = "metadata";
+const BLOCK_FOLDERS: &[&str] = &["blocks", "chunks", "packs"];
+/// Small containers are not worth rewriting, the metadata alone is appended anew
+/// with every update.
+const MIN_DEAD_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Offset and length of every entry, by its path relative to `.helix`.
+type Entries = BTreeMap<String, (u64, u64)>;
+
+struct Index {
+    entries: Entries,
+    /// End of the current index, where the next update starts writing.
+    end: u64,
+    index_length: u64,
+}
+
+pub(super) struct CapsuleContainer {
+    path: PathBuf,
+    staging: PathBuf,
+    index: RefCell<Index>,
+    /// The next save rewrites the container even if little of it is dead.
+    rewrite_requested: Cell<bool>,
+}
+
+impl CapsuleContainer {
+    /// True if the capsule is a container file rather than a folder.
+    pub(super) fn is_container(capsule: &str) -> bool {
+        let path = Path::new(capsule);
+        path.is_file()
+            || path
+                .extension()
+                .is_some_and(|extension| extension == CONTAINER_EXTENSION)
+    }
+
+    pub(super) fn open(capsule: &str) -> Result<Self, HelixError> {
+        if !Path::new(capsule).is_file() {
+            return Err(HelixError::from(
+                "InvalidHelixCapsule",
+                "NoContainer",
+                "capsule container not found",
+            ));
+        }
+        Self::open_or_create(capsule)
+    }
+
+    /// Opens a container, a missing one is created by the first save.
+    pub(super) fn open_or_create(capsule: &str) -> Result<Self, HelixError> {
+        let path = PathBuf::from(capsule);
+        let index = if path.is_file() {
+            read_index(&mut File::open(&path).unwrap())?
+        } else {
+            Index {
+                entries: Entries::new(),
+                end: HEADER_SIZE,
+                index_length: 0,
+            }
+        };
+        let staging = std::env::temp_dir().join(format!("helix-{}", generate()));
+        let container = Self {
+            path,
+            staging,
+            index: RefCell::new(index),
+            rewrite_requested: Cell::new(false),
+        };
+        for folder in BLOCK_FOLDERS {
+            create_dir_all(container.helix_folder().join(folder)).unwrap();
+        }
+        container.unpack_metadata();
+        container.remount(&container.index.borrow(), &[]);
+        Ok(container)
+    }
+
+    /// Folder laid out like a capsule folder that the container is unpacked into.
+    pub(super) fn folder(&self) -> &str {
+        self.staging.to_str().unwrap()
+    }
+
+    /// Makes the next save rewrite the container, dropping every replaced entry and
+    /// old index.
+    pub(super) fn rewrite_on_save(&self) {
+        self.rewrite_requested.set(true);
+    }
+
+    /// Hash of the names and lengths of the blocks, chunks and packs the container
+    /// holds once saved. The metadata records it, see `CapsuleMetadata::save`.
+    pub(super) fn blocks_hash(&self) -> String {
+        let (kept, staged) = self.live_blocks(&self.index.borrow());
+        let mut blocks: BTreeMap<String, u64> = kept
+            .into_iter()
+            .map(|(name, (_, length))| (name, length))
+            .collect();
+        for name in staged {
+            let length = fs::metadata(self.helix_folder().join(&name)).unwrap().len();
+            blocks.insert(name, length);
+        }
+        let listing: String = blocks
+            .iter()
+            .map(|(name, length)| format!("{} {}\n", name, length))
+            .collect();
+        hash_string(&listing)
+    }
+
+    /// Writes the metadata and the staged entries into the container, and drops the
+    /// entries that were removed.
+    pub(super) fn save(&self) {
+        let mut index = self.index.borrow_mut();
+        let mut source = File::open(&self.path).ok();
+        let (mut kept, mut changed) = self.live_blocks(&index);
+        let metadata_path = self.helix_folder().join(METADATA_ENTRY);
+        if metadata_path.exists() {
+            let current = fs::read(&metadata_path).unwrap();
+            let stored = index.entries.get(METADATA_ENTRY).copied().filter(|range| {
+                source
+                    .as_mut()
+                    .is_some_and(|file| read_range(file, *range) == current)
+            });
+            match stored {
+                Some(range) => kept.insert(String::from(METADATA_ENTRY), range),
+                None => {
+                    changed.push(String::from(METADATA_ENTRY));
+                    None
+                }
+            };
+        }
+        let rewrite_requested = self.rewrite_requested.take();
+        if !rewrite_requested && changed.is_empty() && kept.len() == index.entries.len() {
+            return;
+        }
+        let added: u64 = changed
+            .iter()
+            .map(|name| fs::metadata(self.helix_folder().join(name)).unwrap().len())
+            .sum();
+        let live: u64 = kept.values().map(|(_, length)| length).sum::<u64>() + added;
+        let dead = index.end - HEADER_SIZE + added - live;
+        match source.as_mut() {
+            Some(source) if rewrite_requested || (dead > live && dead > MIN_DEAD_BYTES) => {
+                self.rewrite(&mut index, source, kept, &changed)
+            }
+            _ => self.append(&mut index, kept, &changed),
+        }
+        self.remount(&index, &changed);
+    }
+
+    /// Blocks, chunks and packs of the index that are still mounted, and the ones
+    /// staged since.
+    fn live_blocks(&self, index: &Index) -> (Entries, Vec<String>) {
+        let helix_folder = self.helix_folder();
+        let staged = self.staged_blocks();
+        let kept = index
+            .entries
+            .iter()
+            .filter(|(name, _)| {
+                *name != METADATA_ENTRY
+                    && !staged.contains(name)
+                    && mounts::is_mounted(&helix_folder.join(name))
+            })
+            .map(|(name, range)| (name.clone(), *range))
+            .collect();
+        (kept, staged)
+    }
+
+    fn append(&self, index: &mut Index, mut entries: Entries, changed: &[String]) {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .unwrap();
+        // Cuts off what an interrupted update left behind the current index.
+        file.set_len(index.end).unwrap();
+        file.seek(SeekFrom::Start(index.end)).unwrap();
+        let mut offset = index.end;
+        for name in changed {
+            let length = io::copy(
+                &mut File::open(self.helix_folder().join(name)).unwrap(),
+                &mut file,
+            )
+            .unwrap();
+            entries.insert(name.clone(), (offset, length));
+            offset += length;
+        }
+        let mut replaced = vec![(index.end - index.index_length, index.index_length)];
+        if changed.iter().any(|name| name == METADATA_ENTRY) {
+            replaced.extend(index.entries.get(METADATA_ENTRY).copied());
+        }
+        self.finish(index, &mut file, entries, offset);
+        for (offset, length) in replaced {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            io::copy(&mut io::repeat(0).take(length), &mut file).unwrap();
+        }
+        file.sync_all().unwrap();
+    }
+
+    /// Writes the live entries into a new container that replaces the old one.
+    fn rewrite(&self, index: &mut Index, source: &mut File, kept: Entries, changed: &[String]) {
+        let temporary_path = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary_path).unwrap();
+        file.set_len(HEADER_SIZE).unwrap();
+        file.seek(SeekFrom::Start(HEADER_SIZE)).unwrap();
+        let mut entries = Entries::new();
+        let mut offset = HEADER_SIZE;
+        for (name, (stored_offset, length)) in kept {
+            source.seek(SeekFrom::Start(stored_offset)).unwrap();
+            io::copy(&mut source.take(length), &mut file).unwrap();
+            entries.insert(name, (offset, length));
+            offset += length;
+        }
+        for name in changed {
+            let length = io::copy(
+                &mut File::open(self.helix_folder().join(name)).unwrap(),
+                &mut file,
+            )
+            .unwrap();
+            entries.insert(name.clone(), (offset, length));
+            offset += length;
+        }
+        self.finish(index, &mut file, entries, offset);
+        fs::rename(&temporary_path, &self.path).unwrap();
+    }
+
+    /// Writes the index at `offset` and then the header pointing at it.
+    fn finish(&self, index: &mut Index, file: &mut File, entries: Entries, offset: u64) {
+        let mut json_entries = JsonValue::new_object();
+        for (name, (entry_offset, length)) in &entries {
+            json_entries[name.as_str()] = json::array![*entry_offset, *length];
+        }
+        let index_bytes = object! { entries: json_entries }.dump().into_bytes();
+        file.write_all(&index_bytes).unwrap();
+        file.sync_all().unwrap();
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&[FORMAT_VERSION, 0, 0, 0]);
+        header.extend_from_slice(&offset.to_be_bytes());
+        header.extend_from_slice(&(index_bytes.len() as u64).to_be_bytes());
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&header).unwrap();
+        file.sync_all().unwrap();
+        index.entries = entries;
+        index.index_length = index_bytes.len() as u64;
+        index.end = offset + index.index_length;
+    }
+
+    fn unpack_metadata(&self) {
+        let index = self.index.borrow();
+        let (Some(range), Ok(mut file)) =
+            (index.entries.get(METADATA_ENTRY), File::open(&self.path))
+        else {
+            return;
+        };
+        fs::write(
+            self.helix_folder().join(METADATA_ENTRY),
+            read_range(&mut file, *range),
+        )
+        .unwrap();
+    }
+
+    /// Mounts the entries of the index where they are now, and drops the staged
+    /// copies of the ones just written.
+    fn remount(&self, index: &Index, written: &[String]) {
+        let helix_folder = self.helix_folder();
+        mounts::unmount_all(&helix_folder);
+        for (name, (offset, length)) in &index.entries {
+            if name != METADATA_ENTRY {
+                mounts::mount(&helix_folder.join(name), &self.path, *offset, *length);
+            }
+        }
+        for name in written {
+            if name != METADATA_ENTRY {
+                let _ = fs::remove_file(helix_folder.join(name));
+            }
+        }
+    }
+
+    /// Blocks, chunks and packs in the staging folder, partial writes left out.
+    fn staged_blocks(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for folder in BLOCK_FOLDERS {
+            let Ok(entries) = fs::read_dir(self.helix_folder().join(folder)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = format!("{}/{}", folder, entry.file_name().to_string_lossy());
+                if is_valid_name(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    fn helix_folder(&self) -> PathBuf {
+        self.staging.join(".helix")
+    }
+}
+
+impl Drop for CapsuleContainer {
+    fn drop(&mut self) {
+        mounts::unmount_all(&self.staging);
+        let _ = fs::remove_dir_all(&self.staging);
+    }
+}
+
+fn read_index(file: &mut File) -> Result<Index, HelixError> {
+    let file_length = file.metadata().unwrap().len();
+    let mut header = [0u8; HEADER_SIZE as usize];
+    if file.read_exact(&mut header).is_err()
+        || &header[..MAGIC.len()] != MAGIC
+        || header[MAGIC.len()] != FORMAT_VERSION
+    {
+        return Err(invalid_container());
+    }
+    let index_offset = u64::from_be_bytes(header[8..16].try_into().unwrap());
+    let index_length = u64::from_be_bytes(header[16..24].try_into().unwrap());
+    let end = index_offset
+        .checked_add(index_length)
+        .filter(|end| index_offset >= HEADER_SIZE && *end <= file_length)
+        .ok_or_else(invalid_container)?;
+    let index_bytes = read_range(file, (index_offset, index_length));
+    let content = String::from_utf8(index_bytes).map_err(|_| invalid_container())?;
+    let index = json::parse(&content).map_err(|_| invalid_container())?;
+    let mut entries = Entries::new();
+    for (name, range) in index["entries"].entries() {
+        let offset = range[0].as_u64().ok_or_else(invalid_container)?;
+        let length = range[1].as_u64().ok_or_else(invalid_container)?;
+        let in_bounds = offset >= HEADER_SIZE
+            && offset
+                .checked_add(length)
+                .is_some_and(|entry_end| entry_end <= index_offset);
+        if !is_valid_name(name) || !in_bounds {
+            return Err(invalid_container());
+        }
+        entries.insert(String::from(name), (offset, length));
+    }
+    Ok(Index {
+        entries,
+        end,
+        index_length,
+    })
+}
+
+fn read_range(file: &mut File, (offset, length): (u64, u64)) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.take(length).read_to_end(&mut bytes).unwrap();
+    bytes
+}
+
+/// The metadata, or a block or chunk of a plain name. Anything else could unpack
+/// outside the staging folder.
+fn is_valid_name(name: &str) -> bool {
+    if name == METADATA_ENTRY {
+        return true;
+    }
+    let Some((folder, file_name)) = name.split_once('/') else {
+        return false;
+    };
+    BLOCK_FOLDERS.contains(&folder)
+        && !file_name.is_empty()
+        && file_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn invalid_container() -> HelixError {
+    HelixError::from(
+        "InvalidHelixCapsule",
+        "InvalidContainer",
+        "Capsule container is malformed",
+    )
+}
+
+#[test]
+fn container_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", generate()));
+    create_dir_all(&root).unwrap();
+    let path = root.join("backup.hlx");
+    let capsule = path.to_str().unwrap();
+    assert!(CapsuleContainer::is_container(capsule));
+    assert!(CapsuleContainer::open(capsule).is_err());
+
+    let container = CapsuleContainer::open_or_create(capsule).unwrap();
+    let helix_folder = container.helix_folder();
+    fs::write(helix_folder.join(METADATA_ENTRY), b"first metadata").unwrap();
+    fs::write(helix_folder.join("blocks").join("aa"), b"first block").unwrap();
+    fs::write(helix_folder.join("blocks").join("bb.tmp"), b"partial").unwrap();
+    container.save();
+    let length = fs::metadata(&path).unwrap().len();
+    container.save();
+    assert_eq!(fs::metadata(&path).unwrap().len(), length);
+    drop(container);
+
+    // Blocks are read from the container, and appending leaves the bytes written
+    // before untouched.
+    let read_entry = |path: PathBuf| {
+        let mut bytes = Vec::new();
+        let mut entry = mounts::open(path.to_str().unwrap()).unwrap();
+        entry.read_to_end(&mut bytes).unwrap();
+        bytes
+    };
+    let container = CapsuleContainer::open(capsule).unwrap();
+    let helix_folder = container.helix_folder();
+    assert_eq!(
+        fs::read(helix_folder.join(METADATA_ENTRY)).unwrap(),
+        b"first metadata"
+    );
+    assert!(!helix_folder.join("blocks").join("aa").exists());
+    assert_eq!(read_entry(helix_folder.join("blocks").join("aa")), b"first block");
+    assert!(!mounts::exists(&helix_folder.join("blocks").join("bb.tmp")));
+    let before = fs::read(&path).unwrap();
+    let chunk = vec![7u8; MIN_DEAD_BYTES as usize + 1];
+    fs::write(helix_folder.join("chunks").join("cc"), chunk).unwrap();
+    fs::write(helix_folder.join(METADATA_ENTRY), b"second metadata").unwrap();
+    container.save();
+    let after = fs::read(&path).unwrap();
+    let position = |bytes: &[u8], entry: &[u8]| {
+        bytes.windows(entry.len()).position(|window| window == entry)
+    };
+    assert!(position(&before, b"first block").is_some());
+    assert_eq!(position(&after, b"first block"), position(&before, b"first block"));
+    // The old metadata and index are zeroed, the old header would point at nothing.
+    assert_eq!(position(&after, b"first metadata"), None);
+    let mut rolled_back = after.clone();
+    rolled_back[..HEADER_SIZE as usize].copy_from_slice(&before[..HEADER_SIZE as usize]);
+    let rolled_back_path = root.join("rolled_back.hlx");
+    fs::write(&rolled_back_path, rolled_back).unwrap();
+    assert!(CapsuleContainer::open(rolled_back_path.to_str().unwrap()).is_err());
+    assert!(!helix_folder.join("chunks").join("cc").exists());
+    assert!(mounts::is_mounted(&helix_folder.join("chunks").join("cc")));
+    drop(container);
+
+    // Removing most of the bytes rewrites the container without them.
+    let container = CapsuleContainer::open(capsule).unwrap();
+    let blocks_hash = container.blocks_hash();
+    mounts::remove(&container.helix_folder().join("chunks").join("cc"));
+    assert_ne!(container.blocks_hash(), blocks_hash);
+    container.save();
+    assert!(fs::metadata(&path).unwrap().len() < 4096);
+    let staging = container.staging.clone();
+    drop(container);
+    assert!(!staging.exists());
+    assert!(!mounts::is_mounted(&staging.join(".helix").join("blocks").join("aa")));
+
+    let container = CapsuleContainer::open(capsule).unwrap();
+    let helix_folder = container.helix_folder();
+    assert_eq!(
+        fs::read(helix_folder.join(METADATA_ENTRY)).unwrap(),
+        b"second metadata"
+    );
+    assert_eq!(read_entry(helix_folder.join("blocks").join("aa")), b"first block");
+    assert!(!mounts::exists(&helix_folder.join("chunks").join("cc")));
+
+    // A requested rewrite drops replaced entries however few they are.
+    fs::write(helix_folder.join(METADATA_ENTRY), b"third metadata").unwrap();
+    container.rewrite_on_save();
+    container.save();
+    let rewritten = String::from_utf8_lossy(&fs::read(&path).unwrap()).into_owned();
+    assert!(rewritten.contains("third metadata") && !rewritten.contains("second metadata"));
+    assert_eq!(read_entry(helix_folder.join("blocks").join("aa")), b"first block");
+    drop(container);
+
+    // An interrupted update only leaves bytes behind the index.
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"partial entry").unwrap();
+    drop(file);
+    assert!(CapsuleContainer::open(capsule).is_ok());
+
+    let mut bytes = fs::read(&path).unwrap();
+    let index_offset = u64::from_be_bytes(bytes[8..16].try_into().unwrap()) as usize;
+    let index = String::from_utf8(bytes[index_offset..].to_vec()).unwrap();
+    let forged = index.replace("blocks/aa", "blocks/..");
+    bytes.truncate(index_offset);
+    bytes.extend_from_slice(forged.as_bytes());
+    fs::write(&path, bytes).unwrap();
+    assert!(CapsuleContainer::open(capsule).is_err());
+    fs::remove_dir_all(root).unwrap();
+}
@@ -1,39 +1,141 @@
 use std::{
+    collections::HashSet,
     fs::{self, create_dir_all},
     io,
     path::{Path, PathBuf},
 };
 
 use rusqlite::Connection;
+use secrecy::{ExposeSecret, SecretString};
+use zeroize::Zeroizing;
 
 use crate::{
     cli::file::{
         CliDecryptionObserverFactory, CliEncryptionObserverFactory, DecryptionObserverFactory,
         EncryptionObserverFactory,
     },
-    crypto::chacha::keys::Key,
+    crypto::{
+        chacha::keys::Key,
+        kdf::{CapsuleSubKeys, ChunkSubKeys, MasterSubKeys},
+        padding::Padding,
+        recipient::Identity,
+        shares::combine,
+        suite::CipherSuite,
+    },
     errors::HelixError,
-    storage::{schema::HelixSchemaCreator, FileStore},
+    filecrypto::{
+        chunking::Chunking,
+        compression::{Compression, CompressionStats},
+    },
+    fileio::mounts,
+    storage::{
+        FileStore, SettingStore, CHUNKING_SETTING, CIPHER_SUITE_SETTING, COMPRESSION_SETTING,
+        PACK_SIZE_SETTING, PADDING_SETTING,
+    },
 };
 
 use super::{
-    files::{HelixFileDecryptor, HelixFileEncryptor},
+    capsule_secret::CapsuleSecretManager,
+    container::CapsuleContainer,
+    dedup::DedupStore,
+    files::{
+        key_plain_hashes, mac_legacy_rows, mark_legacy_ids, master_sub_keys, upgrade_legacy_files,
+        FileKeyWrapper, HelixFileDecryptor, HelixFileEncryptor, HelixFileReKeyer,
+    },
     folder_walker::get_files,
-    master_key::MasterKeyManager,
+    manifest::{manifest_rollback, unsealed_file, ManifestManager},
+    master_key::{share_mismatch, MasterKeyManager},
+    metadata::CapsuleMetadata,
+    packs::PackStore,
+    recipients::{get_index_sub_keys, get_recipients, unlock_with_identity, CapsuleIdentityManager},
 };
 
 struct HelixState {
-    connection: Connection,
-    master_key: Key,
+    metadata: CapsuleMetadata,
+    master_sub_keys: MasterSubKeys,
+    capsule_sub_keys: CapsuleSubKeys,
+    chunk_sub_keys: ChunkSubKeys,
+    capsule_identity: Option<Identity>,
     block_directory: PathBuf,
+    chunk_directory: PathBuf,
+    generation: u64,
+    /// Ids of rows encrypt-only hosts added since the last seal, see `manifest`.
+    unsealed: HashSet<String>,
+}
+
+impl HelixState {
+    /// Takes metadata already unlocked with the master key. Nothing is written,
+    /// secrets and upgrades made on the way are saved with the next save, so that a
+    /// decryption leaves the capsule as it is.
+    fn from(
+        mut metadata: CapsuleMetadata,
+        master_key: &Key,
+        block_directory: PathBuf,
+    ) -> Result<Self, HelixError> {
+        let connection = metadata.connection();
+        let master_sub_keys = master_sub_keys(connection, master_key);
+        mark_legacy_ids(connection);
+        let capsule_secret =
+            CapsuleSecretManager::from(connection).get_or_create(&master_sub_keys)?;
+        let capsule_sub_keys = CapsuleSubKeys::derive(&capsule_secret);
+        let chunk_secret =
+            CapsuleSecretManager::for_chunks(connection).get_or_create(&master_sub_keys)?;
+        let chunk_sub_keys = ChunkSubKeys::derive(&chunk_secret);
+        mac_legacy_rows(connection, &master_sub_keys);
+        key_plain_hashes(connection, &master_sub_keys, &capsule_sub_keys)?;
+        let (generation, unsealed) = ManifestManager::from(connection)
+            .verify_or_create(&master_sub_keys, metadata.needs_first_seal())?;
+        let capsule_identity = CapsuleIdentityManager::from(connection).get(&master_sub_keys)?;
+        let chunk_directory = block_directory.with_file_name("chunks");
+        metadata.wrap_for_index(&capsule_sub_keys);
+        Ok(Self {
+            metadata,
+            master_sub_keys,
+            capsule_sub_keys,
+            chunk_sub_keys,
+            capsule_identity,
+            block_directory,
+            chunk_directory,
+            generation,
+            unsealed,
+        })
+    }
+
+    fn connection(&self) -> &Connection {
+        self.metadata.connection()
+    }
+
+    fn dedup_store(&self) -> DedupStore<'_> {
+        DedupStore::from(&self.chunk_directory, &self.chunk_sub_keys, self.connection())
+    }
+
+    fn pack_store(&self) -> PackStore<'_> {
+        PackStore::from(&self.block_directory, &self.capsule_sub_keys, self.connection())
+    }
+
+    /// Seals the files table as the next generation of the manifest and saves it.
+    /// Rows encrypt-only hosts added are sealed with it.
+    fn seal(&mut self) -> Result<(), HelixError> {
+        self.generation = ManifestManager::from(self.connection()).seal(&self.master_sub_keys);
+        self.unsealed.clear();
+        self.metadata.save()
+    }
 }
 pub struct HelixEncryptor<'a> {
     source: &'a str,
     destination: &'a str,
-    passphrase: &'a str,
+    passphrase: &'a SecretString,
+    keyfile: Option<&'a [u8]>,
+    recovery_code: Option<Zeroizing<String>>,
     helix_state: Option<HelixState>,
     encryption_observer_factory: &'a dyn EncryptionObserverFactory,
-    delete: bool
+    delete: bool,
+    cipher_suite: Option<CipherSuite>,
+    padding: Option<Padding>,
+    compression: Option<Compression>,
+    chunking: Option<Chunking>,
+    pack_size: Option<u64>,
+    compression_stats: Option<CompressionStats>,
 }
 
 const CAP: u32 = 1024 * 1024 * 2;
@@ -42,50 +144,120 @@ impl<'a> HelixEncryptor<'a> {
     pub fn from(
         source: &'a str,
         destination: &'a str,
-        passphrase: &'a str,
+        passphrase: &'a SecretString,
         encryption_observer_factory: &'a impl EncryptionObserverFactory,
-        delete: bool
+        delete: bool,
+        cipher_suite: Option<CipherSuite>,
     ) -> Self {
         Self {
             source,
             destination,
             passphrase,
+            keyfile: None,
+            recovery_code: None,
             helix_state: None,
             encryption_observer_factory,
-            delete
+            delete,
+            cipher_suite,
+            padding: None,
+            compression: None,
+            chunking: None,
+            pack_size: None,
+            compression_stats: None,
         }
     }
 
+    /// Pads the blocks of files encrypted in this run. An explicitly chosen padding
+    /// becomes the capsule default.
+    pub fn with_padding(mut self, padding: Option<Padding>) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Compresses the files encrypted in this run, remembered like the padding.
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Splits the files encrypted in this run into deduplicated chunks, remembered
+    /// like the padding.
+    pub fn with_chunking(mut self, chunking: Option<Chunking>) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Packs small blocks into packs of about this many bytes, 0 turns packing off.
+    /// Remembered like the padding.
+    pub fn with_pack_size(mut self, pack_size: Option<u64>) -> Self {
+        self.pack_size = pack_size;
+        self
+    }
+
+    /// Plain and stored bytes of the last run, None if it did not compress.
+    pub fn compression_stats(&self) -> Option<CompressionStats> {
+        self.compression_stats
+    }
+
+    /// Unlocks with a keyfile as well. A new capsule then needs both the keyfile and
+    /// the passphrase, an empty passphrase makes the keyfile alone unlock it.
+    pub fn with_keyfile(mut self, keyfile: Option<&'a [u8]>) -> Self {
+        self.keyfile = keyfile;
+        self
+    }
+
+    /// True if the destination already is a capsule, a folder or a container.
     pub fn has_helix_folder(folder: &str) -> bool {
         let path = Path::new(folder).join(".helix");
-        path.exists()
+        path.exists() || Path::new(folder).is_file()
     }
 
     fn check_helix_setup(&mut self) -> Result<(), HelixError> {
         if self.helix_state.is_some() {
             return Ok(());
         }
-        let destination_path = Path::new(self.destination);
-        let helix_folder = destination_path.join(".helix");
-        let db_file_path = helix_folder.join("metadata.db");
-        let block_path = helix_folder.join("blocks");
-        create_dir_all(&block_path).unwrap();
-        let connection = Connection::open(db_file_path).unwrap();
-        HelixSchemaCreator::create(&connection);
-        let master_key = self.get_master_key(&connection)?;
-        self.helix_state = Some(HelixState {
-            connection,
-            master_key: master_key,
-            block_directory: block_path,
-        });
+        let (mut metadata, block_path) = open_or_create_capsule(self.destination)?;
+        // A new capsule gets a master key of the suite it is created with.
+        let new_suite = self.cipher_suite.unwrap_or_default();
+        let master_key = self.get_master_key(metadata.connection(), new_suite)?;
+        metadata.unlock(&MasterSubKeys::derive(&master_key))?;
+        let connection = metadata.connection();
+        self.cipher_suite = Some(get_cipher_suite(connection, self.cipher_suite)?);
+        self.padding = Some(get_padding(connection, self.padding)?);
+        self.compression = Some(get_compression(connection, self.compression)?);
+        self.chunking = Some(get_chunking(connection, self.chunking)?);
+        self.pack_size = Some(get_pack_size(connection, self.pack_size)?);
+        let state = HelixState::from(metadata, &master_key, block_path)?;
+        // A new capsule is written even if there is nothing to encrypt.
+        state.metadata.save()?;
+        self.helix_state = Some(state);
         Ok(())
     }
 
-    fn get_master_key(&self, connection: &Connection) -> Result<Key, HelixError> {
-        let master_key_manager = MasterKeyManager::from(connection);
-        let master_key = match master_key_manager.get(self.passphrase)? {
+    /// Manifest generation of the capsule after the last run, see `manifest`.
+    pub fn generation(&self) -> Option<u64> {
+        self.helix_state.as_ref().map(|state| state.generation)
+    }
+
+    /// Recovery code of a capsule created by this encryptor. It is handed out once.
+    pub fn take_recovery_code(&mut self) -> Option<Zeroizing<String>> {
+        self.recovery_code.take()
+    }
+
+    fn get_master_key(
+        &mut self,
+        connection: &Connection,
+        cipher_suite: CipherSuite,
+    ) -> Result<Key, HelixError> {
+        let master_key_manager = MasterKeyManager::from(connection).with_keyfile(self.keyfile)?;
+        let master_key = match master_key_manager.get(self.passphrase.expose_secret())? {
             Some(key) => key,
-            None => master_key_manager.generate(self.passphrase),
+            None => {
+                let (key, recovery_code) =
+                    master_key_manager.generate(self.passphrase.expose_secret(), cipher_suite);
+                self.recovery_code = Some(recovery_code);
+                key
+            }
         };
         Ok(master_key)
     }
@@ -96,25 +268,277 @@ impl<'a> HelixEncryptor<'a> {
         if paths.len() == 0 {
             return Ok(());
         }
-        let state = self.helix_state.as_ref().unwrap();
+        let state = self.helix_state.as_mut().unwrap();
+        let (sources, obsolete_blocks, stats, packed) = {
+            let dedup_store = state.dedup_store();
+            let pack_store = state.pack_store();
+            // Rows from before file keys had a format are upgraded by the owner's next
+            // write, before any of them is looked up.
+            let helix_file_rekeyer = HelixFileReKeyer::from(
+                state.block_directory.to_str().unwrap(),
+                &state.master_sub_keys,
+                &state.capsule_sub_keys,
+                state.capsule_identity.as_ref(),
+                state.connection(),
+                self.encryption_observer_factory,
+            )
+            .with_pack_store(Some(&pack_store));
+            upgrade_legacy_files(state.connection(), &helix_file_rekeyer);
+            let helix_encryptor = HelixFileEncryptor::from(
+                self.source,
+                state.block_directory.to_str().unwrap(),
+                FileKeyWrapper::MasterKey(&state.master_sub_keys),
+                &state.capsule_sub_keys,
+                state.connection(),
+                CAP,
+                self.cipher_suite.unwrap(),
+            )
+            .with_padding(self.padding.unwrap())
+            .with_compression(self.compression.unwrap())
+            .with_dedup_store(self.chunking.unwrap(), Some(&dedup_store))
+            .with_pack_store(Some(&pack_store));
+            let sources = encrypt_files(
+                paths,
+                &helix_encryptor,
+                self.encryption_observer_factory,
+                self.delete,
+            );
+            let mut obsolete_blocks = helix_file_rekeyer.take_obsolete_blocks();
+            obsolete_blocks.extend(helix_encryptor.take_obsolete_blocks());
+            pack_store.release(&obsolete_blocks);
+            let packed = pack_store.pack(
+                self.pack_size.unwrap(),
+                self.cipher_suite.unwrap(),
+                self.padding.unwrap(),
+                &obsolete_blocks,
+            );
+            (sources, obsolete_blocks, helix_encryptor.stats(), packed)
+        };
+        if self.compression != Some(Compression::None) {
+            self.compression_stats = Some(stats);
+        }
+        state.seal()?;
+        // Only now that the rows pointing at their replacements are saved.
+        delete_sources(sources);
+        remove_blocks(obsolete_blocks);
+        state.pack_store().collect_garbage();
+        state.dedup_store().collect_garbage();
+        state.metadata.save()?;
+        // Blocks that could not be packed stay loose until the next run packs them.
+        packed.map(|_| ())
+    }
+}
+
+/// Encrypts into an existing capsule without its passphrase. New file keys are
+/// wrapped to the capsule and its recipients, so this host can add files but can
+/// not decrypt any of them. The index key only computes file ids and plain hashes.
+/// Files are always stored as single blocks, the chunk store needs the chunk secret.
+pub struct HelixRecipientEncryptor<'a> {
+    source: &'a str,
+    destination: &'a str,
+    index_key: &'a str,
+    encryption_observer_factory: &'a dyn EncryptionObserverFactory,
+    delete: bool,
+    cipher_suite: Option<CipherSuite>,
+    padding: Option<Padding>,
+    compression: Option<Compression>,
+    pack_size: Option<u64>,
+    compression_stats: Option<CompressionStats>,
+}
+
+impl<'a> HelixRecipientEncryptor<'a> {
+    pub fn from(
+        source: &'a str,
+        destination: &'a str,
+        index_key: &'a str,
+        encryption_observer_factory: &'a impl EncryptionObserverFactory,
+        delete: bool,
+        cipher_suite: Option<CipherSuite>,
+    ) -> Self {
+        Self {
+            source,
+            destination,
+            index_key,
+            encryption_observer_factory,
+            delete,
+            cipher_suite,
+            padding: None,
+            compression: None,
+            pack_size: None,
+            compression_stats: None,
+        }
+    }
+
+    pub fn with_padding(mut self, padding: Option<Padding>) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_pack_size(mut self, pack_size: Option<u64>) -> Self {
+        self.pack_size = pack_size;
+        self
+    }
+
+    pub fn compression_stats(&self) -> Option<CompressionStats> {
+        self.compression_stats
+    }
+
+    pub fn encrypt(&mut self) -> Result<(), HelixError> {
+        let (mut metadata, block_path) = open_capsule(self.destination)?;
+        let capsule_sub_keys = get_index_sub_keys(&mut metadata, self.index_key)?;
+        let connection = metadata.connection();
+        let recipients = get_recipients(connection)?;
+        let cipher_suite = get_cipher_suite(connection, self.cipher_suite)?;
+        let padding = get_padding(connection, self.padding)?;
+        let compression = get_compression(connection, self.compression)?;
+        let pack_size = get_pack_size(connection, self.pack_size)?;
+        let paths = get_files(self.source);
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let pack_store = PackStore::from(&block_path, &capsule_sub_keys, connection);
         let helix_encryptor = HelixFileEncryptor::from(
             self.source,
-            state.block_directory.to_str().unwrap(),
-            &state.master_key,
-            &state.connection,
+            block_path.to_str().unwrap(),
+            FileKeyWrapper::Recipients(&recipients),
+            &capsule_sub_keys,
+            connection,
             CAP,
-        );
-        for path in paths {
-            let path_str = path.to_str().unwrap();
-            let size = fs::metadata(path.clone()).unwrap().len();
-            let mut observer = self.encryption_observer_factory.create(path.clone(), size);
-            helix_encryptor.encrypt(path_str, &mut *observer);
-            if self.delete{
-                fs::remove_file(path);
-            }
+            cipher_suite,
+        )
+        .with_padding(padding)
+        .with_compression(compression)
+        .with_pack_store(Some(&pack_store));
+        let sources =
+            encrypt_files(paths, &helix_encryptor, self.encryption_observer_factory, self.delete);
+        if compression != Compression::None {
+            self.compression_stats = Some(helix_encryptor.stats());
         }
-       
-        Ok(())
+        let obsolete_blocks = helix_encryptor.take_obsolete_blocks();
+        pack_store.release(&obsolete_blocks);
+        let packed = pack_store.pack(pack_size, cipher_suite, padding, &obsolete_blocks);
+        metadata.save()?;
+        delete_sources(sources);
+        remove_blocks(obsolete_blocks);
+        pack_store.collect_garbage();
+        metadata.save()?;
+        packed.map(|_| ())
+    }
+}
+
+/// Suite for files encrypted in this run. An explicitly chosen suite becomes the
+/// capsule default, files encrypted earlier keep the suite recorded with their key.
+fn get_cipher_suite(
+    connection: &Connection,
+    cipher_suite: Option<CipherSuite>,
+) -> Result<CipherSuite, HelixError> {
+    let setting_store = SettingStore::from(connection);
+    let cipher_suite = match (cipher_suite, setting_store.get(CIPHER_SUITE_SETTING)) {
+        (Some(cipher_suite), _) => cipher_suite,
+        (None, Some(name)) => CipherSuite::from_name(&name)?,
+        (None, None) => CipherSuite::default(),
+    };
+    setting_store.set(CIPHER_SUITE_SETTING, cipher_suite.name());
+    Ok(cipher_suite)
+}
+
+/// Padding for blocks written in this run, remembered like the cipher suite.
+fn get_padding(connection: &Connection, padding: Option<Padding>) -> Result<Padding, HelixError> {
+    let setting_store = SettingStore::from(connection);
+    let padding = match (padding, setting_store.get(PADDING_SETTING)) {
+        (Some(padding), _) => padding,
+        (None, Some(name)) => Padding::from_name(&name)?,
+        (None, None) => Padding::default(),
+    };
+    setting_store.set(PADDING_SETTING, padding.name());
+    Ok(padding)
+}
+
+/// Target pack size for this run, remembered like the cipher suite. Packing is off
+/// unless it was asked for.
+fn get_pack_size(connection: &Connection, pack_size: Option<u64>) -> Result<u64, HelixError> {
+    let setting_store = SettingStore::from(connection);
+    let pack_size = match (pack_size, setting_store.get(PACK_SIZE_SETTING)) {
+        (Some(pack_size), _) => pack_size,
+        (None, Some(value)) => value.parse().map_err(|_| {
+            HelixError::from(
+                "InvalidHelixCapsule",
+                "InvalidPackSize",
+                &format!("Pack size {} is not a number of bytes", value),
+            )
+        })?,
+        (None, None) => 0,
+    };
+    setting_store.set(PACK_SIZE_SETTING, &pack_size.to_string());
+    Ok(pack_size)
+}
+
+/// Chunking for files encrypted in this run, remembered like the cipher suite.
+fn get_chunking(
+    connection: &Connection,
+    chunking: Option<Chunking>,
+) -> Result<Chunking, HelixError> {
+    let setting_store = SettingStore::from(connection);
+    let chunking = match (chunking, setting_store.get(CHUNKING_SETTING)) {
+        (Some(chunking), _) => chunking,
+        (None, Some(name)) => Chunking::from_name(&name)?,
+        (None, None) => Chunking::default(),
+    };
+    setting_store.set(CHUNKING_SETTING, chunking.name());
+    Ok(chunking)
+}
+
+/// Compression for blocks written in this run, remembered like the cipher suite.
+fn get_compression(
+    connection: &Connection,
+    compression: Option<Compression>,
+) -> Result<Compression, HelixError> {
+    let setting_store = SettingStore::from(connection);
+    let compression = match (compression, setting_store.get(COMPRESSION_SETTING)) {
+        (Some(compression), _) => compression,
+        (None, Some(name)) => Compression::from_name(&name)?,
+        (None, None) => Compression::default(),
+    };
+    setting_store.set(COMPRESSION_SETTING, compression.name());
+    Ok(compression)
+}
+
+/// Returns the source files to delete. They are only deleted by the caller once the
+/// metadata holding their rows is saved.
+fn encrypt_files(
+    paths: Vec<PathBuf>,
+    helix_encryptor: &HelixFileEncryptor,
+    encryption_observer_factory: &dyn EncryptionObserverFactory,
+    delete: bool,
+) -> Vec<PathBuf> {
+    let mut encrypted = Vec::new();
+    for path in paths {
+        let path_str = path.to_str().unwrap();
+        let size = fs::metadata(path.clone()).unwrap().len();
+        let mut observer = encryption_observer_factory.create(path.clone(), size);
+        helix_encryptor.encrypt(path_str, &mut *observer);
+        if delete {
+            encrypted.push(path);
+        }
+    }
+    encrypted
+}
+
+fn delete_sources(paths: Vec<PathBuf>) {
+    for path in paths {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Removes blocks that were replaced, once the metadata no longer points at them.
+fn remove_blocks(block_paths: Vec<String>) {
+    for block_path in block_paths {
+        mounts::remove(Path::new(&block_path));
     }
 }
 
@@ -136,10 +560,68 @@ fn delete_empty_directories_recursively(directory_path: &str) -> io::Result<()>
     Ok(())
 }
 
+/// Opens the metadata of an existing capsule, returning it with the block folder.
+/// The metadata is still locked. A container capsule is unpacked into a staging
+/// folder first, see `CapsuleContainer`.
+pub(super) fn open_capsule(capsule: &str) -> Result<(CapsuleMetadata, PathBuf), HelixError> {
+    if CapsuleContainer::is_container(capsule) {
+        let container = CapsuleContainer::open(capsule)?;
+        let (metadata, block_path) = open_capsule(container.folder())?;
+        return Ok((metadata.with_container(container), block_path));
+    }
+    let source_path = Path::new(capsule);
+    let helix_folder = source_path.join(".helix");
+    if !helix_folder.exists() {
+        return Err(HelixError::from(
+            "InvalidHelixCapsule",
+            "NoHelixFolder",
+            ".helix folder not found",
+        ));
+    }
+    if !CapsuleMetadata::exists(&helix_folder) {
+        return Err(HelixError::from(
+            "InvalidHelixCapsule",
+            "NoDBFile",
+            "metadata file not found",
+        ));
+    }
+    let block_path = helix_folder.join("blocks");
+    if !block_path.exists() {
+        return Err(HelixError::from(
+            "InvalidHelixCapsule",
+            "NoBlocksFolder",
+            "blocks folder not found",
+        ));
+    }
+    Ok((CapsuleMetadata::open(&helix_folder)?, block_path))
+}
+
+/// Like `open_capsule`, but a capsule that does not exist yet is created.
+fn open_or_create_capsule(capsule: &str) -> Result<(CapsuleMetadata, PathBuf), HelixError> {
+    if CapsuleContainer::is_container(capsule) {
+        let container = CapsuleContainer::open_or_create(capsule)?;
+        let (metadata, block_path) = open_or_create_capsule(container.folder())?;
+        return Ok((metadata.with_container(container), block_path));
+    }
+    let helix_folder = Path::new(capsule).join(".helix");
+    let block_path = helix_folder.join("blocks");
+    create_dir_all(&block_path).unwrap();
+    Ok((CapsuleMetadata::open(&helix_folder)?, block_path))
+}
+
+/// What unlocks the master key of a capsule.
+enum Credential<'a> {
+    Passphrase(&'a SecretString),
+    Identity(&'a Identity),
+    Shares(&'a [Zeroizing<String>]),
+}
+
 pub(crate) struct HelixDecryptor<'a> {
     source: &'a str,
     destination: &'a str,
-    passphrase: &'a str,
+    credential: Credential<'a>,
+    keyfile: Option<&'a [u8]>,
+    min_generation: Option<u64>,
     helix_state: Option<HelixState>,
     decryption_observer_factory: &'a dyn DecryptionObserverFactory,
 }
@@ -148,109 +630,1255 @@ impl<'a> HelixDecryptor<'a> {
     pub fn from(
         source: &'a str,
         destination: &'a str,
-        passphrase: &'a str,
+        passphrase: &'a SecretString,
         decryption_observer_factory: &'a dyn DecryptionObserverFactory,
     ) -> Self {
         Self {
             source,
             destination,
-            passphrase,
+            credential: Credential::Passphrase(passphrase),
+            keyfile: None,
+            min_generation: None,
             helix_state: None,
             decryption_observer_factory,
         }
     }
 
+    /// Decrypts with the identity of a recipient instead of a passphrase.
+    pub fn from_identity(
+        source: &'a str,
+        destination: &'a str,
+        identity: &'a Identity,
+        decryption_observer_factory: &'a dyn DecryptionObserverFactory,
+    ) -> Self {
+        Self {
+            source,
+            destination,
+            credential: Credential::Identity(identity),
+            keyfile: None,
+            min_generation: None,
+            helix_state: None,
+            decryption_observer_factory,
+        }
+    }
+
+    /// Decrypts with shares of the master key instead of a passphrase.
+    pub fn from_shares(
+        source: &'a str,
+        destination: &'a str,
+        shares: &'a [Zeroizing<String>],
+        decryption_observer_factory: &'a dyn DecryptionObserverFactory,
+    ) -> Self {
+        Self {
+            source,
+            destination,
+            credential: Credential::Shares(shares),
+            keyfile: None,
+            min_generation: None,
+            helix_state: None,
+            decryption_observer_factory,
+        }
+    }
+
+    /// Keyfile for key slots that need one.
+    pub fn with_keyfile(mut self, keyfile: Option<&'a [u8]>) -> Self {
+        self.keyfile = keyfile;
+        self
+    }
+
+    /// Manifest generation the capsule was last seen at. An older capsule, e.g. a
+    /// restored copy of its metadata, is rejected before anything is decrypted.
+    pub fn with_min_generation(mut self, min_generation: Option<u64>) -> Self {
+        self.min_generation = min_generation;
+        self
+    }
+
     fn check_helix_setup(&mut self) -> Result<(), HelixError> {
         if self.helix_state.is_some() {
             return Ok(());
         }
-        let source_path = Path::new(self.source);
-        let helix_folder = source_path.join(".helix");
-        if !helix_folder.exists() {
-            return Err(HelixError::from(
-                "InvalidHelixCapsule",
-                "NoHelixFolder",
-                ".helix folder not found",
-            ));
-        }
-        let db_file_path = helix_folder.join("metadata.db");
-        if !db_file_path.exists() {
-            return Err(HelixError::from(
-                "InvalidHelixCapsule",
-                "NoDBFile",
-                "metadata.db file not found",
-            ));
-        }
-        let block_path = helix_folder.join("blocks");
-        if !block_path.exists() {
-            return Err(HelixError::from(
-                "InvalidHelixCapsule",
-                "NoBlocksFolder",
-                "blocks folder not found",
-            ));
-        }
-        let connection = Connection::open(db_file_path).unwrap();
-        let master_key = self.get_master_key(&connection)?;
-        self.helix_state = Some(HelixState {
-            connection,
-            master_key: master_key,
-            block_directory: block_path,
-        });
+        let (mut metadata, block_path) = open_capsule(self.source)?;
+        let master_key = self.unlock(&mut metadata)?;
+        self.helix_state = Some(HelixState::from(metadata, &master_key, block_path)?);
         Ok(())
     }
 
-    fn get_master_key(&self, connection: &Connection) -> Result<Key, HelixError> {
-        let master_key_manager = MasterKeyManager::from(connection);
-        match master_key_manager.get(self.passphrase)? {
-            Some(key) => Ok(key),
-            None => Err(HelixError::from(
-                "InvalidHelixCapsule",
-                "NoMasterKey",
-                "Master Key not found in db",
-            )),
-        }
+    /// Unlocks the master key with the credential and the metadata with it.
+    fn unlock(&self, metadata: &mut CapsuleMetadata) -> Result<Key, HelixError> {
+        let connection = metadata.connection();
+        let passphrase = match self.credential {
+            Credential::Passphrase(passphrase) => passphrase,
+            Credential::Identity(identity) => {
+                let master_key = unlock_with_identity(connection, identity)?;
+                metadata.unlock(&MasterSubKeys::derive(&master_key))?;
+                return Ok(master_key);
+            }
+            Credential::Shares(shares) => {
+                // Shares of another capsule already fail to open the metadata.
+                let master_key = combine(shares)?;
+                metadata
+                    .unlock(&MasterSubKeys::derive(&master_key))
+                    .map_err(|_| share_mismatch())?;
+                return MasterKeyManager::from(metadata.connection()).unlock_with_shares(shares);
+            }
+        };
+        let master_key_manager = MasterKeyManager::from(connection).with_keyfile(self.keyfile)?;
+        let master_key = match master_key_manager.get(passphrase.expose_secret())? {
+            Some(key) => key,
+            None => {
+                return Err(HelixError::from(
+                    "InvalidHelixCapsule",
+                    "NoMasterKey",
+                    "Master Key not found in db",
+                ))
+            }
+        };
+        metadata.unlock(&MasterSubKeys::derive(&master_key))?;
+        Ok(master_key)
     }
 
     pub fn decrypt(&mut self) -> Result<(), HelixError> {
         self.check_helix_setup()?;
         let state = self.helix_state.as_ref().unwrap();
-        let file_store = FileStore::from(&state.connection);
+        if self.min_generation.is_some_and(|generation| state.generation < generation) {
+            return Err(manifest_rollback());
+        }
+        let file_store = FileStore::from(state.connection());
         let files = file_store.get_all();
         if files.len() == 0 {
             return Ok(());
         }
+        let dedup_store = state.dedup_store();
+        let pack_store = state.pack_store();
         let helix_file_decryptor = HelixFileDecryptor::from(
             self.destination,
             state.block_directory.to_str().unwrap(),
-            &state.master_key,
+            &state.master_sub_keys,
+            &state.capsule_sub_keys,
+            state.capsule_identity.as_ref(),
             self.decryption_observer_factory,
-        );
+        )
+        .with_dedup_store(Some(&dedup_store))
+        .with_pack_store(Some(&pack_store));
+        let total = files.len();
+        let mut failed = 0;
         for file in files {
-            helix_file_decryptor.decrypt(file);
+            // Nothing the master key vouches for covers these rows yet.
+            if state.unsealed.contains(&file.id) {
+                let observer = self.decryption_observer_factory.create(PathBuf::from(&file.id));
+                observer.failed(unsealed_file());
+                failed += 1;
+                continue;
+            }
+            if !helix_file_decryptor.decrypt(file) {
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(HelixError::from(
+                "MalformedData",
+                "FilesNotDecrypted",
+                &format!("{} of {} files could not be decrypted", failed, total),
+            ));
         }
         Ok(())
     }
 }
 
+/// Re-encrypts every block of a capsule under a fresh file key. The chunks of a
+/// deduplicated capsule are keyed from its chunk secret, which rekeying does not
+/// change, so they stay as they are in `.helix/chunks`. A container is rewritten
+/// without the replaced blocks.
+pub struct HelixReKeyer<'a> {
+    capsule: &'a str,
+    passphrase: &'a SecretString,
+    keyfile: Option<&'a [u8]>,
+    encryption_observer_factory: &'a dyn EncryptionObserverFactory,
+}
+
+impl<'a> HelixReKeyer<'a> {
+    pub fn from(
+        capsule: &'a str,
+        passphrase: &'a SecretString,
+        encryption_observer_factory: &'a dyn EncryptionObserverFactory,
+    ) -> Self {
+        Self {
+            capsule,
+            passphrase,
+            keyfile: None,
+            encryption_observer_factory,
+        }
+    }
+
+    pub fn with_keyfile(mut self, keyfile: Option<&'a [u8]>) -> Self {
+        self.keyfile = keyfile;
+        self
+    }
+
+    pub fn rekey(&self) -> Result<(), HelixError> {
+        let mut state = open_with_passphrase(self.capsule, self.passphrase, self.keyfile)?;
+        let (obsolete_blocks, packed) = {
+            let connection = state.connection();
+            let file_store = FileStore::from(connection);
+            let pack_store = state.pack_store();
+            let helix_file_rekeyer = HelixFileReKeyer::from(
+                state.block_directory.to_str().unwrap(),
+                &state.master_sub_keys,
+                &state.capsule_sub_keys,
+                state.capsule_identity.as_ref(),
+                connection,
+                self.encryption_observer_factory,
+            )
+            .with_pack_store(Some(&pack_store));
+            for file in file_store.get_all() {
+                helix_file_rekeyer.rekey(file);
+            }
+            let obsolete_blocks = helix_file_rekeyer.take_obsolete_blocks();
+            pack_store.release(&obsolete_blocks);
+            let packed = pack_store.pack(
+                get_pack_size(connection, None)?,
+                get_cipher_suite(connection, None)?,
+                get_padding(connection, None)?,
+                &obsolete_blocks,
+            );
+            (obsolete_blocks, packed)
+        };
+        state.seal()?;
+        remove_blocks(obsolete_blocks);
+        state.pack_store().collect_garbage();
+        state.metadata.rewrite_container();
+        state.metadata.save()?;
+        packed.map(|_| ())
+    }
+}
+
+/// Rewrites the packs of a capsule that are mostly released blocks, or too small on
+/// their own, into full packs. With packing turned off the packed blocks are moved
+/// back out of their packs.
+pub struct HelixCompactor<'a> {
+    capsule: &'a str,
+    passphrase: &'a SecretString,
+    keyfile: Option<&'a [u8]>,
+}
+
+impl<'a> HelixCompactor<'a> {
+    pub fn from(capsule: &'a str, passphrase: &'a SecretString) -> Self {
+        Self {
+            capsule,
+            passphrase,
+            keyfile: None,
+        }
+    }
+
+    pub fn with_keyfile(mut self, keyfile: Option<&'a [u8]>) -> Self {
+        self.keyfile = keyfile;
+        self
+    }
+
+    /// Returns how many blocks were repacked.
+    pub fn compact(&self) -> Result<usize, HelixError> {
+        let state = open_with_passphrase(self.capsule, self.passphrase, self.keyfile)?;
+        let connection = state.connection();
+        let compacted = state.pack_store().compact(
+            get_pack_size(connection, None)?,
+            get_cipher_suite(connection, None)?,
+            get_padding(connection, None)?,
+        )?;
+        state.metadata.save()?;
+        // The old packs only go once the index pointing at the new ones is saved.
+        state.pack_store().collect_garbage();
+        state.metadata.save()?;
+        Ok(compacted)
+    }
+}
+
+/// Opens a capsule and unlocks it with a passphrase, and the keyfile if its slot
+/// needs one.
+fn open_with_passphrase(
+    capsule: &str,
+    passphrase: &SecretString,
+    keyfile: Option<&[u8]>,
+) -> Result<HelixState, HelixError> {
+    let (mut metadata, block_path) = open_capsule(capsule)?;
+    let master_key_manager = MasterKeyManager::from(metadata.connection()).with_keyfile(keyfile)?;
+    let master_key = match master_key_manager.get(passphrase.expose_secret())? {
+        Some(key) => key,
+        None => {
+            return Err(HelixError::from(
+                "InvalidHelixCapsule",
+                "NoMasterKey",
+                "Master Key not found in db",
+            ))
+        }
+    };
+    metadata.unlock(&MasterSubKeys::derive(&master_key))?;
+    HelixState::from(metadata, &master_key, block_path)
+}
+
 #[test]
 fn encryption_test() {
+    let passphrase = SecretString::from("passphrase");
     let mut encryptor = HelixEncryptor::from(
         "../test",
         "../test",
-        "passphrase",
+        &passphrase,
         &CliEncryptionObserverFactory,
         true,
+        None,
     );
     encryptor.encrypt().unwrap();
 }
 
 #[test]
 fn decryption_test() {
+    let passphrase = SecretString::from("passphrase");
     let mut decryptor = HelixDecryptor::from(
         "../test",
         "../test",
-        "passphrase",
+        &passphrase,
         &CliDecryptionObserverFactory,
     );
     decryptor.decrypt().unwrap();
 }
+
+#[test]
+fn mixed_cipher_suite_capsule_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let source_str = source.to_str().unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+
+    fs::write(source.join("aes.txt"), b"encrypted with aes").unwrap();
+    HelixEncryptor::from(
+        source_str,
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        true,
+        Some(CipherSuite::Aes256Gcm),
+    )
+    .encrypt()
+    .unwrap();
+    fs::write(source.join("chacha.txt"), b"encrypted with chacha").unwrap();
+    HelixEncryptor::from(
+        source_str,
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        true,
+        Some(CipherSuite::ChaCha20Poly1305),
+    )
+    .encrypt()
+    .unwrap();
+
+    HelixDecryptor::from(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap();
+    assert_eq!(fs::read(restored.join("aes.txt")).unwrap(), b"encrypted with aes");
+    assert_eq!(fs::read(restored.join("chacha.txt")).unwrap(), b"encrypted with chacha");
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn padded_capsule_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let source_str = source.to_str().unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+
+    fs::write(source.join("short.txt"), vec![1u8; 700]).unwrap();
+    fs::write(source.join("long.txt"), vec![2u8; 1000]).unwrap();
+    HelixEncryptor::from(
+        source_str,
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        true,
+        None,
+    )
+    .with_padding(Some(Padding::PowerOfTwo))
+    .encrypt()
+    .unwrap();
+    // The padding chosen in the first run is the capsule default for the next.
+    fs::write(source.join("later.txt"), vec![3u8; 900]).unwrap();
+    HelixEncryptor::from(
+        source_str,
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        true,
+        None,
+    )
+    .encrypt()
+    .unwrap();
+
+    let block_sizes: Vec<u64> = fs::read_dir(capsule.join(".helix").join("blocks"))
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .collect();
+    assert_eq!(block_sizes.len(), 3);
+    assert!(block_sizes.iter().all(|size| *size == block_sizes[0]));
+
+    HelixDecryptor::from(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap();
+    assert_eq!(fs::read(restored.join("short.txt")).unwrap(), vec![1u8; 700]);
+    assert_eq!(fs::read(restored.join("long.txt")).unwrap(), vec![2u8; 1000]);
+    assert_eq!(fs::read(restored.join("later.txt")).unwrap(), vec![3u8; 900]);
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn compressed_capsule_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let source_str = source.to_str().unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    let log = b"GET /index.html 200\n".repeat(500);
+
+    fs::write(source.join("access.log"), &log).unwrap();
+    let passphrase = SecretString::from("passphrase");
+    let mut encryptor = HelixEncryptor::from(
+        source_str,
+        capsule_str,
+        &passphrase,
+        &CliEncryptionObserverFactory,
+        false,
+        None,
+    )
+    .with_compression(Some(Compression::Zstd));
+    encryptor.encrypt().unwrap();
+    let stats = encryptor.compression_stats().unwrap();
+    assert_eq!(stats.plain_bytes, log.len() as u64);
+    assert!(stats.stored_bytes < stats.plain_bytes / 10);
+
+    HelixDecryptor::from(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &passphrase,
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap();
+    assert_eq!(fs::read(restored.join("access.log")).unwrap(), log);
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn deduplicated_capsule_test() {
+    use rand::RngCore;
+
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let source_str = source.to_str().unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    let chunk_folder = capsule.join(".helix").join("chunks");
+    let stored_size = || -> u64 {
+        fs::read_dir(&chunk_folder)
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    let mut image = vec![0u8; 6 * 1024 * 1024];
+    rand::thread_rng().fill_bytes(&mut image);
+    let mut log = vec![0u8; 5 * 1024 * 1024];
+    rand::thread_rng().fill_bytes(&mut log);
+    fs::write(source.join("image.bin"), &image).unwrap();
+    fs::write(source.join("copy.bin"), &image).unwrap();
+    fs::write(source.join("app.log"), &log).unwrap();
+    let passphrase = SecretString::from("passphrase");
+    let encrypt = || {
+        HelixEncryptor::from(
+            source_str,
+            capsule_str,
+            &passphrase,
+            &CliEncryptionObserverFactory,
+            false,
+            None,
+        )
+        .with_chunking(Some(Chunking::ContentDefined))
+        .encrypt()
+        .unwrap();
+    };
+    encrypt();
+    // The copy takes no space of its own.
+    let overhead = 64 * 1024;
+    assert!(stored_size() < (image.len() + log.len() + overhead) as u64);
+
+    // Only the chunks around the appended end are stored again, and the replaced
+    // last chunk of the log is collected.
+    log.extend_from_slice(b"GET /index.html 200\n");
+    fs::write(source.join("app.log"), &log).unwrap();
+    encrypt();
+    assert!(stored_size() < (image.len() + log.len() + overhead) as u64);
+
+    HelixDecryptor::from(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &passphrase,
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap();
+    assert_eq!(fs::read(restored.join("image.bin")).unwrap(), image);
+    assert_eq!(fs::read(restored.join("copy.bin")).unwrap(), image);
+    assert_eq!(fs::read(restored.join("app.log")).unwrap(), log);
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn packed_capsule_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let source_str = source.to_str().unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    let block_folder = capsule.join(".helix").join("blocks");
+    let pack_folder = capsule.join(".helix").join("packs");
+    let listing = |folder: &Path| -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(folder)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+    let pack_bytes = || -> u64 {
+        fs::read_dir(&pack_folder)
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    for index in 0..300 {
+        fs::write(source.join(format!("{}.txt", index)), vec![index as u8; 300]).unwrap();
+    }
+    fs::write(source.join("large.bin"), vec![7u8; 100 * 1024]).unwrap();
+    let passphrase = SecretString::from("passphrase");
+    let encrypt_with_pack_size = |pack_size: u64| {
+        HelixEncryptor::from(
+            source_str,
+            capsule_str,
+            &passphrase,
+            &CliEncryptionObserverFactory,
+            false,
+            None,
+        )
+        .with_pack_size(Some(pack_size))
+        .encrypt()
+        .unwrap();
+    };
+    let encrypt = || encrypt_with_pack_size(64 * 1024);
+    let decrypt = || {
+        let _ = fs::remove_dir_all(&restored);
+        HelixDecryptor::from(
+            capsule_str,
+            restored.to_str().unwrap(),
+            &passphrase,
+            &CliDecryptionObserverFactory,
+        )
+        .decrypt()
+        .unwrap();
+        for index in 0..300 {
+            let content = fs::read(restored.join(format!("{}.txt", index))).unwrap();
+            assert_eq!(content, fs::read(source.join(format!("{}.txt", index))).unwrap());
+        }
+        assert_eq!(fs::read(restored.join("large.bin")).unwrap(), vec![7u8; 100 * 1024]);
+    };
+    // Only the large file keeps a block of its own.
+    encrypt();
+    assert_eq!(listing(&block_folder).len(), 1);
+    let packs = listing(&pack_folder);
+    assert_eq!(packs.len(), 2);
+    // Unchanged packed files are not encrypted again.
+    encrypt();
+    assert_eq!(listing(&pack_folder), packs);
+    // Packed blocks are read from a decrypted copy outside of the capsule.
+    let blocks = listing(&block_folder);
+    decrypt();
+    assert_eq!(listing(&block_folder), blocks);
+
+    // The replaced blocks stay in the old packs until they are compacted.
+    for index in 0..250 {
+        fs::write(source.join(format!("{}.txt", index)), vec![1u8; 200]).unwrap();
+    }
+    encrypt();
+    assert_eq!(listing(&block_folder).len(), 1);
+    let before = pack_bytes();
+    let moved = HelixCompactor::from(capsule_str, &passphrase).compact().unwrap();
+    assert!(moved > 0);
+    assert!(pack_bytes() < before);
+    assert_eq!(listing(&block_folder).len(), 1);
+    decrypt();
+
+    HelixReKeyer::from(capsule_str, &passphrase, &CliEncryptionObserverFactory)
+        .rekey()
+        .unwrap();
+    assert_eq!(listing(&block_folder).len(), 1);
+    assert!(listing(&pack_folder).iter().all(|pack| !packs.contains(pack)));
+    decrypt();
+
+    // Turning packing off moves every packed block back out of its pack.
+    encrypt_with_pack_size(0);
+    HelixCompactor::from(capsule_str, &passphrase).compact().unwrap();
+    assert_eq!(listing(&block_folder).len(), 301);
+    assert!(listing(&pack_folder).is_empty());
+    decrypt();
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn keyed_file_id_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    create_dir_all(&source).unwrap();
+    let source_str = source.to_str().unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    let file_path = source.join("taxes.pdf");
+
+    for content in ["first", "second"] {
+        fs::write(&file_path, content).unwrap();
+        HelixEncryptor::from(
+            source_str,
+            capsule_str,
+            &SecretString::from("passphrase"),
+            &CliEncryptionObserverFactory,
+            false,
+            None,
+        )
+        .encrypt()
+        .unwrap();
+    }
+
+    let metadata = unlocked_metadata(&capsule, "passphrase");
+    let files = FileStore::from(metadata.connection()).get_all();
+    assert_eq!(files.len(), 1);
+    let plain_id = crate::util::hash::hash_string(file_path.to_str().unwrap());
+    assert_ne!(files[0].id, plain_id);
+    assert_eq!(fs::read_dir(capsule.join(".helix").join("blocks")).unwrap().count(), 1);
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn rotate_master_key_test() {
+    use super::{rotation::HelixMasterKeyRotator, slots::HelixKeySlots};
+
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    fs::write(source.join("notes.txt"), b"rotate me").unwrap();
+    HelixEncryptor::from(
+        source.to_str().unwrap(),
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        false,
+        None,
+    )
+    .encrypt()
+    .unwrap();
+    HelixKeySlots::open(capsule_str)
+        .unwrap()
+        .add("passphrase", "bob", "bob passphrase")
+        .unwrap();
+    let blocks = capsule.join(".helix").join("blocks");
+    let block_before = fs::read_dir(&blocks).unwrap().next().unwrap().unwrap().path();
+    let block_bytes = fs::read(&block_before).unwrap();
+
+    // Bob's slot could not be rewrapped, so it has to go first.
+    let error = HelixMasterKeyRotator::open(capsule_str)
+        .unwrap()
+        .rotate("passphrase")
+        .unwrap_err();
+    assert_eq!(error.detailed_code, "OtherKeySlots");
+    HelixKeySlots::open(capsule_str)
+        .unwrap()
+        .remove("passphrase", "bob")
+        .unwrap();
+    let recovery_code = HelixMasterKeyRotator::open(capsule_str)
+        .unwrap()
+        .rotate("passphrase")
+        .unwrap();
+    assert_eq!(fs::read(&block_before).unwrap(), block_bytes);
+    assert_eq!(HelixKeySlots::open(capsule_str).unwrap().list(), vec!["default", "recovery"]);
+    HelixKeySlots::open(capsule_str)
+        .unwrap()
+        .recover(&recovery_code.unwrap(), "passphrase")
+        .unwrap();
+
+    HelixDecryptor::from(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap();
+    assert_eq!(fs::read(restored.join("notes.txt")).unwrap(), b"rotate me");
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn rekey_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    fs::write(source.join("notes.txt"), b"rekey me").unwrap();
+    HelixEncryptor::from(
+        source.to_str().unwrap(),
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        false,
+        None,
+    )
+    .encrypt()
+    .unwrap();
+    let metadata = unlocked_metadata(&capsule, "passphrase");
+    let key_before = FileStore::from(metadata.connection()).get_all().remove(0).key;
+    let blocks = capsule.join(".helix").join("blocks");
+    let block_before = fs::read_dir(&blocks).unwrap().next().unwrap().unwrap().path();
+
+    HelixReKeyer::from(capsule_str, &SecretString::from("passphrase"), &CliEncryptionObserverFactory)
+        .rekey()
+        .unwrap();
+    let metadata = unlocked_metadata(&capsule, "passphrase");
+    let key_after = FileStore::from(metadata.connection()).get_all().remove(0).key;
+    assert_ne!(key_before, key_after);
+    assert!(!block_before.exists());
+    assert_eq!(fs::read_dir(&blocks).unwrap().count(), 1);
+
+    HelixDecryptor::from(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap();
+    assert_eq!(fs::read(restored.join("notes.txt")).unwrap(), b"rekey me");
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn failed_file_decryption_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    fs::write(source.join("notes.txt"), b"one block").unwrap();
+    HelixEncryptor::from(
+        source.to_str().unwrap(),
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        false,
+        None,
+    )
+    .encrypt()
+    .unwrap();
+    let blocks = capsule.join(".helix").join("blocks");
+    let block = fs::read_dir(&blocks).unwrap().next().unwrap().unwrap().path();
+    fs::write(&block, b"damaged").unwrap();
+
+    // A capsule whose files could not all be restored is not a successful decryption.
+    let error = HelixDecryptor::from(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap_err();
+    assert_eq!(error.detailed_code, "FilesNotDecrypted");
+    assert!(!restored.join("notes.txt").exists());
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn recipient_encryption_test() {
+    use super::recipients::HelixRecipients;
+
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    create_dir_all(&source).unwrap();
+    let source_str = source.to_str().unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    fs::write(source.join("old.txt"), b"from the owner").unwrap();
+    HelixEncryptor::from(
+        source_str,
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        true,
+        None,
+    )
+    .encrypt()
+    .unwrap();
+    let bob = Identity::generate();
+    let mut recipients = HelixRecipients::open(capsule_str).unwrap();
+    recipients
+        .add("passphrase", "bob", &bob.recipient().to_hex())
+        .unwrap();
+    let index_key = recipients.index_key("passphrase").unwrap();
+    assert!(HelixRecipientEncryptor::from(
+        source_str,
+        capsule_str,
+        &"00".repeat(32),
+        &CliEncryptionObserverFactory,
+        false,
+        None,
+    )
+    .encrypt()
+    .is_err());
+
+    fs::write(source.join("new.txt"), b"from the host").unwrap();
+    // Files the owner sealed can not be replaced by an encrypt-only host.
+    fs::write(source.join("old.txt"), b"replaced by the host").unwrap();
+    HelixRecipientEncryptor::from(
+        source_str,
+        capsule_str,
+        &index_key,
+        &CliEncryptionObserverFactory,
+        true,
+        None,
+    )
+    .encrypt()
+    .unwrap();
+
+    // Until the owner seals them, files added by the host are not decrypted.
+    let unsealed = root.join("unsealed");
+    let error = HelixDecryptor::from(
+        capsule_str,
+        unsealed.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap_err();
+    assert_eq!(error.detailed_code, "FilesNotDecrypted");
+    assert_eq!(fs::read(unsealed.join("old.txt")).unwrap(), b"from the owner");
+    assert!(!unsealed.join("new.txt").exists());
+    let owner_source = root.join("owner-source");
+    create_dir_all(&owner_source).unwrap();
+    fs::write(owner_source.join("owner.txt"), b"sealed by the owner").unwrap();
+    HelixEncryptor::from(
+        owner_source.to_str().unwrap(),
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        false,
+        None,
+    )
+    .encrypt()
+    .unwrap();
+
+    let by_passphrase = root.join("by-passphrase");
+    HelixDecryptor::from(
+        capsule_str,
+        by_passphrase.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap();
+    let by_identity = root.join("by-identity");
+    HelixDecryptor::from_identity(
+        capsule_str,
+        by_identity.to_str().unwrap(),
+        &bob,
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap();
+    for restored in [by_passphrase, by_identity] {
+        assert_eq!(fs::read(restored.join("old.txt")).unwrap(), b"from the owner");
+        assert_eq!(fs::read(restored.join("new.txt")).unwrap(), b"from the host");
+    }
+    let eve = Identity::generate();
+    assert!(HelixDecryptor::from_identity(
+        capsule_str,
+        root.join("eve").to_str().unwrap(),
+        &eve,
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .is_err());
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn keyfile_capsule_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    let keyfile = crate::util::uuid::generate().repeat(2).into_bytes();
+    fs::write(source.join("notes.txt"), b"two factors").unwrap();
+    HelixEncryptor::from(
+        source.to_str().unwrap(),
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        false,
+        None,
+    )
+    .with_keyfile(Some(&keyfile))
+    .encrypt()
+    .unwrap();
+
+    let error = HelixDecryptor::from(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap_err();
+    assert_eq!(error.detailed_code, "KeyfileRequired");
+    HelixDecryptor::from(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .with_keyfile(Some(&keyfile))
+    .decrypt()
+    .unwrap();
+    assert_eq!(fs::read(restored.join("notes.txt")).unwrap(), b"two factors");
+
+    // Every command that unlocks takes the keyfile.
+    let passphrase = SecretString::from("passphrase");
+    let rekeyer = HelixReKeyer::from(capsule_str, &passphrase, &CliEncryptionObserverFactory);
+    assert_eq!(rekeyer.rekey().unwrap_err().detailed_code, "KeyfileRequired");
+    rekeyer.with_keyfile(Some(&keyfile)).rekey().unwrap();
+    let mut key_slots = super::slots::HelixKeySlots::open(capsule_str)
+        .unwrap()
+        .with_keyfile(Some(&keyfile));
+    key_slots.add("passphrase", "bob", "bob passphrase").unwrap();
+    key_slots.change_passphrase("bob passphrase", "new bob passphrase").unwrap();
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn shares_decryption_test() {
+    use super::slots::HelixKeySlots;
+
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    fs::write(source.join("archive.txt"), b"split custody").unwrap();
+    HelixEncryptor::from(
+        source.to_str().unwrap(),
+        capsule_str,
+        &SecretString::from("passphrase"),
+        &CliEncryptionObserverFactory,
+        false,
+        None,
+    )
+    .encrypt()
+    .unwrap();
+    let shares = HelixKeySlots::open(capsule_str)
+        .unwrap()
+        .split("passphrase", 3, 5)
+        .unwrap();
+
+    let error = HelixDecryptor::from_shares(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &shares[..2],
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap_err();
+    assert_eq!(error.detailed_code, "NotEnoughShares");
+    HelixDecryptor::from_shares(
+        capsule_str,
+        restored.to_str().unwrap(),
+        &shares[2..],
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap();
+    assert_eq!(fs::read(restored.join("archive.txt")).unwrap(), b"split custody");
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn manifest_test() {
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let source_str = source.to_str().unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    let metadata = capsule.join(".helix").join("metadata");
+    let passphrase = SecretString::from("passphrase");
+    let encrypt = || {
+        let mut encryptor = HelixEncryptor::from(
+            source_str,
+            capsule_str,
+            &passphrase,
+            &CliEncryptionObserverFactory,
+            false,
+            None,
+        );
+        encryptor.encrypt().unwrap();
+        encryptor.generation().unwrap()
+    };
+    let decrypt = |min_generation| {
+        HelixDecryptor::from(
+            capsule_str,
+            restored.to_str().unwrap(),
+            &passphrase,
+            &CliDecryptionObserverFactory,
+        )
+        .with_min_generation(min_generation)
+        .decrypt()
+    };
+
+    fs::write(source.join("first.txt"), b"first").unwrap();
+    let old_generation = encrypt();
+    let old_metadata = fs::read(&metadata).unwrap();
+    fs::write(source.join("second.txt"), b"second").unwrap();
+    let generation = encrypt();
+    assert!(generation > old_generation);
+    decrypt(Some(generation)).unwrap();
+
+    // Older metadata still authenticates, only its generation gives it away.
+    let metadata_now = fs::read(&metadata).unwrap();
+    fs::write(&metadata, &old_metadata).unwrap();
+    decrypt(None).unwrap();
+    assert_eq!(decrypt(Some(generation)).unwrap_err().detailed_code, "ManifestRollback");
+
+    fs::write(&metadata, &metadata_now).unwrap();
+    let unlocked = unlocked_metadata(&capsule, "passphrase");
+    let connection = unlocked.connection();
+    let file_id = FileStore::from(connection).get_all().remove(0).id;
+    connection.execute("DELETE FROM files where id = ?1", [file_id]).unwrap();
+    unlocked.save().unwrap();
+    assert_eq!(decrypt(None).unwrap_err().detailed_code, "ManifestMismatch");
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[cfg(test)]
+fn unlocked_metadata(capsule: &Path, passphrase: &str) -> CapsuleMetadata {
+    let mut metadata = CapsuleMetadata::open(&capsule.join(".helix")).unwrap();
+    let master_key = MasterKeyManager::from(metadata.connection())
+        .get(passphrase)
+        .unwrap()
+        .unwrap();
+    metadata.unlock(&MasterSubKeys::derive(&master_key)).unwrap();
+    metadata
+}
+
+#[test]
+fn encrypted_metadata_test() {
+    use crate::storage::{schema::HelixSchemaCreator, snapshot};
+
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let capsule = root.join("capsule");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let capsule_str = capsule.to_str().unwrap();
+    let helix_folder = capsule.join(".helix");
+    fs::write(source.join("diary.txt"), b"dear diary").unwrap();
+    let encrypt = || {
+        HelixEncryptor::from(
+            source.to_str().unwrap(),
+            capsule_str,
+            &SecretString::from("passphrase"),
+            &CliEncryptionObserverFactory,
+            false,
+            None,
+        )
+        .encrypt()
+        .unwrap();
+    };
+    let decrypt = || {
+        HelixDecryptor::from(
+            capsule_str,
+            restored.to_str().unwrap(),
+            &SecretString::from("passphrase"),
+            &CliDecryptionObserverFactory,
+        )
+        .decrypt()
+        .unwrap();
+    };
+    encrypt();
+
+    let metadata = unlocked_metadata(&capsule, "passphrase");
+    let file_id = FileStore::from(metadata.connection()).get_all().remove(0).id;
+    let stored = String::from_utf8_lossy(&fs::read(helix_folder.join("metadata")).unwrap())
+        .into_owned();
+    assert!(!stored.contains(&file_id));
+    assert!(!stored.contains(CIPHER_SUITE_SETTING));
+
+    // A capsule with plain metadata.db is still read, decrypting it writes nothing.
+    let legacy_path = helix_folder.join("metadata.db");
+    let legacy = Connection::open(&legacy_path).unwrap();
+    HelixSchemaCreator::create(&legacy);
+    let tables = snapshot::table_names(metadata.connection());
+    snapshot::load(&legacy, &snapshot::dump(metadata.connection(), &tables)).unwrap();
+    drop(legacy);
+    fs::remove_file(helix_folder.join("metadata")).unwrap();
+    let legacy_bytes = fs::read(&legacy_path).unwrap();
+    decrypt();
+    assert_eq!(fs::read(restored.join("diary.txt")).unwrap(), b"dear diary");
+    assert_eq!(fs::read(&legacy_path).unwrap(), legacy_bytes);
+    assert!(!helix_folder.join("metadata").exists());
+
+    // The next save encrypts it and keeps the plain database aside.
+    encrypt();
+    assert!(helix_folder.join("metadata").exists());
+    assert!(!legacy_path.exists());
+    assert_eq!(fs::read(helix_folder.join("metadata.db.bak")).unwrap(), legacy_bytes);
+    decrypt();
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn container_capsule_test() {
+    use super::slots::HelixKeySlots;
+
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let source = root.join("source");
+    let restored = root.join("restored");
+    create_dir_all(&source).unwrap();
+    let container = source.join("backup.hlx");
+    let container_str = container.to_str().unwrap();
+    let encrypt = |passphrase: &str| {
+        HelixEncryptor::from(
+            source.to_str().unwrap(),
+            container_str,
+            &SecretString::from(passphrase),
+            &CliEncryptionObserverFactory,
+            false,
+            None,
+        )
+        .encrypt()
+        .unwrap();
+    };
+    let decrypt = |passphrase: &str| {
+        HelixDecryptor::from(
+            container_str,
+            restored.to_str().unwrap(),
+            &SecretString::from(passphrase),
+            &CliDecryptionObserverFactory,
+        )
+        .decrypt()
+        .unwrap();
+    };
+
+    fs::write(source.join("letter.txt"), b"first draft").unwrap();
+    encrypt("passphrase");
+    assert!(container.is_file());
+    assert!(!source.join(".helix").exists());
+    assert!(HelixEncryptor::has_helix_folder(container_str));
+    let created = fs::read(&container).unwrap();
+    decrypt("passphrase");
+    assert_eq!(fs::read(restored.join("letter.txt")).unwrap(), b"first draft");
+    assert!(!restored.join("backup.hlx").exists());
+    assert_eq!(fs::read(&container).unwrap(), created);
+
+    // An update appends to what is there, the header can not be pointed back at the
+    // state before it.
+    fs::write(source.join("letter.txt"), b"second draft").unwrap();
+    fs::write(source.join("notes.txt"), b"notes").unwrap();
+    encrypt("passphrase");
+    let updated = fs::read(&container).unwrap();
+    assert!(updated.len() > created.len());
+    decrypt("passphrase");
+    assert_eq!(fs::read(restored.join("letter.txt")).unwrap(), b"second draft");
+    assert_eq!(fs::read(restored.join("notes.txt")).unwrap(), b"notes");
+    let mut rolled_back = updated.clone();
+    rolled_back[..24].copy_from_slice(&created[..24]);
+    fs::write(&container, rolled_back).unwrap();
+    assert!(CapsuleContainer::open(container_str).is_err());
+    // An index that lists other blocks than the metadata recorded is rejected.
+    let index_offset = u64::from_be_bytes(updated[8..16].try_into().unwrap()) as usize;
+    let mut index = json::parse(std::str::from_utf8(&updated[index_offset..]).unwrap()).unwrap();
+    let name = index["entries"]
+        .entries()
+        .map(|(name, _)| String::from(name))
+        .find(|name| name.starts_with("blocks/"))
+        .unwrap();
+    index["entries"].remove(&name);
+    let mut forged = updated[..index_offset].to_vec();
+    forged.extend_from_slice(index.dump().as_bytes());
+    forged[16..24].copy_from_slice(&(index.dump().len() as u64).to_be_bytes());
+    fs::write(&container, forged).unwrap();
+    let error = HelixDecryptor::from(
+        container_str,
+        restored.to_str().unwrap(),
+        &SecretString::from("passphrase"),
+        &CliDecryptionObserverFactory,
+    )
+    .decrypt()
+    .unwrap_err();
+    assert_eq!(error.detailed_code, "ContainerMismatch");
+    fs::write(&container, &updated).unwrap();
+    // The replaced block of the first draft is no longer in the container, and a
+    // decrypt stages nothing.
+    let opened = CapsuleContainer::open(container_str).unwrap();
+    let block_folder = Path::new(opened.folder()).join(".helix").join("blocks");
+    assert_eq!(mounts::list(&block_folder).len(), 2);
+    assert_eq!(fs::read_dir(&block_folder).unwrap().count(), 0);
+    drop(opened);
+
+    // Rekeying rewrites the container without the blocks it replaced.
+    let passphrase = SecretString::from("passphrase");
+    HelixReKeyer::from(container_str, &passphrase, &CliEncryptionObserverFactory)
+        .rekey()
+        .unwrap();
+    assert!(fs::metadata(&container).unwrap().len() < updated.len() as u64);
+    decrypt("passphrase");
+    assert_eq!(fs::read(restored.join("letter.txt")).unwrap(), b"second draft");
+
+    HelixKeySlots::open(container_str)
+        .unwrap()
+        .change_passphrase("passphrase", "new passphrase")
+        .unwrap();
+    decrypt("new passphrase");
+    fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,262 @@
+//! Deduplicated chunk store.
+//!
+//! Files encrypted with content-defined chunking are cut into chunks, and each chunk
+//! is stored once in `.helix/chunks` under its keyed hash. The file's own block is
+//! then a chunk list, the ids of its chunks in order, encrypted with the file key.
+//! Chunks are reference counted in the metadata DB, and a chunk nobody references
+//! any more is removed by `collect_garbage`.
+
+use std::{
+    fs::{self, create_dir_all},
+    iter::once,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::Connection;
+
+use crate::{
+    crypto::{
+        binding::{associated_data, Field},
+        kdf::ChunkSubKeys,
+        padding::Padding,
+        suite::CipherSuite,
+    },
+    errors::HelixError,
+    filecrypto::{
+        chacha::{decryptors::CCFileDecryptor, encryptors::CCFileEncryptor, ChunkObserver},
+        chunking::{content_defined_chunks, MAX_CHUNK_SIZE},
+        compression::{Compression, CompressionStats},
+    },
+    fileio::{mounts, readers::ChunkReader, writers::FileWriter},
+    storage::ChunkStore,
+    util::hex::{decode_vec, encode},
+};
+
+const CHUNK_ID_SIZE: usize = 32;
+
+/// Progress of chunk objects and chunk lists is reported per file, not per block.
+pub(super) struct QuietObserver;
+
+impl ChunkObserver for QuietObserver {
+    fn bytes_processed(&mut self, _: u64) {}
+}
+
+pub(super) struct DedupStore<'a> {
+    chunk_folder: PathBuf,
+    chunk_sub_keys: &'a ChunkSubKeys,
+    chunk_store: ChunkStore<'a>,
+}
+
+impl<'a> DedupStore<'a> {
+    pub(super) fn from(
+        chunk_folder: &Path,
+        chunk_sub_keys: &'a ChunkSubKeys,
+        connection: &'a Connection,
+    ) -> Self {
+        Self {
+            chunk_folder: chunk_folder.to_path_buf(),
+            chunk_sub_keys,
+            chunk_store: ChunkStore::from(connection),
+        }
+    }
+
+    /// Stores the chunks of a file that are not stored yet and references all of
+    /// them. Returns the chunk list of the file, its stats count chunks that were
+    /// already stored as plain bytes that took no space.
+    pub(super) fn store(
+        &self,
+        file_path: &str,
+        suite: CipherSuite,
+        padding: Padding,
+        compression: Compression,
+        observer: &mut dyn ChunkObserver,
+    ) -> Result<(Vec<u8>, CompressionStats), HelixError> {
+        create_dir_all(&self.chunk_folder).unwrap();
+        let mut chunk_list = Vec::new();
+        let mut stats = CompressionStats::default();
+        let seed = self.chunk_sub_keys.boundary_seed();
+        for chunk in content_defined_chunks(file_path, seed)? {
+            let chunk = chunk?;
+            let chunk_id = self.chunk_sub_keys.chunk_id(&chunk);
+            let len = chunk.len() as u64;
+            let chunk_path = self.chunk_path(&chunk_id);
+            if !mounts::exists(Path::new(&chunk_path)) {
+                let written = self.write_chunk(&chunk_id, chunk, suite, padding, compression)?;
+                stats.stored_bytes += written.stored_bytes;
+            }
+            stats.plain_bytes += len;
+            observer.bytes_processed(len);
+            chunk_list.extend_from_slice(&decode_vec(&chunk_id));
+        }
+        for chunk_id in chunk_ids(&chunk_list)? {
+            self.chunk_store.add_reference(&chunk_id);
+        }
+        Ok((chunk_list, stats))
+    }
+
+    /// Chunks are written under a temporary name first, so an interrupted write
+    /// never leaves a partial chunk under a valid id.
+    fn write_chunk(
+        &self,
+        chunk_id: &str,
+        chunk: Vec<u8>,
+        suite: CipherSuite,
+        padding: Padding,
+        compression: Compression,
+    ) -> Result<CompressionStats, HelixError> {
+        let chunk_key = self.chunk_sub_keys.chunk_key(chunk_id, suite);
+        let chunk_path = self.chunk_path(chunk_id);
+        let temporary_path = format!("{}.tmp", chunk_path);
+        let mut observer = QuietObserver;
+        let mut encryptor = CCFileEncryptor::from(&chunk_key, MAX_CHUNK_SIZE, &mut observer)
+            .with_padding(padding)
+            .with_compression(compression)
+            .with_associated_data(associated_data(Field::Content, chunk_id));
+        if let Err(error) = encryptor.encrypt_chunks(once(Ok(chunk)), &temporary_path) {
+            let _ = fs::remove_file(&temporary_path);
+            return Err(error);
+        }
+        fs::rename(&temporary_path, &chunk_path).unwrap();
+        Ok(encryptor.stats())
+    }
+
+    /// Writes the plain file of a chunk list to `destination`.
+    pub(super) fn restore(
+        &self,
+        chunk_list: &[u8],
+        destination: &str,
+        observer: &mut dyn ChunkObserver,
+    ) -> Result<(), HelixError> {
+        let chunk_ids = chunk_ids(chunk_list)?;
+        let mut writer = FileWriter::from(destination);
+        for chunk_id in chunk_ids {
+            let chunk_path = self.chunk_path(&chunk_id);
+            if !mounts::exists(Path::new(&chunk_path)) {
+                return Err(HelixError::from(
+                    "MalformedBlock",
+                    "ChunkNotFound",
+                    &format!("Chunk {} is missing from the chunk store", chunk_id),
+                ));
+            }
+            let suite = ChunkReader::from(&chunk_path)?.header().suite;
+            let chunk_key = self.chunk_sub_keys.chunk_key(&chunk_id, suite);
+            let content_binding = associated_data(Field::Content, &chunk_id);
+            for buffer in CCFileDecryptor::chunks(&chunk_key, &chunk_path, &content_binding)? {
+                let buffer = buffer?;
+                let len = buffer.len();
+                writer.write(buffer);
+                observer.bytes_processed(len as u64);
+            }
+        }
+        writer.close();
+        Ok(())
+    }
+
+    /// Size of the stored chunks of a chunk list, missing chunks count as empty.
+    pub(super) fn stored_size(&self, chunk_list: &[u8]) -> u64 {
+        chunk_ids(chunk_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|chunk_id| mounts::len(Path::new(&self.chunk_path(chunk_id))))
+            .sum()
+    }
+
+    /// Drops the references of a chunk list that is no longer used.
+    pub(super) fn release(&self, chunk_list: &[u8]) -> Result<(), HelixError> {
+        for chunk_id in chunk_ids(chunk_list)? {
+            self.chunk_store.remove_reference(&chunk_id);
+        }
+        Ok(())
+    }
+
+    /// Removes chunks that no chunk list references, including chunks left behind
+    /// by an interrupted run that never got a reference. Returns how many went.
+    pub(super) fn collect_garbage(&self) -> usize {
+        let mut removed = 0;
+        for chunk_id in self.chunk_store.get_unreferenced() {
+            mounts::remove(Path::new(&self.chunk_path(&chunk_id)));
+            self.chunk_store.delete(&chunk_id);
+            removed += 1;
+        }
+        for (name, _) in mounts::list(&self.chunk_folder) {
+            if !self.chunk_store.contains(&name) {
+                mounts::remove(&self.chunk_folder.join(name));
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn chunk_path(&self, chunk_id: &str) -> String {
+        let binding = self.chunk_folder.join(chunk_id);
+        String::from(binding.to_str().unwrap())
+    }
+}
+
+/// Hex ids of the chunks in a chunk list.
+fn chunk_ids(chunk_list: &[u8]) -> Result<Vec<String>, HelixError> {
+    if !chunk_list.len().is_multiple_of(CHUNK_ID_SIZE) {
+        return Err(HelixError::from(
+            "MalformedBlock",
+            "InvalidChunkList",
+            "Chunk list is not a whole number of chunk ids",
+        ));
+    }
+    Ok(chunk_list.chunks(CHUNK_ID_SIZE).map(encode).collect())
+}
+
+#[test]
+fn dedup_store_test() {
+    use crate::{crypto::chacha::keys::Key, storage::schema::HelixSchemaCreator};
+
+    let root = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    let chunk_folder = root.join("chunks");
+    create_dir_all(&root).unwrap();
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let chunk_sub_keys = ChunkSubKeys::derive(&Key::new());
+    let dedup_store = DedupStore::from(&chunk_folder, &chunk_sub_keys, &connection);
+    let data: Vec<u8> = (0..3 * MAX_CHUNK_SIZE)
+        .map(|_| rand::random::<u8>())
+        .collect();
+    let plain_path = root.join("plain");
+    fs::write(&plain_path, &data).unwrap();
+    let plain_path = plain_path.to_str().unwrap();
+
+    let suite = CipherSuite::default();
+    let store = || {
+        dedup_store
+            .store(
+                plain_path,
+                suite,
+                Padding::None,
+                Compression::None,
+                &mut QuietObserver,
+            )
+            .unwrap()
+            .0
+    };
+    let chunk_list = store();
+    let chunk_count = fs::read_dir(&chunk_folder).unwrap().count();
+    assert_eq!(chunk_list.len(), chunk_count * CHUNK_ID_SIZE);
+    let other_list = store();
+    assert_eq!(other_list, chunk_list);
+    assert_eq!(fs::read_dir(&chunk_folder).unwrap().count(), chunk_count);
+
+    let restored_path = root.join("restored");
+    let restored_path = restored_path.to_str().unwrap();
+    dedup_store
+        .restore(&chunk_list, restored_path, &mut QuietObserver)
+        .unwrap();
+    assert_eq!(fs::read(restored_path).unwrap(), data);
+    assert!(dedup_store
+        .restore(&chunk_list[1..], restored_path, &mut QuietObserver)
+        .is_err());
+
+    dedup_store.release(&chunk_list).unwrap();
+    assert_eq!(dedup_store.collect_garbage(), 0);
+    dedup_store.release(&other_list).unwrap();
+    assert_eq!(dedup_store.collect_garbage(), chunk_count);
+    assert_eq!(fs::read_dir(&chunk_folder).unwrap().count(), 0);
+    fs::remove_dir_all(root).unwrap();
+}
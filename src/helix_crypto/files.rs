@@ -1,6 +1,7 @@
 use std::{
+    cell::{Cell, RefCell},
     fs::{self, create_dir_all},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 use rand::Error;
@@ -9,21 +10,37 @@ use rusqlite::Connection;
 use crate::{
     cli::file::{
         DecryptionEndState, DecryptionObserver, DecryptionObserverFactory, DecryptionStates,
-        EncryptionObserver, EncryptionStates,
+        EncryptionObserver, EncryptionObserverFactory, EncryptionStates,
     },
     crypto::{
+        binding::{associated_data, Field},
         chacha::{
             decryptors::ByteDecryptorImpl,
             encryptors::ByteEncryptorImpl,
             keys::{Key, KeyDecryptor, KeyEncryptor},
         },
+        kdf::{CapsuleSubKeys, FileSubKeys, MasterSubKeys},
+        padding::Padding,
+        recipient::{
+            self, is_recipient_wrapped, unwrap_with_identity, wrap_to_recipients, wrapped_block,
+            Identity, Recipient,
+        },
+        suite::CipherSuite,
         ByteDecryptor, ByteEncryptor,
     },
+    errors::HelixError,
     filecrypto::{
         chacha::{decryptors::CCFileDecryptor, encryptors::CCFileEncryptor, ChunkObserver},
+        chunking::Chunking,
+        compression::{Compression, CompressionStats},
         FileDecryptor, FileEncryptor,
     },
-    storage::{schema::HelixSchemaCreator, File, FileStore, MasterKey, MasterKeyStore},
+    fileio::{header::BlockKind, mounts, readers::ChunkReader},
+    storage::{
+        schema::HelixSchemaCreator, File, FileStore, ManifestStore, MasterKey, MasterKeyStore,
+        SettingStore, KEYED_PLAIN_HASH, LEGACY_IDS_SETTING, LEGACY_KEY_NONCE_SETTING,
+        LEGACY_ROWS_SETTING, PLAIN_HASH_SETTING,
+    },
     util::{
         hash::{hash_file, hash_string},
         hex::{decode, decode_vec, encode_vec},
@@ -31,6 +48,12 @@ use crate::{
     },
 };
 
+use super::{
+    capsule_secret::CapsuleSecretManager,
+    dedup::{DedupStore, QuietObserver},
+    packs::PackStore,
+};
+
 struct EncryptionChunkObserverWrapper<'a> {
     encryption_observer: &'a mut dyn EncryptionObserver,
 }
@@ -45,42 +68,162 @@ pub(super) struct HelixFileEncryptor<'a> {
     source_folder: &'a str,
     block_folder: &'a str,
     file_store: FileStore<'a>,
-    key_encryptor: KeyEncryptor<'a>,
+    manifest_store: ManifestStore<'a>,
+    file_key_wrapper: FileKeyWrapper<'a>,
+    capsule_sub_keys: &'a CapsuleSubKeys,
     chunk_size: u32,
+    cipher_suite: CipherSuite,
+    padding: Padding,
+    compression: Compression,
+    chunking: Chunking,
+    dedup_store: Option<&'a DedupStore<'a>>,
+    pack_store: Option<&'a PackStore<'a>>,
+    /// The capsule has rows from before keyed ids, see `adopt_legacy_row`.
+    legacy_ids: bool,
+    stats: Cell<CompressionStats>,
+    obsolete_blocks: RefCell<Vec<String>>,
+}
+
+/// How the file keys of new rows are wrapped and the rows authenticated.
+pub(super) enum FileKeyWrapper<'a> {
+    /// The master key is unlocked, its subkeys wrap keys and authenticate rows.
+    MasterKey(&'a MasterSubKeys),
+    /// Encrypt-only. Keys are wrapped to public key recipients and rows are
+    /// authenticated with a capsule subkey, no secret that could decrypt is held.
+    Recipients(&'a [Recipient]),
 }
 
 impl<'a> HelixFileEncryptor<'a> {
     pub(super) fn from(
         source_folder: &'a str,
         block_folder: &'a str,
-        master_key: &'a Key,
+        file_key_wrapper: FileKeyWrapper<'a>,
+        capsule_sub_keys: &'a CapsuleSubKeys,
         connection: &'a Connection,
         chunk_size: u32,
+        cipher_suite: CipherSuite,
     ) -> Self {
         Self {
             source_folder,
             block_folder,
             file_store: FileStore::from(connection),
-            key_encryptor: KeyEncryptor::from(master_key),
+            manifest_store: ManifestStore::from(connection),
+            file_key_wrapper,
+            capsule_sub_keys,
             chunk_size,
+            cipher_suite,
+            padding: Padding::None,
+            compression: Compression::None,
+            chunking: Chunking::Fixed,
+            dedup_store: None,
+            pack_store: None,
+            legacy_ids: SettingStore::from(connection)
+                .get(LEGACY_IDS_SETTING)
+                .is_some(),
+            stats: Cell::new(CompressionStats::default()),
+            obsolete_blocks: RefCell::new(Vec::new()),
         }
     }
 
+    pub(super) fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub(super) fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Chunk store of the capsule. Content-defined chunking needs it, and without it
+    /// the chunks of replaced chunk lists are not released.
+    pub(super) fn with_dedup_store(
+        mut self,
+        chunking: Chunking,
+        dedup_store: Option<&'a DedupStore<'a>>,
+    ) -> Self {
+        self.chunking = chunking;
+        self.dedup_store = dedup_store;
+        self
+    }
+
+    /// Pack store of the capsule, needed to check and replace packed blocks.
+    pub(super) fn with_pack_store(mut self, pack_store: Option<&'a PackStore<'a>>) -> Self {
+        self.pack_store = pack_store;
+        self
+    }
+
+    /// Plain and stored bytes of every file encrypted so far.
+    pub(super) fn stats(&self) -> CompressionStats {
+        self.stats.get()
+    }
+
+    /// Blocks replaced so far. They are removed by the caller once the metadata
+    /// pointing at their replacements is saved.
+    pub(super) fn take_obsolete_blocks(&self) -> Vec<String> {
+        self.obsolete_blocks.take()
+    }
+
     pub(super) fn encrypt(&self, file_path: &str, observer: &mut dyn EncryptionObserver) {
-        let file_id = hash_string(file_path);
-        let file_option = self.file_store.get(&file_id);
+        let file_id = self.capsule_sub_keys.file_id(file_path);
+        let file_option = self
+            .file_store
+            .get(&file_id)
+            .or_else(|| self.adopt_legacy_row(file_path, &file_id));
         match file_option {
             None => self.create_file(file_path, &file_id, observer),
             Some(file) => self.update_file(file_path, &file_id, &file, observer),
         };
     }
 
+    /// Rows written before keyed ids used the plain SHA-256 of the path. Such a row
+    /// is moved to its keyed id the next time the file is seen. Only capsules marked
+    /// by `mark_legacy_ids` are looked up, their rows are MACed by then.
+    fn adopt_legacy_row(&self, file_path: &str, file_id: &str) -> Option<File> {
+        let FileKeyWrapper::MasterKey(master_sub_keys) = self.file_key_wrapper else {
+            return None;
+        };
+        if !self.legacy_ids {
+            return None;
+        }
+        let legacy_id = hash_string(file_path);
+        let mut file = self.file_store.get(&legacy_id)?;
+        // The block of a v1 row is named after its id, it keeps the id until
+        // `upgrade_legacy_files` moves it to the current format.
+        if has_legacy_key(&file) {
+            return None;
+        }
+        if !master_sub_keys.verify_row_mac(&row_fields(&file), &file.row_mac) {
+            return None;
+        }
+        if is_bound(&file) {
+            // Bound to the legacy id by a rekey or rotation. The key and path are
+            // rebound, the block can only be rebound by encrypting it again.
+            let file_key = unwrap_file_key(master_sub_keys, &file).ok()?;
+            let file_sub_keys = FileSubKeys::derive(&file_key);
+            let plain_path = decrypt_filepath(&file_sub_keys, &file).ok()?;
+            file.id = String::from(file_id);
+            file.key = wrap_file_key(master_sub_keys, &file_key, file_id);
+            file.file_path = encrypt_filepath(&file_sub_keys.file_path, &plain_path, file_id);
+            file.encrypted_hash = String::new();
+        } else {
+            file.id = String::from(file_id);
+        }
+        file.row_mac = master_sub_keys.row_mac(&row_fields(&file));
+        self.file_store.change_id(&legacy_id, &file);
+        Some(file)
+    }
+
     fn create_file(&self, file_path: &str, file_id: &str, observer: &mut dyn EncryptionObserver) {
         observer.update_state(EncryptionStates::PlainFileCheck);
-        let plain_hash = hash_file(file_path);
-        let file = self.encrypt_internal(file_path, file_id, &plain_hash, observer);
-        self.file_store.store(file);
-        observer.end(crate::cli::file::EncryptionEndState::Done)
+        let plain_hash = self.capsule_sub_keys.content_hash(&hash_file(file_path));
+        match self.encrypt_internal(file_path, file_id, &plain_hash, observer) {
+            Ok(file) => {
+                self.file_store.store(file);
+                observer.end(crate::cli::file::EncryptionEndState::Done)
+            }
+            Err(error) => observer.failed(error),
+        }
     }
 
     fn encrypt_internal(
@@ -89,25 +232,100 @@ impl<'a> HelixFileEncryptor<'a> {
         file_id: &str,
         plain_hash: &str,
         observer: &mut dyn EncryptionObserver,
-    ) -> File {
+    ) -> Result<File, HelixError> {
         let mut chunk_observer = EncryptionChunkObserverWrapper {
             encryption_observer: observer,
         };
-        let file_key = Key::new();
-        let mut file_encryptor =
-            CCFileEncryptor::from(&file_key, self.chunk_size, &mut chunk_observer);
-        let block_path = self.get_block_path(file_id);
-        file_encryptor.encrypt(file_path, &block_path);
+        let file_key = Key::generate(self.cipher_suite);
+        let file_sub_keys = FileSubKeys::derive(&file_key);
+        let block_path = self.get_block_path(&file_sub_keys.block_name);
+        let file_stats = match (self.chunking, self.dedup_store) {
+            (Chunking::ContentDefined, Some(dedup_store)) => self.encrypt_chunk_list(
+                dedup_store,
+                file_path,
+                file_id,
+                &file_sub_keys.content,
+                &block_path,
+                &mut chunk_observer,
+            )?,
+            _ => {
+                let mut file_encryptor = CCFileEncryptor::from(
+                    &file_sub_keys.content,
+                    self.chunk_size,
+                    &mut chunk_observer,
+                )
+                .with_padding(self.padding)
+                .with_compression(self.compression)
+                .with_associated_data(associated_data(Field::Content, file_id));
+                file_encryptor.encrypt(file_path, &block_path)?;
+                file_encryptor.stats()
+            }
+        };
+        let mut stats = self.stats.get();
+        stats += file_stats;
+        self.stats.set(stats);
         let encrypted_hash = hash_file(&block_path);
-        let encrypted_key = self.key_encryptor.encrypt(&file_key);
         let stripped_path = self.strip_source(file_path);
-        let encrypted_file_path = Self::encrypt_filepath(&file_key, stripped_path);
-        File {
+        let encrypted_file_path =
+            encrypt_filepath(&file_sub_keys.file_path, stripped_path, file_id);
+        let mut file = File {
             id: String::from(file_id),
             plain_hash: String::from(plain_hash),
             encrypted_hash: encrypted_hash,
-            key: encrypted_key,
+            key: String::new(),
             file_path: encrypted_file_path,
+            row_mac: String::new(),
+        };
+        self.seal(&mut file, &file_key, &file_sub_keys);
+        Ok(file)
+    }
+
+    /// Stores the chunks of a file in the chunk store and writes its chunk list as
+    /// the block of the file.
+    fn encrypt_chunk_list(
+        &self,
+        dedup_store: &DedupStore,
+        file_path: &str,
+        file_id: &str,
+        content_key: &Key,
+        block_path: &str,
+        chunk_observer: &mut dyn ChunkObserver,
+    ) -> Result<CompressionStats, HelixError> {
+        let (chunk_list, stats) = dedup_store.store(
+            file_path,
+            self.cipher_suite,
+            self.padding,
+            self.compression,
+            chunk_observer,
+        )?;
+        let mut observer = QuietObserver;
+        let mut list_encryptor = CCFileEncryptor::from(content_key, self.chunk_size, &mut observer)
+            .with_padding(self.padding)
+            .with_kind(BlockKind::ChunkList)
+            .with_associated_data(associated_data(Field::Content, file_id));
+        let chunk_ids = chunk_list.chunks(self.chunk_size as usize).map(|ids| Ok(ids.to_vec()));
+        if let Err(error) = list_encryptor.encrypt_chunks(chunk_ids, block_path) {
+            dedup_store.release(&chunk_list)?;
+            return Err(error);
+        }
+        Ok(stats)
+    }
+
+    fn seal(&self, file: &mut File, file_key: &Key, file_sub_keys: &FileSubKeys) {
+        match self.file_key_wrapper {
+            FileKeyWrapper::MasterKey(master_sub_keys) => {
+                file.key = wrap_file_key(master_sub_keys, file_key, &file.id);
+                file.row_mac = master_sub_keys.row_mac(&row_fields(file));
+            }
+            FileKeyWrapper::Recipients(recipients) => {
+                file.key = wrap_to_recipients(
+                    file_key,
+                    recipients,
+                    Some(&file_sub_keys.block_name),
+                    Some(&associated_data(Field::FileKey, &file.id)),
+                );
+                file.row_mac = self.capsule_sub_keys.row_mac(&row_fields(file));
+            }
         }
     }
 
@@ -117,19 +335,12 @@ impl<'a> HelixFileEncryptor<'a> {
         file.strip_prefix(source).unwrap().to_str().unwrap()
     }
 
-    fn get_block_path(&self, file_id: &str) -> String {
-        let binding = Path::new(self.block_folder).join(file_id);
+    fn get_block_path(&self, block_name: &str) -> String {
+        let binding = Path::new(self.block_folder).join(block_name);
         let path = binding.to_str().unwrap();
         String::from(path)
     }
 
-    fn encrypt_filepath(key: &Key, file_path: &str) -> String {
-        let mut vec = Vec::from(file_path.as_bytes());
-        let encryptor = ByteEncryptorImpl::from(key);
-        encryptor.encrypt(&mut vec);
-        encode_vec(vec)
-    }
-
     fn update_file(
         &self,
         file_path: &str,
@@ -137,22 +348,93 @@ impl<'a> HelixFileEncryptor<'a> {
         file: &File,
         observer: &mut dyn EncryptionObserver,
     ) {
-        let current_hash = hash_file(file_path);
+        let current_hash = self.capsule_sub_keys.content_hash(&hash_file(file_path));
+        let old_block_path = self.stored_block_path(file);
         if current_hash.eq(&file.plain_hash) {
             observer.update_state(EncryptionStates::EncryptedBlockCheck);
-            if self.encrypted_file_unchanged(file_id, &file.encrypted_hash) {
-                observer.end(crate::cli::file::EncryptionEndState::Unchanged);
-                return;
+            if let Some(old_block_path) = &old_block_path {
+                if self.block_unchanged(old_block_path, &file.encrypted_hash) {
+                    observer.end(crate::cli::file::EncryptionEndState::Unchanged);
+                    return;
+                }
             }
         }
-        let file = self.encrypt_internal(file_path, file_id, &current_hash, observer);
-        self.file_store.update(file);
-        observer.end(crate::cli::file::EncryptionEndState::Done);
+        // The manifest vouches for sealed rows as they are, only the owner replaces them.
+        let encrypt_only = matches!(self.file_key_wrapper, FileKeyWrapper::Recipients(_));
+        if encrypt_only && self.manifest_store.contains(file_id) {
+            observer.failed(HelixError::from(
+                "BadInput",
+                "SealedFile",
+                "File was sealed by the capsule owner, an encrypt-only host can not replace it",
+            ));
+            return;
+        }
+        match self.encrypt_internal(file_path, file_id, &current_hash, observer) {
+            Ok(new_file) => {
+                self.file_store.update(new_file);
+                // The new key has a new block name, the old block is only removed
+                // once the saved row points at its replacement.
+                if let Some(old_block_path) = old_block_path {
+                    self.release_chunk_list(file, &old_block_path);
+                    self.obsolete_blocks.borrow_mut().push(old_block_path);
+                }
+                observer.end(crate::cli::file::EncryptionEndState::Done);
+            }
+            Err(error) => observer.failed(error),
+        }
+    }
+
+    /// Block of an existing row, None when it can not be determined. An encrypt-only
+    /// run can not open rows wrapped under the master key, their old block is left.
+    fn stored_block_path(&self, file: &File) -> Option<String> {
+        if is_recipient_wrapped(&file.key) {
+            return wrapped_block(&file.key)
+                .filter(|block| is_block_name(block))
+                .map(|block| self.get_block_path(&block));
+        }
+        let file_sub_keys = self.stored_sub_keys(file)?;
+        Some(self.get_block_path(&file_sub_keys.block_name))
     }
 
-    fn encrypted_file_unchanged(&self, file_id: &str, encrypted_hash: &str) -> bool {
-        let encrypted_path = self.get_block_path(file_id);
-        if Path::new(&encrypted_path).exists() {
+    /// Subkeys of an existing row wrapped under the master key.
+    fn stored_sub_keys(&self, file: &File) -> Option<FileSubKeys> {
+        let FileKeyWrapper::MasterKey(master_sub_keys) = self.file_key_wrapper else {
+            return None;
+        };
+        if !master_sub_keys.verify_row_mac(&row_fields(file), &file.row_mac) {
+            return None;
+        }
+        unwrap_file_sub_keys(master_sub_keys, file).ok()
+    }
+
+    /// Drops the chunk references of a block that is about to be replaced, if it is
+    /// a chunk list. The chunks themselves go with the next garbage collection.
+    fn release_chunk_list(&self, file: &File, block_path: &str) {
+        let Some(dedup_store) = self.dedup_store else {
+            return;
+        };
+        let Some(file_sub_keys) = self.stored_sub_keys(file) else {
+            return;
+        };
+        if unpack_block(self.pack_store, &file_sub_keys.block_name).is_err() {
+            return;
+        }
+        if let Ok(Some(chunk_list)) = read_chunk_list(&file_sub_keys.content, block_path, &file.id)
+        {
+            let _ = dedup_store.release(&chunk_list);
+        }
+    }
+
+    /// A packed block is taken as unchanged while its pack exists, see
+    /// `PackStore::is_packed`.
+    fn block_unchanged(&self, block_path: &str, encrypted_hash: &str) -> bool {
+        let block_name = Path::new(block_path).file_name().unwrap().to_str().unwrap();
+        self.pack_store.is_some_and(|pack_store| pack_store.is_packed(block_name))
+            || Self::encrypted_file_unchanged(block_path, encrypted_hash)
+    }
+
+    fn encrypted_file_unchanged(encrypted_path: &str, encrypted_hash: &str) -> bool {
+        if mounts::exists(Path::new(&encrypted_path)) {
             let current_hash = hash_file(&encrypted_path);
             return encrypted_hash.eq(&current_hash);
         } else {
@@ -164,7 +446,11 @@ impl<'a> HelixFileEncryptor<'a> {
 pub(super) struct HelixFileDecryptor<'a> {
     destination: &'a str,
     block_folder: &'a str,
-    key_decryptor: KeyDecryptor<'a>,
+    master_sub_keys: &'a MasterSubKeys,
+    capsule_sub_keys: &'a CapsuleSubKeys,
+    capsule_identity: Option<&'a Identity>,
+    dedup_store: Option<&'a DedupStore<'a>>,
+    pack_store: Option<&'a PackStore<'a>>,
     observer_factory: &'a dyn DecryptionObserverFactory,
 }
 
@@ -182,52 +468,137 @@ impl<'a> HelixFileDecryptor<'a> {
     pub(super) fn from(
         destination: &'a str,
         block_folder: &'a str,
-        master_key: &'a Key,
+        master_sub_keys: &'a MasterSubKeys,
+        capsule_sub_keys: &'a CapsuleSubKeys,
+        capsule_identity: Option<&'a Identity>,
         observer_factory: &'a dyn DecryptionObserverFactory,
     ) -> Self {
         Self {
             destination,
             block_folder,
-            key_decryptor: KeyDecryptor::from(master_key),
+            master_sub_keys,
+            capsule_sub_keys,
+            capsule_identity,
+            dedup_store: None,
+            pack_store: None,
             observer_factory,
         }
     }
 
-    pub(super) fn decrypt(&self, file: File) {
-        let key = self.key_decryptor.decrypt(&file.key);
-        let encrypted_file_path = self.get_encrypted_file_path(&file.id);
-        let plain_file_path = Self::decrypt_filepath(&key, &file.file_path);
-        let complete_path = self.append_destination(plain_file_path);
+    /// Chunk store the chunk lists of the capsule are restored from.
+    pub(super) fn with_dedup_store(mut self, dedup_store: Option<&'a DedupStore<'a>>) -> Self {
+        self.dedup_store = dedup_store;
+        self
+    }
+
+    /// Pack store packed blocks are unpacked from.
+    pub(super) fn with_pack_store(mut self, pack_store: Option<&'a PackStore<'a>>) -> Self {
+        self.pack_store = pack_store;
+        self
+    }
+
+    /// Restores a file to the destination, false if it could not be restored. The
+    /// observer of the file is told why.
+    pub(super) fn decrypt(&self, file: File) -> bool {
+        let (file_sub_keys, plain_file_path) = match self.open_record(&file) {
+            Ok(opened) => opened,
+            Err(error) => {
+                let observer = self.observer_factory.create(PathBuf::from(&file.id));
+                observer.failed(error);
+                return false;
+            }
+        };
+        let complete_path = match self.append_destination(&plain_file_path) {
+            Ok(complete_path) => complete_path,
+            Err(error) => {
+                let observer = self.observer_factory.create(PathBuf::from(&file.id));
+                observer.failed(error);
+                return false;
+            }
+        };
+        let encrypted_file_path = self.get_encrypted_file_path(&file_sub_keys.block_name);
         let path_buf = PathBuf::from(&complete_path);
         // create_dir_all(&path_buf).unwrap();
         let mut observer = self.observer_factory.create(path_buf);
         observer.update_state(DecryptionStates::EncryptedBlockCheck);
+        if let Err(error) = unpack_block(self.pack_store, &file_sub_keys.block_name) {
+            observer.failed(error);
+            return false;
+        }
         if Self::encrypted_block_changed(&encrypted_file_path, &file.encrypted_hash, &observer) {
-            return;
+            return false;
         }
-        let size = fs::metadata(&encrypted_file_path).unwrap().len();
+        let size = mounts::len(Path::new(&encrypted_file_path)).unwrap();
+        let chunk_list =
+            match read_chunk_list(&file_sub_keys.content, &encrypted_file_path, &file.id) {
+                Ok(chunk_list) => chunk_list,
+                Err(error) => {
+                    observer.failed(error);
+                    return false;
+                }
+            };
+        let size = match (&chunk_list, self.dedup_store) {
+            (Some(chunk_list), Some(dedup_store)) => dedup_store.stored_size(chunk_list),
+            _ => size,
+        };
         observer.init_size(size);
         let mut wrapper = DecryptionChunkObserverWrapper {
             decryption_observer: &mut *observer,
         };
-        let mut file_decryptor = CCFileDecryptor::from(&key, &mut wrapper);
-        file_decryptor.decrypt(&encrypted_file_path, &complete_path);
-        observer.end(DecryptionEndState::Done)
+        let result = match (chunk_list, self.dedup_store) {
+            (None, _) => {
+                let mut file_decryptor = CCFileDecryptor::from(&file_sub_keys.content, &mut wrapper)
+                    .with_associated_data(associated_data(Field::Content, &file.id));
+                if let Some(legacy_nonce) = &file_sub_keys.legacy_nonce {
+                    file_decryptor = file_decryptor.with_legacy_nonce(legacy_nonce.clone());
+                }
+                file_decryptor.decrypt(&encrypted_file_path, &complete_path)
+            }
+            (Some(chunk_list), Some(dedup_store)) => {
+                dedup_store.restore(&chunk_list, &complete_path, &mut wrapper)
+            }
+            (Some(_), None) => Err(HelixError::from(
+                "InvalidHelixCapsule",
+                "NoChunkStore",
+                "Block is a chunk list but the capsule has no chunk store",
+            )),
+        };
+        match result {
+            Ok(_) => {
+                observer.end(DecryptionEndState::Done);
+                true
+            }
+            Err(error) => {
+                let _ = fs::remove_file(&complete_path);
+                observer.failed(error);
+                false
+            }
+        }
     }
 
-    fn append_destination(&self, plain_file_path: String) -> String {
-        Path::new(self.destination)
-            .join(plain_file_path)
+    /// The stored path comes from a files row, a path that could leave the
+    /// destination is refused.
+    fn append_destination(&self, plain_file_path: &str) -> Result<String, HelixError> {
+        let relative = Path::new(plain_file_path);
+        let contained = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !contained || relative.file_name().is_none() {
+            return Err(HelixError::from(
+                "MalformedData",
+                "UnsafeFilePath",
+                &format!("File path {} leaves the destination", plain_file_path),
+            ));
+        }
+        Ok(Path::new(self.destination)
+            .join(relative)
             .to_str()
             .unwrap()
-            .to_owned()
+            .to_owned())
     }
 
-    fn decrypt_filepath(key: &Key, file_path: &str) -> String {
-        let mut decoded = decode_vec(file_path);
-        let decryptor = ByteDecryptorImpl::from(key);
-        decryptor.decrypt(&mut decoded);
-        String::from_utf8(decoded).unwrap()
+    fn open_record(&self, file: &File) -> Result<(FileSubKeys, String), HelixError> {
+        open_record(file, self.master_sub_keys, self.capsule_sub_keys, self.capsule_identity)
     }
 
     fn encrypted_block_changed(
@@ -235,7 +606,7 @@ impl<'a> HelixFileDecryptor<'a> {
         file_hash: &str,
         observer: &Box<dyn DecryptionObserver>,
     ) -> bool {
-        if !Path::new(file_path).exists() {
+        if !mounts::exists(Path::new(file_path)) {
             observer.end(DecryptionEndState::BlockNotFound);
             return true;
         }
@@ -247,13 +618,433 @@ impl<'a> HelixFileDecryptor<'a> {
         return false;
     }
 
-    fn get_encrypted_file_path(&self, file_id: &str) -> String {
-        let binding = Path::new(self.block_folder).join(file_id);
+    fn get_encrypted_file_path(&self, block_name: &str) -> String {
+        let binding = Path::new(self.block_folder).join(block_name);
+        let path = binding.to_str().unwrap();
+        String::from(path)
+    }
+}
+
+/// Authenticates a files row and returns the subkeys of its file key with the plain path.
+/// Rows wrapped to recipients are opened with the capsule identity.
+fn open_record(
+    file: &File,
+    master_sub_keys: &MasterSubKeys,
+    capsule_sub_keys: &CapsuleSubKeys,
+    capsule_identity: Option<&Identity>,
+) -> Result<(FileSubKeys, String), HelixError> {
+    let file_sub_keys = if is_recipient_wrapped(&file.key) {
+        if !capsule_sub_keys.verify_row_mac(&row_fields(file), &file.row_mac) {
+            return Err(row_authentication_failed());
+        }
+        let key_binding = associated_data(Field::FileKey, &file.id);
+        let key = capsule_identity
+            .map(|identity| unwrap_with_identity(&file.key, identity, Some(&key_binding)))
+            .transpose()?
+            .flatten()
+            .ok_or(HelixError::from(
+                "MalformedData",
+                "NotARecipient",
+                "File key is not wrapped to this capsule",
+            ))?;
+        FileSubKeys::derive(&key)
+    } else {
+        if !master_sub_keys.verify_row_mac(&row_fields(file), &file.row_mac) {
+            return Err(row_authentication_failed());
+        }
+        unwrap_file_sub_keys(master_sub_keys, file)?
+    };
+    let plain_file_path = decrypt_filepath(&file_sub_keys, file)?;
+    Ok((file_sub_keys, plain_file_path))
+}
+
+/// Rows written before binding existed, and never rewritten since, carry an unbound
+/// key and path. Their row MAC still ties the two to the row.
+fn is_bound(file: &File) -> bool {
+    KeyDecryptor::is_bound(&file.key) || recipient::is_bound(&file.key)
+}
+
+fn wrap_file_key(master_sub_keys: &MasterSubKeys, file_key: &Key, file_id: &str) -> String {
+    KeyEncryptor::from(&master_sub_keys.file_key_wrap)
+        .with_associated_data(associated_data(Field::FileKey, file_id))
+        .encrypt(file_key)
+}
+
+fn unwrap_file_key(master_sub_keys: &MasterSubKeys, file: &File) -> Result<Key, HelixError> {
+    KeyDecryptor::from(&master_sub_keys.file_key_wrap)
+        .with_associated_data(associated_data(Field::FileKey, &file.id))
+        .decrypt(&file.key)
+}
+
+/// Subkeys of a row wrapped under the master key. A key wrapped in the v1 format is
+/// used as it is, see `FileSubKeys::legacy`.
+fn unwrap_file_sub_keys(
+    master_sub_keys: &MasterSubKeys,
+    file: &File,
+) -> Result<FileSubKeys, HelixError> {
+    let Some(legacy_nonce) = KeyDecryptor::legacy_nonce(&file.key) else {
+        return Ok(FileSubKeys::derive(&unwrap_file_key(master_sub_keys, file)?));
+    };
+    let (master_key, master_nonce) =
+        master_sub_keys.legacy_file_key_wrap().ok_or(HelixError::from(
+            "InvalidHelixCapsule",
+            "LegacyKeyNonceMissing",
+            "File key is wrapped in the v1 format but the capsule no longer has its nonce",
+        ))?;
+    // The id names the block, it must not point outside the block folder.
+    if !is_block_name(&file.id) {
+        return Err(row_authentication_failed());
+    }
+    let file_key = KeyDecryptor::with_legacy_nonce(master_key, master_nonce).decrypt(&file.key)?;
+    Ok(FileSubKeys::legacy(&file_key, legacy_nonce, &file.id))
+}
+
+/// True for rows written before file keys had a format version, see
+/// `upgrade_legacy_files`.
+fn has_legacy_key(file: &File) -> bool {
+    !is_recipient_wrapped(&file.key) && KeyDecryptor::legacy_nonce(&file.key).is_some()
+}
+
+fn encrypt_filepath(key: &Key, file_path: &str, file_id: &str) -> String {
+    let mut vec = Vec::from(file_path.as_bytes());
+    let encryptor = ByteEncryptorImpl::from(key)
+        .with_associated_data(associated_data(Field::FilePath, file_id));
+    encryptor.encrypt(&mut vec);
+    encode_vec(vec)
+}
+
+fn decrypt_filepath(file_sub_keys: &FileSubKeys, file: &File) -> Result<String, HelixError> {
+    let mut decoded = decode_vec(&file.file_path);
+    let mut decryptor = ByteDecryptorImpl::from(&file_sub_keys.file_path);
+    if is_bound(file) {
+        decryptor = decryptor.with_associated_data(associated_data(Field::FilePath, &file.id));
+    }
+    // A v1 key encrypted the path with the nonce stored next to it.
+    match &file_sub_keys.legacy_nonce {
+        Some(legacy_nonce) => decryptor.decrypt_with_nonce(legacy_nonce, &mut decoded)?,
+        None => decryptor.decrypt(&mut decoded)?,
+    }
+    String::from_utf8(decoded).map_err(|_| {
+        HelixError::from(
+            "MalformedData",
+            "InvalidFilePath",
+            "File path is not valid UTF-8",
+        )
+    })
+}
+
+fn row_authentication_failed() -> HelixError {
+    HelixError::from(
+        "MalformedData",
+        "RowAuthenticationFailed",
+        "File record was modified or written with another master key",
+    )
+}
+
+/// Chunk list held by a block, None if the block holds file data.
+fn read_chunk_list(
+    content_key: &Key,
+    block_path: &str,
+    file_id: &str,
+) -> Result<Option<Vec<u8>>, HelixError> {
+    // The kind is only trusted once the chunks decrypt, it is part of their AAD.
+    if ChunkReader::from(block_path)?.header().kind != BlockKind::ChunkList {
+        return Ok(None);
+    }
+    let content_binding = associated_data(Field::Content, file_id);
+    let chunks = CCFileDecryptor::chunks(content_key, block_path, &content_binding)?;
+    let chunk_list = chunks.collect::<Result<Vec<_>, _>>()?.concat();
+    Ok(Some(chunk_list))
+}
+
+/// Makes a packed block available in the block folder, see `PackStore::unpack`.
+fn unpack_block(pack_store: Option<&PackStore>, block_name: &str) -> Result<(), HelixError> {
+    pack_store.map_or(Ok(()), |pack_store| pack_store.unpack(block_name))
+}
+
+/// Block names are hex HKDF output. Names read from unauthenticated fields are
+/// checked so they can not point outside the block folder.
+pub(super) fn is_block_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Re-encrypts blocks under fresh file keys. Each block is decrypted chunk by chunk
+/// straight into the encryptor of its replacement, plaintext is never written.
+pub(super) struct HelixFileReKeyer<'a> {
+    block_folder: &'a str,
+    file_store: FileStore<'a>,
+    master_sub_keys: &'a MasterSubKeys,
+    capsule_sub_keys: &'a CapsuleSubKeys,
+    capsule_identity: Option<&'a Identity>,
+    observer_factory: &'a dyn EncryptionObserverFactory,
+    pack_store: Option<&'a PackStore<'a>>,
+    obsolete_blocks: RefCell<Vec<String>>,
+}
+
+impl<'a> HelixFileReKeyer<'a> {
+    pub(super) fn from(
+        block_folder: &'a str,
+        master_sub_keys: &'a MasterSubKeys,
+        capsule_sub_keys: &'a CapsuleSubKeys,
+        capsule_identity: Option<&'a Identity>,
+        connection: &'a Connection,
+        observer_factory: &'a dyn EncryptionObserverFactory,
+    ) -> Self {
+        Self {
+            block_folder,
+            file_store: FileStore::from(connection),
+            master_sub_keys,
+            capsule_sub_keys,
+            capsule_identity,
+            observer_factory,
+            pack_store: None,
+            obsolete_blocks: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Pack store packed blocks are unpacked from before they are rekeyed.
+    pub(super) fn with_pack_store(mut self, pack_store: Option<&'a PackStore<'a>>) -> Self {
+        self.pack_store = pack_store;
+        self
+    }
+
+    /// Blocks that were rekeyed, to be removed once the metadata is saved.
+    pub(super) fn take_obsolete_blocks(&self) -> Vec<String> {
+        self.obsolete_blocks.take()
+    }
+
+    pub(super) fn rekey(&self, file: File) {
+        let opened = open_record(
+            &file,
+            self.master_sub_keys,
+            self.capsule_sub_keys,
+            self.capsule_identity,
+        );
+        let (file_sub_keys, plain_file_path) = match opened {
+            Ok(opened) => opened,
+            Err(error) => {
+                let observer = self.observer_factory.create(PathBuf::from(&file.id), 0);
+                observer.failed(error);
+                return;
+            }
+        };
+        if let Err(error) = unpack_block(self.pack_store, &file_sub_keys.block_name) {
+            let observer = self.observer_factory.create(PathBuf::from(&plain_file_path), 0);
+            observer.failed(error);
+            return;
+        }
+        let block_path = self.get_block_path(&file_sub_keys.block_name);
+        let size = mounts::len(Path::new(&block_path)).unwrap_or(0);
+        let mut observer = self
+            .observer_factory
+            .create(PathBuf::from(&plain_file_path), size);
+        observer.update_state(EncryptionStates::EncryptedBlockCheck);
+        if !mounts::exists(Path::new(&block_path))
+            || !hash_file(&block_path).eq(&file.encrypted_hash)
+        {
+            observer.failed(HelixError::from(
+                "MalformedBlock",
+                "BlockChanged",
+                "Encrypted block is missing or does not match its record",
+            ));
+            return;
+        }
+        match self.rekey_internal(file, &file_sub_keys, &plain_file_path, &mut *observer) {
+            Ok(file) => {
+                self.file_store.update(file);
+                self.obsolete_blocks.borrow_mut().push(block_path);
+                observer.end(crate::cli::file::EncryptionEndState::Done);
+            }
+            Err(error) => observer.failed(error),
+        }
+    }
+
+    fn rekey_internal(
+        &self,
+        mut file: File,
+        file_sub_keys: &FileSubKeys,
+        plain_file_path: &str,
+        observer: &mut dyn EncryptionObserver,
+    ) -> Result<File, HelixError> {
+        let block_path = self.get_block_path(&file_sub_keys.block_name);
+        let content_binding = associated_data(Field::Content, &file.id);
+        let chunks = match &file_sub_keys.legacy_nonce {
+            Some(legacy_nonce) => {
+                CCFileDecryptor::legacy_chunks(&file_sub_keys.content, legacy_nonce, &block_path)?
+            }
+            None => CCFileDecryptor::chunks(&file_sub_keys.content, &block_path, &content_binding)?,
+        };
+        let file_key = Key::generate(file_sub_keys.content.suite());
+        let new_sub_keys = FileSubKeys::derive(&file_key);
+        let new_block_path = self.get_block_path(&new_sub_keys.block_name);
+        let mut chunk_observer = EncryptionChunkObserverWrapper {
+            encryption_observer: observer,
+        };
+        // The new block keeps the chunk size, padding, compression and kind of the one
+        // it replaces, and is bound to its row like every new block. Chunks of a chunk
+        // list are keyed from the chunk secret, not the file key, and stay as they are.
+        let chunk_size = chunks.header().chunk_size;
+        let padding = chunks.header().padding;
+        let compression = chunks.header().compression;
+        let kind = chunks.header().kind;
+        let mut file_encryptor =
+            CCFileEncryptor::from(&new_sub_keys.content, chunk_size, &mut chunk_observer)
+                .with_padding(padding)
+                .with_compression(compression)
+                .with_kind(kind)
+                .with_associated_data(content_binding);
+        if let Err(error) = file_encryptor.encrypt_chunks(chunks, &new_block_path) {
+            let _ = fs::remove_file(&new_block_path);
+            return Err(error);
+        }
+        file.key = wrap_file_key(self.master_sub_keys, &file_key, &file.id);
+        file.encrypted_hash = hash_file(&new_block_path);
+        file.file_path = encrypt_filepath(&new_sub_keys.file_path, plain_file_path, &file.id);
+        file.row_mac = self.master_sub_keys.row_mac(&row_fields(&file));
+        Ok(file)
+    }
+
+    fn get_block_path(&self, block_name: &str) -> String {
+        let binding = Path::new(self.block_folder).join(block_name);
         let path = binding.to_str().unwrap();
         String::from(path)
     }
 }
 
+/// Marks a capsule that has rows but no capsule secret yet, its rows were written
+/// with the plain SHA-256 of their path as id. Call before creating the secret.
+pub(super) fn mark_legacy_ids(connection: &Connection) {
+    if !CapsuleSecretManager::from(connection).exists()
+        && !FileStore::from(connection).get_all().is_empty()
+    {
+        SettingStore::from(connection).set(LEGACY_IDS_SETTING, "sha256");
+    }
+}
+
+/// MACs the rows of capsules older than row MACs. Only rows the schema migration
+/// found are trusted, later rows with an empty MAC are still rejected.
+pub(super) fn mac_legacy_rows(connection: &Connection, master_sub_keys: &MasterSubKeys) {
+    let setting_store = SettingStore::from(connection);
+    if setting_store.get(LEGACY_ROWS_SETTING).is_none() {
+        return;
+    }
+    let transaction = connection.unchecked_transaction().unwrap();
+    let file_store = FileStore::from(connection);
+    for mut file in file_store.get_all() {
+        if file.row_mac.is_empty() {
+            file.row_mac = master_sub_keys.row_mac(&row_fields(&file));
+            file_store.update(file);
+        }
+    }
+    // Such rows predate keyed ids as well.
+    setting_store.set(LEGACY_IDS_SETTING, "sha256");
+    setting_store.delete(LEGACY_ROWS_SETTING);
+    transaction.commit().unwrap();
+}
+
+/// Replaces the bare SHA-256 plain hashes of older capsules with their keyed form.
+/// Runs once per capsule, in a single transaction, after `mac_legacy_rows`.
+pub(super) fn key_plain_hashes(
+    connection: &Connection,
+    master_sub_keys: &MasterSubKeys,
+    capsule_sub_keys: &CapsuleSubKeys,
+) -> Result<(), HelixError> {
+    let setting_store = SettingStore::from(connection);
+    if setting_store.get(PLAIN_HASH_SETTING).is_some() {
+        return Ok(());
+    }
+    let transaction = connection.unchecked_transaction().unwrap();
+    let file_store = FileStore::from(connection);
+    for mut file in file_store.get_all() {
+        // Recipient-wrapped rows are newer than keyed plain hashes.
+        if is_recipient_wrapped(&file.key) {
+            continue;
+        }
+        if !master_sub_keys.verify_row_mac(&row_fields(&file), &file.row_mac) {
+            return Err(row_authentication_failed());
+        }
+        file.plain_hash = capsule_sub_keys.content_hash(&file.plain_hash);
+        file.row_mac = master_sub_keys.row_mac(&row_fields(&file));
+        file_store.update(file);
+    }
+    setting_store.set(PLAIN_HASH_SETTING, KEYED_PLAIN_HASH);
+    transaction.commit().unwrap();
+    Ok(())
+}
+
+/// Moves every file key and row MAC to a new master key. Blocks are not touched,
+/// the file keys themselves stay the same. An unbound key stays unbound, its path
+/// was encrypted without binding too.
+pub(super) fn rewrap_file_keys(
+    connection: &Connection,
+    old_master_sub_keys: &MasterSubKeys,
+    new_master_sub_keys: &MasterSubKeys,
+) -> Result<(), HelixError> {
+    let file_store = FileStore::from(connection);
+    for mut file in file_store.get_all() {
+        // Keys wrapped to recipients do not depend on the master key.
+        if is_recipient_wrapped(&file.key) {
+            continue;
+        }
+        if !old_master_sub_keys.verify_row_mac(&row_fields(&file), &file.row_mac) {
+            return Err(row_authentication_failed());
+        }
+        if has_legacy_key(&file) {
+            return Err(HelixError::from(
+                "UnsupportedFormat",
+                "LegacyFileKey",
+                "File keys in the v1 format are upgraded by the next encryption or rekey first",
+            ));
+        }
+        let file_key = unwrap_file_key(old_master_sub_keys, &file)?;
+        file.key = match is_bound(&file) {
+            true => wrap_file_key(new_master_sub_keys, &file_key, &file.id),
+            false => KeyEncryptor::from(&new_master_sub_keys.file_key_wrap).encrypt(&file_key),
+        };
+        file.row_mac = new_master_sub_keys.row_mac(&row_fields(&file));
+        file_store.update(file);
+    }
+    Ok(())
+}
+
+/// Subkeys of the master key. A capsule that still has v1 file keys also gets the
+/// master key and nonce they were wrapped with, see `MasterKeyManager::unlock`.
+pub(super) fn master_sub_keys(connection: &Connection, master_key: &Key) -> MasterSubKeys {
+    let master_sub_keys = MasterSubKeys::derive(master_key);
+    match SettingStore::from(connection).get(LEGACY_KEY_NONCE_SETTING) {
+        Some(legacy_nonce) => {
+            master_sub_keys.with_legacy_nonce(master_key, decode_vec(&legacy_nonce))
+        }
+        None => master_sub_keys,
+    }
+}
+
+/// Rekeys the rows whose file key is wrapped in the v1 format, which moves them and
+/// their blocks without a header to the current format. A row that fails is left as
+/// it is, the nonce of the master key is dropped once no such row is left.
+pub(super) fn upgrade_legacy_files(connection: &Connection, rekeyer: &HelixFileReKeyer) {
+    let file_store = FileStore::from(connection);
+    for file in file_store.get_all() {
+        if has_legacy_key(&file) {
+            rekeyer.rekey(file);
+        }
+    }
+    if !file_store.get_all().iter().any(has_legacy_key) {
+        SettingStore::from(connection).delete(LEGACY_KEY_NONCE_SETTING);
+    }
+}
+
+/// Fields of a files row covered by its row MAC.
+fn row_fields(file: &File) -> [&str; 5] {
+    [
+        &file.id,
+        &file.key,
+        &file.plain_hash,
+        &file.encrypted_hash,
+        &file.file_path,
+    ]
+}
+
 #[test]
 fn source_striper() {
     let source = Path::new(".").to_path_buf();
@@ -261,3 +1052,204 @@ fn source_striper() {
     let stripped = child.strip_prefix(source).unwrap();
     println!("{:?}", stripped)
 }
+
+#[test]
+fn key_plain_hashes_test() {
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create_at(&connection, 3);
+    let digest = hash_string("known document");
+    connection
+        .execute(
+            "INSERT INTO files values('id','key',?1,'encrypted','path')",
+            [&digest],
+        )
+        .unwrap();
+    HelixSchemaCreator::create(&connection);
+    let master_sub_keys = MasterSubKeys::derive(&Key::new());
+    let capsule_sub_keys = CapsuleSubKeys::derive(&Key::new());
+    assert!(key_plain_hashes(&connection, &master_sub_keys, &capsule_sub_keys).is_err());
+
+    mac_legacy_rows(&connection, &master_sub_keys);
+    key_plain_hashes(&connection, &master_sub_keys, &capsule_sub_keys).unwrap();
+    key_plain_hashes(&connection, &master_sub_keys, &capsule_sub_keys).unwrap();
+    let file = FileStore::from(&connection).get("id").unwrap();
+    assert_eq!(file.plain_hash, capsule_sub_keys.content_hash(&digest));
+    assert!(master_sub_keys.verify_row_mac(&row_fields(&file), &file.row_mac));
+}
+
+#[test]
+fn mac_legacy_rows_test() {
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create_at(&connection, 3);
+    connection
+        .execute(
+            "INSERT INTO files values('id','key','hash','encrypted','path')",
+            (),
+        )
+        .unwrap();
+    HelixSchemaCreator::create(&connection);
+    let master_sub_keys = MasterSubKeys::derive(&Key::new());
+    mac_legacy_rows(&connection, &master_sub_keys);
+    let file = FileStore::from(&connection).get("id").unwrap();
+    assert!(master_sub_keys.verify_row_mac(&row_fields(&file), &file.row_mac));
+
+    // Once MACed, a row with an empty MAC is not adopted any more.
+    let mut forged = file;
+    forged.id = String::from("forged");
+    forged.row_mac = String::new();
+    FileStore::from(&connection).store(forged);
+    mac_legacy_rows(&connection, &master_sub_keys);
+    assert!(FileStore::from(&connection).get("forged").unwrap().row_mac.is_empty());
+}
+
+#[test]
+fn adopt_legacy_row_test() {
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create_at(&connection, 3);
+    let master_sub_keys = MasterSubKeys::derive(&Key::new());
+    let capsule_sub_keys = CapsuleSubKeys::derive(&Key::new());
+    let file_key = Key::new();
+    let file_path = "source/taxes.pdf";
+    let legacy_id = hash_string(file_path);
+    let file_sub_keys = FileSubKeys::derive(&file_key);
+    let encrypted_path = encrypt_filepath(&file_sub_keys.file_path, file_path, &legacy_id);
+    connection
+        .execute(
+            "INSERT INTO files values(?1,?2,'hash','encrypted',?3)",
+            (
+                &legacy_id,
+                wrap_file_key(&master_sub_keys, &file_key, &legacy_id),
+                encrypted_path,
+            ),
+        )
+        .unwrap();
+    HelixSchemaCreator::create(&connection);
+    let file_id = capsule_sub_keys.file_id(file_path);
+    let encryptor = || {
+        HelixFileEncryptor::from(
+            "source",
+            "blocks",
+            FileKeyWrapper::MasterKey(&master_sub_keys),
+            &capsule_sub_keys,
+            &connection,
+            1024,
+            CipherSuite::ChaCha20Poly1305,
+        )
+    };
+    assert!(encryptor().adopt_legacy_row(file_path, &file_id).is_none());
+
+    mac_legacy_rows(&connection, &master_sub_keys);
+    let file = encryptor().adopt_legacy_row(file_path, &file_id).unwrap();
+    assert_eq!(file.id, file_id);
+    assert!(FileStore::from(&connection).get(&legacy_id).is_none());
+    let file = FileStore::from(&connection).get(&file_id).unwrap();
+    assert!(master_sub_keys.verify_row_mac(&row_fields(&file), &file.row_mac));
+    assert_eq!(unwrap_file_key(&master_sub_keys, &file).unwrap().bytes(), file_key.bytes());
+    assert_eq!(decrypt_filepath(&file_sub_keys, &file).unwrap(), file_path);
+}
+
+#[test]
+fn append_destination_test() {
+    let master_sub_keys = MasterSubKeys::derive(&Key::new());
+    let capsule_sub_keys = CapsuleSubKeys::derive(&Key::new());
+    let observer_factory = crate::cli::file::CliDecryptionObserverFactory;
+    let decryptor = HelixFileDecryptor::from(
+        "destination",
+        "blocks",
+        &master_sub_keys,
+        &capsule_sub_keys,
+        None,
+        &observer_factory,
+    );
+    let joined = decryptor.append_destination("source/./taxes.pdf").unwrap();
+    assert_eq!(Path::new(&joined), Path::new("destination/source/taxes.pdf"));
+    for path in ["../taxes.pdf", "source/../../taxes.pdf", "/etc/passwd", "", "."] {
+        let error = decryptor.append_destination(path).unwrap_err();
+        assert_eq!(error.detailed_code, "UnsafeFilePath");
+    }
+}
+
+#[test]
+fn legacy_file_upgrade_test() {
+    // Key, path and block of a files row written at baseline, with a chunk size of 24.
+    let master_key = Key::from_parts(
+        CipherSuite::ChaCha20Poly1305,
+        &decode_vec("f692897885c0cb20a3e4cd106a0b15bc360794f17e73298909b0798873ec7441"),
+    );
+    let wrapped_key = concat!(
+        r#"{"key":"940a3e18be6d7714befee86953c9bb5de121687ef540b8eb1e97cbd3a0996eeb"#,
+        r#"02abcf29f79354616f6799aafdd498c8","nonce":"b0d6878faae801dd851a85fe"}"#,
+    );
+    let encrypted_path = "e499daaa244d433a9701780e0f2ec474685bf9e47a281e6c307af3d09ecf";
+    let block = decode_vec(concat!(
+        "00000028f784d0ad7f46426e900b760e1f3fd386631cb962a9a0509a624a0061fd10fcefc937",
+        "a53c68d2f2ed0000001ea09fd7f97f54436e911a23141c2961fedbc2daf99cce42b3d91a10",
+        "5bd2b0",
+    ));
+    let folder = std::env::temp_dir().join(format!("helix-{}", generate()));
+    let block_folder = folder.join("blocks");
+    let destination = folder.join("destination");
+    create_dir_all(&block_folder).unwrap();
+    let legacy_id = hash_string("source/docs/notes.txt");
+    let block_path = block_folder.join(&legacy_id);
+    fs::write(&block_path, block).unwrap();
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create_at(&connection, 3);
+    connection
+        .execute(
+            "INSERT INTO files values(?1,?2,'hash',?3,?4)",
+            (&legacy_id, wrapped_key, hash_file(block_path.to_str().unwrap()), encrypted_path),
+        )
+        .unwrap();
+    HelixSchemaCreator::create(&connection);
+    mac_legacy_rows(&connection, &MasterSubKeys::derive(&master_key));
+    let capsule_sub_keys = CapsuleSubKeys::derive(&Key::new());
+    let decrypt = |master_sub_keys: &MasterSubKeys| {
+        let file = FileStore::from(&connection).get(&legacy_id).unwrap();
+        let _ = fs::remove_dir_all(&destination);
+        HelixFileDecryptor::from(
+            destination.to_str().unwrap(),
+            block_folder.to_str().unwrap(),
+            master_sub_keys,
+            &capsule_sub_keys,
+            None,
+            &crate::cli::file::CliDecryptionObserverFactory,
+        )
+        .decrypt(file)
+    };
+    let file = FileStore::from(&connection).get(&legacy_id).unwrap();
+    let without_nonce = master_sub_keys(&connection, &master_key);
+    let error = open_record(&file, &without_nonce, &capsule_sub_keys, None).err().unwrap();
+    assert_eq!(error.detailed_code, "LegacyKeyNonceMissing");
+
+    // Unlocking the v1 slot keeps the nonce of the master key.
+    SettingStore::from(&connection).set(LEGACY_KEY_NONCE_SETTING, "f692897885c0cb20a3e4cd10");
+    let legacy_sub_keys = master_sub_keys(&connection, &master_key);
+    assert!(decrypt(&legacy_sub_keys));
+    let restored = fs::read(destination.join("docs").join("notes.txt")).unwrap();
+    assert_eq!(restored, b"written by the baseline, in two chunks");
+
+    let rekeyer = HelixFileReKeyer::from(
+        block_folder.to_str().unwrap(),
+        &legacy_sub_keys,
+        &capsule_sub_keys,
+        None,
+        &connection,
+        &crate::cli::file::CliEncryptionObserverFactory,
+    );
+    upgrade_legacy_files(&connection, &rekeyer);
+    assert_eq!(rekeyer.take_obsolete_blocks(), vec![block_path.to_str().unwrap()]);
+    assert!(SettingStore::from(&connection).get(LEGACY_KEY_NONCE_SETTING).is_none());
+    let file = FileStore::from(&connection).get(&legacy_id).unwrap();
+    assert!(!has_legacy_key(&file) && is_bound(&file));
+    let master_sub_keys = master_sub_keys(&connection, &master_key);
+    let (file_sub_keys, _) = open_record(&file, &master_sub_keys, &capsule_sub_keys, None).unwrap();
+    let new_block_path = block_folder.join(&file_sub_keys.block_name);
+    let header = ChunkReader::from(new_block_path.to_str().unwrap()).unwrap().header().clone();
+    assert_eq!(header.version, crate::fileio::header::BLOCK_FORMAT_VERSION);
+    fs::remove_file(&block_path).unwrap();
+    assert!(decrypt(&master_sub_keys));
+    let restored = fs::read(destination.join("docs").join("notes.txt")).unwrap();
+    assert_eq!(restored, b"written by the baseline, in two chunks");
+    fs::remove_dir_all(folder).unwrap();
+}
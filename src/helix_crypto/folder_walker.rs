@@ -1,6 +1,8 @@
 use std::path::{Path, PathBuf};
 use walkdir::{DirEntry, WalkDir};
 
+use super::container::CONTAINER_EXTENSION;
+
 pub(crate) fn get_files(source: &str) -> Vec<PathBuf> {
     let walker = WalkDir::new(source);
     let paths = walker
@@ -14,8 +16,13 @@ pub(crate) fn get_files(source: &str) -> Vec<PathBuf> {
     Vec::from_iter(paths)
 }
 
+/// Capsule folders and capsule containers are never encrypted into a capsule.
 fn is_helix(entry: &DirEntry) -> bool {
     entry.path().to_str().unwrap().contains(".helix")
+        || entry
+            .path()
+            .extension()
+            .is_some_and(|extension| extension == CONTAINER_EXTENSION)
 }
 
 fn is_hidden(entry: &DirEntry) -> bool {
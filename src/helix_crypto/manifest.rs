@@ -0,0 +1,190 @@
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::Connection;
+
+use crate::{
+    crypto::{kdf::MasterSubKeys, recipient::is_recipient_wrapped},
+    errors::HelixError,
+    storage::{FileStore, ManifestStore, SettingStore, MANIFEST_SETTING},
+};
+
+/// Authenticated manifest of the files table.
+///
+/// Row MACs only authenticate rows one by one, so a deleted row or an older
+/// `metadata.db` restored with its old blocks went unnoticed. The manifest is a
+/// snapshot of the id and row MAC of every row, MACed under a master subkey together
+/// with a generation that grows with every seal. A capsule whose rows no longer match
+/// the snapshot is rejected, and a caller that remembers the last generation it saw
+/// can tell an old capsule from the current one.
+///
+/// Encrypt-only hosts can not seal. Rows they add are wrapped to recipients and
+/// authenticated with a capsule subkey, such rows are left unsealed and are not
+/// decrypted until the next seal with the master key, by an encrypt, rekey or
+/// rotation, takes them in. Sealed rows have to match their entry exactly.
+pub(super) struct ManifestManager<'a> {
+    connection: &'a Connection,
+}
+
+impl<'a> ManifestManager<'a> {
+    pub(super) fn from(connection: &'a Connection) -> Self {
+        Self { connection }
+    }
+
+    /// Checks the files table against the manifest and returns its generation with
+    /// the ids of the rows encrypt-only hosts added since. Metadata that was never
+    /// sealed, see `CapsuleMetadata::needs_first_seal`, is sealed as it is.
+    pub(super) fn verify_or_create(
+        &self,
+        master_sub_keys: &MasterSubKeys,
+        first_seal: bool,
+    ) -> Result<(u64, HashSet<String>), HelixError> {
+        let setting_store = SettingStore::from(self.connection);
+        let Some(manifest) = setting_store.get(MANIFEST_SETTING) else {
+            if !first_seal {
+                return Err(manifest_mismatch());
+            }
+            return Ok((self.seal(master_sub_keys), HashSet::new()));
+        };
+        let (generation, manifest_mac) = parse(&manifest)?;
+        let entries = ManifestStore::from(self.connection).get_all();
+        let generation_field = generation.to_string();
+        let fields = fields(&generation_field, &entries);
+        if !master_sub_keys.verify_manifest_mac(&fields, manifest_mac) {
+            return Err(manifest_mismatch());
+        }
+        let mut files: HashMap<String, _> = FileStore::from(self.connection)
+            .get_all()
+            .into_iter()
+            .map(|file| (file.id.clone(), file))
+            .collect();
+        for (id, row_mac) in &entries {
+            match files.remove(id) {
+                Some(file) if file.row_mac == *row_mac => {}
+                _ => return Err(manifest_mismatch()),
+            }
+        }
+        if files.values().any(|file| !is_recipient_wrapped(&file.key)) {
+            return Err(manifest_mismatch());
+        }
+        Ok((generation, files.into_keys().collect()))
+    }
+
+    /// Snapshots the files table under the next generation and returns it. Only call
+    /// this once the table was verified, a seal vouches for every row it covers.
+    pub(super) fn seal(&self, master_sub_keys: &MasterSubKeys) -> u64 {
+        let setting_store = SettingStore::from(self.connection);
+        let generation = match setting_store.get(MANIFEST_SETTING) {
+            Some(manifest) => parse(&manifest).map(|(generation, _)| generation).unwrap_or(0) + 1,
+            None => 1,
+        };
+        let mut entries: Vec<(String, String)> = FileStore::from(self.connection)
+            .get_all()
+            .into_iter()
+            .map(|file| (file.id, file.row_mac))
+            .collect();
+        entries.sort();
+        let generation_field = generation.to_string();
+        let manifest_mac = master_sub_keys.manifest_mac(&fields(&generation_field, &entries));
+        // A savepoint nests inside the transaction of a master key rotation.
+        self.connection.execute_batch("SAVEPOINT manifest").unwrap();
+        ManifestStore::from(self.connection).replace(&entries);
+        setting_store.set(MANIFEST_SETTING, &format!("{}:{}", generation, manifest_mac));
+        self.connection.execute_batch("RELEASE manifest").unwrap();
+        generation
+    }
+}
+
+fn parse(manifest: &str) -> Result<(u64, &str), HelixError> {
+    manifest
+        .split_once(':')
+        .and_then(|(generation, manifest_mac)| Some((generation.parse().ok()?, manifest_mac)))
+        .ok_or(manifest_mismatch())
+}
+
+/// The generation followed by the id and row MAC of every entry, in id order.
+fn fields<'e>(generation: &'e str, entries: &'e [(String, String)]) -> Vec<&'e str> {
+    let mut fields = vec![generation];
+    for (id, row_mac) in entries {
+        fields.push(id);
+        fields.push(row_mac);
+    }
+    fields
+}
+
+fn manifest_mismatch() -> HelixError {
+    HelixError::from(
+        "MalformedData",
+        "ManifestMismatch",
+        "Files of the capsule were deleted, added or replaced outside of helix",
+    )
+}
+
+pub(super) fn manifest_rollback() -> HelixError {
+    HelixError::from(
+        "MalformedData",
+        "ManifestRollback",
+        "Capsule is older than the generation it was last seen at",
+    )
+}
+
+pub(super) fn unsealed_file() -> HelixError {
+    HelixError::from(
+        "MalformedData",
+        "UnsealedFile",
+        "File was added by an encrypt-only host and is not sealed by the owner yet",
+    )
+}
+
+#[test]
+fn manifest_test() {
+    use crate::{
+        crypto::chacha::keys::Key,
+        storage::{schema::HelixSchemaCreator, File},
+    };
+
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let master_sub_keys = MasterSubKeys::derive(&Key::new());
+    let file_store = FileStore::from(&connection);
+    let file = |id: &str, key: &str| File {
+        id: String::from(id),
+        key: String::from(key),
+        plain_hash: String::new(),
+        encrypted_hash: String::new(),
+        file_path: String::new(),
+        row_mac: master_sub_keys.row_mac(&[id, key]),
+    };
+    for id in ["a", "b"] {
+        file_store.store(file(id, "key"));
+    }
+    let manager = ManifestManager::from(&connection);
+    let verify = |master_sub_keys| manager.verify_or_create(master_sub_keys, false);
+    // Only metadata that was never sealed gets its first manifest.
+    assert_eq!(verify(&master_sub_keys).unwrap_err().detailed_code, "ManifestMismatch");
+    let (generation, unsealed) = manager.verify_or_create(&master_sub_keys, true).unwrap();
+    assert_eq!(generation, 1);
+    assert!(unsealed.is_empty());
+    assert_eq!(manager.seal(&master_sub_keys), 2);
+    assert_eq!(verify(&master_sub_keys).unwrap().0, 2);
+    let other_sub_keys = MasterSubKeys::derive(&Key::new());
+    assert!(verify(&other_sub_keys).is_err());
+
+    // Rows wrapped to recipients may be added after the seal, they stay unsealed
+    // until the next one. They can not replace sealed rows.
+    let recipient_key = r#"{"recipients":[]}"#;
+    file_store.store(file("c", recipient_key));
+    let (generation, unsealed) = verify(&master_sub_keys).unwrap();
+    assert_eq!(generation, 2);
+    assert_eq!(unsealed, HashSet::from([String::from("c")]));
+    file_store.update(file("b", recipient_key));
+    assert_eq!(verify(&master_sub_keys).unwrap_err().detailed_code, "ManifestMismatch");
+    file_store.update(file("b", "key"));
+    assert_eq!(manager.seal(&master_sub_keys), 3);
+    assert!(verify(&master_sub_keys).unwrap().1.is_empty());
+
+    connection.execute("DELETE FROM files where id = 'b'", ()).unwrap();
+    assert_eq!(verify(&master_sub_keys).unwrap_err().detailed_code, "ManifestMismatch");
+    // A missing manifest is only replaced while the metadata was never sealed.
+    connection.execute("DELETE FROM settings where name = 'manifest'", ()).unwrap();
+    assert_eq!(verify(&master_sub_keys).unwrap_err().detailed_code, "ManifestMismatch");
+}
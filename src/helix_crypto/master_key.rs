@@ -5,14 +5,25 @@ use std::{
 
 use rand::Error;
 use rusqlite::Connection;
+use zeroize::Zeroizing;
+
+use super::capsule_secret::CapsuleSecretManager;
 
 use crate::{
     crypto::{
+        kdf::MasterSubKeys,
         chacha::{
             decryptors::ByteDecryptorImpl,
             encryptors::ByteEncryptorImpl,
             keys::{Key, KeyDecryptor, KeyEncryptor},
         },
+        passphrase::{
+            derive_key, generate_salt, keyfile_secret, Argon2Params, ARGON2ID,
+            LEGACY_SHA256,
+        },
+        recovery::{RecoveryKey, RECOVERY_KDF},
+        shares::combine,
+        suite::{CipherSuite, SuiteCipher},
         ByteDecryptor, ByteEncryptor,
     },
     errors::HelixError,
@@ -20,63 +31,367 @@ use crate::{
         chacha::{decryptors::CCFileDecryptor, encryptors::CCFileEncryptor},
         FileDecryptor, FileEncryptor,
     },
-    storage::{schema::HelixSchemaCreator, File, FileStore, MasterKey, MasterKeyStore},
+    storage::{
+        schema::HelixSchemaCreator, File, FileStore, MasterKey, MasterKeyStore, SettingStore,
+        DEFAULT_SLOT_LABEL, LEGACY_KEY_NONCE_SETTING, RECOVERY_SLOT_LABEL,
+    },
     util::{
         hash::{hash_file, hash_string},
-        hex::{decode, decode_vec, encode_vec},
+        hex::{decode, decode_vec, encode, encode_vec},
         uuid::generate,
     },
 };
 
 pub(super) struct MasterKeyManager<'a> {
     connection: &'a Connection,
+    kdf_params: Argon2Params,
+    keyfile_secret: Option<Zeroizing<[u8; 32]>>,
 }
 
 impl<'a> MasterKeyManager<'a> {
     pub(super) fn from(connection: &'a Connection) -> Self {
-        Self { connection }
+        Self {
+            connection,
+            kdf_params: Argon2Params::default(),
+            keyfile_secret: None,
+        }
     }
 
-    pub(super) fn generate(&self, passphrase: &'a str) -> Key {
-        let passphrase_digest = hash_string(passphrase);
-        let passphrase_key = Self::get_passphrase_key(passphrase, &passphrase_digest);
-        let key_encryptor = KeyEncryptor::from(&passphrase_key);
-        let master_key_plain = Key::new();
-        let master_key = key_encryptor.encrypt(&master_key_plain);
+    /// Unlocks slots that need a keyfile. A capsule generated with a keyfile gets a
+    /// default slot that needs both the keyfile and the passphrase, so do added slots.
+    /// Unlocking a slot without a keyfile fails, the keyfile would be silently ignored.
+    pub(super) fn with_keyfile(mut self, keyfile: Option<&[u8]>) -> Result<Self, HelixError> {
+        self.keyfile_secret = keyfile.map(keyfile_secret).transpose()?;
+        Ok(self)
+    }
+
+    /// Creates the master key of a new capsule with its default slot and a recovery
+    /// slot. The recovery code is returned to be shown once, it is not stored. The
+    /// capsule is sealed with its first manifest once it is opened.
+    pub(super) fn generate(
+        &self,
+        passphrase: &str,
+        suite: CipherSuite,
+    ) -> (Key, Zeroizing<String>) {
+        let master_key_plain = Key::generate(suite);
         let master_key_store = MasterKeyStore::from(self.connection);
-        master_key_store.insert(MasterKey {
-            passphrase_digest,
-            master_key,
-        });
-        master_key_plain
+        let keyfile = self.keyfile_secret.is_some();
+        master_key_store.insert(self.wrap(DEFAULT_SLOT_LABEL, passphrase, &master_key_plain, keyfile));
+        let recovery_key = RecoveryKey::generate();
+        master_key_store.insert(Self::wrap_recovery(&recovery_key, &master_key_plain));
+        (master_key_plain, recovery_key.to_code())
     }
 
-    pub fn get(&self, passphrase: &'a str) -> Result<Option<Key>, HelixError> {
-        let passphrase_digest = hash_string(passphrase);
+    /// Unlocks the master key with the recovery code and sets the passphrase of the
+    /// default slot. Other slots, and the recovery slot itself, stay as they are.
+    /// Returns the master key.
+    pub(super) fn recover(
+        &self,
+        recovery_code: &str,
+        new_passphrase: &str,
+    ) -> Result<Key, HelixError> {
+        let recovery_key = RecoveryKey::from_code(recovery_code)?;
         let master_key_store = MasterKeyStore::from(self.connection);
-        match master_key_store.get() {
-            Some(master_key) => {
-                if !master_key.passphrase_digest.eq(&passphrase_digest) {
+        let slot = master_key_store
+            .get_by_label(RECOVERY_SLOT_LABEL)
+            .filter(|slot| slot.kdf == RECOVERY_KDF)
+            .ok_or(HelixError::from(
+                "InvalidHelixCapsule",
+                "NoRecoverySlot",
+                "Capsule has no recovery key",
+            ))?;
+        let master_key_plain = KeyDecryptor::from(&recovery_key.wrapping_key())
+            .decrypt(&slot.master_key)
+            .map_err(|_| {
+                HelixError::from(
+                    "BadInput",
+                    "RecoveryCodeMismatch",
+                    "Recovery code does not belong to this capsule",
+                )
+            })?;
+        let transaction = self.connection.unchecked_transaction().unwrap();
+        match master_key_store.get_by_label(DEFAULT_SLOT_LABEL) {
+            Some(default_slot) => self.rewrap(&default_slot, new_passphrase, &master_key_plain),
+            None => master_key_store.insert(self.wrap(
+                DEFAULT_SLOT_LABEL,
+                new_passphrase,
+                &master_key_plain,
+                false,
+            )),
+        }
+        transaction.commit().unwrap();
+        Ok(master_key_plain)
+    }
+
+    /// Rebuilds the master key from shares. The key is checked against the capsule
+    /// secret, which it wraps, so shares of another capsule are rejected.
+    pub(super) fn unlock_with_shares(&self, shares: &[Zeroizing<String>]) -> Result<Key, HelixError> {
+        let master_key_plain = combine(shares)?;
+        let master_sub_keys = MasterSubKeys::derive(&master_key_plain);
+        if !CapsuleSecretManager::from(self.connection).verify(&master_sub_keys) {
+            return Err(share_mismatch());
+        }
+        Ok(master_key_plain)
+    }
+
+    fn wrap_recovery(recovery_key: &RecoveryKey, master_key_plain: &Key) -> MasterKey {
+        MasterKey {
+            id: 0,
+            label: String::from(RECOVERY_SLOT_LABEL),
+            master_key: KeyEncryptor::from(&recovery_key.wrapping_key()).encrypt(master_key_plain),
+            kdf: String::from(RECOVERY_KDF),
+            kdf_salt: String::new(),
+            kdf_memory_cost: 0,
+            kdf_time_cost: 0,
+            kdf_parallelism: 0,
+            keyfile: false,
+        }
+    }
+
+    /// Unlocks the master key with the passphrase of any slot.
+    pub fn get(&self, passphrase: &str) -> Result<Option<Key>, HelixError> {
+        Ok(self.unlock(passphrase)?.map(|(master_key, _)| master_key))
+    }
+
+    /// Returns the master key together with the slot the passphrase opened.
+    pub(super) fn unlock(&self, passphrase: &str) -> Result<Option<(Key, MasterKey)>, HelixError> {
+        let master_key_store = MasterKeyStore::from(self.connection);
+        let slots = master_key_store.get_all();
+        if slots.is_empty() {
+            return Ok(None);
+        }
+        let needs_keyfile = self.keyfile_secret.is_none()
+            && slots
+                .iter()
+                .filter(|slot| slot.kdf != RECOVERY_KDF)
+                .all(|slot| slot.keyfile);
+        for slot in slots {
+            let (key, legacy_nonce) = match self.get_wrapping_key(passphrase, &slot) {
+                Ok(wrapping_key) => wrapping_key,
+                Err(_) => continue,
+            };
+            let key_decryptor = KeyDecryptor::with_legacy_nonce(&key, &legacy_nonce);
+            // The wrapped master key is authenticated, a wrong passphrase fails here.
+            if let Ok(decrypted) = key_decryptor.decrypt(&slot.master_key) {
+                if self.keyfile_secret.is_some() && !slot.keyfile {
                     return Err(HelixError::from(
                         "BadInput",
-                        "PassphraseMismatch",
-                        "Provided passphrase does not match with the initially entered passphrase.",
+                        "KeyfileNotUsed",
+                        &format!("Key slot {} does not use a keyfile", slot.label),
                     ));
                 }
-                let key = Self::get_passphrase_key(passphrase, &passphrase_digest);
-                let key_decryptor = KeyDecryptor::from(&key);
-                let decrypted = key_decryptor.decrypt(&master_key.master_key);
-                Ok(Some(decrypted))
+                // v1 file keys were wrapped with the nonce this slot stores next to the
+                // master key, the upgraded slot no longer has it.
+                if let Some(legacy_nonce) = KeyDecryptor::legacy_nonce(&slot.master_key) {
+                    SettingStore::from(self.connection)
+                        .set(LEGACY_KEY_NONCE_SETTING, &encode(&legacy_nonce));
+                }
+                if self.needs_upgrade(&slot) {
+                    self.rewrap(&slot, passphrase, &decrypted);
+                }
+                return Ok(Some((decrypted, slot)));
+            }
+        }
+        if needs_keyfile {
+            return Err(HelixError::from(
+                "BadInput",
+                "KeyfileRequired",
+                "Every key slot of the capsule needs a keyfile.",
+            ));
+        }
+        Err(HelixError::from(
+            "BadInput",
+            "PassphraseMismatch",
+            "Provided passphrase does not match any key slot of the capsule.",
+        ))
+    }
+
+    /// Rewraps the slot opened by the old passphrase and returns the master key.
+    /// Other slots and the file keys stay as they are.
+    pub(super) fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<Key, HelixError> {
+        let transaction = self.connection.unchecked_transaction().unwrap();
+        let (master_key_plain, slot) = self.unlock(old_passphrase)?.ok_or(HelixError::from(
+            "InvalidHelixCapsule",
+            "NoMasterKey",
+            "Master Key not found in db",
+        ))?;
+        self.rewrap(&slot, new_passphrase, &master_key_plain);
+        transaction.commit().unwrap();
+        Ok(master_key_plain)
+    }
+
+    fn rewrap(&self, slot: &MasterKey, passphrase: &str, master_key_plain: &Key) {
+        let mut rewrapped = self.wrap(&slot.label, passphrase, master_key_plain, slot.keyfile);
+        rewrapped.id = slot.id;
+        MasterKeyStore::from(self.connection).update(rewrapped);
+    }
+
+    /// Wraps a new master key in the given slot. A capsule that had a recovery slot
+    /// gets a new one, its code is returned. See `only_slot` for other slots.
+    pub(super) fn replace_master_key(
+        &self,
+        slot: &MasterKey,
+        passphrase: &str,
+        master_key_plain: &Key,
+    ) -> Result<Option<Zeroizing<String>>, HelixError> {
+        self.only_slot(slot)?;
+        let master_key_store = MasterKeyStore::from(self.connection);
+        let recovery_slot = master_key_store
+            .get_all()
+            .into_iter()
+            .find(|other| other.kdf == RECOVERY_KDF);
+        self.rewrap(slot, passphrase, master_key_plain);
+        let Some(recovery_slot) = recovery_slot else {
+            return Ok(None);
+        };
+        master_key_store.delete(recovery_slot.id);
+        let recovery_key = RecoveryKey::generate();
+        master_key_store.insert(Self::wrap_recovery(&recovery_key, master_key_plain));
+        Ok(Some(recovery_key.to_code()))
+    }
+
+    /// Fails if a passphrase slot other than `slot` exists. Its passphrase is not
+    /// known here, so it could not be rewrapped under a new master key.
+    pub(super) fn only_slot(&self, slot: &MasterKey) -> Result<(), HelixError> {
+        let others: Vec<String> = MasterKeyStore::from(self.connection)
+            .get_all()
+            .into_iter()
+            .filter(|other| other.id != slot.id && other.kdf != RECOVERY_KDF)
+            .map(|other| other.label)
+            .collect();
+        if others.is_empty() {
+            return Ok(());
+        }
+        Err(HelixError::from(
+            "BadInput",
+            "OtherKeySlots",
+            &format!(
+                "Remove the key slots {} first, their passphrases are needed to rewrap them",
+                others.join(", ")
+            ),
+        ))
+    }
+
+    pub(super) fn add_slot(
+        &self,
+        master_key_plain: &Key,
+        label: &str,
+        passphrase: &str,
+    ) -> Result<(), HelixError> {
+        let master_key_store = MasterKeyStore::from(self.connection);
+        if master_key_store.get_by_label(label).is_some() {
+            return Err(HelixError::from(
+                "BadInput",
+                "DuplicateKeySlot",
+                &format!("A key slot labelled {} already exists", label),
+            ));
+        }
+        let keyfile = self.keyfile_secret.is_some();
+        master_key_store.insert(self.wrap(label, passphrase, master_key_plain, keyfile));
+        Ok(())
+    }
+
+    pub(super) fn remove_slot(&self, label: &str) -> Result<(), HelixError> {
+        let master_key_store = MasterKeyStore::from(self.connection);
+        let slot = master_key_store.get_by_label(label).ok_or(HelixError::from(
+            "BadInput",
+            "UnknownKeySlot",
+            &format!("No key slot labelled {}", label),
+        ))?;
+        if master_key_store.get_all().len() == 1 {
+            return Err(HelixError::from(
+                "BadInput",
+                "LastKeySlot",
+                "The last key slot can not be removed, the capsule would be unreadable",
+            ));
+        }
+        master_key_store.delete(slot.id);
+        Ok(())
+    }
+
+    pub(super) fn slot_labels(&self) -> Vec<String> {
+        let master_key_store = MasterKeyStore::from(self.connection);
+        master_key_store
+            .get_all()
+            .into_iter()
+            .map(|slot| slot.label)
+            .collect()
+    }
+
+    fn wrap(&self, label: &str, passphrase: &str, master_key_plain: &Key, keyfile: bool) -> MasterKey {
+        let salt = generate_salt();
+        let keyfile_secret = self.keyfile_secret.as_deref().filter(|_| keyfile);
+        let (passphrase_key, _) =
+            derive_key(passphrase, keyfile_secret, &salt, &self.kdf_params);
+        let key_encryptor = KeyEncryptor::from(&passphrase_key);
+        MasterKey {
+            id: 0,
+            label: String::from(label),
+            master_key: key_encryptor.encrypt(master_key_plain),
+            kdf: String::from(ARGON2ID),
+            kdf_salt: encode(&salt),
+            kdf_memory_cost: self.kdf_params.memory_cost,
+            kdf_time_cost: self.kdf_params.time_cost,
+            kdf_parallelism: self.kdf_params.parallelism,
+            keyfile: keyfile_secret.is_some(),
+        }
+    }
+
+    fn needs_upgrade(&self, master_key: &MasterKey) -> bool {
+        master_key.kdf != ARGON2ID
+            || Self::stored_params(master_key).weaker_than(&self.kdf_params)
+            || KeyDecryptor::is_legacy(&master_key.master_key)
+    }
+
+    fn stored_params(master_key: &MasterKey) -> Argon2Params {
+        Argon2Params {
+            memory_cost: master_key.kdf_memory_cost,
+            time_cost: master_key.kdf_time_cost,
+            parallelism: master_key.kdf_parallelism,
+        }
+    }
+
+    fn get_wrapping_key(
+        &self,
+        passphrase: &str,
+        master_key: &MasterKey,
+    ) -> Result<(Key, Vec<u8>), HelixError> {
+        let keyfile_secret = match (master_key.keyfile, self.keyfile_secret.as_deref()) {
+            (false, _) => None,
+            (true, Some(keyfile_secret)) => Some(keyfile_secret),
+            (true, None) => {
+                return Err(HelixError::from(
+                    "BadInput",
+                    "KeyfileRequired",
+                    "Key slot needs a keyfile",
+                ))
             }
-            None => Ok(None),
+        };
+        match master_key.kdf.as_str() {
+            ARGON2ID => {
+                let salt = decode_vec(&master_key.kdf_salt);
+                let params = Self::stored_params(master_key);
+                Ok(derive_key(passphrase, keyfile_secret, &salt, &params))
+            }
+            LEGACY_SHA256 => Ok(Self::get_passphrase_key(passphrase)),
+            kdf => Err(HelixError::from(
+                "InvalidHelixCapsule",
+                "UnknownKdf",
+                &format!("Passphrase KDF {} is not supported", kdf),
+            )),
         }
     }
 
-    fn get_passphrase_key(passphrase: &str, passphrase_digest: &str) -> Key {
+    /// Pre-Argon2 derivation, only kept to unlock and upgrade older capsules.
+    fn get_passphrase_key(passphrase: &str) -> (Key, Vec<u8>) {
+        let passphrase_digest = hash_string(passphrase);
         let final_digest_str = format!("{}{}", passphrase, passphrase_digest);
         let final_digest = Self::get_hash_bytes(&final_digest_str);
-        let passphrase_key = Key::from_seed(final_digest);
-        passphrase_key
+        Key::from_seed(final_digest)
     }
 
     fn get_hash_bytes(passphrase: &str) -> [u8; 32] {
@@ -87,6 +402,14 @@ impl<'a> MasterKeyManager<'a> {
     }
 }
 
+pub(super) fn share_mismatch() -> HelixError {
+    HelixError::from(
+        "BadInput",
+        "ShareMismatch",
+        "Shares do not belong to this capsule",
+    )
+}
+
 #[test]
 fn create_schema_test() {
     let connection = Connection::open("../test.db").unwrap();
@@ -95,14 +418,200 @@ fn create_schema_test() {
 
 #[test]
 fn generate_test() {
-    let connection = Connection::open("../test.db").unwrap();
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
     let manager = MasterKeyManager::from(&connection);
-    let key = manager.generate("passphrase");
+    let (master_key, recovery_code) = manager.generate("passphrase", CipherSuite::default());
+    assert!(!recovery_code.is_empty());
+    assert_eq!(MasterKeyStore::from(&connection).get_all().len(), 2);
+    let unlocked = manager.get("passphrase").unwrap().unwrap();
+    assert_eq!(unlocked.bytes(), master_key.bytes());
 }
 
 #[test]
 fn get_test() {
-    let connection = Connection::open("../test.db").unwrap();
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let manager = MasterKeyManager::from(&connection);
+    assert!(manager.get("passphrase").unwrap().is_none());
+    manager.generate("passphrase", CipherSuite::default());
+    assert!(manager.get("wrong passphrase").is_err());
+}
+
+#[test]
+fn legacy_kdf_upgrade_test() {
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let passphrase = "passphrase";
+    let (legacy_key, legacy_nonce) = MasterKeyManager::get_passphrase_key(passphrase);
+    let master_key_plain = Key::generate(CipherSuite::ChaCha20Poly1305);
+    // v1 wraps had no version and reused the nonce derived with the wrapping key.
+    let mut wrapped = master_key_plain.bytes().to_vec();
+    SuiteCipher::new(legacy_key.suite(), legacy_key.bytes())
+        .encrypt_in_place(&legacy_nonce, b"", &mut wrapped)
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO master_key (id, master_key) values(1, ?1)",
+            [json::object! { key: encode(&wrapped), nonce: "f692897885c0cb20a3e4cd10" }.dump()],
+        )
+        .unwrap();
+
+    let manager = MasterKeyManager::from(&connection);
+    let unlocked = manager.get(passphrase).unwrap().unwrap();
+    let stored = MasterKeyStore::from(&connection).get_by_label(DEFAULT_SLOT_LABEL).unwrap();
+    assert_eq!(stored.kdf, ARGON2ID);
+    assert!(!manager.needs_upgrade(&stored));
+    // The nonce of the master key stays for the file keys it wrapped.
+    let legacy_nonce = SettingStore::from(&connection).get(LEGACY_KEY_NONCE_SETTING);
+    assert_eq!(legacy_nonce.unwrap(), "f692897885c0cb20a3e4cd10");
+
+    let mut data = b"helix".to_vec();
+    ByteEncryptorImpl::from(&master_key_plain).encrypt(&mut data);
+    ByteDecryptorImpl::from(&unlocked).decrypt(&mut data).unwrap();
+    let upgraded = manager.get(passphrase).unwrap().unwrap();
+    ByteEncryptorImpl::from(&upgraded).encrypt(&mut data);
+    ByteDecryptorImpl::from(&master_key_plain).decrypt(&mut data).unwrap();
+    assert_eq!(data, b"helix");
+}
+
+#[test]
+fn wrong_passphrase_test() {
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let manager = MasterKeyManager::from(&connection);
+    manager.generate("passphrase", CipherSuite::default());
+    let error = manager.get("not the passphrase").err().unwrap();
+    assert_eq!(error.detailed_code, "PassphraseMismatch");
+    assert!(manager.get("passphrase").unwrap().is_some());
+}
+
+#[test]
+fn key_slots_test() {
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let manager = MasterKeyManager::from(&connection);
+    let (master_key_plain, _) = manager.generate("passphrase", CipherSuite::default());
+    manager.add_slot(&master_key_plain, "alice", "alice passphrase").unwrap();
+    let error = manager.add_slot(&master_key_plain, "alice", "other").unwrap_err();
+    assert_eq!(error.detailed_code, "DuplicateKeySlot");
+    assert_eq!(manager.slot_labels(), vec!["default", "recovery", "alice"]);
+
+    let unlocked = manager.get("alice passphrase").unwrap().unwrap();
+    assert_eq!(unlocked.bytes(), master_key_plain.bytes());
+
+    manager.remove_slot("default").unwrap();
+    manager.remove_slot("recovery").unwrap();
+    assert!(manager.get("passphrase").is_err());
+    let error = manager.remove_slot("alice").unwrap_err();
+    assert_eq!(error.detailed_code, "LastKeySlot");
+}
+
+#[test]
+fn change_passphrase_test() {
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let manager = MasterKeyManager::from(&connection);
+    let (master_key_plain, _) = manager.generate("old passphrase", CipherSuite::default());
+    manager.add_slot(&master_key_plain, "bob", "bob passphrase").unwrap();
+
+    let error = manager.change_passphrase("wrong", "new passphrase").unwrap_err();
+    assert_eq!(error.detailed_code, "PassphraseMismatch");
+    manager.change_passphrase("old passphrase", "new passphrase").unwrap();
+
+    assert!(manager.get("old passphrase").is_err());
+    let unlocked = manager.get("new passphrase").unwrap().unwrap();
+    assert_eq!(unlocked.bytes(), master_key_plain.bytes());
+    assert!(manager.get("bob passphrase").unwrap().is_some());
+    assert_eq!(manager.slot_labels(), vec!["default", "recovery", "bob"]);
+}
+
+#[test]
+fn keyfile_test() {
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let keyfile = [7u8; 64];
+    let other_keyfile = [8u8; 64];
+    let manager = MasterKeyManager::from(&connection)
+        .with_keyfile(Some(&keyfile))
+        .unwrap();
+    let (master_key_plain, _) = manager.generate("passphrase", CipherSuite::default());
+    assert!(MasterKeyStore::from(&connection).get_all()[0].keyfile);
+
+    let unlocked = manager.get("passphrase").unwrap().unwrap();
+    assert_eq!(unlocked.bytes(), master_key_plain.bytes());
+    assert!(manager.get("other passphrase").is_err());
+    let error = MasterKeyManager::from(&connection).get("passphrase").unwrap_err();
+    assert_eq!(error.detailed_code, "KeyfileRequired");
+    let other = MasterKeyManager::from(&connection)
+        .with_keyfile(Some(&other_keyfile))
+        .unwrap();
+    assert!(other.get("passphrase").is_err());
+    let error = MasterKeyManager::from(&connection)
+        .with_keyfile(Some(b"short"))
+        .err()
+        .unwrap();
+    assert_eq!(error.detailed_code, "KeyfileTooShort");
+
+    // Keyfile only, with an empty passphrase.
+    manager.change_passphrase("passphrase", "").unwrap();
+    assert!(manager.get("").unwrap().is_some());
+    assert!(MasterKeyManager::from(&connection).get("").is_err());
+
+    // Added slots need the keyfile too.
+    manager.add_slot(&master_key_plain, "bob", "bob passphrase").unwrap();
+    assert!(MasterKeyStore::from(&connection).get_by_label("bob").unwrap().keyfile);
+    assert!(MasterKeyManager::from(&connection).get("bob passphrase").is_err());
+
+    // A keyfile given for a slot without one is an error, not silently ignored.
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    MasterKeyManager::from(&connection).generate("passphrase", CipherSuite::default());
+    let error = MasterKeyManager::from(&connection)
+        .with_keyfile(Some(&keyfile))
+        .unwrap()
+        .get("passphrase")
+        .unwrap_err();
+    assert_eq!(error.detailed_code, "KeyfileNotUsed");
+}
+
+#[test]
+fn recover_test() {
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
+    let manager = MasterKeyManager::from(&connection);
+    let (master_key_plain, recovery_code) = manager.generate("forgotten", CipherSuite::default());
+    let other_connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&other_connection);
+    let (_, other_code) =
+        MasterKeyManager::from(&other_connection).generate("other", CipherSuite::default());
+
+    let error = manager.recover(&other_code, "new passphrase").unwrap_err();
+    assert_eq!(error.detailed_code, "RecoveryCodeMismatch");
+    manager.recover(&recovery_code, "new passphrase").unwrap();
+    assert!(manager.get("forgotten").is_err());
+    let unlocked = manager.get("new passphrase").unwrap().unwrap();
+    assert_eq!(unlocked.bytes(), master_key_plain.bytes());
+    // The recovery code keeps working.
+    manager.recover(&recovery_code, "newer passphrase").unwrap();
+    assert!(manager.get("newer passphrase").unwrap().is_some());
+}
+
+#[test]
+fn unlock_with_shares_test() {
+    use crate::crypto::shares::split;
+
+    let connection = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create(&connection);
     let manager = MasterKeyManager::from(&connection);
-    let key = manager.get("passphrase");
+    let (master_key_plain, _) = manager.generate("passphrase", CipherSuite::default());
+    CapsuleSecretManager::from(&connection)
+        .get_or_create(&MasterSubKeys::derive(&master_key_plain))
+        .unwrap();
+    let shares = split(&master_key_plain, 2, 3).unwrap();
+    let unlocked = manager.unlock_with_shares(&shares[1..]).unwrap();
+    assert_eq!(unlocked.bytes(), master_key_plain.bytes());
+    let other_shares = split(&Key::new(), 2, 3).unwrap();
+    let error = manager.unlock_with_shares(&other_shares[..2]).unwrap_err();
+    assert_eq!(error.detailed_code, "ShareMismatch");
 }
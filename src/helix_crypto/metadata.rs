@@ -0,0 +1,566 @@
+use std::{
+    cell::RefCell,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use json::{object, JsonValue};
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{
+    crypto::{
+        chacha::{
+            decryptors::ByteDecryptorImpl,
+            encryptors::ByteEncryptorImpl,
+            keys::{Key, KeyDecryptor, KeyEncryptor},
+        },
+        kdf::{CapsuleSubKeys, MasterSubKeys},
+        passphrase::LEGACY_SHA256,
+        ByteDecryptor, ByteEncryptor,
+    },
+    errors::HelixError,
+    storage::{
+        schema::{HelixSchemaCreator, MANIFEST_VERSION},
+        snapshot, MasterKeyStore, SettingStore, CONTAINER_BLOCKS_SETTING,
+    },
+    util::hash::hash_string,
+};
+
+use super::{capsule_secret::CapsuleSecretManager, container::CapsuleContainer};
+
+const METADATA_FILE: &str = "metadata";
+const LEGACY_METADATA_FILE: &str = "metadata.db";
+const LEGACY_BACKUP_FILE: &str = "metadata.db.bak";
+const MAGIC: &[u8] = b"HLXM";
+const FORMAT_VERSION: u8 = 1;
+const METADATA_LABEL: &[u8] = b"helix/metadata/v1";
+/// Tables needed before the master key is unlocked. Everything else is encrypted.
+const CLEAR_TABLES: &[&str] = &["master_key", "recipients"];
+
+/// Metadata of a capsule, encrypted at rest.
+///
+/// While a capsule is open its metadata lives in an in-memory database. On disk it
+/// is the single file `.helix/metadata`: a small clear header followed by the
+/// encrypted snapshot of the metadata. The header holds the key slots with their
+/// KDF parameters, the recipient entries and the metadata key wrapped under the
+/// master key, plus under the index key for encrypt-only hosts. File count, ids,
+/// hashes and settings are only readable once unlocked. The hash of the clear
+/// tables is authenticated with the snapshot, so only metadata that is unlocked is
+/// saved.
+///
+/// Changes are written by `save`, which replaces the file in one rename. A
+/// capsule with a plain `metadata.db` is read into memory and never written to.
+/// The first save after the master key opened it encrypts it, and once the
+/// encrypted file reads back the plain database is kept as `metadata.db.bak`. The
+/// metadata of a container capsule is saved into the container as well.
+pub(super) struct CapsuleMetadata {
+    folder: PathBuf,
+    connection: Connection,
+    /// The connection holds the plain `metadata.db` of an older capsule.
+    legacy: bool,
+    /// Key slots of the plain database were upgraded, which only happens in a capsule
+    /// that was opened since metadata is encrypted. See `unlock`.
+    upgraded_slots: bool,
+    /// See `needs_first_seal`.
+    first_seal: bool,
+    metadata_key: Option<Key>,
+    wrapped_key: Option<String>,
+    index_wrapped_key: Option<String>,
+    /// Schema version, hash of the clear tables and encrypted snapshot as read.
+    sealed: Option<(usize, String, Vec<u8>)>,
+    /// Digest of the plain state as last read or written. A save that would not
+    /// change it is skipped, so that opening a capsule does not rewrite it.
+    saved_state: RefCell<Option<String>>,
+    container: Option<CapsuleContainer>,
+}
+
+impl CapsuleMetadata {
+    /// True if the folder holds the metadata of a capsule, encrypted or not.
+    pub(super) fn exists(helix_folder: &Path) -> bool {
+        helix_folder.join(METADATA_FILE).exists()
+            || helix_folder.join(LEGACY_METADATA_FILE).exists()
+    }
+
+    /// Opens the metadata in `helix_folder`, or empty metadata for a new capsule.
+    /// Only the header is loaded until it is unlocked.
+    pub(super) fn open(helix_folder: &Path) -> Result<Self, HelixError> {
+        let folder = helix_folder.to_path_buf();
+        let path = folder.join(METADATA_FILE);
+        let legacy_path = folder.join(LEGACY_METADATA_FILE);
+        if !path.exists() && legacy_path.exists() {
+            let flags = OpenFlags::SQLITE_OPEN_READ_ONLY;
+            let connection =
+                Connection::open_with_flags(legacy_path, flags).map_err(|_| invalid_metadata())?;
+            let first_seal = HelixSchemaCreator::version(&connection) < MANIFEST_VERSION;
+            let connection = copy_to_memory(&connection)?;
+            HelixSchemaCreator::create(&connection);
+            let upgraded_slots = MasterKeyStore::from(&connection)
+                .get_all()
+                .iter()
+                .any(|slot| slot.kdf != LEGACY_SHA256);
+            let mut metadata = Self::from(folder, connection, true, None);
+            metadata.upgraded_slots = upgraded_slots;
+            metadata.first_seal = first_seal;
+            return Ok(metadata);
+        }
+        let connection = Connection::open_in_memory().unwrap();
+        if !path.exists() {
+            HelixSchemaCreator::create(&connection);
+            let mut metadata = Self::from(folder, connection, false, None);
+            metadata.first_seal = true;
+            return Ok(metadata);
+        }
+        let bytes = fs::read(&path).unwrap();
+        let (header, sealed) = parse(&bytes)?;
+        let schema = header["schema"].as_usize().ok_or_else(invalid_metadata)?;
+        let tables = &header["tables"];
+        if schema > HelixSchemaCreator::latest()
+            || tables
+                .entries()
+                .any(|(table, _)| !CLEAR_TABLES.contains(&table))
+        {
+            return Err(invalid_metadata());
+        }
+        HelixSchemaCreator::create_at(&connection, schema);
+        snapshot::load(&connection, tables)?;
+        let sealed = Some((schema, clear_hash(tables), sealed.to_vec()));
+        let mut metadata = Self::from(folder, connection, false, sealed);
+        metadata.wrapped_key = header["metadata_key"].as_str().map(String::from);
+        metadata.index_wrapped_key = header["index_metadata_key"].as_str().map(String::from);
+        metadata.saved_state.replace(Some(metadata.state()));
+        Ok(metadata)
+    }
+
+    fn from(
+        folder: PathBuf,
+        connection: Connection,
+        legacy: bool,
+        sealed: Option<(usize, String, Vec<u8>)>,
+    ) -> Self {
+        Self {
+            folder,
+            connection,
+            legacy,
+            upgraded_slots: false,
+            first_seal: false,
+            metadata_key: None,
+            wrapped_key: None,
+            index_wrapped_key: None,
+            sealed,
+            saved_state: RefCell::new(None),
+            container: None,
+        }
+    }
+
+    /// Container the metadata was unpacked from, it is updated with every save.
+    pub(super) fn with_container(mut self, container: CapsuleContainer) -> Self {
+        self.container = Some(container);
+        self
+    }
+
+    /// Makes the next save rewrite the container of a container capsule, so that
+    /// nothing replaced since stays in it.
+    pub(super) fn rewrite_container(&self) {
+        if let Some(container) = &self.container {
+            container.rewrite_on_save();
+        }
+    }
+
+    pub(super) fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// True for a new capsule and for a plain database older than the manifest,
+    /// their files table is sealed as it is. Encrypted metadata always was sealed,
+    /// its schema version is authenticated with it.
+    pub(super) fn needs_first_seal(&self) -> bool {
+        self.first_seal
+    }
+
+    /// Decrypts the metadata with the master key. New and plain metadata gets a
+    /// metadata key of its own, it is encrypted with the next save. Unlocked
+    /// metadata stays as it is.
+    ///
+    /// A plain database is only trusted without a capsule secret while its key slots
+    /// are as old as it is. Upgraded slots in a plain database without one were
+    /// copied from the header of encrypted metadata that went missing.
+    pub(super) fn unlock(&mut self, master_sub_keys: &MasterSubKeys) -> Result<(), HelixError> {
+        if self.metadata_key.is_some() {
+            return Ok(());
+        }
+        if self.legacy {
+            let capsule_secret = CapsuleSecretManager::from(&self.connection);
+            if capsule_secret.exists() && !capsule_secret.verify(master_sub_keys) {
+                return Err(key_mismatch());
+            }
+            if !capsule_secret.exists() && self.upgraded_slots {
+                return Err(HelixError::from(
+                    "InvalidHelixCapsule",
+                    "UnverifiedMetadata",
+                    "Plain metadata.db can not be verified, the encrypted metadata is missing",
+                ));
+            }
+            self.legacy = false;
+        }
+        if self.sealed.is_none() {
+            let metadata_key = Key::generate(master_sub_keys.metadata_wrap.suite());
+            self.wrapped_key =
+                Some(KeyEncryptor::from(&master_sub_keys.metadata_wrap).encrypt(&metadata_key));
+            self.metadata_key = Some(metadata_key);
+            return Ok(());
+        }
+        let wrapped_key = self.wrapped_key.as_ref().ok_or_else(invalid_metadata)?;
+        let metadata_key = KeyDecryptor::from(&master_sub_keys.metadata_wrap)
+            .decrypt(wrapped_key)
+            .map_err(|_| key_mismatch())?;
+        self.open_sealed(metadata_key)
+    }
+
+    /// Decrypts the metadata with the index key of an encrypt-only host. Plain
+    /// metadata stays plain, it can only be encrypted with the master key.
+    pub(super) fn unlock_with_index(
+        &mut self,
+        capsule_sub_keys: &CapsuleSubKeys,
+    ) -> Result<(), HelixError> {
+        if self.legacy || self.metadata_key.is_some() {
+            return Ok(());
+        }
+        let metadata_key = self
+            .index_wrapped_key
+            .as_ref()
+            .and_then(|wrapped_key| {
+                KeyDecryptor::from(&capsule_sub_keys.metadata_wrap)
+                    .decrypt(wrapped_key)
+                    .ok()
+            })
+            .ok_or(HelixError::from(
+                "BadInput",
+                "IndexKeyMismatch",
+                "Index key does not open the metadata of this capsule",
+            ))?;
+        self.open_sealed(metadata_key)
+    }
+
+    fn open_sealed(&mut self, metadata_key: Key) -> Result<(), HelixError> {
+        let (schema, read_hash, sealed) = self.sealed.as_ref().ok_or_else(invalid_metadata)?;
+        // Clear tables changed before the metadata was unlocked, e.g. an upgraded key
+        // slot, still have to be saved.
+        let clear_changed = clear_hash(&self.clear_tables()) != *read_hash;
+        let mut buffer = sealed.clone();
+        ByteDecryptorImpl::from(&metadata_key)
+            .with_associated_data(associated_data(*schema, read_hash))
+            .decrypt(&mut buffer)
+            .map_err(|_| invalid_metadata())?;
+        let content = String::from_utf8(buffer).map_err(|_| invalid_metadata())?;
+        let tables = json::parse(&content).map_err(|_| invalid_metadata())?;
+        if tables
+            .entries()
+            .any(|(table, _)| CLEAR_TABLES.contains(&table))
+        {
+            return Err(invalid_metadata());
+        }
+        snapshot::load(&self.connection, &tables)?;
+        HelixSchemaCreator::create(&self.connection);
+        if let Some(container) = &self.container {
+            let blocks_hash = SettingStore::from(&self.connection).get(CONTAINER_BLOCKS_SETTING);
+            if blocks_hash != Some(container.blocks_hash()) {
+                return Err(HelixError::from(
+                    "InvalidHelixCapsule",
+                    "ContainerMismatch",
+                    "Capsule container does not hold the blocks its metadata records",
+                ));
+            }
+        }
+        self.metadata_key = Some(metadata_key);
+        self.saved_state.replace((!clear_changed).then(|| self.state()));
+        Ok(())
+    }
+
+    /// Wraps the metadata key under the index key as well, so that encrypt-only
+    /// hosts can open the metadata.
+    pub(super) fn wrap_for_index(&mut self, capsule_sub_keys: &CapsuleSubKeys) {
+        let wrapped = self.index_wrapped_key.as_ref().and_then(|wrapped_key| {
+            KeyDecryptor::from(&capsule_sub_keys.metadata_wrap)
+                .decrypt(wrapped_key)
+                .ok()
+        });
+        if let (Some(wrapped), Some(metadata_key)) = (wrapped, &self.metadata_key) {
+            if wrapped.bytes() == metadata_key.bytes() {
+                return;
+            }
+        }
+        if let Some(metadata_key) = &self.metadata_key {
+            self.index_wrapped_key =
+                Some(KeyEncryptor::from(&capsule_sub_keys.metadata_wrap).encrypt(metadata_key));
+        }
+    }
+
+    /// Wraps the metadata key under a new master key, e.g. after rotation.
+    pub(super) fn rewrap(&mut self, master_sub_keys: &MasterSubKeys) {
+        if let Some(metadata_key) = &self.metadata_key {
+            self.wrapped_key =
+                Some(KeyEncryptor::from(&master_sub_keys.metadata_wrap).encrypt(metadata_key));
+        }
+    }
+
+    /// Writes the metadata if it changed. The container of a container capsule is
+    /// brought up to date with its blocks either way, the metadata records which
+    /// blocks it holds.
+    pub(super) fn save(&self) -> Result<(), HelixError> {
+        if self.legacy {
+            return Ok(());
+        }
+        if let (Some(container), Some(_)) = (&self.container, &self.metadata_key) {
+            SettingStore::from(&self.connection)
+                .set(CONTAINER_BLOCKS_SETTING, &container.blocks_hash());
+        }
+        let state = self.state();
+        if self.saved_state.borrow().as_ref() != Some(&state) {
+            self.write()?;
+            self.saved_state.replace(Some(state));
+        }
+        if let Some(container) = &self.container {
+            container.save();
+        }
+        Ok(())
+    }
+
+    /// Locked metadata is not written, its snapshot could not authenticate changed
+    /// clear tables.
+    fn write(&self) -> Result<(), HelixError> {
+        let Some(metadata_key) = &self.metadata_key else {
+            return Ok(());
+        };
+        let tables: Vec<String> = snapshot::table_names(&self.connection)
+            .into_iter()
+            .filter(|table| !CLEAR_TABLES.contains(&table.as_str()))
+            .collect();
+        let clear_tables = self.clear_tables();
+        let schema = HelixSchemaCreator::version(&self.connection);
+        let content = snapshot::dump(&self.connection, &tables).dump();
+        let digest = hash_string(&content);
+        let mut sealed = content.into_bytes();
+        ByteEncryptorImpl::from(metadata_key)
+            .with_associated_data(associated_data(schema, &clear_hash(&clear_tables)))
+            .encrypt(&mut sealed);
+        let header = object! {
+            schema: schema,
+            metadata_key: self.wrapped_key.clone(),
+            index_metadata_key: self.index_wrapped_key.clone(),
+            tables: clear_tables,
+        };
+        let header = header.dump().into_bytes();
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 5 + header.len() + sealed.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&sealed);
+        let path = self.folder.join(METADATA_FILE);
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, bytes).map_err(write_failed)?;
+        fs::rename(&temporary_path, &path).map_err(write_failed)?;
+        let legacy_path = self.folder.join(LEGACY_METADATA_FILE);
+        if legacy_path.exists() {
+            verify_written(&path, metadata_key, &digest)?;
+            fs::rename(&legacy_path, self.folder.join(LEGACY_BACKUP_FILE))
+                .map_err(write_failed)?;
+        }
+        Ok(())
+    }
+
+    fn clear_tables(&self) -> JsonValue {
+        let clear_tables: Vec<String> = snapshot::table_names(&self.connection)
+            .into_iter()
+            .filter(|table| CLEAR_TABLES.contains(&table.as_str()))
+            .collect();
+        snapshot::dump(&self.connection, &clear_tables)
+    }
+
+    fn state(&self) -> String {
+        let tables = snapshot::table_names(&self.connection);
+        let state = object! {
+            schema: HelixSchemaCreator::version(&self.connection),
+            metadata_key: self.wrapped_key.clone(),
+            index_metadata_key: self.index_wrapped_key.clone(),
+            tables: snapshot::dump(&self.connection, &tables),
+        };
+        hash_string(&state.dump())
+    }
+}
+
+/// Header and encrypted snapshot of a metadata file.
+fn parse(bytes: &[u8]) -> Result<(JsonValue, &[u8]), HelixError> {
+    let prefix = MAGIC.len() + 5;
+    if bytes.len() < prefix
+        || &bytes[..MAGIC.len()] != MAGIC
+        || bytes[MAGIC.len()] != FORMAT_VERSION
+    {
+        return Err(invalid_metadata());
+    }
+    let header_length =
+        u32::from_be_bytes(bytes[MAGIC.len() + 1..prefix].try_into().unwrap()) as usize;
+    let header_end = prefix
+        .checked_add(header_length)
+        .filter(|end| *end <= bytes.len());
+    let Some(header_end) = header_end else {
+        return Err(invalid_metadata());
+    };
+    let header = std::str::from_utf8(&bytes[prefix..header_end]).map_err(|_| invalid_metadata())?;
+    let header = json::parse(header).map_err(|_| invalid_metadata())?;
+    Ok((header, &bytes[header_end..]))
+}
+
+/// The schema version is authenticated with the snapshot, it decides how the
+/// snapshot is loaded, and so is the hash of the clear tables.
+fn associated_data(schema: usize, clear_hash: &str) -> Vec<u8> {
+    [METADATA_LABEL, &(schema as u64).to_be_bytes(), clear_hash.as_bytes()].concat()
+}
+
+/// Hash of the clear tables as they are stored in the header. Key slots and
+/// recipients can not be changed without the metadata key.
+fn clear_hash(clear_tables: &JsonValue) -> String {
+    hash_string(&clear_tables.dump())
+}
+
+/// Copies a database into memory at its own schema version, so that it can be
+/// migrated without writing to it.
+fn copy_to_memory(connection: &Connection) -> Result<Connection, HelixError> {
+    let copy = Connection::open_in_memory().unwrap();
+    HelixSchemaCreator::create_at(&copy, HelixSchemaCreator::version(connection));
+    let tables = snapshot::table_names(connection);
+    snapshot::load(&copy, &snapshot::dump(connection, &tables))?;
+    Ok(copy)
+}
+
+/// Reads written metadata back and checks that it decrypts to the snapshot with
+/// the given digest. The plain database it replaces is only set aside after that.
+fn verify_written(path: &Path, metadata_key: &Key, digest: &str) -> Result<(), HelixError> {
+    let bytes = fs::read(path).map_err(write_failed)?;
+    let (header, sealed) = parse(&bytes)?;
+    let schema = header["schema"].as_usize().ok_or_else(invalid_metadata)?;
+    let mut buffer = sealed.to_vec();
+    ByteDecryptorImpl::from(metadata_key)
+        .with_associated_data(associated_data(schema, &clear_hash(&header["tables"])))
+        .decrypt(&mut buffer)
+        .map_err(|_| invalid_metadata())?;
+    let content = String::from_utf8(buffer).map_err(|_| invalid_metadata())?;
+    if hash_string(&content) != digest {
+        return Err(invalid_metadata());
+    }
+    Ok(())
+}
+
+fn write_failed(error: io::Error) -> HelixError {
+    HelixError::from(
+        "InvalidHelixCapsule",
+        "MetadataNotWritten",
+        &format!("Capsule metadata could not be written: {}", error),
+    )
+}
+
+fn invalid_metadata() -> HelixError {
+    HelixError::from(
+        "InvalidHelixCapsule",
+        "InvalidMetadata",
+        "Capsule metadata is damaged or of an unknown format",
+    )
+}
+
+fn key_mismatch() -> HelixError {
+    HelixError::from(
+        "BadInput",
+        "MasterKeyMismatch",
+        "Master key does not open the metadata of this capsule",
+    )
+}
+
+#[test]
+fn metadata_test() {
+    use crate::storage::{FileStore, SettingStore};
+
+    let folder = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    fs::create_dir_all(&folder).unwrap();
+    let master_sub_keys = MasterSubKeys::derive(&Key::new());
+    let mut metadata = CapsuleMetadata::open(&folder).unwrap();
+    metadata.unlock(&master_sub_keys).unwrap();
+    SettingStore::from(metadata.connection()).set("padding", "padme");
+    metadata.save().unwrap();
+    let bytes = fs::read(folder.join(METADATA_FILE)).unwrap();
+    assert!(!bytes.windows(5).any(|window| window == b"padme"));
+
+    let mut metadata = CapsuleMetadata::open(&folder).unwrap();
+    assert!(metadata
+        .unlock(&MasterSubKeys::derive(&Key::new()))
+        .is_err());
+    metadata.unlock(&master_sub_keys).unwrap();
+    assert_eq!(
+        SettingStore::from(metadata.connection())
+            .get("padding")
+            .unwrap(),
+        "padme"
+    );
+    assert!(FileStore::from(metadata.connection()).get_all().is_empty());
+
+    // A locked save keeps the encrypted part as it is.
+    CapsuleMetadata::open(&folder).unwrap().save().unwrap();
+    assert_eq!(fs::read(folder.join(METADATA_FILE)).unwrap(), bytes);
+
+    // A recipient added to the clear header does not match the snapshot.
+    let (mut header, sealed) = parse(&bytes).unwrap();
+    header["tables"]["recipients"]["rows"]
+        .push(json::array!["eve", "00", "00"])
+        .unwrap();
+    let header = header.dump().into_bytes();
+    let tampered = [
+        MAGIC,
+        &[FORMAT_VERSION],
+        &(header.len() as u32).to_be_bytes(),
+        &header,
+        sealed,
+    ]
+    .concat();
+    fs::write(folder.join(METADATA_FILE), tampered).unwrap();
+    let mut metadata = CapsuleMetadata::open(&folder).unwrap();
+    let error = metadata.unlock(&master_sub_keys).unwrap_err();
+    assert_eq!(error.detailed_code, "InvalidMetadata");
+    fs::remove_dir_all(folder).unwrap();
+}
+
+#[test]
+fn legacy_metadata_test() {
+    use super::master_key::MasterKeyManager;
+    use crate::crypto::suite::CipherSuite;
+
+    let folder = std::env::temp_dir().join(format!("helix-{}", crate::util::uuid::generate()));
+    fs::create_dir_all(&folder).unwrap();
+    let connection = Connection::open(folder.join(LEGACY_METADATA_FILE)).unwrap();
+    HelixSchemaCreator::create(&connection);
+    let (master_key, _) =
+        MasterKeyManager::from(&connection).generate("passphrase", CipherSuite::default());
+    let master_sub_keys = MasterSubKeys::derive(&master_key);
+
+    // Upgraded slots in a plain database without a capsule secret were copied there.
+    let error = CapsuleMetadata::open(&folder)
+        .unwrap()
+        .unlock(&master_sub_keys)
+        .unwrap_err();
+    assert_eq!(error.detailed_code, "UnverifiedMetadata");
+    CapsuleSecretManager::from(&connection)
+        .get_or_create(&master_sub_keys)
+        .unwrap();
+    drop(connection);
+    let mut metadata = CapsuleMetadata::open(&folder).unwrap();
+    metadata.unlock(&master_sub_keys).unwrap();
+    // Written since capsules have a manifest, a missing one was removed.
+    assert!(!metadata.needs_first_seal());
+    assert!(CapsuleMetadata::open(&folder.join("new")).unwrap().needs_first_seal());
+
+    let older = folder.join("older");
+    fs::create_dir_all(&older).unwrap();
+    let connection = Connection::open(older.join(LEGACY_METADATA_FILE)).unwrap();
+    HelixSchemaCreator::create_at(&connection, MANIFEST_VERSION - 1);
+    drop(connection);
+    assert!(CapsuleMetadata::open(&older).unwrap().needs_first_seal());
+    fs::remove_dir_all(folder).unwrap();
+}
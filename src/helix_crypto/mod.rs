@@ -4,7 +4,16 @@ use self::{
     core::{HelixDecryptor, HelixEncryptor},
 };
 
+mod capsule_secret;
+mod container;
 pub mod core;
+mod dedup;
 mod files;
 pub mod folder_walker;
-mod master_key;
\ No newline at end of file
+mod manifest;
+mod master_key;
+mod metadata;
+mod packs;
+pub mod recipients;
+pub mod rotation;
+pub mod slots;
\ No newline at end of file
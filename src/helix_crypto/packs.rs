@@ -0,0 +1,369 @@
+//! Pack files for small blocks.
+//!
+//! One block per file leaks the file count and size distribution of a capsule, and
+//! makes capsules of many small files slow to copy. Small blocks are therefore packed
+//! into `.helix/packs`, concatenated and encrypted once more under a key derived from
+//! the capsule secret and the random pack id. The pack index in the metadata DB
+//! records where each block lives. A packed block is read from a private decrypted
+//! copy of its pack, mounted at its path in the block folder, so reading a capsule
+//! never writes to it.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs::{self, create_dir_all},
+    io::{self, Read, Write},
+    iter::from_fn,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::Connection;
+
+use crate::{
+    crypto::{
+        binding::{associated_data, Field},
+        kdf::CapsuleSubKeys,
+        padding::Padding,
+        suite::CipherSuite,
+    },
+    errors::HelixError,
+    filecrypto::chacha::{decryptors::CCFileDecryptor, encryptors::CCFileEncryptor},
+    fileio::{header::BlockKind, mounts, readers::ChunkReader},
+    storage::{PackedBlock, PackedBlockStore},
+    util::{hex::encode, uuid::generate},
+};
+
+use super::{dedup::QuietObserver, files::is_block_name};
+
+/// Chunks of a pack are of a fixed size, so they do not reveal block boundaries.
+const PACK_CHUNK_SIZE: u32 = 1024 * 1024;
+/// Blocks of up to this fraction of the target size count as small.
+const SMALL_BLOCK_FRACTION: u64 = 4;
+
+pub(super) struct PackStore<'a> {
+    pack_folder: PathBuf,
+    block_folder: PathBuf,
+    capsule_sub_keys: &'a CapsuleSubKeys,
+    packed_blocks: PackedBlockStore<'a>,
+    /// Packs unpacked in this run, each is only decrypted once.
+    unpacked: RefCell<HashSet<String>>,
+    /// Decrypted copies of the unpacked packs, removed on drop.
+    decrypted_packs: RefCell<Vec<PathBuf>>,
+}
+
+impl<'a> PackStore<'a> {
+    pub(super) fn from(
+        block_folder: &Path,
+        capsule_sub_keys: &'a CapsuleSubKeys,
+        connection: &'a Connection,
+    ) -> Self {
+        Self {
+            pack_folder: block_folder.with_file_name("packs"),
+            block_folder: block_folder.to_path_buf(),
+            capsule_sub_keys,
+            packed_blocks: PackedBlockStore::from(connection),
+            unpacked: RefCell::new(HashSet::new()),
+            decrypted_packs: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// True if the block is in a pack that exists. Packs are authenticated as a
+    /// whole when they are read, so a packed block is not hashed to spot changes.
+    pub(super) fn is_packed(&self, block_name: &str) -> bool {
+        self.packed_blocks
+            .get(block_name)
+            .is_some_and(|packed_block| mounts::exists(&self.pack_path(&packed_block.pack_id)))
+    }
+
+    /// Makes a packed block readable at its path in the block folder. Blocks that
+    /// are not packed, or already there, are left alone.
+    pub(super) fn unpack(&self, block_name: &str) -> Result<(), HelixError> {
+        let Some(packed_block) = self.packed_blocks.get(block_name) else {
+            return Ok(());
+        };
+        if mounts::exists(&self.block_path(block_name))
+            || !self
+                .unpacked
+                .borrow_mut()
+                .insert(packed_block.pack_id.clone())
+        {
+            return Ok(());
+        }
+        self.unpack_pack(&packed_block.pack_id)
+    }
+
+    /// Decrypts a pack into a temporary file of its own and mounts every indexed
+    /// block of it at its path in the block folder, see `mounts`. Bytes of blocks
+    /// that were released since the pack was written are not mounted.
+    fn unpack_pack(&self, pack_id: &str) -> Result<(), HelixError> {
+        let pack_path = self.pack_path(pack_id);
+        let pack_path = pack_path.to_str().unwrap();
+        if !mounts::exists(Path::new(pack_path)) {
+            return Err(HelixError::from(
+                "MalformedBlock",
+                "PackNotFound",
+                &format!("Pack {} is missing from the capsule", pack_id),
+            ));
+        }
+        let suite = ChunkReader::from(pack_path)?.header().suite;
+        let pack_key = self.capsule_sub_keys.pack_key(pack_id, suite);
+        let pack_binding = associated_data(Field::Pack, pack_id);
+        let chunks = CCFileDecryptor::chunks(&pack_key, pack_path, &pack_binding)?;
+        let decrypted_path = std::env::temp_dir().join(format!("helix-{}", generate()));
+        let mut decrypted = fs::File::create(&decrypted_path).unwrap();
+        self.decrypted_packs.borrow_mut().push(decrypted_path.clone());
+        let mut length = 0;
+        for buffer in chunks {
+            let buffer = buffer?;
+            decrypted.write_all(&buffer).unwrap();
+            length += buffer.len() as u64;
+        }
+        let members = self.packed_blocks.get_pack(pack_id);
+        if members
+            .iter()
+            .any(|packed_block| packed_block.offset + packed_block.length > length)
+        {
+            return Err(HelixError::from(
+                "MalformedBlock",
+                "TruncatedPack",
+                &format!("Pack {} ends before its last block", pack_id),
+            ));
+        }
+        for packed_block in members {
+            let block_path = self.block_path(&packed_block.block_name);
+            if !mounts::exists(&block_path) {
+                mounts::mount(
+                    &block_path,
+                    &decrypted_path,
+                    packed_block.offset,
+                    packed_block.length,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Packs the small loose blocks of the block folder into packs of about
+    /// `target_size` bytes, leaving out blocks that are about to be removed. A target
+    /// size of 0 packs nothing. Returns how many blocks were packed.
+    pub(super) fn pack(
+        &self,
+        target_size: u64,
+        suite: CipherSuite,
+        padding: Padding,
+        obsolete_blocks: &[String],
+    ) -> Result<usize, HelixError> {
+        if target_size == 0 {
+            return Ok(0);
+        }
+        let obsolete: HashSet<&str> = obsolete_blocks
+            .iter()
+            .filter_map(|block_path| Path::new(block_path).file_name()?.to_str())
+            .collect();
+        let mut small_blocks = self.loose_blocks(target_size / SMALL_BLOCK_FRACTION);
+        small_blocks.retain(|(block_name, _)| !obsolete.contains(block_name.as_str()));
+        self.write_packs(small_blocks, target_size, suite, padding)
+    }
+
+    /// Packs the given loose blocks in order, starting a new pack whenever one
+    /// reaches the target size.
+    fn write_packs(
+        &self,
+        blocks: Vec<(String, u64)>,
+        target_size: u64,
+        suite: CipherSuite,
+        padding: Padding,
+    ) -> Result<usize, HelixError> {
+        let mut packed = 0;
+        let mut group = Vec::new();
+        let mut group_size = 0;
+        for (block_name, length) in blocks {
+            group.push((block_name, length));
+            group_size += length;
+            if group_size >= target_size {
+                packed += self.write_pack(&group, suite, padding)?;
+                group.clear();
+                group_size = 0;
+            }
+        }
+        if !group.is_empty() {
+            packed += self.write_pack(&group, suite, padding)?;
+        }
+        Ok(packed)
+    }
+
+    /// Blocks in the block folder that are not packed and not larger than `limit`,
+    /// with their length.
+    fn loose_blocks(&self, limit: u64) -> Vec<(String, u64)> {
+        mounts::list(&self.block_folder)
+            .into_iter()
+            .filter(|(block_name, length)| {
+                is_block_name(block_name)
+                    && *length <= limit
+                    && self.packed_blocks.get(block_name).is_none()
+            })
+            .collect()
+    }
+
+    /// Packs are written under a temporary name first, and indexed once complete.
+    /// The loose blocks stay until the index is saved.
+    fn write_pack(
+        &self,
+        group: &[(String, u64)],
+        suite: CipherSuite,
+        padding: Padding,
+    ) -> Result<usize, HelixError> {
+        create_dir_all(&self.pack_folder).unwrap();
+        let pack_id = encode(&rand::random::<[u8; 16]>());
+        let pack_path = self.pack_path(&pack_id);
+        let temporary_path = format!("{}.tmp", pack_path.to_str().unwrap());
+        let mut reader: Box<dyn Read> = Box::new(io::empty());
+        for (block_name, _) in group {
+            let block_path = self.block_path(block_name);
+            reader = Box::new(reader.chain(mounts::open(block_path.to_str().unwrap()).unwrap()));
+        }
+        let chunks = from_fn(|| {
+            let mut chunk = Vec::with_capacity(PACK_CHUNK_SIZE as usize);
+            let read = (&mut reader)
+                .take(PACK_CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)
+                .unwrap();
+            (read > 0).then_some(Ok(chunk))
+        });
+        let pack_key = self.capsule_sub_keys.pack_key(&pack_id, suite);
+        let mut observer = QuietObserver;
+        let mut encryptor = CCFileEncryptor::from(&pack_key, PACK_CHUNK_SIZE, &mut observer)
+            .with_padding(padding)
+            .with_kind(BlockKind::Pack)
+            .with_associated_data(associated_data(Field::Pack, &pack_id));
+        if let Err(error) = encryptor.encrypt_chunks(chunks, &temporary_path) {
+            let _ = fs::remove_file(&temporary_path);
+            return Err(error);
+        }
+        let total: u64 = group.iter().map(|(_, length)| length).sum();
+        if encryptor.stats().plain_bytes != total {
+            let _ = fs::remove_file(&temporary_path);
+            return Err(HelixError::from(
+                "MalformedBlock",
+                "BlockChanged",
+                "A block changed while it was being packed",
+            ));
+        }
+        fs::rename(&temporary_path, &pack_path).unwrap();
+        let mut offset = 0;
+        for (block_name, length) in group {
+            self.packed_blocks.insert(&PackedBlock {
+                block_name: block_name.clone(),
+                pack_id: pack_id.clone(),
+                offset,
+                length: *length,
+            });
+            offset += length;
+        }
+        Ok(group.len())
+    }
+
+    /// Drops replaced blocks from the pack index. Their bytes stay in the pack until
+    /// it is compacted, or removed once nothing in it is indexed.
+    pub(super) fn release(&self, obsolete_blocks: &[String]) {
+        for block_path in obsolete_blocks {
+            if let Some(block_name) = Path::new(block_path).file_name() {
+                self.packed_blocks.delete(block_name.to_str().unwrap());
+            }
+        }
+    }
+
+    /// Rewrites the packs that are less than half full together, so released blocks
+    /// stop taking space. A single pack is only rewritten if most of it is released
+    /// blocks. A target size of 0 moves every packed block back out of its pack.
+    /// Returns how many blocks were moved, the caller saves the index and then
+    /// collects garbage.
+    pub(super) fn compact(
+        &self,
+        target_size: u64,
+        suite: CipherSuite,
+        padding: Padding,
+    ) -> Result<usize, HelixError> {
+        let mut live_bytes: HashMap<String, u64> = HashMap::new();
+        for packed_block in self.packed_blocks.get_all() {
+            *live_bytes.entry(packed_block.pack_id).or_default() += packed_block.length;
+        }
+        let mut sparse_packs: Vec<(String, u64)> = live_bytes
+            .into_iter()
+            .filter(|(_, live)| target_size == 0 || *live < target_size / 2)
+            .collect();
+        if let [(pack_id, live)] = &sparse_packs[..] {
+            let pack_size = mounts::len(&self.pack_path(pack_id)).unwrap_or(0);
+            if target_size > 0 && live * 3 >= pack_size {
+                sparse_packs.clear();
+            }
+        }
+        let mut moved = Vec::new();
+        for (pack_id, _) in sparse_packs {
+            self.unpack_pack(&pack_id)?;
+            for packed_block in self.packed_blocks.get_pack(&pack_id) {
+                self.packed_blocks.delete(&packed_block.block_name);
+                moved.push((packed_block.block_name, packed_block.length));
+            }
+        }
+        moved.sort();
+        let moved_count = moved.len();
+        if target_size > 0 {
+            self.write_packs(moved, target_size, suite, padding)?;
+        } else {
+            for (block_name, _) in &moved {
+                self.write_loose(block_name);
+            }
+        }
+        Ok(moved_count)
+    }
+
+    /// Writes an unpacked block to the block folder as a file of its own.
+    fn write_loose(&self, block_name: &str) {
+        let block_path = self.block_path(block_name);
+        let temporary_path = self.block_folder.join(format!("{}.tmp", block_name));
+        let mut block = mounts::open(block_path.to_str().unwrap()).unwrap();
+        io::copy(&mut block, &mut fs::File::create(&temporary_path).unwrap()).unwrap();
+        fs::rename(temporary_path, block_path).unwrap();
+    }
+
+    /// Removes loose copies of packed blocks, e.g. the ones that were just packed.
+    pub(super) fn remove_unpacked(&self) {
+        for (block_name, _) in mounts::list(&self.block_folder) {
+            if self.packed_blocks.get(&block_name).is_some() {
+                mounts::remove(&self.block_path(&block_name));
+            }
+        }
+    }
+
+    /// Removes loose copies of packed blocks and packs that nothing in the index
+    /// points at, including packs of an interrupted run. Returns how many packs went.
+    pub(super) fn collect_garbage(&self) -> usize {
+        self.remove_unpacked();
+        let mut removed = 0;
+        for (pack_id, _) in mounts::list(&self.pack_folder) {
+            if !self.packed_blocks.contains_pack(&pack_id) {
+                mounts::remove(&self.pack_path(&pack_id));
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn pack_path(&self, pack_id: &str) -> PathBuf {
+        self.pack_folder.join(pack_id)
+    }
+
+    fn block_path(&self, block_name: &str) -> PathBuf {
+        self.block_folder.join(block_name)
+    }
+}
+
+impl Drop for PackStore<'_> {
+    fn drop(&mut self) {
+        for decrypted_path in self.decrypted_packs.borrow().iter() {
+            mounts::unmount_file(decrypted_path);
+            let _ = fs::remove_file(decrypted_path);
+        }
+    }
+}
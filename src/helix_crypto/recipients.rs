@@ -0,0 +1,248 @@
+use rusqlite::Connection;
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{
+        chacha::keys::{Key, KeyDecryptor, KeyEncryptor},
+        kdf::{CapsuleSubKeys, MasterSubKeys},
+        recipient::{unwrap_with_identity, wrap_to_recipients, Identity, Recipient},
+        suite::CipherSuite,
+    },
+    errors::HelixError,
+    storage::{
+        RecipientRecord, RecipientStore, SettingStore, CAPSULE_IDENTITY_SETTING,
+        CAPSULE_RECIPIENT_SETTING, INDEX_KEY_CHECK_SETTING,
+    },
+    util::hex::encode,
+};
+
+use super::{
+    capsule_secret::CapsuleSecretManager, core::open_capsule, master_key::MasterKeyManager,
+    metadata::CapsuleMetadata,
+};
+
+const INDEX_KEY_CHECK: &str = "helix/index-key-check";
+
+/// The capsule's own X25519 identity. Its private key is wrapped under a master
+/// subkey, so whoever unlocks the master key can open recipient-wrapped file keys.
+pub(super) struct CapsuleIdentityManager<'a> {
+    connection: &'a Connection,
+}
+
+impl<'a> CapsuleIdentityManager<'a> {
+    pub(super) fn from(connection: &'a Connection) -> Self {
+        Self { connection }
+    }
+
+    pub(super) fn get(&self, master_sub_keys: &MasterSubKeys) -> Result<Option<Identity>, HelixError> {
+        match SettingStore::from(self.connection).get(CAPSULE_IDENTITY_SETTING) {
+            Some(wrapped) => {
+                let secret = KeyDecryptor::from(&master_sub_keys.secret_wrap).decrypt(&wrapped)?;
+                Ok(Some(Identity::from_bytes(secret.bytes().try_into().unwrap())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(super) fn get_or_create(&self, master_sub_keys: &MasterSubKeys) -> Result<Identity, HelixError> {
+        if let Some(identity) = self.get(master_sub_keys)? {
+            return Ok(identity);
+        }
+        let identity = Identity::generate();
+        self.store(&identity, master_sub_keys);
+        Ok(identity)
+    }
+
+    pub(super) fn rewrap(
+        &self,
+        old_master_sub_keys: &MasterSubKeys,
+        new_master_sub_keys: &MasterSubKeys,
+    ) -> Result<(), HelixError> {
+        if let Some(identity) = self.get(old_master_sub_keys)? {
+            self.store(&identity, new_master_sub_keys);
+        }
+        Ok(())
+    }
+
+    fn store(&self, identity: &Identity, master_sub_keys: &MasterSubKeys) {
+        let secret = Key::from_parts(master_sub_keys.secret_wrap.suite(), identity.bytes());
+        let wrapped = KeyEncryptor::from(&master_sub_keys.secret_wrap).encrypt(&secret);
+        let setting_store = SettingStore::from(self.connection);
+        setting_store.set(CAPSULE_IDENTITY_SETTING, &wrapped);
+        setting_store.set(CAPSULE_RECIPIENT_SETTING, &identity.recipient().to_hex());
+    }
+}
+
+/// Everything an encrypt-only run wraps new file keys to: the capsule itself and
+/// every registered recipient. The recipients table is in the clear header, which
+/// was authenticated when the index key unlocked the metadata.
+pub(super) fn get_recipients(connection: &Connection) -> Result<Vec<Recipient>, HelixError> {
+    let capsule_recipient = SettingStore::from(connection)
+        .get(CAPSULE_RECIPIENT_SETTING)
+        .ok_or(HelixError::from(
+            "InvalidHelixCapsule",
+            "NoRecipients",
+            "Capsule has no recipients, add one before encrypting without a passphrase",
+        ))?;
+    let mut recipients = vec![Recipient::from_hex(&capsule_recipient)?];
+    for record in RecipientStore::from(connection).get_all() {
+        recipients.push(Recipient::from_hex(&record.public_key)?);
+    }
+    Ok(recipients)
+}
+
+/// Unlocks the master key with the identity of a registered recipient.
+pub(super) fn unlock_with_identity(connection: &Connection, identity: &Identity) -> Result<Key, HelixError> {
+    let public_key = identity.recipient().to_hex();
+    let record = RecipientStore::from(connection)
+        .get_all()
+        .into_iter()
+        .find(|record| record.public_key == public_key)
+        .ok_or(HelixError::from(
+            "BadInput",
+            "NotARecipient",
+            "Identity is not a recipient of this capsule",
+        ))?;
+    unwrap_with_identity(&record.master_key, identity, None)?.ok_or(HelixError::from(
+        "MalformedData",
+        "NotARecipient",
+        "Master key is not wrapped to this identity",
+    ))
+}
+
+/// Wraps the master key to every registered recipient again, e.g. after rotation.
+pub(super) fn rewrap_recipients(connection: &Connection, master_key: &Key) -> Result<(), HelixError> {
+    let recipient_store = RecipientStore::from(connection);
+    for mut record in recipient_store.get_all() {
+        let recipient = Recipient::from_hex(&record.public_key)?;
+        record.master_key = wrap_to_recipients(master_key, &[recipient], None, None);
+        recipient_store.update(record);
+    }
+    Ok(())
+}
+
+/// Reads an index key exported with `HelixRecipients::index_key`, checks that it
+/// belongs to the capsule and unlocks the metadata with it.
+pub(super) fn get_index_sub_keys(
+    metadata: &mut CapsuleMetadata,
+    index_key: &str,
+) -> Result<CapsuleSubKeys, HelixError> {
+    let mismatch = HelixError::from(
+        "BadInput",
+        "IndexKeyMismatch",
+        "Index key does not belong to this capsule",
+    );
+    let bytes = hex::decode(index_key.trim())
+        .ok()
+        .map(Zeroizing::new)
+        .filter(|bytes| bytes.len() == 32);
+    let Some(bytes) = bytes else {
+        return Err(mismatch);
+    };
+    let capsule_sub_keys = CapsuleSubKeys::derive(&Key::from_parts(CipherSuite::default(), &bytes));
+    metadata.unlock_with_index(&capsule_sub_keys)?;
+    match SettingStore::from(metadata.connection()).get(INDEX_KEY_CHECK_SETTING) {
+        Some(check) if check == capsule_sub_keys.file_id(INDEX_KEY_CHECK) => Ok(capsule_sub_keys),
+        _ => Err(mismatch),
+    }
+}
+
+/// Public key recipients of a capsule. A recipient's identity unlocks the capsule
+/// like a passphrase, and an encrypt-only host can add files for all recipients
+/// without holding any secret that decrypts.
+pub struct HelixRecipients {
+    metadata: CapsuleMetadata,
+    keyfile: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl HelixRecipients {
+    pub fn open(capsule: &str) -> Result<Self, HelixError> {
+        let (metadata, _) = open_capsule(capsule)?;
+        Ok(Self {
+            metadata,
+            keyfile: None,
+        })
+    }
+
+    /// Keyfile of the slot the passphrase opens, see `HelixKeySlots::with_keyfile`.
+    pub fn with_keyfile(mut self, keyfile: Option<&[u8]>) -> Self {
+        self.keyfile = keyfile.map(|keyfile| Zeroizing::new(keyfile.to_vec()));
+        self
+    }
+
+    /// Label and public key of every recipient.
+    pub fn list(&self) -> Vec<(String, String)> {
+        RecipientStore::from(self.metadata.connection())
+            .get_all()
+            .into_iter()
+            .map(|record| (record.label, record.public_key))
+            .collect()
+    }
+
+    pub fn add(&mut self, passphrase: &str, label: &str, public_key: &str) -> Result<(), HelixError> {
+        let recipient = Recipient::from_hex(public_key)?;
+        if self.list().iter().any(|(existing, _)| existing == label) {
+            return Err(HelixError::from(
+                "BadInput",
+                "DuplicateRecipient",
+                &format!("A recipient labelled {} already exists", label),
+            ));
+        }
+        let master_key = self.unlock(passphrase)?;
+        let master_sub_keys = MasterSubKeys::derive(&master_key);
+        let connection = self.metadata.connection();
+        CapsuleIdentityManager::from(connection).get_or_create(&master_sub_keys)?;
+        RecipientStore::from(connection).insert(RecipientRecord {
+            label: String::from(label),
+            public_key: recipient.to_hex(),
+            master_key: wrap_to_recipients(&master_key, &[recipient], None, None),
+        });
+        self.metadata.save()
+    }
+
+    pub fn remove(&mut self, passphrase: &str, label: &str) -> Result<(), HelixError> {
+        self.unlock(passphrase)?;
+        if !RecipientStore::from(self.metadata.connection()).delete(label) {
+            return Err(HelixError::from(
+                "BadInput",
+                "UnknownRecipient",
+                &format!("No recipient labelled {}", label),
+            ));
+        }
+        self.metadata.save()
+    }
+
+    /// Key that lets an encrypt-only host open the metadata, compute file ids and
+    /// change detection hashes. It can not decrypt any file.
+    pub fn index_key(&mut self, passphrase: &str) -> Result<Zeroizing<String>, HelixError> {
+        let master_key = self.unlock(passphrase)?;
+        let master_sub_keys = MasterSubKeys::derive(&master_key);
+        let connection = self.metadata.connection();
+        CapsuleIdentityManager::from(connection).get_or_create(&master_sub_keys)?;
+        let capsule_secret = CapsuleSecretManager::from(connection).get_or_create(&master_sub_keys)?;
+        let capsule_sub_keys = CapsuleSubKeys::derive(&capsule_secret);
+        SettingStore::from(connection)
+            .set(INDEX_KEY_CHECK_SETTING, &capsule_sub_keys.file_id(INDEX_KEY_CHECK));
+        self.metadata.wrap_for_index(&capsule_sub_keys);
+        self.metadata.save()?;
+        Ok(Zeroizing::new(encode(capsule_secret.bytes())))
+    }
+
+    /// Unlocks the master key with a passphrase and the metadata with it.
+    fn unlock(&mut self, passphrase: &str) -> Result<Key, HelixError> {
+        let master_key_manager = MasterKeyManager::from(self.metadata.connection())
+            .with_keyfile(self.keyfile.as_deref().map(Vec::as_slice))?;
+        let master_key = match master_key_manager.get(passphrase)? {
+            Some(key) => key,
+            None => {
+                return Err(HelixError::from(
+                    "InvalidHelixCapsule",
+                    "NoMasterKey",
+                    "Master Key not found in db",
+                ))
+            }
+        };
+        self.metadata.unlock(&MasterSubKeys::derive(&master_key))?;
+        Ok(master_key)
+    }
+}
@@ -0,0 +1,87 @@
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{chacha::keys::Key, kdf::MasterSubKeys},
+    errors::HelixError,
+};
+
+use super::{
+    capsule_secret::CapsuleSecretManager, core::open_capsule, files::rewrap_file_keys,
+    manifest::ManifestManager, master_key::MasterKeyManager, metadata::CapsuleMetadata,
+    recipients::{rewrap_recipients, CapsuleIdentityManager},
+};
+
+/// Replaces the master key of a capsule.
+///
+/// Every file key, the capsule and chunk secrets, the capsule identity, the
+/// recipient entries, the manifest, the metadata key and the key slot of the given
+/// passphrase are rewrapped under a new master key in memory, and written with a
+/// single save of the metadata. An interrupted rotation leaves the capsule as it
+/// was and can simply be run again. Blocks are never rewritten, they are encrypted
+/// with the file keys, which do not change. A container is rewritten without the
+/// metadata under the old master key. The recovery code is replaced, the new one is
+/// returned. Other passphrase slots have to be removed first.
+pub struct HelixMasterKeyRotator {
+    metadata: CapsuleMetadata,
+    keyfile: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl HelixMasterKeyRotator {
+    pub fn open(capsule: &str) -> Result<Self, HelixError> {
+        let (metadata, _) = open_capsule(capsule)?;
+        Ok(Self {
+            metadata,
+            keyfile: None,
+        })
+    }
+
+    /// Keyfile of the slot the passphrase opens, the slot keeps needing it.
+    pub fn with_keyfile(mut self, keyfile: Option<&[u8]>) -> Self {
+        self.keyfile = keyfile.map(|keyfile| Zeroizing::new(keyfile.to_vec()));
+        self
+    }
+
+    pub fn rotate(
+        &mut self,
+        passphrase: &str,
+    ) -> Result<Option<Zeroizing<String>>, HelixError> {
+        let (old_master_key, slot) = self
+            .master_key_manager()?
+            .unlock(passphrase)?
+            .ok_or(HelixError::from(
+                "InvalidHelixCapsule",
+                "NoMasterKey",
+                "Master Key not found in db",
+            ))?;
+        self.master_key_manager()?.only_slot(&slot)?;
+        let new_master_key = Key::generate(old_master_key.suite());
+        let old_master_sub_keys = MasterSubKeys::derive(&old_master_key);
+        let new_master_sub_keys = MasterSubKeys::derive(&new_master_key);
+        self.metadata.unlock(&old_master_sub_keys)?;
+        let first_seal = self.metadata.needs_first_seal();
+        let connection = self.metadata.connection();
+        let manifest_manager = ManifestManager::from(connection);
+        manifest_manager.verify_or_create(&old_master_sub_keys, first_seal)?;
+
+        rewrap_file_keys(connection, &old_master_sub_keys, &new_master_sub_keys)?;
+        CapsuleSecretManager::from(connection).rewrap(&old_master_sub_keys, &new_master_sub_keys)?;
+        CapsuleSecretManager::for_chunks(connection)
+            .rewrap(&old_master_sub_keys, &new_master_sub_keys)?;
+        CapsuleIdentityManager::from(connection)
+            .rewrap(&old_master_sub_keys, &new_master_sub_keys)?;
+        rewrap_recipients(connection, &new_master_key)?;
+        manifest_manager.seal(&new_master_sub_keys);
+        let recovery_code = MasterKeyManager::from(connection)
+            .with_keyfile(self.keyfile.as_deref().map(Vec::as_slice))?
+            .replace_master_key(&slot, passphrase, &new_master_key)?;
+        self.metadata.rewrap(&new_master_sub_keys);
+        self.metadata.rewrite_container();
+        self.metadata.save()?;
+        Ok(recovery_code)
+    }
+
+    fn master_key_manager(&self) -> Result<MasterKeyManager<'_>, HelixError> {
+        MasterKeyManager::from(self.metadata.connection())
+            .with_keyfile(self.keyfile.as_deref().map(Vec::as_slice))
+    }
+}
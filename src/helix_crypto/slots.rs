@@ -0,0 +1,115 @@
+use zeroize::Zeroizing;
+
+use crate::{
+    crypto::{chacha::keys::Key, kdf::MasterSubKeys, shares::split},
+    errors::HelixError,
+};
+
+use super::{core::open_capsule, master_key::MasterKeyManager, metadata::CapsuleMetadata};
+
+/// Passphrase key slots of a capsule. Slots only wrap the master key, so adding or
+/// removing one never touches the encrypted files. Slots live in the clear part of
+/// the metadata, which the encrypted part authenticates, so every change unlocks the
+/// metadata to save it.
+pub struct HelixKeySlots {
+    metadata: CapsuleMetadata,
+    keyfile: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl HelixKeySlots {
+    pub fn open(capsule: &str) -> Result<Self, HelixError> {
+        let (metadata, _) = open_capsule(capsule)?;
+        Ok(Self {
+            metadata,
+            keyfile: None,
+        })
+    }
+
+    /// Keyfile of the slots the passphrases open, added slots need it as well.
+    pub fn with_keyfile(mut self, keyfile: Option<&[u8]>) -> Self {
+        self.keyfile = keyfile.map(|keyfile| Zeroizing::new(keyfile.to_vec()));
+        self
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        MasterKeyManager::from(self.metadata.connection()).slot_labels()
+    }
+
+    pub fn add(
+        &mut self,
+        passphrase: &str,
+        label: &str,
+        new_passphrase: &str,
+    ) -> Result<(), HelixError> {
+        let master_key_manager = self.master_key_manager()?;
+        let master_key = self.unlock(&master_key_manager, passphrase)?;
+        master_key_manager.add_slot(&master_key, label, new_passphrase)?;
+        self.save(&master_key)
+    }
+
+    pub fn change_passphrase(
+        &mut self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), HelixError> {
+        let master_key = self
+            .master_key_manager()?
+            .change_passphrase(old_passphrase, new_passphrase)?;
+        self.save(&master_key)
+    }
+
+    /// Sets the passphrase of the default slot with the recovery code printed when
+    /// the capsule was created.
+    pub fn recover(&mut self, recovery_code: &str, new_passphrase: &str) -> Result<(), HelixError> {
+        let master_key = self.master_key_manager()?.recover(recovery_code, new_passphrase)?;
+        self.save(&master_key)
+    }
+
+    /// Splits the master key into `count` shares, any `threshold` of which unlock the
+    /// capsule without a passphrase, see `crypto::shares`. Shares stay valid until the
+    /// master key is rotated.
+    pub fn split(
+        &mut self,
+        passphrase: &str,
+        threshold: u8,
+        count: u8,
+    ) -> Result<Vec<Zeroizing<String>>, HelixError> {
+        let master_key = self.unlock(&self.master_key_manager()?, passphrase)?;
+        // Unlocking may have upgraded the slot.
+        self.save(&master_key)?;
+        split(&master_key, threshold, count)
+    }
+
+    /// Any slot's passphrase may remove any other slot, like adding one.
+    pub fn remove(&mut self, passphrase: &str, label: &str) -> Result<(), HelixError> {
+        let master_key_manager = self.master_key_manager()?;
+        let master_key = self.unlock(&master_key_manager, passphrase)?;
+        master_key_manager.remove_slot(label)?;
+        self.save(&master_key)
+    }
+
+    fn save(&mut self, master_key: &Key) -> Result<(), HelixError> {
+        self.metadata.unlock(&MasterSubKeys::derive(master_key))?;
+        self.metadata.save()
+    }
+
+    fn master_key_manager(&self) -> Result<MasterKeyManager<'_>, HelixError> {
+        MasterKeyManager::from(self.metadata.connection())
+            .with_keyfile(self.keyfile.as_deref().map(Vec::as_slice))
+    }
+
+    fn unlock(
+        &self,
+        master_key_manager: &MasterKeyManager,
+        passphrase: &str,
+    ) -> Result<Key, HelixError> {
+        match master_key_manager.get(passphrase)? {
+            Some(key) => Ok(key),
+            None => Err(HelixError::from(
+                "InvalidHelixCapsule",
+                "NoMasterKey",
+                "Master Key not found in db",
+            )),
+        }
+    }
+}
@@ -14,11 +14,12 @@ use crate::{
 };
 
 pub struct File {
-    pub id: String, //hash of path only. PK. No other indentifier.
+    pub id: String, //keyed MAC of path only. PK. No other indentifier.
     pub key: String,
-    pub plain_hash: String,     //for reencryption
+    pub plain_hash: String,     //keyed, for reencryption
     pub encrypted_hash: String, //for integrity check as well as encryption duplication test
     pub file_path: String,      //for decryption
+    pub row_mac: String,        //binds the fields above to the master key
 }
 
 pub struct FileStore<'a> {
@@ -35,7 +36,7 @@ impl Clone for FileStore<'_> {
 
 impl Copy for FileStore<'_> {}
 
-type FileTuple = (String, String, String, String, String);
+type FileTuple = (String, String, String, String, String, String);
 
 impl<'a> FileStore<'a> {
     pub fn from(connection: &'a Connection) -> Self {
@@ -43,7 +44,7 @@ impl<'a> FileStore<'a> {
     }
 
     pub fn get_all(&self) -> Vec<File> {
-        let query = "SELECT * FROM files";
+        let query = "SELECT id, key, plain_hash, encrypted_hash, file_path, row_mac FROM files";
         let mut stmt = self.connection.prepare(query).unwrap();
         let files = stmt
             .query_map([], |row| {
@@ -53,6 +54,7 @@ impl<'a> FileStore<'a> {
                     plain_hash: row.get(2)?,
                     encrypted_hash: row.get(3)?,
                     file_path: row.get(4)?,
+                    row_mac: row.get(5)?,
                 })
             })
             .unwrap();
@@ -61,7 +63,8 @@ impl<'a> FileStore<'a> {
     }
 
     pub fn get(&self, id: &str) -> Option<File> {
-        let query = "SELECT * FROM files where id = ?1";
+        let query = "SELECT id, key, plain_hash, encrypted_hash, file_path, row_mac
+         FROM files where id = ?1";
         let mut stmt = self.connection.prepare(query).unwrap();
         let mut files = stmt
             .query_map([id], |row| {
@@ -71,6 +74,7 @@ impl<'a> FileStore<'a> {
                     plain_hash: row.get(2)?,
                     encrypted_hash: row.get(3)?,
                     file_path: row.get(4)?,
+                    row_mac: row.get(5)?,
                 })
             })
             .unwrap();
@@ -81,13 +85,38 @@ impl<'a> FileStore<'a> {
     }
 
     pub fn store(&self, file: File) {
-        let query = "INSERT INTO files values(?1,?2,?3,?4,?5)";
+        let query = "INSERT INTO files
+         (id, key, plain_hash, encrypted_hash, file_path, row_mac)
+         values(?1,?2,?3,?4,?5,?6)";
         let params: FileTuple = (
             file.id,
             file.key,
             file.plain_hash,
             file.encrypted_hash,
             file.file_path,
+            file.row_mac,
+        );
+        self.connection.execute(query, params).unwrap();
+    }
+
+    /// Moves a row to a new primary key and writes its fields, which may be bound to
+    /// the id.
+    pub fn change_id(&self, old_id: &str, file: &File) {
+        let query = "UPDATE files SET id = ?2,
+         key = ?3,
+         plain_hash = ?4,
+         encrypted_hash = ?5,
+         file_path = ?6,
+         row_mac = ?7
+         where id = ?1";
+        let params = (
+            old_id,
+            &file.id,
+            &file.key,
+            &file.plain_hash,
+            &file.encrypted_hash,
+            &file.file_path,
+            &file.row_mac,
         );
         self.connection.execute(query, params).unwrap();
     }
@@ -96,7 +125,8 @@ impl<'a> FileStore<'a> {
         let query = "UPDATE files SET key = ?2,
          plain_hash = ?3, 
          encrypted_hash = ?4,
-         file_path = ?5
+         file_path = ?5,
+         row_mac = ?6
          where id = ?1";
         let params: FileTuple = (
             file.id,
@@ -104,17 +134,31 @@ impl<'a> FileStore<'a> {
             file.plain_hash,
             file.encrypted_hash,
             file.file_path,
+            file.row_mac,
         );
         self.connection.execute(query, params).unwrap();
     }
 }
 
+/// One key slot. Every slot wraps the same master key under its own passphrase.
+/// A slot with `keyfile` set also needs the keyfile, its passphrase may be empty.
 #[derive(Debug)]
 pub struct MasterKey {
-    pub passphrase_digest: String,
+    pub id: i64,
+    pub label: String,
     pub master_key: String,
+    pub kdf: String,
+    pub kdf_salt: String,
+    pub kdf_memory_cost: u32,
+    pub kdf_time_cost: u32,
+    pub kdf_parallelism: u32,
+    pub keyfile: bool,
 }
 
+pub const DEFAULT_SLOT_LABEL: &str = "default";
+/// Slot wrapping the master key under the recovery code, see `crypto::recovery`.
+pub const RECOVERY_SLOT_LABEL: &str = "recovery";
+
 pub struct MasterKeyStore<'a> {
     connection: &'a Connection,
 }
@@ -124,36 +168,354 @@ impl<'a> MasterKeyStore<'a> {
         Self { connection }
     }
 
+    /// Adds a slot, the id is assigned by the database.
     pub fn insert(&self, master_key: MasterKey) {
-        let query = "INSERT INTO master_key values(?1,?2,?3)";
-        let params = (1, master_key.passphrase_digest, master_key.master_key);
+        let query = "INSERT INTO master_key
+         (label, master_key, kdf, kdf_salt, kdf_memory_cost, kdf_time_cost, kdf_parallelism, keyfile)
+         values(?1,?2,?3,?4,?5,?6,?7,?8)";
+        let params = (
+            master_key.label,
+            master_key.master_key,
+            master_key.kdf,
+            master_key.kdf_salt,
+            master_key.kdf_memory_cost,
+            master_key.kdf_time_cost,
+            master_key.kdf_parallelism,
+            master_key.keyfile,
+        );
         self.connection.execute(query, params).unwrap();
     }
 
-    fn update(self, master_key: MasterKey) {
-        let query = "UPDATE master_key SET passphrase_hash = ?2, master_key = ?3 where id = ?1";
-        let params = (1, master_key.passphrase_digest, master_key.master_key);
+    pub fn update(&self, master_key: MasterKey) {
+        let query = "UPDATE master_key SET master_key = ?2,
+         kdf = ?3,
+         kdf_salt = ?4,
+         kdf_memory_cost = ?5,
+         kdf_time_cost = ?6,
+         kdf_parallelism = ?7,
+         label = ?8,
+         keyfile = ?9
+         where id = ?1";
+        let params = (
+            master_key.id,
+            master_key.master_key,
+            master_key.kdf,
+            master_key.kdf_salt,
+            master_key.kdf_memory_cost,
+            master_key.kdf_time_cost,
+            master_key.kdf_parallelism,
+            master_key.label,
+            master_key.keyfile,
+        );
         self.connection.execute(query, params).unwrap();
     }
 
-    pub fn get(self) -> Option<MasterKey> {
-        let query = "SELECT * FROM master_key where id = ?1";
+    pub fn delete(&self, id: i64) {
+        let query = "DELETE FROM master_key where id = ?1";
+        self.connection.execute(query, [id]).unwrap();
+    }
+
+    pub fn get_all(&self) -> Vec<MasterKey> {
+        let query = "SELECT id, label, master_key, kdf, kdf_salt,
+         kdf_memory_cost, kdf_time_cost, kdf_parallelism, keyfile
+         FROM master_key ORDER BY id";
         let mut stmt = self.connection.prepare(query).unwrap();
-        let mut master_keys = stmt
-            .query_map([1], |row| {
+        let master_keys = stmt
+            .query_map([], |row| {
                 Ok(MasterKey {
-                    passphrase_digest: row.get(1)?,
+                    id: row.get(0)?,
+                    label: row.get(1)?,
                     master_key: row.get(2)?,
+                    kdf: row.get(3)?,
+                    kdf_salt: row.get(4)?,
+                    kdf_memory_cost: row.get(5)?,
+                    kdf_time_cost: row.get(6)?,
+                    kdf_parallelism: row.get(7)?,
+                    keyfile: row.get(8)?,
                 })
             })
             .unwrap();
-        match master_keys.next() {
-            Some(res) => Some(res.unwrap()),
-            None => None,
+        Vec::from_iter(master_keys.map(|data| data.unwrap()))
+    }
+
+    pub fn get_by_label(&self, label: &str) -> Option<MasterKey> {
+        self.get_all()
+            .into_iter()
+            .find(|master_key| master_key.label == label)
+    }
+}
+
+/// A public key recipient. `master_key` is the master key wrapped to it, so its
+/// identity unlocks the capsule like a passphrase does.
+#[derive(Debug)]
+pub struct RecipientRecord {
+    pub label: String,
+    pub public_key: String,
+    pub master_key: String,
+}
+
+pub struct RecipientStore<'a> {
+    connection: &'a Connection,
+}
+
+impl<'a> RecipientStore<'a> {
+    pub fn from(connection: &'a Connection) -> Self {
+        Self { connection }
+    }
+
+    pub fn insert(&self, recipient: RecipientRecord) {
+        let query = "INSERT INTO recipients (label, public_key, master_key) values(?1,?2,?3)";
+        let params = (recipient.label, recipient.public_key, recipient.master_key);
+        self.connection.execute(query, params).unwrap();
+    }
+
+    pub fn update(&self, recipient: RecipientRecord) {
+        let query = "UPDATE recipients SET public_key = ?2, master_key = ?3 where label = ?1";
+        let params = (recipient.label, recipient.public_key, recipient.master_key);
+        self.connection.execute(query, params).unwrap();
+    }
+
+    pub fn delete(&self, label: &str) -> bool {
+        let query = "DELETE FROM recipients where label = ?1";
+        self.connection.execute(query, [label]).unwrap() > 0
+    }
+
+    pub fn get_all(&self) -> Vec<RecipientRecord> {
+        let query = "SELECT label, public_key, master_key FROM recipients ORDER BY label";
+        let mut stmt = self.connection.prepare(query).unwrap();
+        let recipients = stmt
+            .query_map([], |row| {
+                Ok(RecipientRecord {
+                    label: row.get(0)?,
+                    public_key: row.get(1)?,
+                    master_key: row.get(2)?,
+                })
+            })
+            .unwrap();
+        Vec::from_iter(recipients.map(|data| data.unwrap()))
+    }
+}
+
+/// Reference counts of the chunks in the chunk store, see `helix_crypto::dedup`.
+/// A chunk is referenced once for every time it appears in a chunk list.
+pub struct ChunkStore<'a> {
+    connection: &'a Connection,
+}
+
+impl<'a> ChunkStore<'a> {
+    pub fn from(connection: &'a Connection) -> Self {
+        Self { connection }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        let query = "SELECT 1 FROM chunks where id = ?1";
+        let mut stmt = self.connection.prepare(query).unwrap();
+        stmt.exists([id]).unwrap()
+    }
+
+    pub fn add_reference(&self, id: &str) {
+        let query = "INSERT INTO chunks (id, ref_count) values(?1, 1)
+         ON CONFLICT(id) DO UPDATE SET ref_count = ref_count + 1";
+        self.connection.execute(query, [id]).unwrap();
+    }
+
+    pub fn remove_reference(&self, id: &str) {
+        let query = "UPDATE chunks SET ref_count = ref_count - 1 where id = ?1";
+        self.connection.execute(query, [id]).unwrap();
+    }
+
+    pub fn get_unreferenced(&self) -> Vec<String> {
+        let query = "SELECT id FROM chunks where ref_count <= 0";
+        let mut stmt = self.connection.prepare(query).unwrap();
+        let ids = stmt.query_map([], |row| row.get(0)).unwrap();
+        Vec::from_iter(ids.map(|data| data.unwrap()))
+    }
+
+    pub fn delete(&self, id: &str) {
+        let query = "DELETE FROM chunks where id = ?1";
+        self.connection.execute(query, [id]).unwrap();
+    }
+}
+
+/// Snapshot of the files table a capsule manifest covers, see `helix_crypto::manifest`.
+pub struct ManifestStore<'a> {
+    connection: &'a Connection,
+}
+
+impl<'a> ManifestStore<'a> {
+    pub fn from(connection: &'a Connection) -> Self {
+        Self { connection }
+    }
+
+    /// Ids and row MACs of the sealed rows, ordered by id.
+    pub fn get_all(&self) -> Vec<(String, String)> {
+        let query = "SELECT id, row_mac FROM manifest ORDER BY id";
+        let mut stmt = self.connection.prepare(query).unwrap();
+        let entries = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        Vec::from_iter(entries.map(|data| data.unwrap()))
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        let query = "SELECT 1 FROM manifest where id = ?1";
+        let mut stmt = self.connection.prepare(query).unwrap();
+        stmt.exists([id]).unwrap()
+    }
+
+    pub fn replace(&self, entries: &[(String, String)]) {
+        self.connection.execute("DELETE FROM manifest", ()).unwrap();
+        let query = "INSERT INTO manifest (id, row_mac) values(?1,?2)";
+        let mut stmt = self.connection.prepare(query).unwrap();
+        for (id, row_mac) in entries {
+            stmt.execute((id, row_mac)).unwrap();
         }
     }
 }
 
+/// Where a block lives inside a pack file, see `helix_crypto::packs`.
+#[derive(Debug, Clone)]
+pub struct PackedBlock {
+    pub block_name: String,
+    pub pack_id: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Pack index, the location of every packed block.
+pub struct PackedBlockStore<'a> {
+    connection: &'a Connection,
+}
+
+impl<'a> PackedBlockStore<'a> {
+    pub fn from(connection: &'a Connection) -> Self {
+        Self { connection }
+    }
+
+    pub fn get(&self, block_name: &str) -> Option<PackedBlock> {
+        let query = "SELECT block_name, pack_id, block_offset, block_length
+         FROM packed_blocks where block_name = ?1";
+        self.query(query, [block_name]).pop()
+    }
+
+    /// Blocks of a pack, ordered by offset.
+    pub fn get_pack(&self, pack_id: &str) -> Vec<PackedBlock> {
+        let query = "SELECT block_name, pack_id, block_offset, block_length
+         FROM packed_blocks where pack_id = ?1 ORDER BY block_offset";
+        self.query(query, [pack_id])
+    }
+
+    pub fn get_all(&self) -> Vec<PackedBlock> {
+        let query = "SELECT block_name, pack_id, block_offset, block_length
+         FROM packed_blocks ORDER BY pack_id, block_offset";
+        self.query(query, [])
+    }
+
+    pub fn contains_pack(&self, pack_id: &str) -> bool {
+        let query = "SELECT 1 FROM packed_blocks where pack_id = ?1";
+        let mut stmt = self.connection.prepare(query).unwrap();
+        stmt.exists([pack_id]).unwrap()
+    }
+
+    pub fn insert(&self, packed_block: &PackedBlock) {
+        let query = "INSERT INTO packed_blocks values(?1,?2,?3,?4)
+         ON CONFLICT(block_name) DO UPDATE SET pack_id = excluded.pack_id,
+         block_offset = excluded.block_offset, block_length = excluded.block_length";
+        self.connection
+            .execute(
+                query,
+                (
+                    &packed_block.block_name,
+                    &packed_block.pack_id,
+                    packed_block.offset as i64,
+                    packed_block.length as i64,
+                ),
+            )
+            .unwrap();
+    }
+
+    pub fn delete(&self, block_name: &str) {
+        let query = "DELETE FROM packed_blocks where block_name = ?1";
+        self.connection.execute(query, [block_name]).unwrap();
+    }
+
+    fn query<P: rusqlite::Params>(&self, query: &str, params: P) -> Vec<PackedBlock> {
+        let mut stmt = self.connection.prepare(query).unwrap();
+        let packed_blocks = stmt
+            .query_map(params, |row| {
+                Ok(PackedBlock {
+                    block_name: row.get(0)?,
+                    pack_id: row.get(1)?,
+                    offset: row.get::<_, i64>(2)? as u64,
+                    length: row.get::<_, i64>(3)? as u64,
+                })
+            })
+            .unwrap();
+        Vec::from_iter(packed_blocks.map(|data| data.unwrap()))
+    }
+}
+
+pub const CIPHER_SUITE_SETTING: &str = "cipher_suite";
+pub const PADDING_SETTING: &str = "padding";
+pub const COMPRESSION_SETTING: &str = "compression";
+pub const CHUNKING_SETTING: &str = "chunking";
+/// Random key of the chunk store, wrapped under a master subkey like the capsule secret.
+pub const CHUNK_SECRET_SETTING: &str = "chunk_secret";
+pub const CAPSULE_SECRET_SETTING: &str = "capsule_secret";
+/// The capsule's own X25519 identity, wrapped under a master subkey, and its public key.
+/// Every recipient-wrapped file key is also wrapped to it so the passphrase opens them.
+pub const CAPSULE_IDENTITY_SETTING: &str = "capsule_identity";
+pub const CAPSULE_RECIPIENT_SETTING: &str = "capsule_recipient";
+/// Keyed check value that lets an encrypt-only host verify its index key.
+pub const INDEX_KEY_CHECK_SETTING: &str = "index_key_check";
+/// Set once every `files.plain_hash` is keyed, see `CapsuleSubKeys::content_hash`.
+pub const PLAIN_HASH_SETTING: &str = "plain_hash";
+pub const KEYED_PLAIN_HASH: &str = "hmac-sha256";
+/// Generation and MAC of the capsule manifest, as `<generation>:<mac>`.
+pub const MANIFEST_SETTING: &str = "manifest";
+/// Target size of pack files in bytes, 0 when small blocks are not packed.
+pub const PACK_SIZE_SETTING: &str = "pack_size";
+/// Set by the migration that found rows older than row MACs, until the next unlock
+/// MACs them, see `files::mac_legacy_rows`.
+pub const LEGACY_ROWS_SETTING: &str = "legacy_rows";
+/// Set while rows may still have the plain SHA-256 of their path as id, see
+/// `CapsuleSubKeys::file_id`.
+pub const LEGACY_IDS_SETTING: &str = "legacy_ids";
+/// Nonce of the master key, kept from its v1 slot while files rows still have file
+/// keys wrapped in the v1 format, see `MasterSubKeys::with_legacy_nonce`.
+pub const LEGACY_KEY_NONCE_SETTING: &str = "legacy_key_nonce";
+/// Hash of the blocks a capsule container holds, see `CapsuleContainer::blocks_hash`.
+pub const CONTAINER_BLOCKS_SETTING: &str = "container_blocks";
+
+/// Capsule wide name/value settings.
+pub struct SettingStore<'a> {
+    connection: &'a Connection,
+}
+
+impl<'a> SettingStore<'a> {
+    pub fn from(connection: &'a Connection) -> Self {
+        Self { connection }
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        let query = "SELECT value FROM settings where name = ?1";
+        let mut stmt = self.connection.prepare(query).unwrap();
+        let mut values = stmt.query_map([name], |row| row.get(0)).unwrap();
+        values.next().map(|value| value.unwrap())
+    }
+
+    pub fn set(&self, name: &str, value: &str) {
+        let query = "INSERT INTO settings values(?1,?2)
+         ON CONFLICT(name) DO UPDATE SET value = excluded.value";
+        self.connection.execute(query, (name, value)).unwrap();
+    }
+
+    pub fn delete(&self, name: &str) {
+        let query = "DELETE FROM settings where name = ?1";
+        self.connection.execute(query, [name]).unwrap();
+    }
+}
+
 pub mod schema {
     use rusqlite::Connection;
 
@@ -169,21 +531,210 @@ pub mod schema {
         encrypted_hash TEXT NOT NULL,
         file_path TEXT NOT NULL);";
 
+    /// Applied in order on top of the tables above. The number of applied
+    /// migrations is tracked in `PRAGMA user_version`; only ever append here.
+    pub(super) const MIGRATIONS: &[&str] = &[
+        // Argon2id passphrase KDF. Existing rows keep the legacy KDF until next unlock.
+        "ALTER TABLE master_key ADD COLUMN kdf TEXT NOT NULL DEFAULT 'sha256-seed';
+         ALTER TABLE master_key ADD COLUMN kdf_salt TEXT NOT NULL DEFAULT '';
+         ALTER TABLE master_key ADD COLUMN kdf_memory_cost INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE master_key ADD COLUMN kdf_time_cost INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE master_key ADD COLUMN kdf_parallelism INTEGER NOT NULL DEFAULT 0;",
+        // The passphrase is verified by authenticating the wrapped master key instead.
+        "ALTER TABLE master_key DROP COLUMN passphrase_hash;",
+        "CREATE TABLE settings (
+         name TEXT NOT NULL PRIMARY KEY,
+         value TEXT NOT NULL);",
+        // HMAC of each files row under a master subkey, see `kdf::MasterSubKeys`.
+        "ALTER TABLE files ADD COLUMN row_mac TEXT NOT NULL DEFAULT '';",
+        // Key slots, the existing row becomes the default slot.
+        "ALTER TABLE master_key ADD COLUMN label TEXT NOT NULL DEFAULT 'default';
+         CREATE UNIQUE INDEX master_key_label ON master_key (label);",
+        "CREATE TABLE recipients (
+         label TEXT NOT NULL PRIMARY KEY,
+         public_key TEXT NOT NULL,
+         master_key TEXT NOT NULL);",
+        // Slots that also need a keyfile, see `passphrase::keyfile_secret`.
+        "ALTER TABLE master_key ADD COLUMN keyfile INTEGER NOT NULL DEFAULT 0;",
+        "CREATE TABLE chunks (
+         id TEXT NOT NULL PRIMARY KEY,
+         ref_count INTEGER NOT NULL);",
+        "CREATE TABLE manifest (
+         id TEXT NOT NULL PRIMARY KEY,
+         row_mac TEXT NOT NULL);",
+        "CREATE TABLE packed_blocks (
+         block_name TEXT NOT NULL PRIMARY KEY,
+         pack_id TEXT NOT NULL,
+         block_offset INTEGER NOT NULL,
+         block_length INTEGER NOT NULL);",
+        // Rows from before row MACs still have an empty one, marks them for the next unlock.
+        "INSERT INTO settings (name, value) SELECT 'legacy_rows', 'unauthenticated'
+         WHERE EXISTS (SELECT 1 FROM files WHERE row_mac = '');",
+        // Capsules that were never sealed get their first manifest on the next unlock.
+        "INSERT INTO settings (name, value) SELECT 'manifest_pending', 'unsealed'
+         WHERE EXISTS (SELECT 1 FROM master_key)
+         AND NOT EXISTS (SELECT 1 FROM settings WHERE name = 'manifest');",
+        // Anyone who can write the metadata could set it, the schema version it was
+        // written at tells instead, see `CapsuleMetadata::needs_first_seal`.
+        "DELETE FROM settings WHERE name = 'manifest_pending';",
+    ];
+
+    /// Schema version that added the manifest. Metadata written before it was never
+    /// sealed.
+    pub const MANIFEST_VERSION: usize = 9;
+
     pub struct HelixSchemaCreator;
 
     impl HelixSchemaCreator {
         pub fn create(connection: &Connection) {
+            Self::create_at(connection, Self::latest());
+        }
+
+        /// Creates the schema as it was after the first `version` migrations, e.g.
+        /// to load a snapshot taken at that version before migrating it.
+        pub fn create_at(connection: &Connection, version: usize) {
             connection.execute(MASTER_KEY, ()).unwrap();
             connection.execute(FILES, ()).unwrap();
+            Self::migrate(connection, version);
+        }
+
+        /// Schema version `create` migrates to.
+        pub fn latest() -> usize {
+            MIGRATIONS.len()
+        }
+
+        pub fn version(connection: &Connection) -> usize {
+            connection
+                .query_row("PRAGMA user_version", (), |row| row.get(0))
+                .unwrap()
+        }
+
+        fn migrate(connection: &Connection, target: usize) {
+            let version = Self::version(connection);
+            for (index, migration) in MIGRATIONS.iter().enumerate().take(target).skip(version) {
+                let batch = format!(
+                    "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+                    migration,
+                    index + 1
+                );
+                connection.execute_batch(&batch).unwrap();
+            }
         }
     }
 }
 
+/// Table rows as JSON, so metadata can be kept in an in-memory database and stored
+/// encrypted. A snapshot only holds data, loading one never runs SQL read from disk.
+pub mod snapshot {
+    use json::{object, JsonValue};
+    use rusqlite::{types::Value, Connection};
+
+    use crate::errors::HelixError;
+
+    /// Names of the tables in the schema.
+    pub fn table_names(connection: &Connection) -> Vec<String> {
+        let query = "SELECT name FROM sqlite_master
+         where type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY rowid";
+        let mut stmt = connection.prepare(query).unwrap();
+        let names = stmt.query_map([], |row| row.get(0)).unwrap();
+        Vec::from_iter(names.map(|data| data.unwrap()))
+    }
+
+    /// Rows of the given tables, as `{table: {columns: [..], rows: [[..], ..]}}`.
+    pub fn dump(connection: &Connection, tables: &[String]) -> JsonValue {
+        let mut snapshot = JsonValue::new_object();
+        for table in tables {
+            let query = format!("SELECT * FROM {}", quote(table));
+            let mut stmt = connection.prepare(&query).unwrap();
+            let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+            let mut rows = JsonValue::new_array();
+            let mut cursor = stmt.query([]).unwrap();
+            while let Some(row) = cursor.next().unwrap() {
+                let values = (0..columns.len()).map(|index| to_json(row.get(index).unwrap()));
+                rows.push(JsonValue::Array(values.collect())).unwrap();
+            }
+            snapshot[table.as_str()] = object! { columns: columns, rows: rows };
+        }
+        snapshot
+    }
+
+    /// Inserts the rows of a snapshot into tables of the schema it was taken from.
+    pub fn load(connection: &Connection, snapshot: &JsonValue) -> Result<(), HelixError> {
+        let tables = table_names(connection);
+        for (table, content) in snapshot.entries() {
+            if !tables.iter().any(|name| name == table) {
+                return Err(invalid_snapshot());
+            }
+            let columns: Vec<String> = content["columns"]
+                .members()
+                .map(|column| column.as_str().map(quote).ok_or_else(invalid_snapshot))
+                .collect::<Result<_, _>>()?;
+            let placeholders: Vec<String> =
+                (1..=columns.len()).map(|index| format!("?{}", index)).collect();
+            let query = format!(
+                "INSERT INTO {} ({}) values({})",
+                quote(table),
+                columns.join(","),
+                placeholders.join(",")
+            );
+            let mut stmt = connection.prepare(&query).map_err(|_| invalid_snapshot())?;
+            for row in content["rows"].members() {
+                let values: Vec<Value> = row.members().map(from_json).collect::<Result<_, _>>()?;
+                if values.len() != columns.len() {
+                    return Err(invalid_snapshot());
+                }
+                stmt.execute(rusqlite::params_from_iter(values))
+                    .map_err(|_| invalid_snapshot())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn quote(identifier: &str) -> String {
+        format!("\"{}\"", identifier.replace('"', "\"\""))
+    }
+
+    fn to_json(value: Value) -> JsonValue {
+        match value {
+            Value::Null => JsonValue::Null,
+            Value::Integer(integer) => integer.into(),
+            Value::Real(real) => real.into(),
+            Value::Text(text) => text.into(),
+            Value::Blob(blob) => object! { blob: hex::encode(blob) },
+        }
+    }
+
+    fn from_json(value: &JsonValue) -> Result<Value, HelixError> {
+        let value = match value {
+            JsonValue::Null => Value::Null,
+            JsonValue::Short(_) | JsonValue::String(_) => Value::Text(value.to_string()),
+            JsonValue::Number(_) => match value.as_i64() {
+                Some(integer) => Value::Integer(integer),
+                None => Value::Real(value.as_f64().unwrap()),
+            },
+            JsonValue::Object(_) => {
+                let blob = value["blob"].as_str().and_then(|blob| hex::decode(blob).ok());
+                Value::Blob(blob.ok_or_else(invalid_snapshot)?)
+            }
+            _ => return Err(invalid_snapshot()),
+        };
+        Ok(value)
+    }
+
+    fn invalid_snapshot() -> HelixError {
+        HelixError::from(
+            "MalformedData",
+            "InvalidMetadata",
+            "Capsule metadata could not be loaded",
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rusqlite::Connection;
 
-    use super::{schema::HelixSchemaCreator, MasterKeyStore};
+    use super::{schema::HelixSchemaCreator, snapshot, MasterKeyStore, SettingStore};
 
     #[test]
     fn create_schema_test() {
@@ -196,8 +747,15 @@ mod tests {
         let connection = Connection::open("../test.db").unwrap();
         let store = MasterKeyStore::from(&connection);
         store.insert(super::MasterKey {
-            passphrase_digest: String::from("hello"),
+            id: 0,
+            label: crate::util::uuid::generate(),
             master_key: String::from("world"),
+            kdf: String::from("argon2id"),
+            kdf_salt: String::from("salt"),
+            kdf_memory_cost: 1,
+            kdf_time_cost: 1,
+            kdf_parallelism: 1,
+            keyfile: false,
         });
     }
 
@@ -205,7 +763,53 @@ mod tests {
     fn get_master_key() {
         let connection = Connection::open("../test.db").unwrap();
         let store = MasterKeyStore::from(&connection);
-        let master_key = store.get();
-        print!("{:?}", master_key)
+        let master_keys = store.get_all();
+        print!("{:?}", master_keys)
+    }
+
+    #[test]
+    fn migrate_schema_test() {
+        let connection = Connection::open_in_memory().unwrap();
+        HelixSchemaCreator::create(&connection);
+        HelixSchemaCreator::create(&connection);
+        let version: usize = connection
+            .query_row("PRAGMA user_version", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, super::schema::MIGRATIONS.len());
+    }
+
+    #[test]
+    fn manifest_version_test() {
+        let has_manifest = |version| {
+            let connection = Connection::open_in_memory().unwrap();
+            HelixSchemaCreator::create_at(&connection, version);
+            snapshot::table_names(&connection).contains(&String::from("manifest"))
+        };
+        assert!(!has_manifest(super::schema::MANIFEST_VERSION - 1));
+        assert!(has_manifest(super::schema::MANIFEST_VERSION));
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let connection = Connection::open_in_memory().unwrap();
+        HelixSchemaCreator::create(&connection);
+        SettingStore::from(&connection).set("name", "it's quoted");
+        connection
+            .execute("INSERT INTO chunks (id, ref_count) values('chunk', -2)", ())
+            .unwrap();
+        let tables = snapshot::table_names(&connection);
+        let dumped = snapshot::dump(&connection, &tables).dump();
+
+        let restored = Connection::open_in_memory().unwrap();
+        HelixSchemaCreator::create(&restored);
+        snapshot::load(&restored, &json::parse(&dumped).unwrap()).unwrap();
+        assert_eq!(SettingStore::from(&restored).get("name").unwrap(), "it's quoted");
+        let ref_count: i64 = restored
+            .query_row("SELECT ref_count FROM chunks where id = 'chunk'", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(ref_count, -2);
+
+        let injected = json::object! { "settings\"; DROP TABLE files; --": { columns: [], rows: [] } };
+        assert!(snapshot::load(&restored, &injected).is_err());
     }
 }
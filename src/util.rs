@@ -27,11 +27,13 @@ pub(crate) mod uuid {
 pub(crate) mod hash {
 
     use sha2::{Sha256, Digest};
-use std::{io, fs};
+use std::io;
+
+use crate::fileio::mounts;
 
     pub fn hash_file(path: &str) -> String {
         let mut hasher = Sha256::new();
-        let mut file = fs::File::open(path).unwrap();
+        let mut file = mounts::open(path).unwrap();
         io::copy(&mut file, &mut hasher).unwrap();
         let hash_bytes = hasher.finalize();
         hex::encode(hash_bytes)